@@ -1,4 +1,8 @@
+use std::any::Any;
+
 use luminal::{
+    dispatch_dtype,
+    kernels::{binary_into, ViewReader},
     op::*,
     prelude::{petgraph::visit::EdgeRef, *},
 };
@@ -10,29 +14,37 @@ pub struct Sub;
 
 impl Operator for Sub {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let (a_data, b_data) = (get_vec(&tensors[0].0), get_vec(&tensors[1].0));
-        let (a_ind, a_val, b_ind, b_val) = (
-            tensors[0].1.index_expression(),
-            tensors[0].1.valid_expression(),
-            tensors[1].1.index_expression(),
-            tensors[1].1.valid_expression(),
+        let (a_data, b_data) = (
+            tensors[0].0.borrowed().as_elements::<f32>(),
+            tensors[1].0.borrowed().as_elements::<f32>(),
         );
         let mut data = vec![0.; tensors[0].1.n_elements().to_usize().unwrap()];
-        for i in 0..data.len() {
-            let lhs = if a_val.exec_single_var(i) != 0 {
-                a_data[a_ind.exec_single_var(i)]
-            } else {
-                0.0
-            };
-            let rhs = if b_val.exec_single_var(i) != 0 {
-                b_data[b_ind.exec_single_var(i)]
-            } else {
-                0.0
-            };
-            data[i] = lhs - rhs;
-        }
+        binary_into(
+            &ViewReader::new(&a_data, &tensors[0].1),
+            &ViewReader::new(&b_data, &tensors[1].1),
+            &mut data,
+            |a, b| a - b,
+        );
         vec![Tensor::new(data)]
     }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        // Replaces a multiply by an f32 constant, so always outputs f32
+        if key == "dtype" {
+            return Some(Box::new(DType::F32));
+        }
+        None
+    }
+}
+
+impl SerializeOp for Sub {
+    const NAME: &'static str = "CPUSub";
+    fn serialize_op(&self) -> OpAttributes {
+        OpAttributes::default()
+    }
+    fn deserialize_op(_: &OpAttributes, _: &Graph) -> Result<Self, String> {
+        Ok(Sub)
+    }
 }
 
 #[derive(Debug, Default)]
@@ -94,29 +106,38 @@ pub struct Equal;
 
 impl Operator for Equal {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let (a_data, b_data) = (get_vec(&tensors[0].0), get_vec(&tensors[1].0));
+        let (a, b) = (tensors[0].0.borrowed(), tensors[1].0.borrowed());
+        // Compare in the common type of both inputs so integers stay exact
+        let dtype = a.dtype().unwrap().promote(b.dtype().unwrap());
         let mut data = vec![0.; tensors[0].1.n_elements().to_usize().unwrap()];
-        let (a_ind, a_val, b_ind, b_val) = (
-            tensors[0].1.index_expression(),
-            tensors[0].1.valid_expression(),
-            tensors[1].1.index_expression(),
-            tensors[1].1.valid_expression(),
-        );
-        for i in 0..data.len() {
-            let a = if a_val.exec_single_var(i) != 0 {
-                a_data[a_ind.exec_single_var(i)]
-            } else {
-                0.0
-            };
-            let b = if b_val.exec_single_var(i) != 0 {
-                b_data[b_ind.exec_single_var(i)]
-            } else {
-                0.0
-            };
-            data[i] = if a < b { 1. } else { 0. };
-        }
+        dispatch_dtype!(dtype, T => {
+            let (a_data, b_data) = (a.as_elements::<T>(), b.as_elements::<T>());
+            binary_into(
+                &ViewReader::new(&a_data, &tensors[0].1),
+                &ViewReader::new(&b_data, &tensors[1].1),
+                &mut data,
+                |a, b| if a == b { 1. } else { 0. },
+            );
+        });
         vec![Tensor::new(data)]
     }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "dtype" {
+            return Some(Box::new(DType::F32));
+        }
+        None
+    }
+}
+
+impl SerializeOp for Equal {
+    const NAME: &'static str = "CPUEqual";
+    fn serialize_op(&self) -> OpAttributes {
+        OpAttributes::default()
+    }
+    fn deserialize_op(_: &OpAttributes, _: &Graph) -> Result<Self, String> {
+        Ok(Equal)
+    }
 }
 
 #[derive(Debug, Default)]
@@ -174,20 +195,60 @@ pub struct Gather {
 
 impl Operator for Gather {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        // Inp 1 should be Vec<f32> and inp 2 should be a CudaSlice<T>
-        let indexes = tensors[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
-        let weights = tensors[1].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+        // Integer indexes are used as-is so large ids stay exact
+        let indexes = tensors[0].0.borrowed();
+        let indexes = if let Some(ids) = indexes.as_slice::<i32>() {
+            ids.iter().map(|i| *i as usize).collect::<Vec<_>>()
+        } else {
+            indexes
+                .as_elements::<f32>()
+                .iter()
+                .map(|i| *i as usize)
+                .collect()
+        };
+        let weights = tensors[1].0.borrowed();
 
         let mut out = vec![0.; indexes.len() * self.embed_dim];
-        for token in 0..indexes.len() {
-            let e = indexes[token] as usize;
-            for dim in 0..self.embed_dim {
-                out[token * self.embed_dim + dim] = weights[e * self.embed_dim + dim];
+        // Only convert the gathered rows, so half precision weights stay half precision
+        dispatch_dtype!(weights.dtype().unwrap(), T => {
+            let weights = weights.as_slice::<T>().unwrap();
+            for (token, e) in indexes.iter().enumerate() {
+                for dim in 0..self.embed_dim {
+                    out[token * self.embed_dim + dim] = weights[e * self.embed_dim + dim].to_f32();
+                }
             }
-        }
+        });
 
         vec![Tensor::new(out)]
     }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        // Replaces a multiply by an f32 one-hot, so always outputs f32
+        if key == "dtype" {
+            return Some(Box::new(DType::F32));
+        }
+        None
+    }
+}
+
+impl SerializeOp for Gather {
+    const NAME: &'static str = "CPUGather";
+    fn serialize_op(&self) -> OpAttributes {
+        OpAttributes {
+            ints: vec![self.embed_dim as i64],
+            ..Default::default()
+        }
+    }
+    fn deserialize_op(attrs: &OpAttributes, _: &Graph) -> Result<Self, String> {
+        Ok(Gather {
+            embed_dim: attrs.int(0)? as usize,
+        })
+    }
+}
+
+/// An ARange op, or a folded constant counting up from zero
+fn is_arange(op: &dyn Operator) -> bool {
+    op.as_any().is::<ARange>() || is_folded_arange(op)
 }
 
 #[derive(Debug, Default)]
@@ -195,15 +256,18 @@ pub struct GatherCompiler;
 
 impl Compiler for GatherCompiler {
     type Output = ();
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         let indexes = node();
-        let eq = binary::<Equal>(indexes.clone(), op::<ARange>());
+        let mut arange = node();
+        arange.check(|op, _| is_arange(op));
+        let eq = binary::<Equal>(indexes.clone(), arange);
         let embedding = node();
         let mul = binary::<Mul>(embedding.clone(), eq.clone());
         let sum_reduce = unary::<SumReduce>(mul.clone());
         let mut s = sum_reduce.clone().search(graph);
         while s.next_match() {
-            if s.check_no_delete(&[embedding.id]) {
+            // The inputs are untouched and the output is replaced, so only intermediates matter
+            if s.check_no_delete(&[embedding.id, indexes.id, sum_reduce.id]) {
                 continue;
             }
             let emb_shape = graph
@@ -241,12 +305,9 @@ impl Compiler for GatherCompiler {
                 .input(s.get(&embedding), 0, emb_shape)
                 .finish();
             move_outgoing_edge(s.get(&sum_reduce), gather, &mut graph.graph);
+            remap(s.get(&sum_reduce), gather, &mut ids, graph);
             graph.remove_node(s.get(&sum_reduce));
             s.try_delete();
         }
     }
 }
-
-fn get_vec<'a>(tensor: &'a InputTensor<'a>) -> &'a Vec<f32> {
-    tensor.borrowed().downcast_ref::<Vec<f32>>().unwrap()
-}
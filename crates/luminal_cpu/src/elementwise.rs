@@ -0,0 +1,693 @@
+use std::any::Any;
+
+use itertools::Itertools;
+use petgraph::{visit::EdgeRef, Direction};
+use rustc_hash::FxHashSet;
+
+use luminal::{
+    dispatch_dtype,
+    kernels::{par_chunks, ViewReader},
+    op::{
+        Add, Constant, ConstantValue, Contiguous, InputTensor, LessThan, Mod, Mul, Operator, Sqrt,
+    },
+    prelude::*,
+};
+
+use crate::{
+    binary::{Equal, Sub},
+    export::rust_float,
+    storage_buffer::{CPUKernel, InPlaceKernel},
+    FusedUnary, Unary,
+};
+
+/// Fuse connected elementwise ops into single loops, so intermediate results are never written out
+#[derive(Debug, Default)]
+pub struct ElementwiseFusionCompiler;
+
+impl Compiler for ElementwiseFusionCompiler {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        let tracked = ids
+            .to_ids_mut()
+            .into_iter()
+            .map(|i| *i)
+            .collect::<FxHashSet<_>>();
+        // Read scalar constants straight from the kernel instead of through an input
+        for node in graph.graph.node_indices().collect_vec() {
+            inline_constants(graph, node, &tracked);
+        }
+        // Merge producers into their only consumer until nothing else can be fused
+        while let Some((producer, consumer)) = graph
+            .graph
+            .node_indices()
+            .find_map(|n| fusable_producer(graph, n).map(|p| (p, n)))
+        {
+            merge(graph, producer, consumer);
+            remap(producer, consumer, &mut ids, graph);
+            graph.graph.remove_node(producer);
+        }
+    }
+}
+
+/// An elementwise op that can run inside a fused loop
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ElementOp {
+    Contiguous,
+    Exp2,
+    Log2,
+    Recip,
+    Sin,
+    Sqrt,
+    Add,
+    Mul,
+    Mod,
+    LessThan,
+    Sub,
+    Equal,
+}
+
+impl ElementOp {
+    const ALL: [ElementOp; 12] = [
+        ElementOp::Contiguous,
+        ElementOp::Exp2,
+        ElementOp::Log2,
+        ElementOp::Recip,
+        ElementOp::Sin,
+        ElementOp::Sqrt,
+        ElementOp::Add,
+        ElementOp::Mul,
+        ElementOp::Mod,
+        ElementOp::LessThan,
+        ElementOp::Sub,
+        ElementOp::Equal,
+    ];
+
+    fn of(op: &dyn Any) -> Option<Self> {
+        if let Some(u) = Unary::of(op) {
+            return Some(Self::from(u));
+        }
+        if op.is::<Contiguous>() {
+            Some(ElementOp::Contiguous)
+        } else if op.is::<Sqrt>() {
+            Some(ElementOp::Sqrt)
+        } else if op.is::<Add>() {
+            Some(ElementOp::Add)
+        } else if op.is::<Mul>() {
+            Some(ElementOp::Mul)
+        } else if op.is::<Mod>() {
+            Some(ElementOp::Mod)
+        } else if op.is::<LessThan>() {
+            Some(ElementOp::LessThan)
+        } else if op.is::<Sub>() {
+            Some(ElementOp::Sub)
+        } else if op.is::<Equal>() {
+            Some(ElementOp::Equal)
+        } else {
+            None
+        }
+    }
+
+    fn arity(self) -> usize {
+        match self {
+            ElementOp::Contiguous
+            | ElementOp::Exp2
+            | ElementOp::Log2
+            | ElementOp::Recip
+            | ElementOp::Sin
+            | ElementOp::Sqrt => 1,
+            _ => 2,
+        }
+    }
+
+    /// Apply the op. Unary ops ignore `b`
+    fn apply(self, a: f32, b: f32) -> f32 {
+        match self {
+            ElementOp::Contiguous => a,
+            ElementOp::Exp2 => a.exp2(),
+            ElementOp::Log2 => a.log2(),
+            ElementOp::Recip => a.recip(),
+            ElementOp::Sin => a.sin(),
+            ElementOp::Sqrt => a.sqrt(),
+            ElementOp::Add => a + b,
+            ElementOp::Mul => a * b,
+            ElementOp::Mod => a % b,
+            ElementOp::LessThan => (a < b) as i32 as f32,
+            ElementOp::Sub => a - b,
+            ElementOp::Equal => (a == b) as i32 as f32,
+        }
+    }
+
+    /// Rust source applying the op to the source of its operands
+    fn rust_source(self, a: &str, b: &str) -> String {
+        match self {
+            ElementOp::Contiguous => a.to_string(),
+            ElementOp::Exp2 => format!("{a}.exp2()"),
+            ElementOp::Log2 => format!("{a}.log2()"),
+            ElementOp::Recip => format!("{a}.recip()"),
+            ElementOp::Sin => format!("{a}.sin()"),
+            ElementOp::Sqrt => format!("{a}.sqrt()"),
+            ElementOp::Add => format!("{a} + {b}"),
+            ElementOp::Mul => format!("{a} * {b}"),
+            ElementOp::Mod => format!("{a} % {b}"),
+            ElementOp::LessThan => format!("({a} < {b}) as i32 as f32"),
+            ElementOp::Sub => format!("{a} - {b}"),
+            ElementOp::Equal => format!("({a} == {b}) as i32 as f32"),
+        }
+    }
+}
+
+impl From<Unary> for ElementOp {
+    fn from(u: Unary) -> Self {
+        match u {
+            Unary::Exp2 => ElementOp::Exp2,
+            Unary::Log2 => ElementOp::Log2,
+            Unary::Recip => ElementOp::Recip,
+            Unary::Sin => ElementOp::Sin,
+        }
+    }
+}
+
+/// Where a fused step reads a value from
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operand {
+    /// An input of the fused op, read through its own view
+    Input(usize),
+    /// The result of an earlier step for the same element
+    Step(usize),
+    Constant(f32),
+}
+
+/// A connected subgraph of elementwise ops, ran as a single loop over the output. Each step reads
+/// inputs, constants or earlier steps, and the last step is the output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FusedElementwise {
+    steps: Vec<(ElementOp, Vec<Operand>)>,
+}
+
+impl FusedElementwise {
+    /// Get the fused form of an elementwise op
+    pub(crate) fn of(op: &dyn Operator) -> Option<Self> {
+        let op = op.as_any();
+        if let Some(fused) = op.downcast_ref::<FusedElementwise>() {
+            Some(fused.clone())
+        } else if let Some(FusedUnary(unaries)) = op.downcast_ref::<FusedUnary>() {
+            Some(FusedElementwise {
+                steps: unaries
+                    .iter()
+                    .enumerate()
+                    .map(|(i, u)| {
+                        let arg = if i == 0 {
+                            Operand::Input(0)
+                        } else {
+                            Operand::Step(i - 1)
+                        };
+                        (ElementOp::from(*u), vec![arg])
+                    })
+                    .collect(),
+            })
+        } else {
+            ElementOp::of(op).map(|e| FusedElementwise {
+                steps: vec![(e, (0..e.arity()).map(Operand::Input).collect())],
+            })
+        }
+    }
+
+    /// Run every step for one element, given the values of each input at that element
+    fn eval(&self, inputs: &[f32], steps: &mut [f32]) -> f32 {
+        for (s, (op, args)) in self.steps.iter().enumerate() {
+            let get = |o: &Operand| match *o {
+                Operand::Input(i) => inputs[i],
+                Operand::Step(i) => steps[i],
+                Operand::Constant(c) => c,
+            };
+            let (a, b) = (get(&args[0]), args.get(1).map(get).unwrap_or_default());
+            steps[s] = op.apply(a, b);
+        }
+        steps[steps.len() - 1]
+    }
+
+    /// Rust source for the statements computing element `i` from the inputs `a0`, `a1`, ..., ending
+    /// with the output
+    pub(crate) fn rust_source(&self) -> String {
+        let mut src = String::new();
+        for (s, (op, args)) in self.steps.iter().enumerate() {
+            let args = args
+                .iter()
+                .map(|o| match *o {
+                    Operand::Input(i) => format!("a{i}[i]"),
+                    Operand::Step(i) => format!("s{i}"),
+                    Operand::Constant(c) => rust_float(c),
+                })
+                .collect_vec();
+            let b = args.get(1).map_or("", |b| b.as_str());
+            src += &format!("let s{s} = {}; ", op.rust_source(&args[0], b));
+        }
+        src + &format!("s{}", self.steps.len() - 1)
+    }
+
+    /// Compute every output element. The input `in_place` is already held in `out`
+    fn run(&self, inputs: &[(&[f32], ShapeTracker)], in_place: Option<usize>, out: &mut [f32]) {
+        let readers = inputs
+            .iter()
+            .map(|(data, sh)| ViewReader::new(data, sh))
+            .collect_vec();
+        let cost = readers.len() + self.steps.len();
+        par_chunks(out, 1, cost, |start, out| {
+            let mut bufs = vec![vec![]; readers.len()];
+            let inputs = readers
+                .iter()
+                .zip(&mut bufs)
+                .enumerate()
+                .map(|(k, (r, buf))| {
+                    if Some(k) == in_place {
+                        &[][..]
+                    } else {
+                        r.read(start, out.len(), buf)
+                    }
+                })
+                .collect_vec();
+            let mut values = vec![0.; inputs.len()];
+            let mut steps = vec![0.; self.steps.len()];
+            for (i, o) in out.iter_mut().enumerate() {
+                for (k, inp) in inputs.iter().enumerate() {
+                    values[k] = if Some(k) == in_place { *o } else { inp[i] };
+                }
+                *o = self.eval(&values, &mut steps);
+            }
+        });
+    }
+}
+
+impl Operator for FusedElementwise {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let data = inp
+            .iter()
+            .map(|(t, _)| t.borrowed().as_elements::<f32>())
+            .collect_vec();
+        let inputs = data
+            .iter()
+            .zip(&inp)
+            .map(|(d, (_, sh))| (d.as_ref(), *sh))
+            .collect_vec();
+        let mut out = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        self.run(&inputs, None, &mut out);
+        // Computed in f32, but stored as the common type of the inputs
+        let dtype = inp
+            .iter()
+            .filter_map(|(t, _)| t.borrowed().dtype())
+            .reduce(DType::promote)
+            .unwrap_or_default();
+        if dtype == DType::F32 {
+            return vec![Tensor::new(out)];
+        }
+        dispatch_dtype!(dtype, T => vec![Tensor::new(out.into_iter().map(T::from_f32).collect::<Vec<_>>())])
+    }
+}
+
+impl CPUKernel for FusedElementwise {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+        vec![input_shapes[0].n_elements()]
+    }
+    fn process_into(&self, inputs: &[(&[f32], ShapeTracker)], outputs: &mut [&mut [f32]]) {
+        self.run(inputs, None, outputs[0]);
+    }
+    fn in_place(&self) -> Option<&dyn InPlaceKernel> {
+        Some(self)
+    }
+}
+
+impl InPlaceKernel for FusedElementwise {
+    fn process_in_place(&self, ind: usize, buffer: &mut [f32], inputs: &[(&[f32], ShapeTracker)]) {
+        self.run(inputs, Some(ind), buffer);
+    }
+}
+
+impl SerializeOp for FusedElementwise {
+    const NAME: &'static str = "CPUFusedElementwise";
+    /// Each step is stored as its op name, with a (kind, value) int pair per operand
+    fn serialize_op(&self) -> OpAttributes {
+        let mut attrs = OpAttributes::default();
+        for (op, args) in &self.steps {
+            attrs.strings.push(format!("{op:?}"));
+            for arg in args {
+                let (kind, value) = match *arg {
+                    Operand::Input(i) => (0, i),
+                    Operand::Step(i) => (1, i),
+                    Operand::Constant(c) => {
+                        attrs.floats.push(c);
+                        (2, attrs.floats.len() - 1)
+                    }
+                };
+                attrs.ints.extend([kind, value as i64]);
+            }
+        }
+        attrs
+    }
+    fn deserialize_op(attrs: &OpAttributes, _: &Graph) -> Result<Self, String> {
+        let mut ints = 0;
+        let mut steps = vec![];
+        for s in &attrs.strings {
+            let op = ElementOp::ALL
+                .into_iter()
+                .find(|o| format!("{o:?}") == *s)
+                .ok_or_else(|| format!("Unknown elementwise op {s}"))?;
+            let mut args = vec![];
+            for _ in 0..op.arity() {
+                let value = attrs.int(ints + 1)? as usize;
+                args.push(match attrs.int(ints)? {
+                    0 => Operand::Input(value),
+                    1 if value < steps.len() => Operand::Step(value),
+                    2 => Operand::Constant(attrs.float(value)?),
+                    k => return Err(format!("Invalid operand ({k}, {value})")),
+                });
+                ints += 2;
+            }
+            steps.push((op, args));
+        }
+        if steps.is_empty() {
+            return Err("Fused elementwise op has no steps".to_string());
+        }
+        Ok(FusedElementwise { steps })
+    }
+}
+
+/// Are all of this node's inputs f32 data?
+fn f32_inputs(graph: &Graph, node: NodeIndex) -> bool {
+    graph
+        .graph
+        .edges_directed(node, Direction::Incoming)
+        .all(|e| e.weight().dtype() == Some(DType::F32))
+}
+
+/// The value of a float constant that's the same at every element of the view
+fn scalar_constant(graph: &Graph, node: NodeIndex, shape: &ShapeTracker) -> Option<f32> {
+    if shape.is_padded() || shape.is_sliced() {
+        return None;
+    }
+    match graph
+        .graph
+        .node_weight(node)?
+        .as_any()
+        .downcast_ref::<Constant>()?
+        .0
+    {
+        ConstantValue::Float(f) => Some(f),
+        _ => None,
+    }
+}
+
+/// Replace the op and incoming edges of a node, merging inputs that read the same tensor the same way
+fn set_inputs(
+    graph: &mut Graph,
+    node: NodeIndex,
+    mut fused: FusedElementwise,
+    srcs: Vec<(NodeIndex, u8, ShapeTracker)>,
+) {
+    let mut unique = vec![];
+    let map = srcs
+        .into_iter()
+        .map(|s| {
+            unique.iter().position(|u| *u == s).unwrap_or_else(|| {
+                unique.push(s);
+                unique.len() - 1
+            })
+        })
+        .collect_vec();
+    for (_, args) in &mut fused.steps {
+        for arg in args {
+            if let Operand::Input(i) = arg {
+                *i = map[*i];
+            }
+        }
+    }
+    for edge in graph
+        .graph
+        .edges_directed(node, Direction::Incoming)
+        .filter(|e| !e.weight().is_schedule())
+        .map(|e| e.id())
+        .collect_vec()
+    {
+        graph.graph.remove_edge(edge);
+    }
+    for (i, (src, output_order, shape)) in unique.into_iter().enumerate() {
+        graph.graph.add_edge(
+            src,
+            node,
+            Dependency::Data {
+                input_order: i as u8,
+                output_order,
+                shape,
+                dtype: DType::F32,
+            },
+        );
+    }
+    *graph.graph.node_weight_mut(node).unwrap() = Box::new(fused);
+}
+
+/// Read scalar constant inputs of an elementwise op from inside its kernel
+fn inline_constants(graph: &mut Graph, node: NodeIndex, tracked: &FxHashSet<NodeIndex>) {
+    let Some(mut fused) = FusedElementwise::of(graph.graph.node_weight(node).unwrap().as_ref())
+    else {
+        return;
+    };
+    if !f32_inputs(graph, node) {
+        return;
+    }
+    let srcs = graph.get_sources(node);
+    let mut constants = srcs
+        .iter()
+        .map(|(src, _, sh)| scalar_constant(graph, *src, sh))
+        .collect_vec();
+    // Keep at least one real input, which sets the number of elements
+    if constants.iter().all(|c| c.is_some()) {
+        constants[0] = None;
+    }
+    if constants.iter().all(|c| c.is_none()) {
+        return;
+    }
+    let mut remaining = vec![];
+    let map = srcs
+        .iter()
+        .zip(&constants)
+        .map(|(s, c)| match c {
+            Some(c) => Operand::Constant(*c),
+            None => {
+                remaining.push(*s);
+                Operand::Input(remaining.len() - 1)
+            }
+        })
+        .collect_vec();
+    for (_, args) in &mut fused.steps {
+        for arg in args {
+            if let Operand::Input(i) = arg {
+                *arg = map[*i];
+            }
+        }
+    }
+    set_inputs(graph, node, fused, remaining);
+    // Drop constants nothing else reads
+    for (src, _, _) in srcs
+        .into_iter()
+        .zip(constants)
+        .filter_map(|(s, c)| c.map(|_| s))
+    {
+        if graph.graph.contains_node(src)
+            && graph
+                .graph
+                .edges_directed(src, Direction::Outgoing)
+                .next()
+                .is_none()
+            && !graph.no_delete.contains(&src)
+            && !tracked.contains(&src)
+        {
+            graph.graph.remove_node(src);
+        }
+    }
+}
+
+/// Find an elementwise input of this elementwise op that can be computed inside its loop
+fn fusable_producer(graph: &Graph, consumer: NodeIndex) -> Option<NodeIndex> {
+    FusedElementwise::of(graph.graph.node_weight(consumer)?.as_ref())?;
+    if !f32_inputs(graph, consumer) {
+        return None;
+    }
+    graph
+        .get_sources(consumer)
+        .into_iter()
+        .map(|(src, _, _)| src)
+        .unique()
+        .find(|&src| {
+            src != consumer
+                && !graph.no_delete.contains(&src)
+                && !graph.to_retrieve.contains_key(&src)
+                && FusedElementwise::of(graph.graph.node_weight(src).unwrap().as_ref()).is_some()
+                && f32_inputs(graph, src)
+                // Only read by this op, at the same element it's computed for
+                && graph
+                    .graph
+                    .edges_directed(src, Direction::Outgoing)
+                    .all(|e| {
+                        e.target() == consumer
+                            && e.weight().dtype() == Some(DType::F32)
+                            && e.weight().as_data().is_some_and(|(_, _, sh)| !sh.is_reshaped())
+                    })
+        })
+}
+
+/// Fuse the producer's steps into the consumer, which reads them through identity views
+fn merge(graph: &mut Graph, producer: NodeIndex, consumer: NodeIndex) {
+    let p = FusedElementwise::of(graph.graph.node_weight(producer).unwrap().as_ref()).unwrap();
+    let c = FusedElementwise::of(graph.graph.node_weight(consumer).unwrap().as_ref()).unwrap();
+    let mut srcs = vec![];
+    let consumer_map = graph
+        .get_sources(consumer)
+        .into_iter()
+        .map(|s| {
+            if s.0 == producer {
+                Operand::Step(p.steps.len() - 1)
+            } else {
+                srcs.push(s);
+                Operand::Input(srcs.len() - 1)
+            }
+        })
+        .collect_vec();
+    let producer_map = graph
+        .get_sources(producer)
+        .into_iter()
+        .map(|s| {
+            srcs.push(s);
+            Operand::Input(srcs.len() - 1)
+        })
+        .collect_vec();
+    let offset = p.steps.len();
+    let steps = p
+        .steps
+        .into_iter()
+        .map(|(op, args)| {
+            let args = args
+                .into_iter()
+                .map(|a| match a {
+                    Operand::Input(i) => producer_map[i],
+                    a => a,
+                })
+                .collect();
+            (op, args)
+        })
+        .chain(c.steps.into_iter().map(|(op, args)| {
+            let args = args
+                .into_iter()
+                .map(|a| match a {
+                    Operand::Input(i) => consumer_map[i],
+                    Operand::Step(i) => Operand::Step(i + offset),
+                    a => a,
+                })
+                .collect();
+            (op, args)
+        }))
+        .collect();
+    set_inputs(graph, consumer, FusedElementwise { steps }, srcs);
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::prelude::*;
+
+    use super::{ElementwiseFusionCompiler, FusedElementwise};
+    use crate::CPUCompiler;
+    luminal::test_imports!();
+
+    fn fused_ops(cx: &Graph) -> usize {
+        cx.graph
+            .node_weights()
+            .filter(|op| op.as_any().is::<FusedElementwise>())
+            .count()
+    }
+
+    #[test]
+    fn test_swish() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<3, 5>>().set(random_vec(15));
+        let mut b = a.swish().retrieve();
+        cx.execute();
+        let unoptimized = b.data();
+        b.drop();
+
+        cx.compile(
+            (GenericCompiler::default(), ElementwiseFusionCompiler),
+            &mut b,
+        );
+        // The whole activation runs in one loop, with the constants read inline
+        assert_eq!(fused_ops(&cx), 1);
+        assert!(!cx
+            .graph
+            .node_weights()
+            .any(|op| op.as_any().is::<luminal::op::Constant>()));
+        cx.execute();
+        assert_close(&b.data(), &unoptimized);
+    }
+
+    #[test]
+    fn test_rms_norm_scaling() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<4, 6>>().set(random_vec(24));
+        let w = cx.tensor::<R1<6>>().set(random_vec(6));
+        let mut b = (a.std_norm::<LAxis<1>, _>(1e-5) * w.expand::<R2<4, 6>, _>())
+            .swish()
+            .retrieve();
+        cx.execute();
+        let unoptimized = b.data();
+        b.drop();
+
+        cx.compile(
+            (GenericCompiler::default(), CPUCompiler::<f32>::default()),
+            &mut b,
+        );
+        assert!(fused_ops(&cx) > 0);
+        cx.execute();
+        assert_close(&b.data(), &unoptimized);
+    }
+
+    #[test]
+    fn test_rotary() {
+        let mut cx = Graph::new();
+        let x = cx.tensor::<R2<4, 8>>().set(random_vec(32));
+        let cos = cx.tensor::<R1<8>>().set(random_vec(8));
+        let sin = cx.tensor::<R1<8>>().set(random_vec(8));
+        let x1 = x.slice((.., ..Expression::from(4))).realize::<R2<4, 4>>();
+        let x2 = x.slice((.., Expression::from(4)..)).realize::<R2<4, 4>>();
+        let rotated = (-x2).concat_along::<R2<4, 8>, LAxis<1>, _>(x1);
+        let mut out = (x * cos.expand::<R2<4, 8>, _>() + rotated * sin.expand())
+            .exp2()
+            .retrieve();
+        cx.execute();
+        let unoptimized = out.data();
+        out.drop();
+
+        let before = cx.graph.node_count();
+        cx.compile(
+            (GenericCompiler::default(), CPUCompiler::<f32>::default()),
+            &mut out,
+        );
+        assert!(fused_ops(&cx) > 0);
+        assert!(cx.graph.node_count() < before);
+        cx.execute();
+        assert_close(&out.data(), &unoptimized);
+    }
+
+    #[test]
+    fn test_shared_input() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<3, 3>>().set(random_vec(9));
+        let b = a.exp2();
+        // b is read twice, and a is read through two different views
+        let mut c = (b * b + a.sin() + a.permute::<_, LAxes2<1, 0>>().sqrt()).retrieve();
+        cx.execute();
+        let unoptimized = c.data();
+        c.drop();
+
+        cx.compile(ElementwiseFusionCompiler, &mut c);
+        assert_eq!(fused_ops(&cx), 1);
+        cx.execute();
+        assert_close(&c.data(), &unoptimized);
+    }
+}
@@ -0,0 +1,539 @@
+//! Exporting compiled graphs as standalone Rust source
+
+use std::fmt::{Display, Write};
+
+use itertools::Itertools;
+use petgraph::Direction;
+use rustc_hash::FxHashSet;
+
+use luminal::{
+    op::{Constant, ConstantValue, Function, MaxReduce, Operator, SumReduce},
+    prelude::*,
+};
+
+use crate::{
+    binary::Gather,
+    matmul::{BatchedMatMul2D, MatMul2D},
+    other::ARange,
+    FusedElementwise,
+};
+
+type OpResult<T> = Result<T, String>;
+
+/// A graph exported as a Rust module, along with the weights it loads
+#[derive(Debug, Clone, PartialEq)]
+pub struct RustModule {
+    /// Source defining a `Model` struct, built with `Model::new(&weights)` and ran with `forward`
+    pub source: String,
+    /// The weights as little endian f32s, in the order they're loaded
+    pub weights: Vec<u8>,
+}
+
+/// The parts of a graph that couldn't be exported, in graph order. Nodes that only failed because an
+/// earlier node did aren't listed
+#[derive(Debug)]
+pub struct ExportError(pub Vec<UnsupportedNode>);
+
+impl Display for ExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unsupported nodes:")?;
+        for node in &self.0 {
+            write!(f, "\n  {node}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+/// Helpers the generated module calls into
+const RUNTIME: &str = r#"
+/// Read the logical elements of a view over some data
+#[allow(dead_code)]
+fn view(data: &[f32], n: i64, index: impl Fn(i64) -> i64, valid: impl Fn(i64) -> i64) -> Vec<f32> {
+    (0..n)
+        .map(|z| if valid(z) != 0 { data[index(z) as usize] } else { 0. })
+        .collect()
+}
+
+/// Fold the middle dim of a [front, dim, back] tensor
+#[allow(dead_code)]
+fn reduce(a: &[f32], front: i64, dim: i64, back: i64, init: f32, f: impl Fn(f32, f32) -> f32) -> Vec<f32> {
+    let (dim, back) = (dim as usize, back as usize);
+    let mut out = vec![init; front as usize * back];
+    for (i, o) in out.iter_mut().enumerate() {
+        let start = (i / back) * dim * back + i % back;
+        for r in 0..dim {
+            *o = f(*o, a[start + r * back]);
+        }
+    }
+    out
+}
+
+/// Multiply each [m, k] matrix of a batch by a shared [k, n] matrix
+#[allow(dead_code)]
+fn matmul(a: &[f32], b: &[f32], batch: i64, m: i64, k: i64, n: i64) -> Vec<f32> {
+    let (m, k, n) = (m as usize, k as usize, n as usize);
+    let mut out = vec![0.; batch as usize * m * n];
+    for (row, out) in out.chunks_mut(n.max(1)).enumerate() {
+        let a = &a[row * k..][..k];
+        for (i, a) in a.iter().enumerate() {
+            for (o, b) in out.iter_mut().zip(&b[i * n..][..n]) {
+                *o += a * b;
+            }
+        }
+    }
+    out
+}
+
+/// Copy out the rows of an embedding table
+#[allow(dead_code)]
+fn gather(ids: &[f32], table: &[f32], dim: usize) -> Vec<f32> {
+    ids.iter()
+        .flat_map(|i| &table[*i as usize * dim..][..dim])
+        .copied()
+        .collect()
+}
+"#;
+
+/// Export a graph compiled with the [`CPUCompiler`](crate::CPUCompiler) as a Rust module depending
+/// only on the standard library. `forward` takes the `inputs` in order, along with a `usize` per dyn
+/// dim in alphabetical order, and returns the `outputs` in order. Other loaded tensors become weights.
+///
+/// Every tensor must be f32. Static shapes are baked in as constants, and dyn dims are computed from
+/// the expressions the graph uses.
+pub fn export_rust<I: ToIds, O: ToIds>(
+    graph: &mut Graph,
+    inputs: I,
+    outputs: O,
+) -> Result<RustModule, ExportError> {
+    let (inputs, outputs) = (inputs.to_ids(), outputs.to_ids());
+    let order = graph.schedule().to_vec();
+    // Only export what the outputs depend on
+    let mut needed = FxHashSet::default();
+    let mut stack = outputs.clone();
+    while let Some(node) = stack.pop() {
+        if needed.insert(node) {
+            stack.extend(graph.get_sources(node).into_iter().map(|(s, _, _)| s));
+        }
+    }
+
+    let mut unsupported = vec![];
+    let mut failed = FxHashSet::default();
+    let mut body = String::new();
+    let mut weights = vec![];
+    let mut weight_sizes = vec![];
+    let mut dyn_dims = FxHashSet::default();
+    for (node, sources) in order.iter().filter(|(n, _)| needed.contains(n)) {
+        if sources.iter().any(|(s, _, _)| failed.contains(s)) {
+            failed.insert(*node);
+            continue;
+        }
+        let dtype = graph.node_dtype(*node);
+        let input = inputs.iter().position(|i| i == node);
+        let load = is_load(graph, *node);
+        let op_type = match (input, load) {
+            (Some(_), _) => "Input".to_string(),
+            (None, true) => "Weight".to_string(),
+            _ => format!("{:?}", graph.graph.node_weight(*node).unwrap()),
+        };
+        let result = if dtype != DType::F32 {
+            Err(format!("{dtype:?} tensors can't be exported"))
+        } else if let Some(i) = input {
+            Ok(format!("inputs[{i}]"))
+        } else if load {
+            // Unset loads produce nothing
+            let tensor = match graph.get_tensor_ref(*node, 0) {
+                Some(t) => Some(t.clone()),
+                None => graph
+                    .graph
+                    .node_weight_mut(*node)
+                    .unwrap()
+                    .process(vec![])
+                    .into_iter()
+                    .next(),
+            };
+            match tensor {
+                Some(tensor) => {
+                    let data = tensor.as_elements::<f32>();
+                    weights.extend(data.iter().flat_map(|f| f.to_le_bytes()));
+                    weight_sizes.push(data.len());
+                    Ok(format!("&self.weights[{}][..]", weight_sizes.len() - 1))
+                }
+                None => Err("weight has no value and isn't an input".to_string()),
+            }
+        } else {
+            node_source(graph.graph.node_weight(*node).unwrap().as_ref(), sources)
+        };
+        match result {
+            Ok(src) => {
+                for (_, _, sh) in sources {
+                    dyn_dims.extend(symbols(sh));
+                }
+                writeln!(body, "        let t{} = {src};", node.index()).unwrap();
+            }
+            Err(reason) => {
+                unsupported.push(UnsupportedNode {
+                    name: node.index().to_string(),
+                    op_type,
+                    reason,
+                });
+                failed.insert(*node);
+            }
+        }
+    }
+
+    let mut results = vec![];
+    for output in &outputs {
+        if failed.contains(output) {
+            continue;
+        }
+        // Retrieved tensors are read through their own shape
+        match graph.to_retrieve.get(output) {
+            Some((_, sh)) if sh.is_reshaped() => {
+                dyn_dims.extend(symbols(sh));
+                results.push(view_source(&format!("t{}", output.index()), sh));
+            }
+            _ => results.push(format!("t{}.to_vec()", output.index())),
+        }
+    }
+    if !unsupported.is_empty() {
+        return Err(ExportError(unsupported));
+    }
+
+    let dyn_dims = dyn_dims.into_iter().sorted().collect_vec();
+    let mut source = String::new();
+    writeln!(source, "// Generated by luminal. Do not edit.").unwrap();
+    source += RUNTIME;
+    write!(
+        source,
+        r#"
+pub struct Model {{
+    weights: Vec<Vec<f32>>,
+}}
+
+impl Model {{
+    /// The number of f32s in each weight
+    pub const WEIGHT_SIZES: [usize; {n_weights}] = {weight_sizes:?};
+
+    /// Load the weights from little endian f32s
+    pub fn new(weights: &[u8]) -> Self {{
+        let mut weights = weights
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        Self {{
+            weights: Self::WEIGHT_SIZES
+                .iter()
+                .map(|n| weights.by_ref().take(*n).collect())
+                .collect(),
+        }}
+    }}
+
+    #[allow(unused_variables, unused_parens, non_snake_case, clippy::all)]
+    pub fn forward(&self, inputs: &[&[f32]; {n_inputs}]{dyn_params}) -> [Vec<f32>; {n_outputs}] {{
+{body}        [{results}]
+    }}
+}}
+"#,
+        n_weights = weight_sizes.len(),
+        n_inputs = inputs.len(),
+        n_outputs = results.len(),
+        dyn_params = dyn_dims
+            .iter()
+            .map(|c| format!(", dyn_{c}: usize"))
+            .join(""),
+        results = results.join(", "),
+    )
+    .unwrap();
+    Ok(RustModule { source, weights })
+}
+
+/// Is this node a tensor loaded from outside the graph, or a constant folded at compile time?
+fn is_load(graph: &Graph, node: NodeIndex) -> bool {
+    let op = graph.graph.node_weight(node).unwrap().as_any();
+    (op.is::<Function>() || op.is::<FoldedConstant>())
+        && graph
+            .graph
+            .edges_directed(node, Direction::Incoming)
+            .next()
+            .is_none()
+}
+
+/// The dyn dims a shape depends on
+fn symbols(sh: &ShapeTracker) -> Vec<char> {
+    sh.shape()
+        .into_iter()
+        .chain([sh.index_expression(), sh.valid_expression()])
+        .flat_map(|e| e.to_symbols())
+        .filter(|c| *c != 'z')
+        .collect()
+}
+
+/// A float literal, including the non-finite ones. Negative literals are bracketed so methods can be
+/// called on them
+pub(crate) fn rust_float(f: f32) -> String {
+    if f.is_nan() {
+        "f32::NAN".to_string()
+    } else if f.is_infinite() {
+        format!("{}f32::INFINITY", if f < 0. { "-" } else { "" })
+    } else if f.is_sign_negative() {
+        format!("({f:?}f32)")
+    } else {
+        format!("{f:?}f32")
+    }
+}
+
+/// An expression as i64 math over the index `z` and the dyn dim parameters
+fn rust_expr(expr: &BigExpression) -> String {
+    let mut stack = vec![];
+    for term in &expr.terms {
+        let src = match *term {
+            Term::Num(n) if n < 0 => format!("({n}i64)"),
+            Term::Num(n) => format!("{n}i64"),
+            Term::Var('z') => "z".to_string(),
+            Term::Var(c) => format!("(dyn_{c} as i64)"),
+            term => {
+                let a = stack.pop().unwrap();
+                let b = stack.pop().unwrap();
+                match term {
+                    Term::Add => format!("({a} + {b})"),
+                    Term::Sub => format!("({a} - {b})"),
+                    Term::Mul => format!("({a} * {b})"),
+                    Term::Div => format!("({a} / {b})"),
+                    Term::Mod => format!("({a} % {b})"),
+                    Term::Min => format!("{a}.min({b})"),
+                    Term::Max => format!("{a}.max({b})"),
+                    Term::And => format!("(({a} != 0 && {b} != 0) as i64)"),
+                    Term::Or => format!("(({a} != 0 || {b} != 0) as i64)"),
+                    Term::Gte => format!("(({a} >= {b}) as i64)"),
+                    Term::Lt => format!("(({a} < {b}) as i64)"),
+                    Term::Num(_) | Term::Var(_) => unreachable!(),
+                }
+            }
+        };
+        stack.push(src);
+    }
+    stack.pop().unwrap()
+}
+
+/// Source reading the logical elements of a tensor through a view
+fn view_source(tensor: &str, sh: &ShapeTracker) -> String {
+    if !sh.is_reshaped() {
+        return format!("&{tensor}[..]");
+    }
+    format!(
+        "view(&{tensor}[..], {}, |z| {}, |z| {})",
+        rust_expr(&sh.n_elements()),
+        rust_expr(&sh.index_expression()),
+        rust_expr(&sh.valid_expression()),
+    )
+}
+
+/// Source computing the output of an op from the outputs of its sources
+fn node_source(op: &dyn Operator, sources: &[(NodeIndex, u8, ShapeTracker)]) -> OpResult<String> {
+    if let Some((_, o, _)) = sources.iter().find(|(_, o, _)| *o != 0) {
+        return Err(format!(
+            "reads output {o}, but only single output ops are supported"
+        ));
+    }
+    let tensor = |i: usize| format!("t{}", sources[i].0.index());
+    let view = |i: usize| view_source(&tensor(i), &sources[i].2);
+    let dims = |i: usize| sources[i].2.shape().iter().map(rust_expr).collect_vec();
+    let inputs = || {
+        (0..sources.len())
+            .map(|i| format!("let a{i} = {}; ", view(i)))
+            .join("")
+    };
+    let op_any = op.as_any();
+    let src = if let Some(fused) = FusedElementwise::of(op) {
+        format!(
+            "{{ {}(0..{} as usize).map(|i| {{ {} }}).collect::<Vec<f32>>() }}",
+            inputs(),
+            rust_expr(&sources[0].2.n_elements()),
+            fused.rust_source()
+        )
+    } else if let Some(Constant(value, ..)) = op_any.downcast_ref() {
+        match value {
+            ConstantValue::Float(f) => format!("vec![{}]", rust_float(*f)),
+            ConstantValue::Expression(e) => format!("vec![{} as f32]", rust_expr(e)),
+        }
+    } else if let Some(axis) = op_any
+        .downcast_ref::<SumReduce>()
+        .map(|r| (r.0, "0f32", "a + b"))
+        .or_else(|| {
+            op_any
+                .downcast_ref::<MaxReduce>()
+                .map(|r| (r.0, "f32::NEG_INFINITY", "if b > a { b } else { a }"))
+        })
+    {
+        let (axis, init, f) = axis;
+        let dims = dims(0);
+        let product = |d: &[String]| {
+            if d.is_empty() {
+                "1i64".to_string()
+            } else {
+                d.join(" * ")
+            }
+        };
+        format!(
+            "{{ {}reduce(&a0[..], {}, {}, {}, {init}, |a, b| {f}) }}",
+            inputs(),
+            product(&dims[..axis]),
+            dims[axis],
+            product(&dims[axis + 1..]),
+        )
+    } else if op_any.is::<MatMul2D>() || op_any.is::<BatchedMatMul2D>() {
+        let (a, b) = (dims(0), dims(1));
+        let (batch, m, k) = match a.as_slice() {
+            [m, k] => ("1i64".to_string(), m, k),
+            [batch, m, k] => (batch.clone(), m, k),
+            _ => unreachable!(),
+        };
+        format!(
+            "{{ {}matmul(&a0[..], &a1[..], {batch}, {m}, {k}, {}) }}",
+            inputs(),
+            b[1]
+        )
+    } else if let Some(Gather { embed_dim }) = op_any.downcast_ref() {
+        format!(
+            "gather(&{}[..], &{}[..], {embed_dim})",
+            tensor(0),
+            tensor(1)
+        )
+    } else if let Some(ARange { size, .. }) = op_any.downcast_ref() {
+        format!(
+            "(0..{}).map(|i| i as f32).collect::<Vec<f32>>()",
+            rust_expr(size)
+        )
+    } else {
+        return Err("no Rust equivalent".to_string());
+    };
+    Ok(src)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use crate::{export_rust, CPUCompiler, ExportError, RustModule};
+    luminal::test_imports!();
+
+    /// Compile the module into a binary calling `forward` with the given arguments, and parse the
+    /// outputs it prints
+    fn run(module: &RustModule, forward_args: &str) -> Vec<Vec<f32>> {
+        let dir = std::env::temp_dir().join(format!(
+            "luminal_export_{}_{}",
+            std::process::id(),
+            forward_args.len()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("model.rs"), &module.source).unwrap();
+        std::fs::write(dir.join("weights.bin"), &module.weights).unwrap();
+        std::fs::write(
+            dir.join("main.rs"),
+            format!(
+                r#"
+mod model {{ include!("model.rs"); }}
+fn main() {{
+    let model = model::Model::new(include_bytes!("weights.bin"));
+    for out in model.forward({forward_args}) {{
+        println!("{{}}", out.iter().map(|f| f.to_string()).collect::<Vec<_>>().join(","));
+    }}
+}}"#
+            ),
+        )
+        .unwrap();
+        let rustc = std::env::var("RUSTC").unwrap_or("rustc".to_string());
+        let status = Command::new(rustc)
+            .args(["--edition", "2021", "-O", "-D", "warnings", "main.rs"])
+            .current_dir(&dir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let out = Command::new(dir.join("main")).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        String::from_utf8(out.stdout)
+            .unwrap()
+            .lines()
+            .map(|l| {
+                l.split(',')
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.parse().unwrap())
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn literal(data: &[f32]) -> String {
+        format!("&{data:?}")
+    }
+
+    #[test]
+    fn test_export_mlp() {
+        let (x_data, w_data, table) = (random_vec(3 * 8), random_vec(8 * 6), random_vec(10 * 6));
+        let mut cx = Graph::new();
+        let x = cx.tensor::<(Dyn<'s'>, LConst<8>)>();
+        let ids = cx.tensor::<(Dyn<'s'>,)>();
+        let w = cx.tensor::<R2<8, 6>>().set(w_data).keep();
+        let embed = cx.tensor::<R2<10, 6>>().set(table).keep();
+        let h = x.matmul(w) + embed.gather(ids);
+        let mut out = (h.softmax::<LAxis<1>>() + cx.arange::<LConst<6>>().expand())
+            .permute::<_, LAxes2<1, 0>>()
+            .retrieve();
+        let mut total = h
+            .max_reduce::<_, LAxis<1>>()
+            .sum_reduce::<_, LAxis<0>>()
+            .retrieve();
+        cx.compile(
+            (GenericCompiler::default(), CPUCompiler::<f32>::default()),
+            (&mut out, &mut total),
+        );
+        x.set_dyn(x_data.clone(), &[3, 8]);
+        ids.set_dyn(vec![4., 0., 9.], &[3]);
+        cx.execute();
+
+        let module = export_rust(&mut cx, (x, ids), (out, total)).unwrap();
+        assert!(module.source.contains("dyn_s: usize"));
+        // Folded constants are stored alongside the weights
+        assert!(module.weights.len() >= (8 * 6 + 10 * 6) * 4);
+        let results = run(
+            &module,
+            &format!("&[{}, {}], 3", literal(&x_data), literal(&[4., 0., 9.])),
+        );
+        assert_close(&results[0], &out.data());
+        assert_close(&results[1], &total.data());
+    }
+
+    #[test]
+    fn test_export_unsupported() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set(vec![1i32, 2, 3]);
+        let b = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+        let mut c = (a.exp2() + b).retrieve();
+        let mut d = b.sin().retrieve();
+        cx.compile(CPUCompiler::<f32>::default(), (&mut c, &mut d));
+        let Err(ExportError(nodes)) = export_rust(&mut cx, (), (c, d)) else {
+            panic!("Integer tensors shouldn't be exported");
+        };
+        // Only the integer tensor is reported, not what reads it
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].op_type, "Weight");
+        assert!(export_rust(&mut cx, (), d).is_ok());
+    }
+
+    #[test]
+    fn test_export_unset_weight() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>();
+        let w = cx.tensor::<R1<3>>();
+        let out = (a * w).retrieve();
+        // `w` has no value and isn't listed as an input
+        let Err(ExportError(nodes)) = export_rust(&mut cx, a, out) else {
+            panic!("Unset weights shouldn't be exported");
+        };
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].op_type, "Weight");
+        assert_eq!(nodes[0].name, w.id.index().to_string());
+        assert!(export_rust(&mut cx, (a, w), out).is_ok());
+    }
+}
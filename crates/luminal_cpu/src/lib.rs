@@ -1,6 +1,20 @@
 mod binary;
+mod elementwise;
+mod export;
 mod matmul;
 mod other;
+mod precision;
+mod quantized;
+mod storage_buffer;
+
+pub use elementwise::{ElementwiseFusionCompiler, FusedElementwise};
+pub use export::{export_rust, ExportError, RustModule};
+pub use matmul::MatMulCompiler;
+pub use precision::{Convert, PrecisionCompiler};
+pub use quantized::{
+    CPUQuantizedCompiler, QuantizedData, QuantizedFormat, QuantizedGather, QuantizedMatMul,
+};
+pub use storage_buffer::{cpu_kernel, CPUKernel, InPlaceKernel, MemoryPlan, StorageBufferCompiler};
 
 use std::any::Any;
 
@@ -8,21 +22,41 @@ use itertools::Itertools;
 use petgraph::visit::EdgeRef;
 
 use luminal::{
+    dispatch_dtype,
     op::{Constant, ConstantValue, Exp2, InputTensor, Log2, Operator, Recip, Sin},
     prelude::*,
 };
 
 // Ops and compilers specific to CPU execution
 
-pub type CPUCompiler = (
+/// Compiles a graph for the CPU, storing float tensors as `T` (f32, f16 or bf16)
+pub type CPUCompiler<T = f32> = (
     matmul::MatMulCompiler,
     binary::SubtractionCompiler,
     binary::EqualCompiler,
     other::ARangeCompiler,
     binary::GatherCompiler,
     UnaryFusionCompiler,
+    ElementwiseFusionCompiler,
+    PrecisionCompiler<T>,
 );
 
+/// The primitive and CPU ops, for saving and loading graphs compiled with the [`CPUCompiler`]
+pub fn cpu_op_registry() -> OpRegistry {
+    OpRegistry::default()
+        .register::<matmul::MatMul2D>()
+        .register::<matmul::BatchedMatMul2D>()
+        .register::<binary::Sub>()
+        .register::<binary::Equal>()
+        .register::<binary::Gather>()
+        .register::<other::ARange>()
+        .register::<FusedUnary>()
+        .register::<FusedElementwise>()
+        .register::<Convert>()
+        .register::<QuantizedMatMul>()
+        .register::<QuantizedGather>()
+}
+
 pub(crate) fn constant(num: f32) -> SelectGraph {
     let mut n = op::<Constant>();
     n.check(move |o, _| {
@@ -45,20 +79,6 @@ pub struct UnaryFusionCompiler;
 impl Compiler for UnaryFusionCompiler {
     type Output = ();
     fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
-        fn is_unary(op: &dyn Any) -> Option<fn(f32) -> f32> {
-            if op.is::<Exp2>() {
-                Some(|i| i.exp2())
-            } else if op.is::<Log2>() {
-                Some(|i| i.log2())
-            } else if op.is::<Recip>() {
-                Some(|i| i.recip())
-            } else if op.is::<Sin>() {
-                Some(|i| i.sin())
-            } else {
-                None
-            }
-        }
-
         // Scan through unary sequential eliminations
         for id in graph.graph.node_indices().collect_vec() {
             if graph.no_delete.contains(&id) {
@@ -76,8 +96,8 @@ impl Compiler for UnaryFusionCompiler {
                 let op = graph.graph.node_weight(id).unwrap();
                 let other = graph.graph.node_weight(outgoing_target).unwrap();
                 let mut replaced = false;
-                if let Some(f) = is_unary(op.as_any()) {
-                    if let Some(of) = is_unary(other.as_any()) {
+                if let Some(f) = Unary::of(op.as_any()) {
+                    if let Some(of) = Unary::of(other.as_any()) {
                         // Unary -> Unary
                         *graph.graph.node_weight_mut(id).unwrap() =
                             Box::new(FusedUnary(vec![f, of]));
@@ -91,7 +111,7 @@ impl Compiler for UnaryFusionCompiler {
                         replaced = true;
                     }
                 } else if let Some(mut fused) = op.as_any().downcast_ref::<FusedUnary>().cloned() {
-                    if let Some(of) = is_unary(other.as_any()) {
+                    if let Some(of) = Unary::of(other.as_any()) {
                         // Fused -> Unary
                         fused.0.push(of);
                         *graph.graph.node_weight_mut(id).unwrap() = Box::new(fused);
@@ -116,30 +136,99 @@ impl Compiler for UnaryFusionCompiler {
     }
 }
 
+/// A unary op that can be fused
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Unary {
+    Exp2,
+    Log2,
+    Recip,
+    Sin,
+}
+
+impl Unary {
+    fn of(op: &dyn Any) -> Option<Self> {
+        if op.is::<Exp2>() {
+            Some(Unary::Exp2)
+        } else if op.is::<Log2>() {
+            Some(Unary::Log2)
+        } else if op.is::<Recip>() {
+            Some(Unary::Recip)
+        } else if op.is::<Sin>() {
+            Some(Unary::Sin)
+        } else {
+            None
+        }
+    }
+
+    fn apply(self, x: f32) -> f32 {
+        match self {
+            Unary::Exp2 => x.exp2(),
+            Unary::Log2 => x.log2(),
+            Unary::Recip => x.recip(),
+            Unary::Sin => x.sin(),
+        }
+    }
+}
+
 /// Multiple unary ops applied in sequence
 #[derive(Debug, Clone, PartialEq)]
-pub struct FusedUnary(Vec<fn(f32) -> f32>);
+pub struct FusedUnary(Vec<Unary>);
 
 impl Operator for FusedUnary {
     fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let mut t = inp.pop().unwrap().0.cloned();
-        for a in t.downcast_mut::<Vec<f32>>().unwrap().iter_mut() {
-            for f in &self.0 {
-                *a = (f)(*a);
+        dispatch_dtype!(t.dtype().unwrap(), T => {
+            // Memory-mapped inputs are read-only, so copy them before applying in place
+            if !t.is::<Vec<T>>() {
+                t = Tensor::new(t.as_elements::<T>().into_owned());
             }
-        }
+            for a in t.downcast_mut::<Vec<T>>().unwrap().iter_mut() {
+                let mut x = a.to_f32();
+                for f in &self.0 {
+                    x = f.apply(x);
+                }
+                *a = T::from_f32(x);
+            }
+        });
 
         vec![t]
     }
 }
 
+impl SerializeOp for FusedUnary {
+    const NAME: &'static str = "CPUFusedUnary";
+    fn serialize_op(&self) -> OpAttributes {
+        OpAttributes {
+            strings: self.0.iter().map(|u| format!("{u:?}")).collect(),
+            ..Default::default()
+        }
+    }
+    fn deserialize_op(attrs: &OpAttributes, _: &Graph) -> Result<Self, String> {
+        attrs
+            .strings
+            .iter()
+            .map(|s| match s.as_str() {
+                "Exp2" => Ok(Unary::Exp2),
+                "Log2" => Ok(Unary::Log2),
+                "Recip" => Ok(Unary::Recip),
+                "Sin" => Ok(Unary::Sin),
+                _ => Err(format!("Unknown unary op {s}")),
+            })
+            .collect::<Result<_, _>>()
+            .map(FusedUnary)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use rand::{rngs::StdRng, SeedableRng};
 
-    use luminal::prelude::*;
+    use luminal::{
+        prelude::*,
+        tests::harness::{test_compiler, test_compiler_with, HarnessConfig},
+    };
 
-    use crate::CPUCompiler;
+    use crate::{cpu_op_registry, CPUCompiler};
     luminal::test_imports!();
 
     #[test]
@@ -149,7 +238,7 @@ mod tests {
         let b = cx.tensor::<(Dyn<'K'>, Dyn<'N'>)>();
         let mut c = a.matmul(b).retrieve();
 
-        cx.compile(CPUCompiler::default(), &mut c);
+        cx.compile(CPUCompiler::<f32>::default(), &mut c);
 
         let d_dev = dfdx::prelude::Cpu::default();
         for m in (1..23).step_by(4) {
@@ -174,6 +263,18 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_equal() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<4>>().set(vec![1., 2., 3., 4.]);
+        let b = cx.tensor::<R1<4>>().set(vec![1., 3., 2., 4.]);
+        let mut c = (a.equals(b) * 2.).retrieve();
+
+        cx.compile(CPUCompiler::<f32>::default(), &mut c);
+        cx.execute();
+        assert_exact(&c.data(), &[2., 0., 0., 2.]);
+    }
+
     #[test]
     fn test_cpu_matmul_2d_2() {
         let mut cx = Graph::new();
@@ -186,8 +287,167 @@ mod tests {
         cx.execute();
 
         let unoptimized_c = c.data();
-        cx.compile(CPUCompiler::default(), &mut c);
+        cx.compile(CPUCompiler::<f32>::default(), &mut c);
         cx.execute();
         assert_close(&c.data(), &unoptimized_c);
     }
+
+    #[test]
+    fn test_batched_matmul() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R3<8, 64, 96>>().set(random_vec(8 * 64 * 96));
+        let b = cx.tensor::<R2<96, 128>>().set(random_vec(96 * 128));
+        let mut c = a.matmul(b).retrieve();
+        cx.execute();
+        let unoptimized_c = c.data();
+        c.drop();
+
+        cx.compile(CPUCompiler::<f32>::default(), &mut c);
+        assert!(cx
+            .graph
+            .node_weights()
+            .any(|op| format!("{op:?}").starts_with("BatchedMatMul2D")));
+        cx.execute();
+        assert_close_precision(&c.data(), &unoptimized_c, 1e-3);
+    }
+
+    #[test]
+    fn test_typed_gather() {
+        let mut cx = Graph::new();
+        let weights = cx
+            .tensor::<R2<3, 2>>()
+            .set((0..6).map(|i| f16::from_f32(i as f32)).collect::<Vec<_>>());
+        let ids = cx.tensor::<R1<2>>().set(vec![2i32, 0]);
+        let mut out = weights.gather(ids).retrieve();
+        cx.execute();
+        let unoptimized = out.data();
+
+        cx.compile(CPUCompiler::<f32>::default(), &mut out);
+        assert!(cx
+            .graph
+            .node_weights()
+            .any(|op| format!("{op:?}").starts_with("Gather")));
+        cx.execute();
+        assert_exact(&out.data(), &unoptimized);
+        assert_exact(&out.data(), &[4., 5., 0., 1.]);
+        assert_eq!(out.dtype(), DType::F32);
+    }
+
+    #[test]
+    fn test_mmap_input() {
+        let path =
+            std::env::temp_dir().join(format!("luminal_cpu_mmap_{}.bin", std::process::id()));
+        std::fs::write(
+            &path,
+            [0f32, 1., 2.]
+                .iter()
+                .flat_map(|f| f.to_le_bytes())
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R1<3>>()
+            // Safety: nothing writes to the file until it's removed
+            .set(MmapData::new(
+                unsafe { map_file(&path) }.unwrap(),
+                0,
+                3,
+                DType::F32,
+            ));
+        let mut out = a.exp2().sin().retrieve();
+        cx.compile(CPUCompiler::<f32>::default(), &mut out);
+        cx.execute();
+        assert_close(&out.data(), &[0f32, 1., 2.].map(|i| i.exp2().sin()));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    type Model = (
+        GraphTensor<R2<2, 3>>,
+        GraphTensor<R2<3, 4>>,
+        GraphTensor<R1<2>>,
+        GraphTensor<R2<2, 4>>,
+    );
+
+    fn build_model(cx: &mut Graph) -> Model {
+        let a = cx.named_tensor::<R2<2, 3>>("A");
+        let b = cx.named_tensor::<R2<3, 4>>("B");
+        let ids = cx.named_tensor::<R1<2>>("Ids");
+        let out = a.matmul(b).exp2().sin()
+            + cx.arange::<LConst<4>>().expand::<_, LAxis<0>>()
+            + b.gather(ids);
+        (a, b, ids, out.retrieve())
+    }
+
+    #[test]
+    fn test_save_compiled() {
+        let (a_data, b_data) = (random_vec(6), random_vec(12));
+        let mut cx = Graph::new();
+        let (mut a, mut b, mut ids, mut out) = build_model(&mut cx);
+        cx.compile(
+            (GenericCompiler::default(), CPUCompiler::<f32>::default()),
+            (&mut a, &mut b, &mut ids, &mut out),
+        );
+        let bytes = cx
+            .serialize_compiled(&cpu_op_registry(), (a, b, ids, out))
+            .unwrap();
+        a.set(a_data.clone());
+        b.set(b_data.clone());
+        ids.set(vec![2i32, 0]);
+        cx.execute();
+
+        let mut cx2 = Graph::new();
+        let (mut a2, mut b2, mut ids2, mut out2) = build_model(&mut cx2);
+        cx2.deserialize_compiled(
+            &cpu_op_registry(),
+            (&mut a2, &mut b2, &mut ids2, &mut out2),
+            &bytes,
+        )
+        .unwrap();
+        for op in ["MatMul2D", "FusedElementwise", "FoldedConstant", "Gather"] {
+            assert!(cx2
+                .graph
+                .node_weights()
+                .any(|o| format!("{o:?}").starts_with(op)));
+        }
+        a2.set(a_data);
+        b2.set(b_data);
+        ids2.set(vec![2i32, 0]);
+        cx2.execute();
+        assert_exact(&out2.data(), &out.data());
+
+        // The CPU ops aren't known without the CPU registry
+        assert!(matches!(
+            Graph::new().deserialize_compiled(
+                &OpRegistry::default(),
+                (&mut a2, &mut b2, &mut ids2, &mut out2),
+                &bytes
+            ),
+            Err(GraphFileError::UnknownOp { .. })
+        ));
+    }
+
+    #[test]
+    fn test_harness() {
+        let build = |cx: &mut Graph| {
+            let mut rng = StdRng::seed_from_u64(0);
+            let a = cx
+                .tensor::<(Dyn<'s'>, LConst<3>)>()
+                .set_dyn(random_vec_rng(2 * 3, &mut rng), &[2, 3]);
+            let b = cx.tensor::<R2<3, 4>>().set(random_vec_rng(3 * 4, &mut rng));
+            let c = a.matmul(b);
+            (c.swish() * c.exp2()).retrieve();
+            c.max_reduce::<_, LAxis<1>>().retrieve();
+        };
+        let compiler = || (GenericCompiler::default(), CPUCompiler::<f32>::default());
+        test_compiler(build, compiler());
+        test_compiler_with(
+            build,
+            compiler(),
+            HarnessConfig {
+                tolerance: 1e-5,
+                intermediates: true,
+            },
+        );
+    }
 }
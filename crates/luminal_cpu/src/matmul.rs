@@ -1,8 +1,11 @@
 use luminal::{
+    dispatch_dtype, kernels,
     op::{InputTensor, Mul, Operator, SumReduce},
     prelude::*,
 };
 
+use crate::storage_buffer::CPUKernel;
+
 pub type MatMulCompiler = (MatMul2DCompiler, BatchMatMul2DCompiler);
 
 #[derive(Debug, Default)]
@@ -58,36 +61,63 @@ impl Compiler for MatMul2DCompiler {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct MatMul2D;
 
 impl Operator for MatMul2D {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let data = inp
+            .iter()
+            .map(|(t, _)| t.borrowed().as_elements::<f32>())
+            .collect::<Vec<_>>();
+        let inputs = data
+            .iter()
+            .zip(&inp)
+            .map(|(d, (_, sh))| (d.as_ref(), *sh))
+            .collect::<Vec<_>>();
         let (a_shape, b_shape) = (inp[0].1.shape(), inp[1].1.shape());
-        let (a_strides, b_strides) = (inp[0].1.strides(), inp[1].1.strides());
-        let a_data = inp[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
-        let b_data = inp[1].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
         let mut c = vec![0.; a_shape[0].to_usize().unwrap() * b_shape[1].to_usize().unwrap()];
+        self.process_into(&inputs, &mut [&mut c]);
+
+        vec![output_tensor(c, &inp)]
+    }
+}
+
+impl CPUKernel for MatMul2D {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+        vec![input_shapes[0].shape()[0].clone() * input_shapes[1].shape()[1].clone()]
+    }
+    fn process_into(&self, inputs: &[(&[f32], ShapeTracker)], outputs: &mut [&mut [f32]]) {
+        let (a_shape, b_shape) = (inputs[0].1.shape(), inputs[1].1.shape());
+        let (a_strides, b_strides) = (inputs[0].1.strides(), inputs[1].1.strides());
         unsafe {
             matrixmultiply::sgemm(
                 a_shape[0].to_usize().unwrap(),
                 a_shape[1].to_usize().unwrap(),
                 b_shape[1].to_usize().unwrap(),
                 1.0,
-                a_data.as_ptr(),
+                inputs[0].0.as_ptr(),
                 a_strides[0].to_usize().unwrap() as isize,
                 a_strides[1].to_usize().unwrap() as isize,
-                b_data.as_ptr(),
+                inputs[1].0.as_ptr(),
                 b_strides[0].to_usize().unwrap() as isize,
                 b_strides[1].to_usize().unwrap() as isize,
                 0.0,
-                c.as_mut_ptr(),
+                outputs[0].as_mut_ptr(),
                 b_shape[1].to_usize().unwrap() as isize,
                 1,
             );
         }
+    }
+}
 
-        vec![Tensor::new(c)]
+impl SerializeOp for MatMul2D {
+    const NAME: &'static str = "CPUMatMul2D";
+    fn serialize_op(&self) -> OpAttributes {
+        OpAttributes::default()
+    }
+    fn deserialize_op(_: &OpAttributes, _: &Graph) -> Result<Self, String> {
+        Ok(MatMul2D)
     }
 }
 
@@ -145,45 +175,102 @@ impl Compiler for BatchMatMul2DCompiler {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct BatchedMatMul2D;
 
 // ABCxCD -> ABD
 impl Operator for BatchedMatMul2D {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let data = inp
+            .iter()
+            .map(|(t, _)| t.borrowed().as_elements::<f32>())
+            .collect::<Vec<_>>();
+        let inputs = data
+            .iter()
+            .zip(&inp)
+            .map(|(d, (_, sh))| (d.as_ref(), *sh))
+            .collect::<Vec<_>>();
         let (a_shape, b_shape) = (inp[0].1.shape(), inp[1].1.shape());
-        let (a_strides, b_strides) = (inp[0].1.strides(), inp[1].1.strides());
-        let a_data = inp[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
-        let b_data = inp[1].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
         let mut c = vec![
             0.;
             a_shape[0].to_usize().unwrap()
                 * a_shape[1].to_usize().unwrap()
                 * b_shape[1].to_usize().unwrap()
         ];
+        self.process_into(&inputs, &mut [&mut c]);
 
-        let mat_size = a_shape[1].to_usize().unwrap() * b_shape[1].to_usize().unwrap();
-        for i in 0..a_shape[0].to_usize().unwrap() {
-            unsafe {
-                matrixmultiply::sgemm(
-                    a_shape[1].to_usize().unwrap(),
-                    a_shape[2].to_usize().unwrap(),
-                    b_shape[1].to_usize().unwrap(),
-                    1.0,
-                    a_data.as_ptr().add(i * a_strides[0].to_usize().unwrap()),
-                    a_strides[1].to_usize().unwrap() as isize,
-                    a_strides[2].to_usize().unwrap() as isize,
-                    b_data.as_ptr(),
-                    b_strides[0].to_usize().unwrap() as isize,
-                    b_strides[1].to_usize().unwrap() as isize,
-                    0.0,
-                    c.as_mut_ptr().add(i * mat_size),
-                    b_shape[1].to_usize().unwrap() as isize,
-                    1,
-                );
+        vec![output_tensor(c, &inp)]
+    }
+}
+
+impl CPUKernel for BatchedMatMul2D {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+        let (a_shape, b_shape) = (input_shapes[0].shape(), input_shapes[1].shape());
+        vec![a_shape[0].clone() * a_shape[1].clone() * b_shape[1].clone()]
+    }
+    fn process_into(&self, inputs: &[(&[f32], ShapeTracker)], outputs: &mut [&mut [f32]]) {
+        let (a_shape, b_shape) = (inputs[0].1.shape(), inputs[1].1.shape());
+        let (a_strides, b_strides) = (inputs[0].1.strides(), inputs[1].1.strides());
+        let (m, k, n) = (
+            a_shape[1].to_usize().unwrap(),
+            a_shape[2].to_usize().unwrap(),
+            b_shape[1].to_usize().unwrap(),
+        );
+        let a_strides = a_strides
+            .iter()
+            .map(|s| s.to_usize().unwrap())
+            .collect::<Vec<_>>();
+        let b_strides = (
+            b_strides[0].to_usize().unwrap() as isize,
+            b_strides[1].to_usize().unwrap() as isize,
+        );
+        // Each thread multiplies its own batches
+        kernels::par_chunks(outputs[0], m * n, k, |start, out| {
+            for (i, c) in out.chunks_mut(m * n).enumerate() {
+                let batch = start / (m * n) + i;
+                unsafe {
+                    matrixmultiply::sgemm(
+                        m,
+                        k,
+                        n,
+                        1.0,
+                        inputs[0].0.as_ptr().add(batch * a_strides[0]),
+                        a_strides[1] as isize,
+                        a_strides[2] as isize,
+                        inputs[1].0.as_ptr(),
+                        b_strides.0,
+                        b_strides.1,
+                        0.0,
+                        c.as_mut_ptr(),
+                        n as isize,
+                        1,
+                    );
+                }
             }
-        }
+        });
+    }
+}
+
+impl SerializeOp for BatchedMatMul2D {
+    const NAME: &'static str = "CPUBatchedMatMul2D";
+    fn serialize_op(&self) -> OpAttributes {
+        OpAttributes::default()
+    }
+    fn deserialize_op(_: &OpAttributes, _: &Graph) -> Result<Self, String> {
+        Ok(BatchedMatMul2D)
+    }
+}
 
-        vec![Tensor::new(c)]
+/// Store a matmul result computed in f32 as the common type of its inputs
+fn output_tensor(c: Vec<f32>, inp: &[(InputTensor, ShapeTracker)]) -> Tensor {
+    let dtype = inp[0]
+        .0
+        .borrowed()
+        .dtype()
+        .unwrap()
+        .promote(inp[1].0.borrowed().dtype().unwrap());
+    if dtype == DType::F32 {
+        return Tensor::new(c);
     }
+    dispatch_dtype!(dtype, T => Tensor::new(c.into_iter().map(T::from_f32).collect::<Vec<_>>()))
 }
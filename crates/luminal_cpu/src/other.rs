@@ -24,6 +24,22 @@ impl Operator for ARange {
     }
 }
 
+impl SerializeOp for ARange {
+    const NAME: &'static str = "CPUARange";
+    fn serialize_op(&self) -> OpAttributes {
+        OpAttributes {
+            exprs: vec![self.size.clone()],
+            ..Default::default()
+        }
+    }
+    fn deserialize_op(attrs: &OpAttributes, graph: &Graph) -> Result<Self, String> {
+        Ok(ARange {
+            size: attrs.expr(0)?.clone(),
+            dyn_map: &graph.dyn_map,
+        })
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct ARangeCompiler;
 
@@ -38,10 +54,14 @@ impl Compiler for ARangeCompiler {
             unary::<SumReduce>(unary::<Contiguous>(unary::<Contiguous>(
                 unary::<Contiguous>(contig1.clone()),
             )));
-        let sub = binary::<Sub>(sum_reduce, one2.clone());
-        let mut s = sub.clone().search(graph);
+        let sub = binary::<Sub>(sum_reduce.clone(), one2.clone());
+        // Subtracting one may already be folded into adding negative one
+        let add = binary::<Add>(sum_reduce, super::constant(-1.));
+        let mut s1 = sub.clone().search(graph);
+        let mut s2 = add.clone().search(graph);
 
-        while s.next_match() {
+        while s1.next_match() || s2.next_match() {
+            let (s, sub) = if s1.matched { (&s1, &sub) } else { (&s2, &add) };
             let arange_amount = {
                 let sh = graph
                     .graph
@@ -65,8 +85,8 @@ impl Compiler for ARangeCompiler {
                     dyn_map: &graph.dyn_map,
                 })
                 .finish();
-            move_outgoing_edge(s.get(&sub), arange_op, &mut graph.graph);
-            graph.graph.remove_node(s.get(&sub));
+            move_outgoing_edge(s.get(sub), arange_op, &mut graph.graph);
+            graph.graph.remove_node(s.get(sub));
             s.try_delete();
         }
     }
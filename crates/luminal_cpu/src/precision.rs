@@ -0,0 +1,294 @@
+use std::{any::Any, marker::PhantomData};
+
+use itertools::Itertools;
+use petgraph::{algo::toposort, visit::EdgeRef, Direction};
+use rustc_hash::FxHashSet;
+
+use luminal::{
+    dispatch_dtype,
+    op::{Function, InputTensor, Operator},
+    prelude::*,
+};
+
+/// Converts float tensors to another element type, keeping their layout. Integer tensors pass through
+#[derive(Debug, Clone, PartialEq)]
+pub struct Convert(pub DType);
+
+impl Operator for Convert {
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let tensor = inp.pop().unwrap().0;
+        match tensor.borrowed().dtype() {
+            Some(dtype) if dtype.is_float() && dtype != self.0 => {
+                dispatch_dtype!(self.0, T => vec![Tensor::new(
+                    tensor.borrowed().as_elements::<T>().into_owned(),
+                )])
+            }
+            _ => vec![tensor.cloned()],
+        }
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "dtype" {
+            let input = input
+                .downcast_ref::<Vec<DType>>()
+                .and_then(|i| i.first().copied())
+                .unwrap_or_default();
+            return Some(Box::new(if input.is_float() { self.0 } else { input }));
+        }
+        None
+    }
+}
+
+impl SerializeOp for Convert {
+    const NAME: &'static str = "CPUConvert";
+    fn serialize_op(&self) -> OpAttributes {
+        OpAttributes {
+            strings: vec![format!("{:?}", self.0)],
+            ..Default::default()
+        }
+    }
+    fn deserialize_op(attrs: &OpAttributes, _: &Graph) -> Result<Self, String> {
+        let name = attrs.string(0)?;
+        [DType::F32, DType::F16, DType::Bf16]
+            .into_iter()
+            .find(|d| format!("{d:?}") == name)
+            .map(Convert)
+            .ok_or_else(|| format!("Unknown float type {name}"))
+    }
+}
+
+/// Stores every float tensor in the graph as `T`. Conversions are inserted after anything producing f32
+/// (inputs, constants and ops that always output f32) and before retrieved outputs, which stay f32.
+///
+/// Kept inputs are converted as they load, so only the converted copy stays in memory. Set them before
+/// compiling, since setting them afterwards loads them as f32 again.
+///
+/// Matmuls and sum reductions still accumulate in f32.
+#[derive(Debug)]
+pub struct PrecisionCompiler<T>(PhantomData<T>);
+
+impl<T> Default for PrecisionCompiler<T> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<T: Element> Compiler for PrecisionCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, ids: To) {
+        set_precision(graph, T::DTYPE, &FxHashSet::default(), ids);
+    }
+}
+
+/// Convert the float tensors of a graph to `dtype`, leaving the outputs of the `skip` nodes as they are
+pub(crate) fn set_precision<To: ToIdsMut>(
+    graph: &mut Graph,
+    dtype: DType,
+    skip: &FxHashSet<NodeIndex>,
+    mut ids: To,
+) {
+    assert!(dtype.is_float(), "Can't store float tensors as {dtype:?}");
+    if dtype == DType::F32 {
+        return;
+    }
+    for node in toposort(&graph.graph, None).unwrap() {
+        if graph.node_dtype(node) != DType::F32 || skip.contains(&node) {
+            refresh_edge_dtypes(graph, node);
+            continue;
+        }
+        // Kept inputs only load once, so convert them as they load to only keep the converted copy
+        if graph.no_delete.contains(&node)
+            && !graph.to_retrieve.contains_key(&node)
+            && graph.check_node_type::<Function>(node)
+        {
+            let convert = move |t| {
+                Convert(dtype)
+                    .process(vec![(InputTensor::Owned(t), ShapeTracker::new(&[]))])
+                    .remove(0)
+            };
+            let op = graph.get_op_mut::<Function>(node);
+            let load = std::mem::replace(&mut op.1, Box::new(|_| vec![]));
+            op.1 = Box::new(move |inp| load(inp).into_iter().map(convert).collect());
+            graph.set_dtype(node, dtype);
+            // Convert anything already loaded
+            for i in 0.. {
+                let Some(t) = graph.tensors.remove(&(node, i)) else {
+                    break;
+                };
+                graph.tensors.insert((node, i), convert(t));
+            }
+        } else {
+            // Outputs are converted separately, so every consumer reads the converted tensor
+            for output in graph
+                .edges_directed(node, Direction::Outgoing)
+                .filter_map(|e| e.weight().as_data().map(|(_, o, _)| o))
+                .unique()
+                .collect_vec()
+            {
+                let convert = graph
+                    .add_op(Convert(dtype))
+                    .input(node, output, ShapeTracker::new(&[]))
+                    .finish();
+                for (edge, target, (input_order, _, shape)) in graph
+                    .edges_directed(node, Direction::Outgoing)
+                    .filter(|e| e.target() != convert)
+                    .filter_map(|e| e.weight().as_data().map(|d| (e.id(), e.target(), d)))
+                    .filter(|(_, _, (_, o, _))| *o == output)
+                    .collect_vec()
+                {
+                    graph.add_edge(
+                        convert,
+                        target,
+                        Dependency::Data {
+                            input_order,
+                            output_order: 0,
+                            shape,
+                            dtype,
+                        },
+                    );
+                    graph.remove_edge(edge);
+                }
+            }
+        }
+        refresh_edge_dtypes(graph, node);
+    }
+
+    // Convert retrieved outputs back to f32
+    for (output, (_, shape)) in graph
+        .to_retrieve
+        .iter()
+        .map(|(n, w)| (*n, *w))
+        .filter(|(n, _)| !graph.node_weight(*n).unwrap().as_any().is::<Function>())
+        .collect_vec()
+    {
+        if graph.node_dtype(output) == dtype {
+            let convert = graph
+                .add_op(Convert(DType::F32))
+                .input(output, 0, shape)
+                .finish();
+            remap(output, convert, &mut ids, graph);
+        }
+    }
+}
+
+/// Set the element type on the outgoing edges of a node, and of the conversions just added after it
+fn refresh_edge_dtypes(graph: &mut Graph, node: NodeIndex) {
+    let dtype = graph.node_dtype(node);
+    let converts = graph
+        .edges_directed(node, Direction::Outgoing)
+        .filter(|e| {
+            graph
+                .node_weight(e.target())
+                .unwrap()
+                .as_any()
+                .is::<Convert>()
+        })
+        .map(|e| e.target())
+        .collect_vec();
+    for edge in graph
+        .edges_directed(node, Direction::Outgoing)
+        .map(|e| e.id())
+        .collect_vec()
+    {
+        if let Some(Dependency::Data { dtype: d, .. }) = graph.graph.edge_weight_mut(edge) {
+            *d = dtype;
+        }
+    }
+    for convert in converts {
+        refresh_edge_dtypes(graph, convert);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use luminal::op::{Function, Tensor};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::CPUCompiler;
+    luminal::test_imports!();
+
+    /// Run a small attention-like block in every precision against the uncompiled f32 graph
+    fn run<T: Element>(tolerance: f32) {
+        let mut rng = StdRng::seed_from_u64(0);
+        let (a_data, w_data) = (
+            random_vec_rng(8 * 32, &mut rng),
+            random_vec_rng(32 * 16, &mut rng),
+        );
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<8, 32>>().set(a_data);
+        let w = cx.tensor::<R2<32, 16>>().set(w_data).keep();
+        let h = a.matmul(w);
+        let mut out = (h.softmax::<LAxis<1>>() * 2. + h.sum_reduce::<_, LAxis<1>>().expand())
+            .exp2()
+            .retrieve();
+        cx.execute();
+        let expected = out.data();
+        out.drop();
+
+        cx.compile(CPUCompiler::<T>::default(), &mut out);
+        cx.execute();
+        assert_eq!(out.dtype(), DType::F32);
+        assert_close_precision(&out.data(), &expected, tolerance);
+        // Weights are only kept in the new precision
+        assert!(cx
+            .tensors
+            .iter()
+            .filter(|((n, _), _)| *n != out.id)
+            .all(|(_, t)| t.dtype() == Some(T::DTYPE)));
+        // Running again reuses the converted weights
+        cx.execute();
+        assert_close_precision(&out.data(), &expected, tolerance);
+    }
+
+    #[test]
+    fn test_f32() {
+        run::<f32>(1e-4);
+    }
+
+    #[test]
+    fn test_f16() {
+        run::<f16>(1e-2);
+    }
+
+    #[test]
+    fn test_bf16() {
+        run::<bf16>(1e-1);
+    }
+
+    #[test]
+    fn test_kept_weights_load_once() {
+        let loads = Rc::new(Cell::new(0));
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(random_vec(6));
+        let w = cx.named_tensor::<R2<3, 4>>("Weight").keep();
+        let counter = loads.clone();
+        cx.get_op_mut::<Function>(w.id).1 = Box::new(move |_| {
+            counter.set(counter.get() + 1);
+            vec![Tensor::new(vec![0.5f32; 12])]
+        });
+        let mut out = a.matmul(w).retrieve();
+        cx.compile(CPUCompiler::<f16>::default(), &mut out);
+        cx.execute();
+        let first = out.data();
+        cx.execute();
+        assert_eq!(loads.get(), 1);
+        assert_exact(&out.data(), &first);
+        assert_eq!(
+            cx.get_tensor_ref(w.id, 0).unwrap().dtype(),
+            Some(DType::F16)
+        );
+    }
+
+    #[test]
+    fn test_f16_sum_accumulates_in_f32() {
+        // Adding one to an f16 of 2048 rounds back down, so an f16 accumulator would stop there
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<4096>>().set(vec![1.; 4096]);
+        let mut out = a.sum_reduce::<_, LAxis<0>>().retrieve();
+        cx.compile(CPUCompiler::<f16>::default(), &mut out);
+        cx.execute();
+        assert_exact(&out.data(), &[4096.]);
+    }
+}
@@ -0,0 +1,466 @@
+use std::{any::Any, marker::PhantomData};
+
+use petgraph::visit::EdgeRef;
+
+use luminal::{
+    graph_tensor::ToData,
+    kernels::{self, ViewReader},
+    op::{Data, InputTensor, Operator},
+    prelude::*,
+};
+
+use crate::{
+    binary::Gather,
+    matmul::{BatchedMatMul2D, MatMul2D},
+    precision::set_precision,
+    CPUCompiler,
+};
+
+/// Block formats the quantized kernels can read, laid out as in GGUF files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuantizedFormat {
+    /// Scale: f16, 32 i8 weights
+    Q8_0,
+    /// Scale: f16, 32 4-bit weights offset by 8
+    Q4_0,
+    /// Scale: f16, minimum: f16, 12 bytes of 6-bit sub-block scales and minimums, 256 4-bit weights
+    Q4K,
+}
+
+impl QuantizedFormat {
+    /// Number of weights in a block
+    pub fn block_size(&self) -> usize {
+        match self {
+            QuantizedFormat::Q8_0 | QuantizedFormat::Q4_0 => 32,
+            QuantizedFormat::Q4K => 256,
+        }
+    }
+
+    /// Number of bytes in a block
+    pub fn block_bytes(&self) -> usize {
+        match self {
+            QuantizedFormat::Q8_0 => 34,
+            QuantizedFormat::Q4_0 => 18,
+            QuantizedFormat::Q4K => 144,
+        }
+    }
+
+    /// Bytes taken by a row of `len` weights
+    pub fn row_bytes(&self, len: usize) -> usize {
+        assert!(
+            len.is_multiple_of(self.block_size()),
+            "{self:?} rows must be a multiple of {} weights, got {len}",
+            self.block_size()
+        );
+        len / self.block_size() * self.block_bytes()
+    }
+
+    /// Dequantize whole blocks into `out`, which holds `block_size` weights per block
+    pub fn dequantize(&self, bytes: &[u8], out: &mut [f32]) {
+        for (block, out) in bytes
+            .chunks_exact(self.block_bytes())
+            .zip(out.chunks_exact_mut(self.block_size()))
+        {
+            let d = half(block);
+            match self {
+                QuantizedFormat::Q8_0 => {
+                    for (o, q) in out.iter_mut().zip(&block[2..]) {
+                        *o = *q as i8 as f32 * d;
+                    }
+                }
+                QuantizedFormat::Q4_0 => {
+                    let (lo, hi) = out.split_at_mut(16);
+                    for ((l, h), q) in lo.iter_mut().zip(hi).zip(&block[2..]) {
+                        *l = ((q & 0xF) as i32 - 8) as f32 * d;
+                        *h = ((q >> 4) as i32 - 8) as f32 * d;
+                    }
+                }
+                QuantizedFormat::Q4K => {
+                    let min = half(&block[2..]);
+                    let (scales, qs) = (&block[4..16], &block[16..]);
+                    for (i, (q, out)) in qs
+                        .chunks_exact(32)
+                        .zip(out.chunks_exact_mut(64))
+                        .enumerate()
+                    {
+                        let (sc1, m1) = scale_min_k4(2 * i, scales);
+                        let (sc2, m2) = scale_min_k4(2 * i + 1, scales);
+                        let (lo, hi) = out.split_at_mut(32);
+                        for ((l, h), q) in lo.iter_mut().zip(hi).zip(q) {
+                            *l = d * sc1 * (q & 0xF) as f32 - min * m1;
+                            *h = d * sc2 * (q >> 4) as f32 - min * m2;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn half(b: &[u8]) -> f32 {
+    f16::from_le_bytes([b[0], b[1]]).to_f32()
+}
+
+/// Get the 6-bit scale and minimum of a Q4_K sub-block from the 12 packed scale bytes
+fn scale_min_k4(j: usize, q: &[u8]) -> (f32, f32) {
+    if j < 4 {
+        ((q[j] & 63) as f32, (q[j + 4] & 63) as f32)
+    } else {
+        (
+            ((q[j + 4] & 0xF) | ((q[j - 4] >> 6) << 4)) as f32,
+            ((q[j + 4] >> 4) | ((q[j] >> 6) << 4)) as f32,
+        )
+    }
+}
+
+/// Quantized weights stored as raw blocks, either owned or mapped from a file
+#[derive(Debug, Clone)]
+pub struct QuantizedData {
+    pub format: QuantizedFormat,
+    data: Tensor,
+}
+
+impl QuantizedData {
+    /// Wrap u8 data holding whole blocks of `format`
+    pub fn new<D: Data>(format: QuantizedFormat, data: D) -> Self {
+        let data = Tensor::new(data);
+        let len = data
+            .as_slice::<u8>()
+            .expect("Quantized data must be stored as u8")
+            .len();
+        assert!(
+            len.is_multiple_of(format.block_bytes()),
+            "{len} bytes isn't a whole number of {format:?} blocks"
+        );
+        Self { format, data }
+    }
+
+    /// The raw blocks
+    pub fn bytes(&self) -> &[u8] {
+        self.data.as_slice().unwrap()
+    }
+}
+
+impl Data for QuantizedData {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn size_bytes(&self) -> Option<usize> {
+        self.data.size_bytes()
+    }
+}
+
+impl<S: Shape> ToData<S, QuantizedData> for QuantizedData {
+    fn to_data_vec(self) -> QuantizedData {
+        self
+    }
+}
+
+fn quantized_input<'a>(tensor: &'a InputTensor) -> &'a QuantizedData {
+    tensor
+        .borrowed()
+        .downcast_ref::<QuantizedData>()
+        .expect("Quantized weights weren't loaded as QuantizedData")
+}
+
+/// Multiplies a (batched) input with a quantized [N, K] weight, seen through a permuted [K, N] view
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantizedMatMul;
+
+impl Operator for QuantizedMatMul {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let a_shape = inp[0].1.shape_usize();
+        let k = *a_shape.last().unwrap();
+        let m = a_shape.iter().rev().skip(1).product::<usize>();
+        let n = inp[1].1.shape_usize()[1];
+        let weights = quantized_input(&inp[1].0);
+        let (format, bytes) = (weights.format, weights.bytes());
+        let row_bytes = format.row_bytes(k);
+        assert_eq!(
+            bytes.len(),
+            row_bytes * n,
+            "Quantized weights aren't {n}x{k}"
+        );
+
+        let a_data = inp[0].0.borrowed().as_elements::<f32>();
+        let mut a_buf = vec![];
+        let a_reader = ViewReader::new(&a_data, &inp[0].1);
+        let a = a_reader.read(0, m * k, &mut a_buf);
+
+        // Each weight row is dequantized once and dotted with every input row, so work is split over
+        // weight rows and written transposed when there's more than one input row
+        let mut out_t = vec![0.; m * n];
+        kernels::par_chunks(&mut out_t, m, k, |start, out| {
+            let mut row = vec![0.; k];
+            for (i, out) in out.chunks_exact_mut(m).enumerate() {
+                let w = start / m + i;
+                format.dequantize(&bytes[w * row_bytes..][..row_bytes], &mut row);
+                for (o, a) in out.iter_mut().zip(a.chunks_exact(k)) {
+                    *o = dot(&row, a);
+                }
+            }
+        });
+        if m == 1 {
+            return vec![Tensor::new(out_t)];
+        }
+        let mut out = vec![0.; m * n];
+        for (w, col) in out_t.chunks_exact(m).enumerate() {
+            for (r, v) in col.iter().enumerate() {
+                out[r * n + w] = *v;
+            }
+        }
+        vec![Tensor::new(out)]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "dtype" {
+            return Some(Box::new(DType::F32));
+        }
+        None
+    }
+}
+
+/// Dot product with independent accumulators, so it vectorizes
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    let mut acc = [0.; 8];
+    let (a_chunks, b_chunks) = (a.chunks_exact(8), b.chunks_exact(8));
+    let tail = a_chunks
+        .remainder()
+        .iter()
+        .zip(b_chunks.remainder())
+        .map(|(a, b)| a * b)
+        .sum::<f32>();
+    for (a, b) in a_chunks.zip(b_chunks) {
+        for i in 0..8 {
+            acc[i] += a[i] * b[i];
+        }
+    }
+    acc.iter().sum::<f32>() + tail
+}
+
+impl SerializeOp for QuantizedMatMul {
+    const NAME: &'static str = "CPUQuantizedMatMul";
+    fn serialize_op(&self) -> OpAttributes {
+        OpAttributes::default()
+    }
+    fn deserialize_op(_: &OpAttributes, _: &Graph) -> Result<Self, String> {
+        Ok(QuantizedMatMul)
+    }
+}
+
+/// Gathers rows of quantized embeddings, dequantizing only the rows used
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantizedGather {
+    pub embed_dim: usize,
+}
+
+impl Operator for QuantizedGather {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let indexes = tensors[0].0.borrowed();
+        let indexes = if let Some(ids) = indexes.as_slice::<i32>() {
+            ids.iter().map(|i| *i as usize).collect::<Vec<_>>()
+        } else {
+            indexes
+                .as_elements::<f32>()
+                .iter()
+                .map(|i| *i as usize)
+                .collect()
+        };
+        let weights = quantized_input(&tensors[1].0);
+        let (format, bytes) = (weights.format, weights.bytes());
+        let row_bytes = format.row_bytes(self.embed_dim);
+
+        let mut out = vec![0.; indexes.len() * self.embed_dim];
+        for (e, out) in indexes.iter().zip(out.chunks_exact_mut(self.embed_dim)) {
+            format.dequantize(&bytes[e * row_bytes..][..row_bytes], out);
+        }
+        vec![Tensor::new(out)]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "dtype" {
+            return Some(Box::new(DType::F32));
+        }
+        None
+    }
+}
+
+impl SerializeOp for QuantizedGather {
+    const NAME: &'static str = "CPUQuantizedGather";
+    fn serialize_op(&self) -> OpAttributes {
+        OpAttributes {
+            ints: vec![self.embed_dim as i64],
+            ..Default::default()
+        }
+    }
+    fn deserialize_op(attrs: &OpAttributes, _: &Graph) -> Result<Self, String> {
+        Ok(QuantizedGather {
+            embed_dim: attrs.int(0)? as usize,
+        })
+    }
+}
+
+/// Runs the [`CPUCompiler`], then swaps the matmuls and gathers reading the quantized weights for
+/// ones reading [`QuantizedData`] blocks directly. Everything else is stored as `T`
+#[derive(Debug)]
+pub struct CPUQuantizedCompiler<T>(Vec<NodeIndex>, PhantomData<T>);
+
+impl<T> CPUQuantizedCompiler<T> {
+    pub fn new<To: ToIds>(weights: To) -> Self {
+        Self(weights.to_ids(), PhantomData)
+    }
+}
+
+impl<T: Element> Compiler for CPUQuantizedCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let mut weight_ids = self.0.clone();
+        let mut local_remap = remap.to_ids_mut();
+        for w in &mut weight_ids {
+            local_remap.push(w);
+        }
+        graph.compile(CPUCompiler::<f32>::default(), &mut local_remap);
+        // Modify ops directly downstream of weights
+        for weight in downstream(&weight_ids, graph) {
+            for (target, (inp_ind, _, _)) in graph
+                .edges_directed(weight, petgraph::Direction::Outgoing)
+                .filter_map(|e| e.weight().as_data().map(|i| (e.target(), i)))
+                .collect::<Vec<_>>()
+            {
+                assert_eq!(
+                    inp_ind, 1,
+                    "Quantized weight {target:?} is the wrong input!",
+                );
+                let op_node = graph.node_weight_mut(target).unwrap();
+                if let Some(gather) = op_node.as_any().downcast_ref::<Gather>() {
+                    *op_node = Box::new(QuantizedGather {
+                        embed_dim: gather.embed_dim,
+                    });
+                } else if op_node.as_any().is::<MatMul2D>()
+                    || op_node.as_any().is::<BatchedMatMul2D>()
+                {
+                    *op_node = Box::new(QuantizedMatMul);
+                } else {
+                    panic!("Quantized weight {target:?} is an input to a node that isn't a matmul or gather!");
+                }
+            }
+        }
+        // The weights are read as blocks, so are never converted
+        set_precision(
+            graph,
+            T::DTYPE,
+            &weight_ids.iter().copied().collect(),
+            &mut remap,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal_gguf::{dequantize, GgmlDType};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::{CPUQuantizedCompiler, QuantizedData, QuantizedFormat};
+    use crate::CPUCompiler;
+    luminal::test_imports!();
+
+    /// Random blocks with small scales, so every format gives well behaved weights
+    fn random_blocks(format: QuantizedFormat, n_blocks: usize, rng: &mut StdRng) -> Vec<u8> {
+        let mut bytes = vec![];
+        for _ in 0..n_blocks {
+            let start = bytes.len();
+            bytes.extend((0..format.block_bytes()).map(|_| rng.gen::<u8>()));
+            bytes[start..start + 2]
+                .copy_from_slice(&f16::from_f32(rng.gen_range(0.01..0.1)).to_le_bytes());
+            if format == QuantizedFormat::Q4K {
+                bytes[start + 2..start + 4]
+                    .copy_from_slice(&f16::from_f32(rng.gen_range(0.01..0.1)).to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    fn ggml_dtype(format: QuantizedFormat) -> GgmlDType {
+        match format {
+            QuantizedFormat::Q8_0 => GgmlDType::Q8_0,
+            QuantizedFormat::Q4_0 => GgmlDType::Q4_0,
+            QuantizedFormat::Q4K => GgmlDType::Q4K,
+        }
+    }
+
+    #[test]
+    fn test_quantized_matmul() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for format in [
+            QuantizedFormat::Q8_0,
+            QuantizedFormat::Q4_0,
+            QuantizedFormat::Q4K,
+        ] {
+            let blocks = random_blocks(format, 64 * 512 / format.block_size(), &mut rng);
+            let mat_data = dequantize(ggml_dtype(format), &blocks).unwrap();
+            let vec_data = random_vec_rng(512, &mut rng);
+            let batch_data = random_vec_rng(3 * 4 * 512, &mut rng);
+
+            let mut cx = Graph::new();
+            let weights = cx
+                .tensor::<R2<64, 512>>()
+                .set(QuantizedData::new(format, blocks))
+                .keep();
+            let vec = cx.tensor::<R2<1, 512>>().set(vec_data.clone());
+            let batch = cx.tensor::<R3<3, 4, 512>>().set(batch_data.clone());
+            let mut out_vec = vec.matmul(weights.permute()).retrieve();
+            let mut out_batch = batch.matmul(weights.permute()).retrieve();
+            cx.compile(
+                CPUQuantizedCompiler::<f32>::new(weights),
+                (&mut out_vec, &mut out_batch),
+            );
+            assert!(cx
+                .graph
+                .node_weights()
+                .any(|op| format!("{op:?}").starts_with("QuantizedMatMul")));
+            cx.execute();
+
+            let mut cx1 = Graph::new();
+            let weights = cx1.tensor::<R2<64, 512>>().set(mat_data);
+            let vec = cx1.tensor::<R2<1, 512>>().set(vec_data);
+            let batch = cx1.tensor::<R3<3, 4, 512>>().set(batch_data);
+            let mut vec_32 = vec.matmul(weights.permute()).retrieve();
+            let mut batch_32 = batch.matmul(weights.permute()).retrieve();
+            cx1.compile(CPUCompiler::<f32>::default(), (&mut vec_32, &mut batch_32));
+            cx1.execute();
+
+            assert_close_precision(&out_vec.data(), &vec_32.data(), 1e-3);
+            assert_close_precision(&out_batch.data(), &batch_32.data(), 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_quantized_gather() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let blocks = random_blocks(QuantizedFormat::Q8_0, 10 * 64 / 32, &mut rng);
+        let embeddings = dequantize(GgmlDType::Q8_0, &blocks).unwrap();
+
+        let mut cx = Graph::new();
+        let weights = cx
+            .tensor::<R2<10, 64>>()
+            .set(QuantizedData::new(QuantizedFormat::Q8_0, blocks))
+            .keep();
+        let ids = cx.tensor::<R1<3>>().set(vec![7i32, 0, 7]);
+        let mut out = weights.gather(ids).retrieve();
+        cx.compile(CPUQuantizedCompiler::<f32>::new(weights), &mut out);
+        assert!(cx
+            .graph
+            .node_weights()
+            .any(|op| format!("{op:?}").starts_with("QuantizedGather")));
+        cx.execute();
+
+        let expected = [7, 0, 7]
+            .iter()
+            .flat_map(|i| embeddings[i * 64..(i + 1) * 64].to_vec())
+            .collect::<Vec<_>>();
+        assert_exact(&out.data(), &expected);
+    }
+}
@@ -0,0 +1,522 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use itertools::Itertools;
+use rustc_hash::FxHashMap;
+
+use luminal::{
+    kernels::{self, ViewReader},
+    op::*,
+    prelude::{
+        petgraph::{algo::toposort, Direction},
+        *,
+    },
+};
+
+use crate::{
+    binary::{Equal, Sub},
+    matmul::{BatchedMatMul2D, MatMul2D},
+    FusedElementwise, FusedUnary,
+};
+
+/// A CPU op that can write its outputs into preallocated buffers
+pub trait CPUKernel: Debug {
+    /// Annotate the number of elements in each output buffer
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression>;
+    /// Run the op, writing into the output buffers. Output buffers are already the right size
+    fn process_into(&self, inputs: &[(&[f32], ShapeTracker)], outputs: &mut [&mut [f32]]);
+    /// The in place version of this op, if its single output can be written over a contiguous input
+    fn in_place(&self) -> Option<&dyn InPlaceKernel> {
+        None
+    }
+}
+
+/// A CPU op that can write its output over one of its inputs
+pub trait InPlaceKernel {
+    /// Run the op, writing the output over input `ind`, which has been moved into `buffer`
+    fn process_in_place(&self, ind: usize, buffer: &mut [f32], inputs: &[(&[f32], ShapeTracker)]);
+}
+
+/// Get the buffer-writing version of an op, if it has one
+pub fn cpu_kernel(op: &dyn Operator) -> Option<Box<dyn CPUKernel>> {
+    fn cast<T: CPUKernel + Clone + 'static>(op: &dyn Operator) -> Option<Box<dyn CPUKernel>> {
+        op.as_any()
+            .downcast_ref::<T>()
+            .map(|o| Box::new(o.clone()) as Box<dyn CPUKernel>)
+    }
+    cast::<Contiguous>(op)
+        .or_else(|| cast::<Log2>(op))
+        .or_else(|| cast::<Exp2>(op))
+        .or_else(|| cast::<Sin>(op))
+        .or_else(|| cast::<Recip>(op))
+        .or_else(|| cast::<Sqrt>(op))
+        .or_else(|| cast::<Add>(op))
+        .or_else(|| cast::<Mul>(op))
+        .or_else(|| cast::<Mod>(op))
+        .or_else(|| cast::<LessThan>(op))
+        .or_else(|| cast::<SumReduce>(op))
+        .or_else(|| cast::<MaxReduce>(op))
+        .or_else(|| cast::<Sub>(op))
+        .or_else(|| cast::<Equal>(op))
+        .or_else(|| cast::<FusedUnary>(op))
+        .or_else(|| cast::<FusedElementwise>(op))
+        .or_else(|| cast::<MatMul2D>(op))
+        .or_else(|| cast::<BatchedMatMul2D>(op))
+}
+
+/// The result of planning memory for a graph. All figures are planner estimates: they assume every
+/// dying buffer makes it back to the arena, which only happens when its last consumer owns it. Buffers
+/// that are still borrowed when they die (multi-consumer tensors, retrieved tensors) are freed by the
+/// executor instead, and the op reusing their slot allocates a fresh buffer.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryPlan {
+    /// Number of elements in each arena buffer
+    pub buffer_sizes: Vec<BigExpression>,
+    /// Arena buffers each planned node writes its outputs to
+    pub output_buffers: FxHashMap<NodeIndex, Vec<usize>>,
+    /// Number of nodes computed in place over a dying input
+    pub in_place: usize,
+    /// Sizes of every planned output, as they would be allocated without planning
+    pub unplanned_sizes: Vec<BigExpression>,
+}
+
+impl MemoryPlan {
+    /// Number of arena buffers in the plan. The executor may allocate more, see [`MemoryPlan`]
+    pub fn allocations(&self) -> usize {
+        self.buffer_sizes.len()
+    }
+
+    /// Planned peak memory in bytes of the arena buffers. Actual usage can be higher, see [`MemoryPlan`]
+    pub fn peak_memory(&self, dyn_map: &FxHashMap<char, usize>) -> usize {
+        total_bytes(&self.buffer_sizes, dyn_map)
+    }
+
+    /// Memory in bytes the planned outputs would take if each got its own allocation
+    pub fn unplanned_memory(&self, dyn_map: &FxHashMap<char, usize>) -> usize {
+        total_bytes(&self.unplanned_sizes, dyn_map)
+    }
+}
+
+fn total_bytes(sizes: &[BigExpression], dyn_map: &FxHashMap<char, usize>) -> usize {
+    sizes
+        .iter()
+        .map(|s| s.exec(dyn_map).unwrap() * std::mem::size_of::<f32>())
+        .sum()
+}
+
+/// Plan CPU memory by running liveness analysis over the graph and assigning op outputs into a
+/// small set of reused arena buffers. Should be ran after all other compilers.
+#[derive(Debug, Default)]
+pub struct StorageBufferCompiler;
+
+impl Compiler for StorageBufferCompiler {
+    type Output = MemoryPlan;
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) -> MemoryPlan {
+        let order = toposort(&graph.graph, None).unwrap();
+        // The last node to use each tensor
+        let mut last_use = FxHashMap::default();
+        for node in &order {
+            for (src, ind, _) in graph.get_sources(*node) {
+                last_use.insert((src, ind), *node);
+            }
+        }
+
+        let mut plan = MemoryPlan::default();
+        let mut assignments = FxHashMap::<(NodeIndex, u8), usize>::default();
+        let mut free = vec![];
+        let mut wrappers = vec![];
+        for node in order {
+            if graph.no_delete.contains(&node) {
+                continue;
+            }
+            let Some(kernel) = cpu_kernel(graph.graph.node_weight(node).unwrap().as_ref()) else {
+                continue;
+            };
+            // Arena buffers only hold f32s
+            if graph.node_dtype(node) != DType::F32
+                || graph
+                    .graph
+                    .edges_directed(node, Direction::Incoming)
+                    .any(|e| e.weight().dtype().is_some_and(|d| d != DType::F32))
+            {
+                continue;
+            }
+            let srcs = graph.get_sources(node);
+            let sizes =
+                kernel.output_buffer_sizes(&srcs.iter().map(|(_, _, sh)| *sh).collect_vec());
+            // Planned inputs this node is the only remaining user of
+            let dying = srcs
+                .iter()
+                .enumerate()
+                .filter(|(_, (src, ind, _))| {
+                    assignments.contains_key(&(*src, *ind))
+                        && last_use[&(*src, *ind)] == node
+                        && srcs.iter().filter(|(s, i, _)| s == src && i == ind).count() == 1
+                })
+                .map(|(i, (src, ind, sh))| (i, assignments[&(*src, *ind)], *sh))
+                .collect_vec();
+
+            // Write over a dying input if we can, otherwise find a free buffer of the same size
+            let mut in_place = None;
+            let mut outputs = vec![];
+            if kernel.in_place().is_some() && sizes.len() == 1 {
+                if let Some((i, buffer, _)) = dying
+                    .iter()
+                    .find(|(_, b, sh)| !sh.is_reshaped() && plan.buffer_sizes[*b] == sizes[0])
+                {
+                    in_place = Some(*i);
+                    outputs.push(*buffer);
+                    plan.in_place += 1;
+                }
+            }
+            if in_place.is_none() {
+                for size in &sizes {
+                    if let Some(pos) = free
+                        .iter()
+                        .position(|b: &usize| plan.buffer_sizes[*b] == *size)
+                    {
+                        outputs.push(free.remove(pos));
+                    } else {
+                        outputs.push(plan.buffer_sizes.len());
+                        plan.buffer_sizes.push(size.clone());
+                    }
+                }
+            }
+            // Release the rest of the dying inputs now that the outputs are placed
+            free.extend(
+                dying
+                    .iter()
+                    .filter(|(i, _, _)| Some(*i) != in_place)
+                    .map(|(_, b, _)| *b),
+            );
+            for (i, buffer) in outputs.iter().enumerate() {
+                assignments.insert((node, i as u8), *buffer);
+            }
+            let input_buffers = srcs
+                .iter()
+                .map(|(src, ind, _)| assignments.get(&(*src, *ind)).copied())
+                .collect();
+            plan.unplanned_sizes.extend(sizes);
+            plan.output_buffers.insert(node, outputs.clone());
+            wrappers.push((node, kernel, outputs, input_buffers, in_place));
+        }
+
+        // Swap planned ops for buffer-writing wrappers
+        let arena = Arc::new(Mutex::new(vec![vec![]; plan.buffer_sizes.len()]));
+        for (node, kernel, output_buffers, input_buffers, in_place) in wrappers {
+            *graph.graph.node_weight_mut(node).unwrap() = Box::new(StorageBufferWrapper {
+                kernel,
+                arena: arena.clone(),
+                output_buffers,
+                input_buffers,
+                in_place,
+            });
+        }
+        plan
+    }
+}
+
+/// Runs a kernel on its planned arena buffers. Buffers of inputs that die here go back to the
+/// arena for later ops to reuse, but only if the executor hands them over as owned. Borrowed inputs
+/// are dropped by the executor, so their slot is refilled by a fresh allocation the next time it's
+/// used.
+struct StorageBufferWrapper {
+    kernel: Box<dyn CPUKernel>,
+    arena: Arc<Mutex<Vec<Vec<f32>>>>,
+    output_buffers: Vec<usize>,
+    input_buffers: Vec<Option<usize>>,
+    in_place: Option<usize>,
+}
+
+impl Debug for StorageBufferWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.kernel.fmt(f)
+    }
+}
+
+impl Operator for StorageBufferWrapper {
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let sizes = self
+            .kernel
+            .output_buffer_sizes(&inp.iter().map(|(_, sh)| *sh).collect_vec())
+            .into_iter()
+            .map(|s| s.to_usize().unwrap())
+            .collect_vec();
+        // Take over the dying input's buffer if we own it
+        let mut in_place_buffer = None;
+        if let (Some(ind), Some(_)) = (self.in_place, self.kernel.in_place()) {
+            if let InputTensor::Owned(t) = &mut inp[ind].0 {
+                if let Some(buffer) = t.downcast_mut::<Vec<f32>>() {
+                    if buffer.len() == sizes[0] {
+                        in_place_buffer = Some(std::mem::take(buffer));
+                    }
+                }
+            }
+        }
+
+        // Inputs only differ from f32 if their type was changed after planning
+        let data = inp
+            .iter()
+            .map(|(t, _)| t.borrowed().as_elements::<f32>())
+            .collect_vec();
+        let inputs = data
+            .iter()
+            .zip(&inp)
+            .map(|(d, (_, sh))| (d.as_ref(), *sh))
+            .collect_vec();
+        let outputs =
+            if let (Some(mut buffer), Some(kernel)) = (in_place_buffer, self.kernel.in_place()) {
+                kernel.process_in_place(self.in_place.unwrap(), &mut buffer, &inputs);
+                vec![buffer]
+            } else {
+                let mut arena = self.arena.lock().unwrap();
+                let mut outputs = self
+                    .output_buffers
+                    .iter()
+                    .zip(&sizes)
+                    .map(|(b, size)| {
+                        let mut buffer = std::mem::take(&mut arena[*b]);
+                        buffer.clear();
+                        buffer.resize(*size, 0.0);
+                        buffer
+                    })
+                    .collect_vec();
+                drop(arena);
+                self.kernel.process_into(
+                    &inputs,
+                    &mut outputs.iter_mut().map(|o| o.as_mut_slice()).collect_vec(),
+                );
+                outputs
+            };
+        drop(inputs);
+        drop(data);
+
+        // Hand buffers of inputs we own back to the arena
+        let mut arena = self.arena.lock().unwrap();
+        for ((t, _), slot) in inp.into_iter().zip(&self.input_buffers) {
+            if let (InputTensor::Owned(mut t), Some(slot)) = (t, slot) {
+                if let Some(buffer) = t.downcast_mut::<Vec<f32>>() {
+                    if buffer.capacity() > arena[*slot].capacity() {
+                        arena[*slot] = std::mem::take(buffer);
+                    }
+                }
+            }
+        }
+        outputs.into_iter().map(Tensor::new).collect()
+    }
+}
+
+/// Write f(a) into the output
+pub(crate) fn unary_into(
+    f: impl Fn(f32) -> f32 + Sync,
+    inp: &(&[f32], ShapeTracker),
+    out: &mut [f32],
+) {
+    kernels::unary_into(&ViewReader::new(inp.0, &inp.1), out, f);
+}
+
+/// Write f(a, b) into the output
+pub(crate) fn binary_into(
+    f: impl Fn(f32, f32) -> f32 + Sync,
+    a: &(&[f32], ShapeTracker),
+    b: &(&[f32], ShapeTracker),
+    out: &mut [f32],
+) {
+    kernels::binary_into(
+        &ViewReader::new(a.0, &a.1),
+        &ViewReader::new(b.0, &b.1),
+        out,
+        f,
+    );
+}
+
+/// Write f(a, b) over whichever input is held in the buffer
+pub(crate) fn binary_in_place(
+    f: impl Fn(f32, f32) -> f32 + Sync,
+    ind: usize,
+    buffer: &mut [f32],
+    inputs: &[(&[f32], ShapeTracker)],
+) {
+    let other = ViewReader::new(inputs[1 - ind].0, &inputs[1 - ind].1);
+    kernels::par_chunks(buffer, 1, 2, |start, chunk| {
+        let mut buf = vec![];
+        let other = other.read(start, chunk.len(), &mut buf);
+        for (o, b) in chunk.iter_mut().zip(other) {
+            *o = if ind == 0 { f(*o, *b) } else { f(*b, *o) };
+        }
+    });
+}
+
+macro_rules! unary_kernel {
+    ($op:ty, $f:expr) => {
+        impl CPUKernel for $op {
+            fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+                vec![input_shapes[0].n_elements()]
+            }
+            fn process_into(&self, inputs: &[(&[f32], ShapeTracker)], outputs: &mut [&mut [f32]]) {
+                unary_into($f, &inputs[0], outputs[0]);
+            }
+            fn in_place(&self) -> Option<&dyn InPlaceKernel> {
+                Some(self)
+            }
+        }
+
+        impl InPlaceKernel for $op {
+            fn process_in_place(&self, _: usize, buffer: &mut [f32], _: &[(&[f32], ShapeTracker)]) {
+                let f: fn(f32) -> f32 = $f;
+                kernels::par_chunks(buffer, 1, 1, |_, chunk| {
+                    for o in chunk {
+                        *o = f(*o);
+                    }
+                });
+            }
+        }
+    };
+}
+
+macro_rules! binary_kernel {
+    ($op:ty, $f:expr) => {
+        impl CPUKernel for $op {
+            fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+                vec![input_shapes[0].n_elements()]
+            }
+            fn process_into(&self, inputs: &[(&[f32], ShapeTracker)], outputs: &mut [&mut [f32]]) {
+                binary_into($f, &inputs[0], &inputs[1], outputs[0]);
+            }
+            fn in_place(&self) -> Option<&dyn InPlaceKernel> {
+                Some(self)
+            }
+        }
+
+        impl InPlaceKernel for $op {
+            fn process_in_place(
+                &self,
+                ind: usize,
+                buffer: &mut [f32],
+                inputs: &[(&[f32], ShapeTracker)],
+            ) {
+                binary_in_place($f, ind, buffer, inputs);
+            }
+        }
+    };
+}
+
+unary_kernel!(Contiguous, |a| a);
+unary_kernel!(Log2, |a| a.log2());
+unary_kernel!(Exp2, |a| a.exp2());
+unary_kernel!(Sin, |a| a.sin());
+unary_kernel!(Recip, |a| a.recip());
+unary_kernel!(Sqrt, |a| a.sqrt());
+binary_kernel!(Add, |a, b| a + b);
+binary_kernel!(Mul, |a, b| a * b);
+binary_kernel!(Mod, |a, b| a % b);
+binary_kernel!(LessThan, |a, b| (a < b) as i32 as f32);
+binary_kernel!(Sub, |a, b| a - b);
+binary_kernel!(Equal, |a, b| (a == b) as i32 as f32);
+
+impl CPUKernel for FusedUnary {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+        vec![input_shapes[0].n_physical_elements()]
+    }
+    fn process_into(&self, inputs: &[(&[f32], ShapeTracker)], outputs: &mut [&mut [f32]]) {
+        outputs[0].copy_from_slice(inputs[0].0);
+        self.process_in_place(0, outputs[0], inputs);
+    }
+    fn in_place(&self) -> Option<&dyn InPlaceKernel> {
+        Some(self)
+    }
+}
+
+impl InPlaceKernel for FusedUnary {
+    fn process_in_place(&self, _: usize, buffer: &mut [f32], _: &[(&[f32], ShapeTracker)]) {
+        kernels::par_chunks(buffer, 1, self.0.len(), |_, chunk| {
+            for a in chunk {
+                for f in &self.0 {
+                    *a = f.apply(*a);
+                }
+            }
+        });
+    }
+}
+
+fn reduce_into(
+    dim: usize,
+    init: f32,
+    f: impl Fn(f32, f32) -> f32 + Sync,
+    inp: &(&[f32], ShapeTracker),
+    out: &mut [f32],
+) {
+    let sh = inp.1.shape_usize();
+    kernels::reduce_into(&ViewReader::new(inp.0, &inp.1), &sh, dim, init, f, out);
+}
+
+fn reduced_size(dim: usize, shape: &ShapeTracker) -> BigExpression {
+    shape
+        .shape()
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| *i != dim)
+        .map(|(_, d)| d)
+        .product::<BigExpression>()
+        .max(1)
+}
+
+impl CPUKernel for SumReduce {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+        vec![reduced_size(self.0, &input_shapes[0])]
+    }
+    fn process_into(&self, inputs: &[(&[f32], ShapeTracker)], outputs: &mut [&mut [f32]]) {
+        reduce_into(self.0, 0.0, |a, b| a + b, &inputs[0], outputs[0]);
+    }
+}
+
+impl CPUKernel for MaxReduce {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+        vec![reduced_size(self.0, &input_shapes[0])]
+    }
+    fn process_into(&self, inputs: &[(&[f32], ShapeTracker)], outputs: &mut [&mut [f32]]) {
+        reduce_into(self.0, -f32::INFINITY, f32::max, &inputs[0], outputs[0]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::prelude::*;
+
+    use super::StorageBufferCompiler;
+    use crate::CPUCompiler;
+    luminal::test_imports!();
+
+    #[test]
+    fn test_memory_plan() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<4, 8>>().set(random_vec(32)).keep();
+        let b = cx.tensor::<R2<8, 8>>().set(random_vec(64)).keep();
+        let c = a.matmul(b).exp2().log2().sin() * 2.0;
+        let d = (c + c.recip()).sqrt().sum_reduce::<_, LAxis<1>>();
+        let mut e = (d.expand::<R2<4, 8>, _>() * c)
+            .max_reduce::<_, LAxis<0>>()
+            .retrieve();
+
+        cx.execute();
+        let unplanned = e.data();
+        e.drop();
+
+        let (_, plan) = cx.compile(
+            (CPUCompiler::<f32>::default(), StorageBufferCompiler),
+            &mut e,
+        );
+        assert!(plan.allocations() < plan.unplanned_sizes.len());
+        assert!(plan.in_place > 0);
+        assert!(plan.peak_memory(&cx.dyn_map) < plan.unplanned_memory(&cx.dyn_map));
+
+        // Run twice to make sure recycled buffers give the same results
+        for _ in 0..2 {
+            cx.execute();
+            assert_close(&e.data(), &unplanned);
+            e.drop();
+        }
+    }
+}
@@ -0,0 +1,394 @@
+//! Lowering kernels to native code with Cranelift
+
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+
+use cranelift_codegen::{
+    ir::{
+        condcodes::{FloatCC, IntCC},
+        types, AbiParam, BlockArg, FuncRef, InstBuilder, MemFlagsData, Signature, Value,
+    },
+    settings::{self, Configurable},
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+use itertools::Itertools;
+use rustc_hash::FxHashMap;
+
+use luminal::prelude::*;
+
+use crate::kernel::{BinaryOp, Expr, ReduceOp, UnaryOp};
+
+/// A compiled kernel. Takes the input pointers and writes outputs `start..end` to the output pointer.
+/// The last argument points to the size of the reduced dim, the number of elements after it, and
+/// then the value of each dyn dim the kernel reads
+pub(crate) type KernelFn = unsafe extern "C" fn(*const *const f32, *mut f32, i64, i64, *const i64);
+
+/// A kernel function along with the module holding its code, which is freed once the last
+/// [`JitKernel`](crate::JitKernel) using it is dropped
+pub(crate) struct CompiledKernel {
+    module: Mutex<Option<JITModule>>,
+    pub(crate) f: KernelFn,
+}
+
+impl Drop for CompiledKernel {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.get_mut().unwrap().take() {
+            // Safety: the kernel can only be called through this struct, which is going away
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+/// Every live kernel, keyed by its ops and the symbolic index math of its views, so new dyn dim
+/// values and identical kernels in other graphs reuse the same code. Entries are weak so modules
+/// don't outlive the graphs using them
+static KERNELS: OnceLock<Mutex<FxHashMap<String, Weak<CompiledKernel>>>> = OnceLock::new();
+
+/// Get the compiled kernel for a body, compiling it if no live kernel matches, along with the dyn
+/// dims it takes values for, in order
+pub(crate) fn kernel(
+    body: &Expr,
+    reduce: Option<ReduceOp>,
+    views: &[ShapeTracker],
+    n_inputs: usize,
+) -> (Arc<CompiledKernel>, Vec<char>) {
+    let views = views
+        .iter()
+        .map(|v| (v.index_expression(), v.valid_expression()))
+        .collect::<Vec<_>>();
+    let dims = views
+        .iter()
+        .flat_map(|(ind, val)| ind.to_symbols().into_iter().chain(val.to_symbols()))
+        .filter(|c| *c != 'z')
+        .sorted()
+        .dedup()
+        .collect::<Vec<_>>();
+    let key = format!("{body:?}|{reduce:?}|{views:?}|{n_inputs}");
+    let mut kernels = KERNELS.get_or_init(Default::default).lock().unwrap();
+    if let Some(kernel) = kernels.get(&key).and_then(Weak::upgrade) {
+        return (kernel, dims);
+    }
+    // Drop entries for kernels that have been freed before adding a new one
+    kernels.retain(|_, k| k.strong_count() > 0);
+    let (module, f) = compile(body, reduce, &views, &dims, n_inputs);
+    let kernel = Arc::new(CompiledKernel {
+        module: Mutex::new(Some(module)),
+        f,
+    });
+    kernels.insert(key, Arc::downgrade(&kernel));
+    (kernel, dims)
+}
+
+extern "C" fn exp2(x: f32) -> f32 {
+    x.exp2()
+}
+
+extern "C" fn log2(x: f32) -> f32 {
+    x.log2()
+}
+
+extern "C" fn sin(x: f32) -> f32 {
+    x.sin()
+}
+
+extern "C" fn fmod(a: f32, b: f32) -> f32 {
+    a % b
+}
+
+/// Math the kernels call back into Rust for
+struct MathFns {
+    exp2: FuncRef,
+    log2: FuncRef,
+    sin: FuncRef,
+    fmod: FuncRef,
+}
+
+/// What expressions are lowered against
+struct Context<'a> {
+    inputs: Vec<Value>,
+    views: &'a [(BigExpression, BigExpression)],
+    /// The value of each dyn dim
+    dims: FxHashMap<char, Value>,
+    math: MathFns,
+}
+
+fn compile(
+    body: &Expr,
+    reduce: Option<ReduceOp>,
+    views: &[(BigExpression, BigExpression)],
+    dims: &[char],
+    n_inputs: usize,
+) -> (JITModule, KernelFn) {
+    let mut flags = settings::builder();
+    flags.set("use_colocated_libcalls", "false").unwrap();
+    flags.set("is_pic", "false").unwrap();
+    flags.set("opt_level", "speed").unwrap();
+    let isa = cranelift_native::builder()
+        .unwrap_or_else(|e| panic!("Host isn't supported by Cranelift: {e}"))
+        .finish(settings::Flags::new(flags))
+        .unwrap();
+    let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+    builder.symbol("luminal_exp2", exp2 as *const u8);
+    builder.symbol("luminal_log2", log2 as *const u8);
+    builder.symbol("luminal_sin", sin as *const u8);
+    builder.symbol("luminal_fmod", fmod as *const u8);
+    let mut module = JITModule::new(builder);
+    let ptr = module.target_config().pointer_type();
+
+    let signature = |params: &[types::Type], returns: &[types::Type]| {
+        let mut sig = module.make_signature();
+        sig.params.extend(params.iter().map(|t| AbiParam::new(*t)));
+        sig.returns
+            .extend(returns.iter().map(|t| AbiParam::new(*t)));
+        sig
+    };
+    let kernel_sig = signature(&[ptr, ptr, types::I64, types::I64, ptr], &[]);
+    let unary_sig = signature(&[types::F32], &[types::F32]);
+    let binary_sig = signature(&[types::F32, types::F32], &[types::F32]);
+    let id = module
+        .declare_function("kernel", Linkage::Local, &kernel_sig)
+        .unwrap();
+
+    let mut ctx = module.make_context();
+    ctx.func.signature = kernel_sig;
+    let mut fn_ctx = FunctionBuilderContext::new();
+    let mut b = FunctionBuilder::new(&mut ctx.func, &mut fn_ctx);
+    let mut import = |name: &str, sig: &Signature| {
+        let f = module.declare_function(name, Linkage::Import, sig).unwrap();
+        module.declare_func_in_func(f, b.func)
+    };
+    let math = MathFns {
+        exp2: import("luminal_exp2", &unary_sig),
+        log2: import("luminal_log2", &unary_sig),
+        sin: import("luminal_sin", &unary_sig),
+        fmod: import("luminal_fmod", &binary_sig),
+    };
+
+    // Load the input pointers and dims, then loop over the outputs
+    let entry = b.create_block();
+    b.append_block_params_for_function_params(entry);
+    b.switch_to_block(entry);
+    let &[inputs, out, start, end, dim_values] = b.block_params(entry) else {
+        unreachable!()
+    };
+    let mut load_dim = |i: usize| {
+        b.ins().load(
+            types::I64,
+            MemFlagsData::trusted(),
+            dim_values,
+            (i * 8) as i32,
+        )
+    };
+    let (dim, back) = (load_dim(0), load_dim(1));
+    let dims = dims
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (*c, load_dim(i + 2)))
+        .collect();
+    let cx = Context {
+        inputs: (0..n_inputs)
+            .map(|i| {
+                b.ins()
+                    .load(ptr, MemFlagsData::trusted(), inputs, (i * 8) as i32)
+            })
+            .collect(),
+        views,
+        dims,
+        math,
+    };
+    let header = b.create_block();
+    let i = b.append_block_param(header, types::I64);
+    let body_block = b.create_block();
+    let exit = b.create_block();
+    b.ins().jump(header, &[BlockArg::from(start)]);
+
+    b.switch_to_block(header);
+    let more = b.ins().icmp(IntCC::SignedLessThan, i, end);
+    b.ins().brif(more, body_block, &[], exit, &[]);
+
+    b.switch_to_block(body_block);
+    let value = match reduce {
+        None => lower(&mut b, &cx, body, i),
+        Some(op) => {
+            // Walk the reduced dim of the domain for this output
+            let outer = b.ins().sdiv(i, back);
+            let stride = b.ins().imul(dim, back);
+            let outer = b.ins().imul(outer, stride);
+            let inner = b.ins().srem(i, back);
+            let base = b.ins().iadd(outer, inner);
+            let init = b.ins().f32const(match op {
+                ReduceOp::Sum => 0.,
+                ReduceOp::Max => f32::NEG_INFINITY,
+            });
+            let r_header = b.create_block();
+            let r = b.append_block_param(r_header, types::I64);
+            let acc = b.append_block_param(r_header, types::F32);
+            let r_body = b.create_block();
+            let done = b.create_block();
+            let result = b.append_block_param(done, types::F32);
+            let zero = b.ins().iconst(types::I64, 0);
+            b.ins()
+                .jump(r_header, &[BlockArg::from(zero), BlockArg::from(init)]);
+
+            b.switch_to_block(r_header);
+            let more = b.ins().icmp(IntCC::SignedLessThan, r, dim);
+            b.ins()
+                .brif(more, r_body, &[], done, &[BlockArg::from(acc)]);
+
+            b.switch_to_block(r_body);
+            let offset = b.ins().imul(r, back);
+            let z = b.ins().iadd(base, offset);
+            let v = lower(&mut b, &cx, body, z);
+            let acc = match op {
+                ReduceOp::Sum => b.ins().fadd(acc, v),
+                ReduceOp::Max => {
+                    let larger = b.ins().fcmp(FloatCC::GreaterThan, v, acc);
+                    b.ins().select(larger, v, acc)
+                }
+            };
+            let next = b.ins().iadd_imm_s(r, 1);
+            b.ins()
+                .jump(r_header, &[BlockArg::from(next), BlockArg::from(acc)]);
+
+            b.switch_to_block(done);
+            result
+        }
+    };
+    let offset = b.ins().isub(i, start);
+    let offset = b.ins().imul_imm_s(offset, 4);
+    let addr = b.ins().iadd(out, offset);
+    b.ins().store(MemFlagsData::trusted(), value, addr, 0);
+    let next = b.ins().iadd_imm_s(i, 1);
+    b.ins().jump(header, &[BlockArg::from(next)]);
+
+    b.switch_to_block(exit);
+    b.ins().return_(&[]);
+    b.seal_all_blocks();
+    b.finalize(module.target_config());
+
+    module.define_function(id, &mut ctx).unwrap();
+    module.clear_context(&mut ctx);
+    module.finalize_definitions().unwrap();
+    let f =
+        unsafe { std::mem::transmute::<*const u8, KernelFn>(module.get_finalized_function(id)) };
+    (module, f)
+}
+
+/// Emit the value of an expression at index `z` of its domain
+fn lower(b: &mut FunctionBuilder, cx: &Context, expr: &Expr, z: Value) -> Value {
+    match expr {
+        Expr::Input(i) => {
+            let offset = b.ins().imul_imm_s(z, 4);
+            let addr = b.ins().iadd(cx.inputs[*i], offset);
+            b.ins().load(types::F32, MemFlagsData::trusted(), addr, 0)
+        }
+        Expr::View(v, inner) => {
+            let (ind, val) = &cx.views[*v];
+            match val.to_usize() {
+                Some(0) => b.ins().f32const(0.),
+                Some(_) => {
+                    let z = index(b, cx, ind, z);
+                    lower(b, cx, inner, z)
+                }
+                None => {
+                    // Only compute the index, and read, where the view is valid
+                    let valid = index(b, cx, val, z);
+                    let read = b.create_block();
+                    let merge = b.create_block();
+                    let result = b.append_block_param(merge, types::F32);
+                    let zero = b.ins().f32const(0.);
+                    b.ins()
+                        .brif(valid, read, &[], merge, &[BlockArg::from(zero)]);
+                    b.switch_to_block(read);
+                    let z = index(b, cx, ind, z);
+                    let value = lower(b, cx, inner, z);
+                    b.ins().jump(merge, &[BlockArg::from(value)]);
+                    b.switch_to_block(merge);
+                    result
+                }
+            }
+        }
+        Expr::Const(c) => b.ins().f32const(*c),
+        Expr::Unary(op, a) => {
+            let a = lower(b, cx, a, z);
+            let call = |b: &mut FunctionBuilder, f| {
+                let inst = b.ins().call(f, &[a]);
+                b.inst_results(inst)[0]
+            };
+            match op {
+                UnaryOp::Exp2 => call(b, cx.math.exp2),
+                UnaryOp::Log2 => call(b, cx.math.log2),
+                UnaryOp::Sin => call(b, cx.math.sin),
+                UnaryOp::Sqrt => b.ins().sqrt(a),
+                UnaryOp::Recip => {
+                    let one = b.ins().f32const(1.);
+                    b.ins().fdiv(one, a)
+                }
+            }
+        }
+        Expr::Binary(op, a, c) => {
+            let (a, c) = (lower(b, cx, a, z), lower(b, cx, c, z));
+            match op {
+                BinaryOp::Add => b.ins().fadd(a, c),
+                BinaryOp::Mul => b.ins().fmul(a, c),
+                BinaryOp::Mod => {
+                    let inst = b.ins().call(cx.math.fmod, &[a, c]);
+                    b.inst_results(inst)[0]
+                }
+                BinaryOp::LessThan => {
+                    let less = b.ins().fcmp(FloatCC::LessThan, a, c);
+                    let (one, zero) = (b.ins().f32const(1.), b.ins().f32const(0.));
+                    b.ins().select(less, one, zero)
+                }
+            }
+        }
+    }
+}
+
+/// Emit an index expression of `z` and the dyn dims as 64 bit integer math
+fn index(b: &mut FunctionBuilder, cx: &Context, expr: &BigExpression, z: Value) -> Value {
+    let mut stack = vec![];
+    for term in &expr.terms {
+        let value = match *term {
+            Term::Num(n) => b.ins().iconst(types::I64, n as i64),
+            Term::Var('z') => z,
+            Term::Var(c) => cx.dims[&c],
+            term => {
+                let x = stack.pop().unwrap();
+                let y = stack.pop().unwrap();
+                let cmp = |b: &mut FunctionBuilder, cc, x, y| {
+                    let c = b.ins().icmp(cc, x, y);
+                    b.ins().uextend(types::I64, c)
+                };
+                match term {
+                    Term::Add => b.ins().iadd(x, y),
+                    Term::Sub => b.ins().isub(x, y),
+                    Term::Mul => b.ins().imul(x, y),
+                    Term::Div => b.ins().sdiv(x, y),
+                    Term::Mod => b.ins().srem(x, y),
+                    Term::Min => b.ins().smin(x, y),
+                    Term::Max => b.ins().smax(x, y),
+                    Term::And => {
+                        let (x, y) = (
+                            b.ins().icmp_imm_s(IntCC::NotEqual, x, 0),
+                            b.ins().icmp_imm_s(IntCC::NotEqual, y, 0),
+                        );
+                        let c = b.ins().band(x, y);
+                        b.ins().uextend(types::I64, c)
+                    }
+                    Term::Or => {
+                        let either = b.ins().bor(x, y);
+                        let c = b.ins().icmp_imm_s(IntCC::NotEqual, either, 0);
+                        b.ins().uextend(types::I64, c)
+                    }
+                    Term::Gte => cmp(b, IntCC::SignedGreaterThanOrEqual, x, y),
+                    Term::Lt => cmp(b, IntCC::SignedLessThan, x, y),
+                    Term::Num(_) | Term::Var(_) => unreachable!(),
+                }
+            }
+        };
+        stack.push(value);
+    }
+    stack.pop().unwrap()
+}
@@ -0,0 +1,153 @@
+use std::{cell::OnceCell, fmt::Debug, sync::Arc};
+
+use itertools::Itertools;
+use rustc_hash::FxHashMap;
+
+use luminal::{
+    kernels::par_chunks,
+    op::{InputTensor, Operator},
+    prelude::*,
+};
+
+use crate::codegen::{self, CompiledKernel};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum UnaryOp {
+    Exp2,
+    Log2,
+    Sin,
+    Sqrt,
+    Recip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BinaryOp {
+    Add,
+    Mul,
+    Mod,
+    LessThan,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ReduceOp {
+    Sum,
+    Max,
+}
+
+/// The value a kernel computes at each index of its domain
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Expr {
+    /// The element of an input at the current index
+    Input(usize),
+    /// Evaluate the inner expression at the index a view maps the current one to, or 0 where the
+    /// view is padding
+    View(usize, Box<Expr>),
+    Const(f32),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// Rebuild the expression bottom up, replacing each node with `f` of it
+    pub(crate) fn map(self, f: &mut impl FnMut(Expr) -> Expr) -> Expr {
+        let e = match self {
+            Expr::View(v, inner) => Expr::View(v, Box::new(inner.map(f))),
+            Expr::Unary(op, a) => Expr::Unary(op, Box::new(a.map(f))),
+            Expr::Binary(op, a, b) => Expr::Binary(op, Box::new(a.map(f)), Box::new(b.map(f))),
+            e => e,
+        };
+        f(e)
+    }
+
+    /// Number of ops evaluated per element
+    fn cost(&self) -> usize {
+        match self {
+            Expr::Input(_) | Expr::Const(_) => 1,
+            Expr::View(_, a) | Expr::Unary(_, a) => 1 + a.cost(),
+            Expr::Binary(_, a, b) => 1 + a.cost() + b.cost(),
+        }
+    }
+}
+
+/// A fused subgraph of elementwise ops, optionally folded along an axis, ran as a JIT compiled loop
+#[derive(Clone)]
+pub struct JitKernel {
+    pub(crate) body: Expr,
+    /// Fold the body along an axis of the domain
+    pub(crate) reduce: Option<(ReduceOp, usize)>,
+    /// The views the body reads through. The shape of the first is the domain it's evaluated over
+    pub(crate) views: Vec<ShapeTracker>,
+    pub(crate) dyn_map: *const FxHashMap<char, usize>,
+    /// The compiled kernel and the dyn dims it reads, looked up on first run
+    pub(crate) compiled: OnceCell<(Arc<CompiledKernel>, Vec<char>)>,
+}
+
+impl PartialEq for JitKernel {
+    fn eq(&self, other: &Self) -> bool {
+        self.body == other.body
+            && self.reduce == other.reduce
+            && self.views == other.views
+            && self.dyn_map == other.dyn_map
+    }
+}
+
+impl Debug for JitKernel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "JitKernel({:?}, {:?})", self.body, self.reduce)
+    }
+}
+
+impl Operator for JitKernel {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let dyn_map = unsafe { self.dyn_map.as_ref().unwrap() };
+        let mut domain = self.views[0];
+        domain.resolve_global_dyn_dims(dyn_map);
+        let shape = domain.shape_usize();
+        let (front, dim, back) = match self.reduce {
+            Some((_, axis)) => (
+                shape[..axis].iter().product::<usize>(),
+                shape[axis],
+                shape[axis + 1..].iter().product::<usize>(),
+            ),
+            None => (shape.iter().product(), 1, 1),
+        };
+        let mut out = vec![0.; front * back];
+        if out.is_empty() {
+            return vec![Tensor::new(out)];
+        }
+        let (kernel, dims) = self.compiled.get_or_init(|| {
+            codegen::kernel(
+                &self.body,
+                self.reduce.map(|(op, _)| op),
+                &self.views,
+                inp.len(),
+            )
+        });
+        let kernel = kernel.f;
+        let dims = [dim, back]
+            .into_iter()
+            .chain(dims.iter().map(|d| {
+                *dyn_map
+                    .get(d)
+                    .unwrap_or_else(|| panic!("Dynamic dimension '{d}' is not set"))
+            }))
+            .map(|v| v as i64)
+            .collect_vec();
+
+        let data = inp
+            .iter()
+            .map(|(t, _)| t.borrowed().as_elements::<f32>())
+            .collect_vec();
+        let ptrs = data.iter().map(|d| d.as_ptr() as usize).collect_vec();
+        par_chunks(&mut out, 1, dim * self.body.cost(), |start, out| unsafe {
+            kernel(
+                ptrs.as_ptr() as *const *const f32,
+                out.as_mut_ptr(),
+                start as i64,
+                (start + out.len()) as i64,
+                dims.as_ptr(),
+            )
+        });
+        vec![Tensor::new(out)]
+    }
+}
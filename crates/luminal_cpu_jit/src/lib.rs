@@ -0,0 +1,293 @@
+//! A CPU backend that compiles fused elementwise ops and reductions, along with the index math of
+//! their views, to native code with Cranelift
+
+mod codegen;
+mod kernel;
+#[cfg(test)]
+mod tests;
+
+pub use kernel::JitKernel;
+
+use itertools::Itertools;
+use petgraph::{visit::EdgeRef, Direction};
+use rustc_hash::FxHashSet;
+
+use luminal::{
+    op::{
+        Add, Constant, ConstantValue, Contiguous, Exp2, LessThan, Log2, MaxReduce, Mod, Mul, Recip,
+        Sin, Sqrt, SumReduce,
+    },
+    prelude::*,
+};
+
+use kernel::{BinaryOp, Expr, ReduceOp, UnaryOp};
+
+/// Compiles a graph for the CPU, running matmuls through the CPU backend and everything else it can
+/// through JIT compiled kernels
+pub type CPUJitCompiler = (luminal_cpu::MatMulCompiler, JitCompiler);
+
+/// Replace f32 elementwise ops and reductions with [`JitKernel`]s, fusing elementwise producers into
+/// their only consumer so each fused subgraph runs as one native loop
+#[derive(Debug, Default)]
+pub struct JitCompiler;
+
+impl Compiler for JitCompiler {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        let tracked = ids
+            .to_ids_mut()
+            .into_iter()
+            .map(|i| *i)
+            .collect::<FxHashSet<_>>();
+        for node in graph.graph.node_indices().collect_vec() {
+            if let Some(kernel) = lower(graph, node) {
+                *graph.graph.node_weight_mut(node).unwrap() = Box::new(kernel);
+            }
+        }
+        // Compute float constants inside the kernels reading them
+        for node in graph.graph.node_indices().collect_vec() {
+            inline_constants(graph, node, &tracked);
+        }
+        // Merge elementwise producers into their only consumer until nothing else can be fused
+        while let Some((producer, consumer)) = graph
+            .graph
+            .node_indices()
+            .find_map(|n| fusable_producer(graph, n).map(|p| (p, n)))
+        {
+            merge(graph, producer, consumer);
+            remap(producer, consumer, &mut ids, graph);
+            graph.graph.remove_node(producer);
+        }
+    }
+}
+
+fn kernel(graph: &Graph, node: NodeIndex) -> Option<&JitKernel> {
+    graph
+        .graph
+        .node_weight(node)?
+        .as_any()
+        .downcast_ref::<JitKernel>()
+}
+
+/// Turn a primitive op on f32 tensors into a kernel reading each input through its own view
+fn lower(graph: &mut Graph, node: NodeIndex) -> Option<JitKernel> {
+    let op = graph.graph.node_weight(node)?.as_any();
+    let input = |i| Box::new(Expr::View(i, Box::new(Expr::Input(i))));
+    let unary = |op| Expr::Unary(op, input(0));
+    let binary = |op| Expr::Binary(op, input(0), input(1));
+    let (body, reduce) = if op.is::<Contiguous>() {
+        (*input(0), None)
+    } else if op.is::<Exp2>() {
+        (unary(UnaryOp::Exp2), None)
+    } else if op.is::<Log2>() {
+        (unary(UnaryOp::Log2), None)
+    } else if op.is::<Sin>() {
+        (unary(UnaryOp::Sin), None)
+    } else if op.is::<Sqrt>() {
+        (unary(UnaryOp::Sqrt), None)
+    } else if op.is::<Recip>() {
+        (unary(UnaryOp::Recip), None)
+    } else if op.is::<Add>() {
+        (binary(BinaryOp::Add), None)
+    } else if op.is::<Mul>() {
+        (binary(BinaryOp::Mul), None)
+    } else if op.is::<Mod>() {
+        (binary(BinaryOp::Mod), None)
+    } else if op.is::<LessThan>() {
+        (binary(BinaryOp::LessThan), None)
+    } else if let Some(SumReduce(axis)) = op.downcast_ref() {
+        (*input(0), Some((ReduceOp::Sum, *axis)))
+    } else if let Some(MaxReduce(axis)) = op.downcast_ref() {
+        (*input(0), Some((ReduceOp::Max, *axis)))
+    } else {
+        return None;
+    };
+    if !f32_inputs(graph, node) || graph.node_dtype(node) != DType::F32 {
+        return None;
+    }
+    Some(JitKernel {
+        body,
+        reduce,
+        views: graph
+            .get_sources(node)
+            .into_iter()
+            .map(|(_, _, sh)| sh)
+            .collect(),
+        dyn_map: &graph.dyn_map,
+        compiled: Default::default(),
+    })
+}
+
+/// Are all of this node's inputs f32 data?
+fn f32_inputs(graph: &Graph, node: NodeIndex) -> bool {
+    graph
+        .graph
+        .edges_directed(node, Direction::Incoming)
+        .all(|e| e.weight().dtype() == Some(DType::F32))
+}
+
+fn float_constant(graph: &Graph, node: NodeIndex) -> Option<f32> {
+    match graph
+        .graph
+        .node_weight(node)?
+        .as_any()
+        .downcast_ref::<Constant>()?
+        .0
+    {
+        ConstantValue::Float(f) => Some(f),
+        _ => None,
+    }
+}
+
+/// Replace the body and incoming edges of a kernel, dropping inputs the body no longer reads and
+/// merging inputs that read the same tensor
+fn set_inputs(
+    graph: &mut Graph,
+    node: NodeIndex,
+    mut kernel: JitKernel,
+    srcs: Vec<(NodeIndex, u8, ShapeTracker)>,
+) {
+    let mut used = FxHashSet::default();
+    kernel.body = kernel.body.map(&mut |e| {
+        if let Expr::Input(i) = e {
+            used.insert(i);
+        }
+        e
+    });
+    let mut unique: Vec<(NodeIndex, u8, ShapeTracker)> = vec![];
+    let map = srcs
+        .into_iter()
+        .enumerate()
+        .map(|(i, s)| {
+            if !used.contains(&i) {
+                return 0;
+            }
+            unique
+                .iter()
+                .position(|u| (u.0, u.1) == (s.0, s.1))
+                .unwrap_or_else(|| {
+                    unique.push(s);
+                    unique.len() - 1
+                })
+        })
+        .collect_vec();
+    kernel.body = kernel.body.map(&mut |e| match e {
+        Expr::Input(i) => Expr::Input(map[i]),
+        e => e,
+    });
+    for edge in graph
+        .graph
+        .edges_directed(node, Direction::Incoming)
+        .filter(|e| !e.weight().is_schedule())
+        .map(|e| e.id())
+        .collect_vec()
+    {
+        graph.graph.remove_edge(edge);
+    }
+    for (i, (src, output_order, shape)) in unique.into_iter().enumerate() {
+        graph.graph.add_edge(
+            src,
+            node,
+            Dependency::Data {
+                input_order: i as u8,
+                output_order,
+                shape,
+                dtype: DType::F32,
+            },
+        );
+    }
+    // The body changed, so it needs a different compiled kernel
+    kernel.compiled = Default::default();
+    *graph.graph.node_weight_mut(node).unwrap() = Box::new(kernel);
+}
+
+/// Compute float constant inputs of a kernel inside it, keeping at least one real input
+fn inline_constants(graph: &mut Graph, node: NodeIndex, tracked: &FxHashSet<NodeIndex>) {
+    let Some(mut kernel) = kernel(graph, node).cloned() else {
+        return;
+    };
+    let srcs = graph.get_sources(node);
+    let constants = srcs
+        .iter()
+        .map(|(src, _, _)| float_constant(graph, *src))
+        .collect_vec();
+    if constants.iter().all(|c| c.is_some()) || constants.iter().all(|c| c.is_none()) {
+        return;
+    }
+    kernel.body = kernel.body.map(&mut |e| match e {
+        Expr::Input(i) => constants[i].map_or(Expr::Input(i), Expr::Const),
+        e => e,
+    });
+    set_inputs(graph, node, kernel, srcs.clone());
+    // Drop constants nothing else reads
+    for (src, _, _) in srcs
+        .into_iter()
+        .zip(constants)
+        .filter_map(|(s, c)| c.map(|_| s))
+    {
+        if graph.graph.contains_node(src)
+            && graph
+                .graph
+                .edges_directed(src, Direction::Outgoing)
+                .next()
+                .is_none()
+            && !graph.no_delete.contains(&src)
+            && !tracked.contains(&src)
+        {
+            graph.graph.remove_node(src);
+        }
+    }
+}
+
+/// Find an elementwise kernel feeding this kernel that can be computed inside it
+fn fusable_producer(graph: &Graph, consumer: NodeIndex) -> Option<NodeIndex> {
+    kernel(graph, consumer)?;
+    graph
+        .get_sources(consumer)
+        .into_iter()
+        .map(|(src, _, _)| src)
+        .unique()
+        .find(|&src| {
+            src != consumer
+                && !graph.no_delete.contains(&src)
+                && !graph.to_retrieve.contains_key(&src)
+                && kernel(graph, src).is_some_and(|k| k.reduce.is_none())
+                // Only read by this kernel, and never broadcast so no element is recomputed
+                && graph
+                    .graph
+                    .edges_directed(src, Direction::Outgoing)
+                    .all(|e| {
+                        e.target() == consumer
+                            && e.weight()
+                                .as_data()
+                                .is_some_and(|(_, _, sh)| !sh.fake.iter().any(|f| *f))
+                    })
+        })
+}
+
+/// Compute the producer's body inside the consumer, at the index each of the consumer's views of it
+/// maps to
+fn merge(graph: &mut Graph, producer: NodeIndex, consumer: NodeIndex) {
+    let p = kernel(graph, producer).unwrap().clone();
+    let mut c = kernel(graph, consumer).unwrap().clone();
+    let c_srcs = graph.get_sources(consumer);
+    let p_srcs = graph.get_sources(producer);
+    // The producer's inputs go after the consumer's, and its views after the consumer's
+    let (n_inputs, n_views) = (c_srcs.len(), c.views.len());
+    let p_body = p.body.map(&mut |e| match e {
+        Expr::Input(i) => Expr::Input(i + n_inputs),
+        Expr::View(v, inner) => Expr::View(v + n_views, inner),
+        e => e,
+    });
+    c.body = c.body.map(&mut |e| match e {
+        Expr::Input(i) if c_srcs[i].0 == producer => p_body.clone(),
+        e => e,
+    });
+    c.views.extend(p.views);
+    set_inputs(
+        graph,
+        consumer,
+        c,
+        c_srcs.into_iter().chain(p_srcs).collect(),
+    );
+}
@@ -0,0 +1,122 @@
+use itertools::Itertools;
+
+use crate::{CPUJitCompiler, JitKernel};
+luminal::test_imports!();
+
+fn kernels(cx: &Graph) -> usize {
+    cx.graph
+        .node_weights()
+        .filter(|op| op.as_any().is::<JitKernel>())
+        .count()
+}
+
+// Primitive op tests, shared with the other backends
+mod prim {
+    luminal::prim_tests!(crate::CPUJitCompiler::default());
+}
+
+#[test]
+fn test_single_kernel() {
+    // Each primitive on its own compiles to exactly one kernel
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+    let b = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+    let mut c = (a.log2() + b).retrieve();
+    let mut d = a.sum_reduce::<_, LAxis<0>>().retrieve();
+    cx.compile(CPUJitCompiler::default(), (&mut c, &mut d));
+    assert_eq!(kernels(&cx), 2);
+    cx.execute();
+
+    assert_close(&c.data(), &[1f32, 2., 3.].map(|a| a.log2() + a));
+    assert_close(&d.data(), &[6.]);
+}
+
+// Fusion tests
+
+#[test]
+fn test_fused_softmax() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<4, 6>>().set(random_vec(24));
+    let mut b = (a.softmax::<LAxis<1>>().pad::<R2<4, 8>>(&[(0, 0), (1, 1)]) * 2.)
+        .sqrt()
+        .retrieve();
+    cx.execute();
+    let unoptimized = b.data();
+    b.drop();
+
+    cx.compile(
+        (GenericCompiler::default(), CPUJitCompiler::default()),
+        &mut b,
+    );
+    // Everything is a kernel, and elementwise chains are fused into the reductions reading them
+    assert!(cx
+        .graph
+        .node_weights()
+        .all(|op| op.as_any().is::<JitKernel>() || op.as_any().is::<luminal::op::Function>()));
+    assert!(kernels(&cx) <= 4);
+    cx.execute();
+    assert_close(&b.data(), &unoptimized);
+}
+
+#[test]
+fn test_dyn_dims() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<(Dyn<'s'>, LConst<4>)>();
+    let mut b = (a.exp2() * 3.)
+        .permute::<_, LAxes2<1, 0>>()
+        .sum_reduce::<_, LAxis<1>>()
+        .retrieve();
+    cx.compile(CPUJitCompiler::default(), &mut b);
+    // The scaled exponent is computed inside the reduction
+    assert_eq!(kernels(&cx), 1);
+    // Every sequence length runs the same kernel, with the length passed in
+    let k = cx
+        .graph
+        .node_weights()
+        .find_map(|op| op.as_any().downcast_ref::<JitKernel>())
+        .unwrap();
+    let (_, dims) = crate::codegen::kernel(&k.body, k.reduce.map(|(op, _)| op), &k.views, 1);
+    assert_eq!(dims, vec!['s']);
+    for s in [1, 5, 3, 5] {
+        let data = random_vec(s * 4);
+        a.set_dyn(data.clone(), &[s, 4]);
+        cx.execute();
+        let expected = (0..4)
+            .map(|j| (0..s).map(|i| data[i * 4 + j].exp2() * 3.).sum::<f32>())
+            .collect_vec();
+        assert_close(&b.data(), &expected);
+        b.drop();
+    }
+}
+
+#[test]
+fn test_kernel_cache() {
+    fn build() -> (Graph, GraphTensor<R1<3>>) {
+        let mut cx = Graph::new();
+        // A body no other test uses, so nothing else keeps its kernel alive
+        let mut b = (cx.tensor::<R1<3>>().set([1., 2., 3.]).sin() * 7.25).retrieve();
+        cx.compile(CPUJitCompiler::default(), &mut b);
+        cx.execute();
+        (cx, b)
+    }
+    fn compiled(cx: &Graph) -> std::sync::Weak<crate::codegen::CompiledKernel> {
+        let k = cx
+            .graph
+            .node_weights()
+            .find_map(|op| op.as_any().downcast_ref::<JitKernel>())
+            .unwrap();
+        std::sync::Arc::downgrade(&k.compiled.get().unwrap().0)
+    }
+
+    // Identical kernels in different graphs share one compiled module
+    let (cx1, b) = build();
+    let (cx2, _) = build();
+    assert!(compiled(&cx1).ptr_eq(&compiled(&cx2)));
+    assert_close(&b.data(), &[1f32, 2., 3.].map(|a| a.sin() * 7.25));
+    // It's freed once no graph uses it
+    let kernel = compiled(&cx1);
+    drop(cx1);
+    assert!(kernel.upgrade().is_some());
+    drop(cx2);
+    assert!(kernel.upgrade().is_none());
+}
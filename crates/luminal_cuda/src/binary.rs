@@ -357,42 +357,50 @@ impl<T: CudaFloat> Compiler for GatherCompiler<T> {
     type Output = ();
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
         let dev = CudaDevice::new(0).unwrap();
-        let indexes = node();
-        let ind_copy = unary::<CudaCopyToDevice<T>>(indexes.clone());
-        let equal = binary::<CudaEqual<T>>(op::<CudaARange<T>>(), ind_copy.clone());
-        let embeddings = node();
-        let mul = binary::<CudaMul<T>>(embeddings.clone(), equal.clone());
-        let sum_reduce = unary::<CudaSumReduce<T>>(mul.clone());
-        let mut s = sum_reduce.clone().search(graph);
-        while s.next_match() {
-            if s.check_no_delete(&[sum_reduce.id, embeddings.id, indexes.id]) {
-                continue;
+        // Static aranges get folded to constants, which are copied to the device like other loads
+        let mut folded_arange = node();
+        folded_arange.check(|op, _| is_folded_arange(op));
+        for arange in [
+            op::<CudaARange<T>>(),
+            unary::<CudaCopyToDevice<T>>(folded_arange),
+        ] {
+            let indexes = node();
+            let ind_copy = unary::<CudaCopyToDevice<T>>(indexes.clone());
+            let equal = binary::<CudaEqual<T>>(arange, ind_copy.clone());
+            let embeddings = node();
+            let mul = binary::<CudaMul<T>>(embeddings.clone(), equal.clone());
+            let sum_reduce = unary::<CudaSumReduce<T>>(mul.clone());
+            let mut s = sum_reduce.clone().search(graph);
+            while s.next_match() {
+                if s.check_no_delete(&[sum_reduce.id, embeddings.id, indexes.id]) {
+                    continue;
+                }
+                let emb_shape = graph
+                    .edges_connecting(s.get(&embeddings), s.get(&mul))
+                    .next()
+                    .unwrap()
+                    .weight()
+                    .as_data()
+                    .unwrap()
+                    .2;
+                let embed_dim = emb_shape.shape()[2].to_usize().unwrap();
+                let index_shape = graph
+                    .edges_connecting(s.get(&indexes), s.get(&ind_copy))
+                    .next()
+                    .unwrap()
+                    .weight()
+                    .as_data()
+                    .unwrap()
+                    .2;
+                let gather = graph
+                    .add_op(CudaGather::<T>::new(dev.clone(), embed_dim))
+                    .input(s.get(&indexes), 0, index_shape)
+                    .input(s.get(&embeddings), 0, emb_shape)
+                    .finish();
+                move_outgoing_edge(s.get(&sum_reduce), gather, graph);
+                graph.remove_node(s.get(&sum_reduce));
+                s.try_delete();
             }
-            let emb_shape = graph
-                .edges_connecting(s.get(&embeddings), s.get(&mul))
-                .next()
-                .unwrap()
-                .weight()
-                .as_data()
-                .unwrap()
-                .2;
-            let embed_dim = emb_shape.shape()[2].to_usize().unwrap();
-            let index_shape = graph
-                .edges_connecting(s.get(&indexes), s.get(&ind_copy))
-                .next()
-                .unwrap()
-                .weight()
-                .as_data()
-                .unwrap()
-                .2;
-            let gather = graph
-                .add_op(CudaGather::<T>::new(dev.clone(), embed_dim))
-                .input(s.get(&indexes), 0, index_shape)
-                .input(s.get(&embeddings), 0, emb_shape)
-                .finish();
-            move_outgoing_edge(s.get(&sum_reduce), gather, graph);
-            graph.remove_node(s.get(&sum_reduce));
-            s.try_delete();
         }
     }
 }
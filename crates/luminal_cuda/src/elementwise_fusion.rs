@@ -240,6 +240,7 @@ impl<T: CudaFloat> Compiler for ElementwiseFusionCompiler<T> {
                 // Add edges to new op
                 move_outgoing_edge(b, new_op, graph);
                 for (i, (node, output, shape)) in b_inputs.into_iter().enumerate() {
+                    let dtype = graph.node_dtype(node);
                     graph.add_edge(
                         node,
                         new_op,
@@ -247,6 +248,7 @@ impl<T: CudaFloat> Compiler for ElementwiseFusionCompiler<T> {
                             input_order: i as u8,
                             output_order: output,
                             shape,
+                            dtype,
                         },
                     );
                 }
@@ -0,0 +1,223 @@
+use luminal::prelude::{bf16, f16};
+
+use crate::{GgmlDType, GgufError};
+
+/// Dequantize raw GGUF tensor data of the given type into f32
+pub fn dequantize(dtype: GgmlDType, bytes: &[u8]) -> Result<Vec<f32>, GgufError> {
+    if !bytes.len().is_multiple_of(dtype.type_size()) {
+        return Err(GgufError::SizeMismatch {
+            name: format!("{dtype:?} data"),
+            expected: bytes.len().next_multiple_of(dtype.type_size()),
+            found: bytes.len(),
+        });
+    }
+    let blocks = bytes.chunks_exact(dtype.type_size());
+    let mut out = Vec::with_capacity(bytes.len() / dtype.type_size() * dtype.block_size());
+    match dtype {
+        GgmlDType::F32 => out.extend(blocks.map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))),
+        GgmlDType::F16 => out.extend(blocks.map(|b| half(b).to_f32())),
+        GgmlDType::BF16 => out.extend(blocks.map(|b| bf16::from_le_bytes([b[0], b[1]]).to_f32())),
+        GgmlDType::Q4_0 => blocks.for_each(|b| dequantize_q4_0(b, &mut out)),
+        GgmlDType::Q4_1 => blocks.for_each(|b| dequantize_q4_1(b, &mut out)),
+        GgmlDType::Q5_0 => blocks.for_each(|b| dequantize_q5_0(b, &mut out)),
+        GgmlDType::Q8_0 => blocks.for_each(|b| dequantize_q8_0(b, &mut out)),
+        GgmlDType::Q4K => blocks.for_each(|b| dequantize_q4_k(b, &mut out)),
+        GgmlDType::Q6K => blocks.for_each(|b| dequantize_q6_k(b, &mut out)),
+        d => return Err(GgufError::UnsupportedDType(d)),
+    }
+    Ok(out)
+}
+
+fn half(b: &[u8]) -> f16 {
+    f16::from_le_bytes([b[0], b[1]])
+}
+
+/// Scale: f16, 32 4-bit weights offset by 8. Low nibbles are the first half of the block, high nibbles the second
+fn dequantize_q4_0(block: &[u8], out: &mut Vec<f32>) {
+    let (d, qs) = (half(block).to_f32(), &block[2..]);
+    out.extend(qs.iter().map(|q| ((q & 0xF) as i32 - 8) as f32 * d));
+    out.extend(qs.iter().map(|q| ((q >> 4) as i32 - 8) as f32 * d));
+}
+
+/// Scale: f16, minimum: f16, 32 unsigned 4-bit weights
+fn dequantize_q4_1(block: &[u8], out: &mut Vec<f32>) {
+    let (d, m, qs) = (
+        half(block).to_f32(),
+        half(&block[2..]).to_f32(),
+        &block[4..],
+    );
+    out.extend(qs.iter().map(|q| (q & 0xF) as f32 * d + m));
+    out.extend(qs.iter().map(|q| (q >> 4) as f32 * d + m));
+}
+
+/// Scale: f16, the fifth bits of all 32 weights packed in a u32, then their low 4 bits as in Q4_0. Weights are offset by 16
+fn dequantize_q5_0(block: &[u8], out: &mut Vec<f32>) {
+    let d = half(block).to_f32();
+    let qh = u32::from_le_bytes([block[2], block[3], block[4], block[5]]);
+    let qs = &block[6..];
+    out.extend(qs.iter().enumerate().map(|(j, q)| {
+        let high = ((qh >> j) << 4) & 0x10;
+        (((q & 0xF) as u32 | high) as i32 - 16) as f32 * d
+    }));
+    out.extend(qs.iter().enumerate().map(|(j, q)| {
+        let high = (qh >> (j + 12)) & 0x10;
+        (((q >> 4) as u32 | high) as i32 - 16) as f32 * d
+    }));
+}
+
+/// Scale: f16, 32 i8 weights
+fn dequantize_q8_0(block: &[u8], out: &mut Vec<f32>) {
+    let d = half(block).to_f32();
+    out.extend(block[2..].iter().map(|q| *q as i8 as f32 * d));
+}
+
+/// Get the 6-bit scale and minimum of a Q4_K sub-block from the 12 packed scale bytes
+fn scale_min_k4(j: usize, q: &[u8]) -> (f32, f32) {
+    if j < 4 {
+        ((q[j] & 63) as f32, (q[j + 4] & 63) as f32)
+    } else {
+        (
+            ((q[j + 4] & 0xF) | ((q[j - 4] >> 6) << 4)) as f32,
+            ((q[j + 4] >> 4) | ((q[j] >> 6) << 4)) as f32,
+        )
+    }
+}
+
+/// Scale: f16, minimum: f16, 12 bytes of packed 6-bit sub-block scales and minimums, then 256 4-bit weights in 8 sub-blocks of 32
+fn dequantize_q4_k(block: &[u8], out: &mut Vec<f32>) {
+    let (d, min) = (half(block).to_f32(), half(&block[2..]).to_f32());
+    let (scales, qs) = (&block[4..16], &block[16..]);
+    for (i, q) in qs.chunks_exact(32).enumerate() {
+        let (sc1, m1) = scale_min_k4(2 * i, scales);
+        let (sc2, m2) = scale_min_k4(2 * i + 1, scales);
+        out.extend(q.iter().map(|q| d * sc1 * (q & 0xF) as f32 - min * m1));
+        out.extend(q.iter().map(|q| d * sc2 * (q >> 4) as f32 - min * m2));
+    }
+}
+
+/// Low 4 bits of 256 6-bit weights, their high 2 bits, 16 i8 sub-block scales, then the scale: f16. Weights are offset by 32
+fn dequantize_q6_k(block: &[u8], out: &mut Vec<f32>) {
+    let (ql, qh, scales) = (&block[..128], &block[128..192], &block[192..208]);
+    let d = half(&block[208..]).to_f32();
+    for n in 0..2 {
+        let (ql, qh, sc) = (&ql[n * 64..], &qh[n * 32..], &scales[n * 8..]);
+        let mut y = [0.; 128];
+        for l in 0..32 {
+            let is = l / 16;
+            let q1 = ((ql[l] & 0xF) | ((qh[l] & 3) << 4)) as i32 - 32;
+            let q2 = ((ql[l + 32] & 0xF) | (((qh[l] >> 2) & 3) << 4)) as i32 - 32;
+            let q3 = ((ql[l] >> 4) | (((qh[l] >> 4) & 3) << 4)) as i32 - 32;
+            let q4 = ((ql[l + 32] >> 4) | (((qh[l] >> 6) & 3) << 4)) as i32 - 32;
+            y[l] = d * (sc[is] as i8) as f32 * q1 as f32;
+            y[l + 32] = d * (sc[is + 2] as i8) as f32 * q2 as f32;
+            y[l + 64] = d * (sc[is + 4] as i8) as f32 * q3 as f32;
+            y[l + 96] = d * (sc[is + 6] as i8) as f32 * q4 as f32;
+        }
+        out.extend(y);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(d: f32, rest: &[u8]) -> Vec<u8> {
+        let mut b = f16::from_f32(d).to_le_bytes().to_vec();
+        b.extend(rest);
+        b
+    }
+
+    #[test]
+    fn test_q8_0() {
+        let b = block(0.5, &(-16..16).map(|i| i as i8 as u8).collect::<Vec<_>>());
+        let out = dequantize(GgmlDType::Q8_0, &b).unwrap();
+        assert_eq!(out, (-16..16).map(|i| i as f32 * 0.5).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_q4() {
+        // Nibble j holds j in the low half and 15 - j in the high half
+        let qs = (0..16).map(|j| j | ((15 - j) << 4)).collect::<Vec<u8>>();
+        let out = dequantize(GgmlDType::Q4_0, &block(2., &qs)).unwrap();
+        let expected = (0..16).chain((0..16).rev()).map(|q| (q - 8) as f32 * 2.);
+        assert_eq!(out, expected.collect::<Vec<_>>());
+
+        let mut b = block(2., &f16::from_f32(-1.).to_le_bytes());
+        b.extend(&qs);
+        let out = dequantize(GgmlDType::Q4_1, &b).unwrap();
+        let expected = (0..16).chain((0..16).rev()).map(|q| q as f32 * 2. - 1.);
+        assert_eq!(out, expected.collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_q5_0() {
+        // Set the fifth bit of every odd weight
+        let qh = (0..32)
+            .filter(|j| j % 2 == 1)
+            .fold(0u32, |a, j| a | (1 << j));
+        let mut rest = qh.to_le_bytes().to_vec();
+        rest.extend([0x21u8; 16]);
+        let out = dequantize(GgmlDType::Q5_0, &block(1., &rest)).unwrap();
+        let expected = (0..32).map(|j| {
+            let low = if j < 16 { 1 } else { 2 };
+            (low + if j % 2 == 1 { 16 } else { 0 } - 16) as f32
+        });
+        assert_eq!(out, expected.collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_q4_k() {
+        let mut b = block(1., &f16::from_f32(0.5).to_le_bytes());
+        // Sub-block i gets scale i + 1 and minimum i, the last four use the packed high bits
+        let mut scales = [0u8; 12];
+        for i in 0..4 {
+            scales[i] = i as u8 + 1;
+            scales[i + 4] = i as u8;
+            scales[i + 8] = (i as u8 + 5) | ((i as u8 + 4) << 4);
+        }
+        b.extend(scales);
+        b.extend([0x31u8; 128]);
+        let out = dequantize(GgmlDType::Q4K, &b).unwrap();
+        assert_eq!(out.len(), 256);
+        for (i, chunk) in out.chunks(32).enumerate() {
+            let q = if i % 2 == 0 { 1. } else { 3. };
+            let expected = (i + 1) as f32 * q - 0.5 * i as f32;
+            assert!(chunk.iter().all(|v| *v == expected), "sub-block {i}");
+        }
+    }
+
+    #[test]
+    fn test_q6_k() {
+        // Every weight is 33 (low bits 1, high bits 2), so dequantizes to its sub-block's scale
+        let mut b = vec![0x11u8; 128];
+        b.extend([0xAAu8; 64]);
+        b.extend((1..=16).map(|s: i8| (if s % 2 == 0 { -s } else { s }) as u8));
+        b.extend(f16::from_f32(0.25).to_le_bytes());
+        let out = dequantize(GgmlDType::Q6K, &b).unwrap();
+        for (i, chunk) in out.chunks(16).enumerate() {
+            // Sub-blocks of 16 are ordered by which quarter of the 128 weight half they're in
+            let (n, quarter, is) = (i / 8, (i % 8) / 2, i % 2);
+            let s = (n * 8 + is + 2 * quarter + 1) as f32;
+            let s = if s as i32 % 2 == 0 { -s } else { s };
+            assert!(chunk.iter().all(|v| *v == 0.25 * s), "sub-block {i}");
+        }
+    }
+
+    #[test]
+    fn test_half_types() {
+        let f16s = [1.5f32, -2.]
+            .iter()
+            .flat_map(|f| f16::from_f32(*f).to_le_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(dequantize(GgmlDType::F16, &f16s).unwrap(), vec![1.5, -2.]);
+        let bf16s = [1.5f32, -2.]
+            .iter()
+            .flat_map(|f| bf16::from_f32(*f).to_le_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(dequantize(GgmlDType::BF16, &bf16s).unwrap(), vec![1.5, -2.]);
+        assert!(matches!(
+            dequantize(GgmlDType::Q2K, &[0; 84]),
+            Err(GgufError::UnsupportedDType(GgmlDType::Q2K))
+        ));
+    }
+}
@@ -0,0 +1,781 @@
+//! Support for the GGUF file format.
+//!
+//! Spec: https://github.com/philpax/ggml/blob/gguf-spec/docs/gguf.md
+
+mod dequantize;
+mod writer;
+
+pub use dequantize::dequantize;
+pub use writer::{save_gguf, GgufWriter};
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::{Cursor, Read},
+    path::Path,
+    sync::Arc,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use itertools::Itertools;
+use luminal::prelude::*;
+use memmap2::Mmap;
+
+pub const DEFAULT_ALIGNMENT: u64 = 32;
+
+const MAGIC: u32 = 0x46554747;
+
+/// The most array elements allocated before reading them
+const MAX_PREALLOCATED: usize = 1 << 16;
+
+/// An error encountered while reading or writing a GGUF file
+#[derive(Debug)]
+pub enum GgufError {
+    Io(std::io::Error),
+    /// The file doesn't start with the GGUF magic number
+    UnknownMagic(u32),
+    UnsupportedVersion(u32),
+    UnknownValueType(u32),
+    UnknownDType(u32),
+    /// A bool value that's neither 0 nor 1
+    InvalidBool(u8),
+    /// `general.alignment` is 0
+    InvalidAlignment(u64),
+    /// The file has no tensor with this name
+    MissingTensor(String),
+    /// Dequantizing this type isn't supported
+    UnsupportedDType(GgmlDType),
+    /// A tensor's data doesn't have as many bytes as its shape and type need
+    SizeMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    /// The graph has no f32, f16 or bf16 CPU data for this parameter
+    MissingData(String),
+    /// A tensor's data can't be viewed in the map
+    Mmap(MmapError),
+    /// A tensor's element or byte count doesn't fit in a usize
+    SizeOverflow(Vec<usize>),
+}
+
+impl Display for GgufError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GgufError::Io(e) => write!(f, "{e}"),
+            GgufError::UnknownMagic(m) => write!(f, "Unknown magic 0x{m:08x}"),
+            GgufError::UnsupportedVersion(v) => write!(f, "Unsupported GGUF version {v}"),
+            GgufError::UnknownValueType(v) => write!(f, "Unknown value type {v}"),
+            GgufError::UnknownDType(d) => write!(f, "Unknown tensor type {d}"),
+            GgufError::InvalidBool(b) => write!(f, "Invalid bool value {b}"),
+            GgufError::InvalidAlignment(a) => write!(f, "Invalid alignment {a}"),
+            GgufError::MissingTensor(name) => write!(f, "No tensor named {name} in file"),
+            GgufError::UnsupportedDType(d) => write!(f, "Can't dequantize {d:?} tensors"),
+            GgufError::SizeMismatch {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Tensor {name} should have {expected} bytes, found {found}"
+            ),
+            GgufError::MissingData(name) => write!(f, "No data for parameter {name}"),
+            GgufError::Mmap(e) => write!(f, "{e}"),
+            GgufError::SizeOverflow(shape) => write!(f, "Tensor of shape {shape:?} is too large"),
+        }
+    }
+}
+
+impl std::error::Error for GgufError {}
+
+impl From<std::io::Error> for GgufError {
+    fn from(e: std::io::Error) -> Self {
+        GgufError::Io(e)
+    }
+}
+
+impl From<MmapError> for GgufError {
+    fn from(e: MmapError) -> Self {
+        GgufError::Mmap(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionedMagic {
+    GgufV1,
+    GgufV2,
+    GgufV3,
+}
+
+impl VersionedMagic {
+    pub fn read<R: std::io::Read>(reader: &mut R) -> Result<Self, GgufError> {
+        let magic = reader.read_u32::<LittleEndian>()?;
+        // Everything after the magic is read as little endian, so big endian files are rejected here
+        if magic != MAGIC {
+            return Err(GgufError::UnknownMagic(magic));
+        }
+        match reader.read_u32::<LittleEndian>()? {
+            1 => Ok(Self::GgufV1),
+            2 => Ok(Self::GgufV2),
+            3 => Ok(Self::GgufV3),
+            v => Err(GgufError::UnsupportedVersion(v)),
+        }
+    }
+
+    /// Read a length or count, which is 32 bits in v1 and 64 bits after
+    fn read_len<R: std::io::Read>(&self, reader: &mut R) -> Result<usize, GgufError> {
+        Ok(match self {
+            VersionedMagic::GgufV1 => reader.read_u32::<LittleEndian>()? as usize,
+            VersionedMagic::GgufV2 | VersionedMagic::GgufV3 => {
+                reader.read_u64::<LittleEndian>()? as usize
+            }
+        })
+    }
+}
+
+/// Where to find a tensor in a GGUF file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TensorInfo {
+    /// Shape of the tensor, outermost dimension first (GGUF stores them innermost first)
+    pub shape: Vec<usize>,
+    /// Offset of the tensor's data from the start of the tensor data section
+    pub offset: usize,
+    pub dtype: GgmlDType,
+}
+
+impl TensorInfo {
+    pub fn n_elements(&self) -> Result<usize, GgufError> {
+        self.shape
+            .iter()
+            .try_fold(1usize, |acc, d| acc.checked_mul(*d))
+            .ok_or_else(|| GgufError::SizeOverflow(self.shape.clone()))
+    }
+
+    /// Size of the tensor's data in bytes
+    pub fn n_bytes(&self) -> Result<usize, GgufError> {
+        (self.n_elements()? / self.dtype.block_size())
+            .checked_mul(self.dtype.type_size())
+            .ok_or_else(|| GgufError::SizeOverflow(self.shape.clone()))
+    }
+}
+
+/// The metadata and tensor infos of a GGUF file
+#[derive(Debug)]
+pub struct Content {
+    pub magic: VersionedMagic,
+    pub metadata: HashMap<String, Value>,
+    pub tensor_infos: HashMap<String, TensorInfo>,
+    /// Offset of the tensor data section from the start of the file
+    pub tensor_data_offset: u64,
+}
+
+pub fn read_string<R: std::io::Read>(
+    reader: &mut R,
+    magic: &VersionedMagic,
+) -> Result<String, GgufError> {
+    let len = magic.read_len(reader)?;
+    // The length comes from the file, so only allocate for the bytes that are actually there
+    let mut v = vec![];
+    reader.take(len as u64).read_to_end(&mut v)?;
+    if v.len() != len {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    // GGUF strings are supposed to be non-null terminated but in practice this happens.
+    while let Some(0) = v.last() {
+        v.pop();
+    }
+    // GGUF strings are utf8 encoded but there are cases that don't seem to be valid.
+    Ok(String::from_utf8_lossy(&v).into_owned())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValueType {
+    // The value is a 8-bit unsigned integer.
+    U8,
+    // The value is a 8-bit signed integer.
+    I8,
+    // The value is a 16-bit unsigned little-endian integer.
+    U16,
+    // The value is a 16-bit signed little-endian integer.
+    I16,
+    // The value is a 32-bit unsigned little-endian integer.
+    U32,
+    // The value is a 32-bit signed little-endian integer.
+    I32,
+    // The value is a 64-bit unsigned little-endian integer.
+    U64,
+    // The value is a 64-bit signed little-endian integer.
+    I64,
+    // The value is a 32-bit IEEE754 floating point number.
+    F32,
+    // The value is a 64-bit IEEE754 floating point number.
+    F64,
+    // The value is a boolean.
+    // 1-byte value where 0 is false and 1 is true.
+    // Anything else is invalid, and should be treated as either the model being invalid or the reader being buggy.
+    Bool,
+    // The value is a UTF-8 non-null-terminated string, with length prepended.
+    String,
+    // The value is an array of other values, with the length and type prepended.
+    //
+    // Arrays can be nested, and the length of the array is the number of elements in the array, not the number of bytes.
+    Array,
+}
+
+impl ValueType {
+    pub fn from_u32(v: u32) -> Result<Self, GgufError> {
+        Ok(match v {
+            0 => Self::U8,
+            1 => Self::I8,
+            2 => Self::U16,
+            3 => Self::I16,
+            4 => Self::U32,
+            5 => Self::I32,
+            6 => Self::F32,
+            7 => Self::Bool,
+            8 => Self::String,
+            9 => Self::Array,
+            10 => Self::U64,
+            11 => Self::I64,
+            12 => Self::F64,
+            v => return Err(GgufError::UnknownValueType(v)),
+        })
+    }
+
+    pub fn to_u32(self) -> u32 {
+        match self {
+            Self::U8 => 0,
+            Self::I8 => 1,
+            Self::U16 => 2,
+            Self::I16 => 3,
+            Self::U32 => 4,
+            Self::I32 => 5,
+            Self::F32 => 6,
+            Self::Bool => 7,
+            Self::String => 8,
+            Self::Array => 9,
+            Self::U64 => 10,
+            Self::I64 => 11,
+            Self::F64 => 12,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    String(String),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub fn read<R: std::io::Read>(
+        reader: &mut R,
+        value_type: ValueType,
+        magic: &VersionedMagic,
+    ) -> Result<Self, GgufError> {
+        let v = match value_type {
+            ValueType::U8 => Self::U8(reader.read_u8()?),
+            ValueType::I8 => Self::I8(reader.read_i8()?),
+            ValueType::U16 => Self::U16(reader.read_u16::<LittleEndian>()?),
+            ValueType::I16 => Self::I16(reader.read_i16::<LittleEndian>()?),
+            ValueType::U32 => Self::U32(reader.read_u32::<LittleEndian>()?),
+            ValueType::I32 => Self::I32(reader.read_i32::<LittleEndian>()?),
+            ValueType::U64 => Self::U64(reader.read_u64::<LittleEndian>()?),
+            ValueType::I64 => Self::I64(reader.read_i64::<LittleEndian>()?),
+            ValueType::F32 => Self::F32(reader.read_f32::<LittleEndian>()?),
+            ValueType::F64 => Self::F64(reader.read_f64::<LittleEndian>()?),
+            ValueType::Bool => match reader.read_u8()? {
+                0 => Self::Bool(false),
+                1 => Self::Bool(true),
+                b => return Err(GgufError::InvalidBool(b)),
+            },
+            ValueType::String => Self::String(read_string(reader, magic)?),
+            ValueType::Array => {
+                let value_type = ValueType::from_u32(reader.read_u32::<LittleEndian>()?)?;
+                let len = magic.read_len(reader)?;
+                // Every element takes at least a byte, so a length past the end of the file fails
+                // on reading instead of allocating
+                let mut vs = Vec::with_capacity(len.min(MAX_PREALLOCATED));
+                for _ in 0..len {
+                    vs.push(Value::read(reader, value_type, magic)?)
+                }
+                Self::Array(vs)
+            }
+        };
+        Ok(v)
+    }
+
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::U8(_) => ValueType::U8,
+            Value::I8(_) => ValueType::I8,
+            Value::U16(_) => ValueType::U16,
+            Value::I16(_) => ValueType::I16,
+            Value::U32(_) => ValueType::U32,
+            Value::I32(_) => ValueType::I32,
+            Value::U64(_) => ValueType::U64,
+            Value::I64(_) => ValueType::I64,
+            Value::F32(_) => ValueType::F32,
+            Value::F64(_) => ValueType::F64,
+            Value::Bool(_) => ValueType::Bool,
+            Value::String(_) => ValueType::String,
+            Value::Array(_) => ValueType::Array,
+        }
+    }
+
+    /// The value as an unsigned integer, if it's a non-negative integer
+    pub fn to_u64(&self) -> Option<u64> {
+        match self {
+            Value::U8(v) => Some(*v as u64),
+            Value::U16(v) => Some(*v as u64),
+            Value::U32(v) => Some(*v as u64),
+            Value::U64(v) => Some(*v),
+            Value::I8(v) => u64::try_from(*v).ok(),
+            Value::I16(v) => u64::try_from(*v).ok(),
+            Value::I32(v) => u64::try_from(*v).ok(),
+            Value::I64(v) => u64::try_from(*v).ok(),
+            _ => None,
+        }
+    }
+
+    /// The value as a float, if it's a number
+    pub fn to_f64(&self) -> Option<f64> {
+        match self {
+            Value::F32(v) => Some(*v as f64),
+            Value::F64(v) => Some(*v),
+            Value::I8(v) => Some(*v as f64),
+            Value::I16(v) => Some(*v as f64),
+            Value::I32(v) => Some(*v as f64),
+            Value::I64(v) => Some(*v as f64),
+            v => v.to_u64().map(|v| v as f64),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+impl Content {
+    pub fn read<R: std::io::Seek + std::io::Read>(reader: &mut R) -> Result<Self, GgufError> {
+        let magic = VersionedMagic::read(reader)?;
+        let tensor_count = magic.read_len(reader)?;
+        let metadata_kv_count = magic.read_len(reader)?;
+
+        // Read metadata
+        let mut metadata = HashMap::new();
+        for _idx in 0..metadata_kv_count {
+            let key = read_string(reader, &magic)?;
+            let value_type = ValueType::from_u32(reader.read_u32::<LittleEndian>()?)?;
+            let value = Value::read(reader, value_type, &magic)?;
+            metadata.insert(key, value);
+        }
+        // Read tensor infos
+        let mut tensor_infos = HashMap::new();
+        for _idx in 0..tensor_count {
+            let tensor_name = read_string(reader, &magic)?;
+            let n_dimensions = reader.read_u32::<LittleEndian>()? as usize;
+            let mut shape = (0..n_dimensions)
+                .map(|_| magic.read_len(reader))
+                .collect::<Result<Vec<_>, _>>()?;
+            shape.reverse();
+            let dtype = GgmlDType::from_u32(reader.read_u32::<LittleEndian>()?)?;
+            let offset = reader.read_u64::<LittleEndian>()? as usize;
+            let info = TensorInfo {
+                shape,
+                offset,
+                dtype,
+            };
+            info.n_bytes()?;
+            tensor_infos.insert(tensor_name, info);
+        }
+        let position = reader.stream_position()?;
+        let alignment = alignment(metadata.get("general.alignment"))?;
+        let tensor_data_offset = position.div_ceil(alignment) * alignment;
+        Ok(Self {
+            magic,
+            metadata,
+            tensor_infos,
+            tensor_data_offset,
+        })
+    }
+
+    /// Iterate over the tensor infos in the order their data is stored
+    pub fn tensors(&self) -> impl Iterator<Item = (&str, &TensorInfo)> {
+        self.tensor_infos
+            .iter()
+            .sorted_by_key(|(_, info)| info.offset)
+            .map(|(name, info)| (name.as_str(), info))
+    }
+
+    pub fn tensor_info(&self, name: &str) -> Result<&TensorInfo, GgufError> {
+        self.tensor_infos
+            .get(name)
+            .ok_or_else(|| GgufError::MissingTensor(name.to_string()))
+    }
+}
+
+/// The alignment of tensor data given by a `general.alignment` value
+fn alignment(value: Option<&Value>) -> Result<u64, GgufError> {
+    match value.and_then(|v| v.to_u64()).unwrap_or(DEFAULT_ALIGNMENT) {
+        0 => Err(GgufError::InvalidAlignment(0)),
+        a => Ok(a),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GgmlDType {
+    F32,
+    F16,
+    BF16,
+    Q4_0,
+    Q4_1,
+    Q5_0,
+    Q5_1,
+    Q8_0,
+    Q8_1,
+    Q2K,
+    Q3K,
+    Q4K,
+    Q5K,
+    Q6K,
+    Q8K,
+}
+
+impl GgmlDType {
+    pub fn from_u32(u: u32) -> Result<Self, GgufError> {
+        Ok(match u {
+            0 => Self::F32,
+            1 => Self::F16,
+            2 => Self::Q4_0,
+            3 => Self::Q4_1,
+            6 => Self::Q5_0,
+            7 => Self::Q5_1,
+            8 => Self::Q8_0,
+            9 => Self::Q8_1,
+            10 => Self::Q2K,
+            11 => Self::Q3K,
+            12 => Self::Q4K,
+            13 => Self::Q5K,
+            14 => Self::Q6K,
+            15 => Self::Q8K,
+            30 => Self::BF16,
+            u => return Err(GgufError::UnknownDType(u)),
+        })
+    }
+
+    pub fn to_u32(self) -> u32 {
+        match self {
+            Self::F32 => 0,
+            Self::F16 => 1,
+            Self::Q4_0 => 2,
+            Self::Q4_1 => 3,
+            Self::Q5_0 => 6,
+            Self::Q5_1 => 7,
+            Self::Q8_0 => 8,
+            Self::Q8_1 => 9,
+            Self::Q2K => 10,
+            Self::Q3K => 11,
+            Self::Q4K => 12,
+            Self::Q5K => 13,
+            Self::Q6K => 14,
+            Self::Q8K => 15,
+            Self::BF16 => 30,
+        }
+    }
+
+    /// Number of elements stored together in a block
+    pub fn block_size(&self) -> usize {
+        match self {
+            Self::F32 | Self::F16 | Self::BF16 => 1,
+            Self::Q4_0 | Self::Q4_1 | Self::Q5_0 | Self::Q5_1 | Self::Q8_0 | Self::Q8_1 => 32,
+            Self::Q2K | Self::Q3K | Self::Q4K | Self::Q5K | Self::Q6K | Self::Q8K => 256,
+        }
+    }
+
+    /// Size of a block in bytes
+    pub fn type_size(&self) -> usize {
+        match self {
+            Self::F32 => 4,
+            Self::F16 | Self::BF16 => 2,
+            Self::Q4_0 => 18,
+            Self::Q4_1 => 20,
+            Self::Q5_0 => 22,
+            Self::Q5_1 => 24,
+            Self::Q8_0 => 34,
+            Self::Q8_1 => 36,
+            Self::Q2K => 84,
+            Self::Q3K => 110,
+            Self::Q4K => 144,
+            Self::Q5K => 176,
+            Self::Q6K => 210,
+            Self::Q8K => 292,
+        }
+    }
+}
+
+/// A memory-mapped GGUF file
+#[derive(Debug)]
+pub struct GgufFile {
+    pub content: Content,
+    mmap: Arc<Mmap>,
+}
+
+impl GgufFile {
+    /// Map a GGUF file and read its header. Tensors loaded from it view the map, so the file must
+    /// not be modified while they're in use
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, GgufError> {
+        // Safety: the map is only read, and the caller keeps the file unchanged while it's in use
+        let mmap = unsafe { map_file(path)? };
+        let content = Content::read(&mut Cursor::new(&mmap[..]))?;
+        Ok(Self { content, mmap })
+    }
+
+    /// The raw bytes of a tensor's data
+    pub fn tensor_bytes(&self, name: &str) -> Result<&[u8], GgufError> {
+        let info = self.content.tensor_info(name)?;
+        let start = (self.content.tensor_data_offset as usize).saturating_add(info.offset);
+        let n_bytes = info.n_bytes()?;
+        self.mmap
+            .get(start..start.saturating_add(n_bytes))
+            .ok_or_else(|| GgufError::SizeMismatch {
+                name: name.to_string(),
+                expected: n_bytes,
+                found: self.mmap.len().saturating_sub(start),
+            })
+    }
+
+    /// The raw bytes of a tensor's data as a u8 view over the map, without copying
+    pub fn tensor_data(&self, name: &str) -> Result<MmapData, GgufError> {
+        let info = self.content.tensor_info(name)?;
+        let start = (self.content.tensor_data_offset as usize).saturating_add(info.offset);
+        Ok(MmapData::try_new(
+            self.mmap.clone(),
+            start,
+            self.tensor_bytes(name)?.len(),
+            DType::U8,
+        )?)
+    }
+
+    /// Dequantize a tensor into f32
+    pub fn dequantize(&self, name: &str) -> Result<Vec<f32>, GgufError> {
+        dequantize(
+            self.content.tensor_info(name)?.dtype,
+            self.tensor_bytes(name)?,
+        )
+    }
+
+    /// Load a tensor as an f32 CPU tensor. F32 tensors are used from the map without copying, everything else is dequantized
+    pub fn tensor(&self, name: &str) -> Result<Tensor, GgufError> {
+        let info = self.content.tensor_info(name)?;
+        let start = (self.content.tensor_data_offset as usize).saturating_add(info.offset);
+        let bytes = self.tensor_bytes(name)?;
+        if info.dtype == GgmlDType::F32 {
+            match MmapData::try_new(self.mmap.clone(), start, info.n_elements()?, DType::F32) {
+                Ok(data) => return Ok(Tensor::new(data)),
+                // Misaligned data gets copied out instead
+                Err(MmapError::Misaligned { .. }) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(Tensor::new(dequantize(info.dtype, bytes)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("luminal_{}.gguf", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let path = temp_path();
+        let mut q8 = f16::from_f32(0.5).to_le_bytes().to_vec();
+        q8.extend((0..32).map(|i| i as u8));
+        GgufWriter::new()
+            .metadata("general.name", Value::String("test".to_string()))
+            .metadata("llama.block_count", Value::U32(2))
+            .metadata(
+                "tokenizer.ggml.scores",
+                Value::Array(vec![Value::F32(0.5), Value::F32(-1.)]),
+            )
+            .tensor(
+                "weight",
+                &[2, 3],
+                GgmlDType::F32,
+                (0..6).flat_map(|i| (i as f32).to_le_bytes()).collect(),
+            )
+            .unwrap()
+            .tensor("quantized", &[32], GgmlDType::Q8_0, q8)
+            .unwrap()
+            .write_to_file(&path)
+            .unwrap();
+
+        let file = GgufFile::open(&path).unwrap();
+        let content = &file.content;
+        assert_eq!(content.magic, VersionedMagic::GgufV3);
+        assert_eq!(content.metadata["general.name"].as_str(), Some("test"));
+        assert_eq!(content.metadata["llama.block_count"].to_u64(), Some(2));
+        assert_eq!(
+            content.metadata["tokenizer.ggml.scores"]
+                .as_array()
+                .unwrap()[1]
+                .to_f64(),
+            Some(-1.)
+        );
+        assert_eq!(
+            content
+                .tensors()
+                .map(|(n, i)| (n, i.shape.clone()))
+                .collect::<Vec<_>>(),
+            vec![("weight", vec![2, 3]), ("quantized", vec![32])]
+        );
+
+        let weight = file.tensor("weight").unwrap();
+        assert!(weight.is::<MmapData>());
+        assert_eq!(weight.as_slice::<f32>().unwrap(), &[0., 1., 2., 3., 4., 5.]);
+        assert_eq!(
+            file.dequantize("quantized").unwrap(),
+            (0..32).map(|i| i as f32 * 0.5).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            file.tensor_data("quantized").unwrap().bytes(),
+            file.tensor_bytes("quantized").unwrap()
+        );
+        assert!(matches!(
+            file.tensor("missing"),
+            Err(GgufError::MissingTensor(_))
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_save_gguf() {
+        struct Model(GraphTensor<R2<2, 2>>, GraphTensor<R1<3>>);
+        impl SerializeModule for Model {
+            fn serialize(&self, s: &mut Serializer) {
+                s.tensor("layer/weight", self.0);
+                s.tensor("norm", self.1);
+            }
+        }
+        let mut cx = Graph::new();
+        let model = Model(cx.tensor(), cx.tensor());
+        model.0.set(vec![1., 2., 3., 4.]).keep();
+        model
+            .1
+            .set(vec![bf16::ONE, bf16::ZERO, bf16::NEG_ONE])
+            .keep();
+        cx.execute();
+
+        let path = temp_path();
+        save_gguf(
+            &model,
+            &cx,
+            vec![(
+                "general.architecture".to_string(),
+                Value::String("test".to_string()),
+            )],
+            &path,
+        )
+        .unwrap();
+        let file = GgufFile::open(&path).unwrap();
+        let info = file.content.tensor_info("layer.weight").unwrap();
+        assert_eq!(
+            (info.shape.clone(), info.dtype),
+            (vec![2, 2], GgmlDType::F32)
+        );
+        assert_eq!(
+            file.content.tensor_info("norm").unwrap().dtype,
+            GgmlDType::BF16
+        );
+        assert_eq!(file.dequantize("norm").unwrap(), vec![1., 0., -1.]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        let path = temp_path();
+        std::fs::write(&path, b"GGML\x03\x00\x00\x00").unwrap();
+        assert!(matches!(
+            GgufFile::open(&path),
+            Err(GgufError::UnknownMagic(_))
+        ));
+        // Big endian magic
+        std::fs::write(&path, b"FUGG\x00\x00\x00\x03").unwrap();
+        assert!(matches!(
+            GgufFile::open(&path),
+            Err(GgufError::UnknownMagic(_))
+        ));
+        std::fs::write(&path, b"GGUF\x03\x00\x00\x00").unwrap();
+        assert!(matches!(GgufFile::open(&path), Err(GgufError::Io(_))));
+        // A tensor whose element count overflows
+        let mut huge_tensor = b"GGUF\x03\x00\x00\x00".to_vec();
+        huge_tensor.extend(1u64.to_le_bytes());
+        huge_tensor.extend(0u64.to_le_bytes());
+        huge_tensor.extend(1u64.to_le_bytes());
+        huge_tensor.extend(b"t");
+        huge_tensor.extend(2u32.to_le_bytes());
+        huge_tensor.extend(u64::MAX.to_le_bytes());
+        huge_tensor.extend(2u64.to_le_bytes());
+        huge_tensor.extend(0u32.to_le_bytes());
+        huge_tensor.extend(0u64.to_le_bytes());
+        std::fs::write(&path, huge_tensor).unwrap();
+        assert!(matches!(
+            GgufFile::open(&path),
+            Err(GgufError::SizeOverflow(_))
+        ));
+        // A string claiming to be far longer than the file
+        let mut huge_string = b"GGUF\x03\x00\x00\x00".to_vec();
+        huge_string.extend(0u64.to_le_bytes());
+        huge_string.extend(1u64.to_le_bytes());
+        huge_string.extend(u64::MAX.to_le_bytes());
+        std::fs::write(&path, huge_string).unwrap();
+        assert!(matches!(GgufFile::open(&path), Err(GgufError::Io(_))));
+        GgufWriter::new()
+            .metadata("general.alignment", Value::U32(0))
+            .write_to_file(&path)
+            .unwrap_err();
+        // Write a zero alignment by hand, since the writer rejects it
+        let mut zero_alignment = b"GGUF\x03\x00\x00\x00".to_vec();
+        zero_alignment.extend(0u64.to_le_bytes());
+        zero_alignment.extend(1u64.to_le_bytes());
+        zero_alignment.extend(17u64.to_le_bytes());
+        zero_alignment.extend(b"general.alignment");
+        zero_alignment.extend(ValueType::U32.to_u32().to_le_bytes());
+        zero_alignment.extend(0u32.to_le_bytes());
+        std::fs::write(&path, zero_alignment).unwrap();
+        assert!(matches!(
+            GgufFile::open(&path),
+            Err(GgufError::InvalidAlignment(0))
+        ));
+        assert!(matches!(
+            GgufWriter::new().tensor("t", &[64], GgmlDType::Q8_0, vec![0; 34]),
+            Err(GgufError::SizeMismatch { expected: 68, .. })
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+use std::{io::Write, path::Path};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use itertools::Itertools;
+use luminal::prelude::*;
+
+use crate::{alignment, GgmlDType, GgufError, Value, ValueType, MAGIC};
+
+/// Builds a GGUF v3 file out of metadata and raw tensor data
+#[derive(Debug, Clone, Default)]
+pub struct GgufWriter {
+    metadata: Vec<(String, Value)>,
+    tensors: Vec<(String, Vec<usize>, GgmlDType, Vec<u8>)>,
+}
+
+impl GgufWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a metadata key
+    pub fn metadata(mut self, key: &str, value: Value) -> Self {
+        self.metadata.push((key.to_string(), value));
+        self
+    }
+
+    /// Add a tensor, with its shape outermost dimension first and its data already encoded as `dtype`
+    pub fn tensor(
+        mut self,
+        name: &str,
+        shape: &[usize],
+        dtype: GgmlDType,
+        data: Vec<u8>,
+    ) -> Result<Self, GgufError> {
+        let expected = shape.iter().product::<usize>() / dtype.block_size() * dtype.type_size();
+        if data.len() != expected {
+            return Err(GgufError::SizeMismatch {
+                name: name.to_string(),
+                expected,
+                found: data.len(),
+            });
+        }
+        self.tensors
+            .push((name.to_string(), shape.to_vec(), dtype, data));
+        Ok(self)
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), GgufError> {
+        let alignment = alignment(
+            self.metadata
+                .iter()
+                .find(|(k, _)| k == "general.alignment")
+                .map(|(_, v)| v),
+        )? as usize;
+
+        let mut header = vec![];
+        header.write_u32::<LittleEndian>(MAGIC)?;
+        header.write_u32::<LittleEndian>(3)?;
+        header.write_u64::<LittleEndian>(self.tensors.len() as u64)?;
+        header.write_u64::<LittleEndian>(self.metadata.len() as u64)?;
+        for (key, value) in &self.metadata {
+            write_string(&mut header, key)?;
+            header.write_u32::<LittleEndian>(value.value_type().to_u32())?;
+            write_value(&mut header, value)?;
+        }
+        let mut offset = 0;
+        for (name, shape, dtype, data) in &self.tensors {
+            write_string(&mut header, name)?;
+            header.write_u32::<LittleEndian>(shape.len() as u32)?;
+            for dim in shape.iter().rev() {
+                header.write_u64::<LittleEndian>(*dim as u64)?;
+            }
+            header.write_u32::<LittleEndian>(dtype.to_u32())?;
+            header.write_u64::<LittleEndian>(offset as u64)?;
+            offset = (offset + data.len()).next_multiple_of(alignment);
+        }
+        header.resize(header.len().next_multiple_of(alignment), 0);
+        writer.write_all(&header)?;
+
+        for (_, _, _, data) in &self.tensors {
+            writer.write_all(data)?;
+            writer.write_all(&vec![
+                0;
+                data.len().next_multiple_of(alignment) - data.len()
+            ])?;
+        }
+        Ok(())
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), GgufError> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.write(&mut file)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+fn write_string<W: Write>(writer: &mut W, s: &str) -> Result<(), GgufError> {
+    writer.write_u64::<LittleEndian>(s.len() as u64)?;
+    writer.write_all(s.as_bytes())?;
+    Ok(())
+}
+
+fn write_value<W: Write>(writer: &mut W, value: &Value) -> Result<(), GgufError> {
+    match value {
+        Value::U8(v) => writer.write_u8(*v)?,
+        Value::I8(v) => writer.write_i8(*v)?,
+        Value::U16(v) => writer.write_u16::<LittleEndian>(*v)?,
+        Value::I16(v) => writer.write_i16::<LittleEndian>(*v)?,
+        Value::U32(v) => writer.write_u32::<LittleEndian>(*v)?,
+        Value::I32(v) => writer.write_i32::<LittleEndian>(*v)?,
+        Value::U64(v) => writer.write_u64::<LittleEndian>(*v)?,
+        Value::I64(v) => writer.write_i64::<LittleEndian>(*v)?,
+        Value::F32(v) => writer.write_f32::<LittleEndian>(*v)?,
+        Value::F64(v) => writer.write_f64::<LittleEndian>(*v)?,
+        Value::Bool(v) => writer.write_u8(*v as u8)?,
+        Value::String(s) => write_string(writer, s)?,
+        Value::Array(values) => {
+            // Empty arrays still need an element type
+            let value_type = values
+                .first()
+                .map(|v| v.value_type())
+                .unwrap_or(ValueType::U8);
+            writer.write_u32::<LittleEndian>(value_type.to_u32())?;
+            writer.write_u64::<LittleEndian>(values.len() as u64)?;
+            for v in values {
+                write_value(writer, v)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Save a model's parameters to a GGUF file, with the `/` in `param_dict` names replaced by `.` as GGUF names usually are. Tensors are stored in their f32, f16 or bf16 CPU type
+pub fn save_gguf<P: AsRef<Path>>(
+    model: impl SerializeModule,
+    graph: &Graph,
+    metadata: Vec<(String, Value)>,
+    path: P,
+) -> Result<(), GgufError> {
+    let mut s = Serializer::default();
+    model.serialize(&mut s);
+    let mut writer = GgufWriter {
+        metadata,
+        ..Default::default()
+    };
+    for (name, node) in s.state.iter().sorted_by_key(|(k, _)| *k) {
+        let missing = || GgufError::MissingData(name.clone());
+        let tensor = graph.get_tensor_ref(*node, 0).ok_or_else(missing)?;
+        let (ggml_dtype, data) = match tensor.dtype() {
+            Some(DType::F32) => (
+                GgmlDType::F32,
+                le_bytes(tensor.as_slice::<f32>(), f32::to_le_bytes).ok_or_else(missing)?,
+            ),
+            Some(DType::F16) => (
+                GgmlDType::F16,
+                le_bytes(tensor.as_slice::<f16>(), f16::to_le_bytes).ok_or_else(missing)?,
+            ),
+            Some(DType::Bf16) => (
+                GgmlDType::BF16,
+                le_bytes(tensor.as_slice::<bf16>(), bf16::to_le_bytes).ok_or_else(missing)?,
+            ),
+            _ => return Err(missing()),
+        };
+        let shape = s.shapes[name]
+            .shape()
+            .iter()
+            .map(|d| d.exec(&graph.dyn_map))
+            .collect::<Option<Vec<_>>>()
+            .unwrap_or_else(|| vec![data.len() / ggml_dtype.type_size()]);
+        writer = writer.tensor(&name.replace('/', "."), &shape, ggml_dtype, data)?;
+    }
+    writer.write_to_file(path)
+}
+
+fn le_bytes<T: Copy, const N: usize>(data: Option<&[T]>, f: fn(T) -> [u8; N]) -> Option<Vec<u8>> {
+    Some(data?.iter().flat_map(|v| f(*v)).collect())
+}
@@ -479,44 +479,52 @@ impl<T: MetalFloat> Compiler for MetalGatherCompiler<T> {
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         let dev = Device::system_default().unwrap();
         let queue = dev.new_command_queue();
-        let indexes = node();
-        let ind_copy = unary::<MetalCopyToDevice<T>>(indexes.clone());
-        let equal = binary::<MetalEqual<T>>(op::<MetalARange<T>>(), ind_copy.clone());
-        let embeddings = node();
-        let mul = binary::<MetalMul<T>>(embeddings.clone(), equal.clone());
-        let sum_reduce = unary::<MetalSumReduce<T>>(mul.clone());
-        let mut s = sum_reduce.clone().search(graph);
-        while s.next_match() {
-            if s.check_no_delete(&[sum_reduce.id, embeddings.id, indexes.id]) {
-                continue;
+        // Static aranges get folded to constants, which are copied to the device like other loads
+        let mut folded_arange = node();
+        folded_arange.check(|op, _| is_folded_arange(op));
+        for arange in [
+            op::<MetalARange<T>>(),
+            unary::<MetalCopyToDevice<T>>(folded_arange),
+        ] {
+            let indexes = node();
+            let ind_copy = unary::<MetalCopyToDevice<T>>(indexes.clone());
+            let equal = binary::<MetalEqual<T>>(arange, ind_copy.clone());
+            let embeddings = node();
+            let mul = binary::<MetalMul<T>>(embeddings.clone(), equal.clone());
+            let sum_reduce = unary::<MetalSumReduce<T>>(mul.clone());
+            let mut s = sum_reduce.clone().search(graph);
+            while s.next_match() {
+                if s.check_no_delete(&[sum_reduce.id, embeddings.id, indexes.id]) {
+                    continue;
+                }
+                let emb_shape = graph
+                    .edges_connecting(s.get(&embeddings), s.get(&mul))
+                    .next()
+                    .unwrap()
+                    .weight()
+                    .as_data()
+                    .unwrap()
+                    .2;
+                let embed_dim = emb_shape.shape()[2].to_usize().unwrap();
+                let index_shape = graph
+                    .edges_connecting(s.get(&indexes), s.get(&ind_copy))
+                    .next()
+                    .unwrap()
+                    .weight()
+                    .as_data()
+                    .unwrap()
+                    .2;
+                let gather = graph
+                    .add_op(MetalGather::<T>::new(dev.clone(), queue.clone(), embed_dim))
+                    .input(s.get(&indexes), 0, index_shape)
+                    .input(s.get(&embeddings), 0, emb_shape)
+                    .finish();
+                move_outgoing_edge(s.get(&sum_reduce), gather, graph);
+                remap(s.get(&sum_reduce), gather, &mut ids, graph);
+
+                graph.remove_node(s.get(&sum_reduce));
+                s.try_delete();
             }
-            let emb_shape = graph
-                .edges_connecting(s.get(&embeddings), s.get(&mul))
-                .next()
-                .unwrap()
-                .weight()
-                .as_data()
-                .unwrap()
-                .2;
-            let embed_dim = emb_shape.shape()[2].to_usize().unwrap();
-            let index_shape = graph
-                .edges_connecting(s.get(&indexes), s.get(&ind_copy))
-                .next()
-                .unwrap()
-                .weight()
-                .as_data()
-                .unwrap()
-                .2;
-            let gather = graph
-                .add_op(MetalGather::<T>::new(dev.clone(), queue.clone(), embed_dim))
-                .input(s.get(&indexes), 0, index_shape)
-                .input(s.get(&embeddings), 0, emb_shape)
-                .finish();
-            move_outgoing_edge(s.get(&sum_reduce), gather, graph);
-            remap(s.get(&sum_reduce), gather, &mut ids, graph);
-
-            graph.remove_node(s.get(&sum_reduce));
-            s.try_delete();
         }
     }
 }
@@ -1616,6 +1616,7 @@ impl<T: MetalFloat + 'static> Compiler for PrimitiveCompiler<T> {
                 .collect::<Vec<_>>()
             {
                 let (input_order, output_order, shape) = edge_weight.as_data().unwrap();
+                let dtype = edge_weight.dtype().unwrap();
                 let copy_from_node = graph
                     .add_op(MetalCopyFromDevice::<T>::default())
                     .input(source, output_order, shape)
@@ -1627,6 +1628,7 @@ impl<T: MetalFloat + 'static> Compiler for PrimitiveCompiler<T> {
                         input_order,
                         output_order: 0,
                         shape,
+                        dtype,
                     },
                 );
                 graph.remove_edge(edge);
@@ -1,14 +1,22 @@
-use itertools::Itertools;
-use std::fs::File;
-use std::io::{Read, Seek};
 use std::path::Path;
 
+#[cfg(any(feature = "metal", feature = "cuda"))]
+use std::fs::File;
+#[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
+use std::sync::Arc;
+
 use luminal::{op::Function, prelude::*};
 
+#[cfg(any(feature = "metal", feature = "cuda"))]
+use {
+    itertools::Itertools,
+    std::io::{Read, Seek},
+};
+
 #[cfg(feature = "cuda")]
 use {luminal_cuda::CudaData, luminal_cudarc::driver::CudaDevice};
 
-use crate::llama::gguf::*;
+use luminal_gguf::*;
 
 #[cfg(feature = "metal")]
 use {
@@ -40,16 +48,14 @@ pub fn q8_load<P: AsRef<Path>, M: SerializeModule>(
             .and_then(|op| op.as_any_mut().downcast_mut::<Function>())
         {
             let file_path = path.as_ref().to_owned();
-            let (n_elements, buffer_offset, data_type) =
-                tensor_infos.remove(&weight_name.replace('/', ".")).unwrap();
-            let n_bytes = match data_type {
-                GgmlDType::F32 => n_elements * 4,
-                GgmlDType::Q8_0 => {
-                    q8_weights.push(node_index);
-                    n_elements + (n_elements / 16)
-                }
+            let info = tensor_infos.remove(&weight_name.replace('/', ".")).unwrap();
+            let (buffer_offset, data_type, n_bytes) =
+                (info.offset, info.dtype, info.n_bytes().unwrap());
+            match data_type {
+                GgmlDType::F32 => {}
+                GgmlDType::Q8_0 => q8_weights.push(node_index),
                 _ => panic!("Unsupported dtype: {data_type:?}"),
-            };
+            }
             if let GgmlDType::F32 = data_type {
                 loading_node.1 = Box::new(move |_| {
                     // Read bytes
@@ -120,16 +126,14 @@ pub fn q8_load<P: AsRef<Path>, M: SerializeModule>(
             .and_then(|op| op.as_any_mut().downcast_mut::<Function>())
         {
             let file_path = path.as_ref().to_owned();
-            let (n_elements, buffer_offset, data_type) =
-                tensor_infos.remove(&weight_name.replace('/', ".")).unwrap();
-            let n_bytes = match data_type {
-                GgmlDType::F32 => n_elements * 4,
-                GgmlDType::Q8_0 => {
-                    q8_weights.push(node_index);
-                    n_elements + (n_elements / 16)
-                }
+            let info = tensor_infos.remove(&weight_name.replace('/', ".")).unwrap();
+            let (buffer_offset, data_type, n_bytes) =
+                (info.offset, info.dtype, info.n_bytes().unwrap());
+            match data_type {
+                GgmlDType::F32 => {}
+                GgmlDType::Q8_0 => q8_weights.push(node_index),
                 _ => panic!("Unsupported dtype: {data_type:?}"),
-            };
+            }
             loading_node.1 = Box::new(move |_| {
                 // Read bytes
                 let mut bytes = vec![0; n_bytes];
@@ -170,20 +174,8 @@ pub fn q8_load<P: AsRef<Path>, M: SerializeModule>(
     model: &M,
     graph: &mut Graph,
 ) -> Vec<NodeIndex> {
-    #[repr(C, packed)]
-    #[derive(Clone, Copy)]
-    struct Q8Block {
-        delta: f16,
-        weights: [i8; 32],
-    }
-
-    // Read metadata from file
-    let mut reader = File::open(&path).unwrap();
-    let Content {
-        mut tensor_infos,
-        tensor_data_offset,
-        ..
-    } = Content::read(&mut reader).unwrap();
+    // Map the file once, so every weight is read from the same pages
+    let gguf = Arc::new(GgufFile::open(&path).unwrap());
 
     // Create weight loading closures
     let mut q8_weights = vec![];
@@ -193,56 +185,13 @@ pub fn q8_load<P: AsRef<Path>, M: SerializeModule>(
             .node_weight_mut(node_index)
             .and_then(|op| op.as_any_mut().downcast_mut::<Function>())
         {
-            let file_path = path.as_ref().to_owned();
-            let (n_elements, buffer_offset, data_type) =
-                tensor_infos.remove(&weight_name.replace('/', ".")).unwrap();
-            let n_bytes = match data_type {
-                GgmlDType::F32 => n_elements * 4,
-                GgmlDType::Q8_0 => {
-                    q8_weights.push(node_index);
-                    n_elements + (n_elements / 16)
-                }
-                _ => panic!("Unsupported dtype: {data_type:?}"),
-            };
-            loading_node.1 = Box::new(move |_| {
-                // Load all bytes
-                let mut bytes = vec![0; n_bytes];
-                let mut file = File::open(&file_path).unwrap();
-                file.seek(std::io::SeekFrom::Start(
-                    buffer_offset as u64 + tensor_data_offset,
-                ))
-                .unwrap();
-                file.read_exact(&mut bytes).unwrap();
-                // Dequantize into f32
-                let data: Vec<f32> = match data_type {
-                    GgmlDType::F32 => bytes
-                        .into_iter()
-                        .chunks(4)
-                        .into_iter()
-                        .map(|c| {
-                            let c = c.collect::<Vec<_>>();
-                            f32::from_le_bytes([c[0], c[1], c[2], c[3]])
-                        })
-                        .collect(),
-                    GgmlDType::Q8_0 => bytes
-                        .into_iter()
-                        .chunks(34)
-                        .into_iter()
-                        .map(|c| {
-                            let chunk = c.collect::<Vec<_>>();
-                            unsafe { chunk.align_to::<Q8Block>().1[0] }
-                        })
-                        .flat_map(|chunk| {
-                            chunk
-                                .weights
-                                .into_iter()
-                                .map(move |i| i as f32 * chunk.delta.to_f32())
-                        })
-                        .collect(),
-                    _ => panic!("Unsupported dtype: {data_type:?}"),
-                };
-                vec![Tensor::new(data)]
-            });
+            let name = weight_name.replace('/', ".");
+            if gguf.content.tensor_info(&name).unwrap().dtype == GgmlDType::Q8_0 {
+                q8_weights.push(node_index);
+            }
+            let gguf = gguf.clone();
+            // F32 weights are used from the map without copying, everything else is dequantized when loaded
+            loading_node.1 = Box::new(move |_| vec![gguf.tensor(&name).unwrap()]);
         }
     }
     q8_weights
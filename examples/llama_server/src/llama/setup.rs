@@ -54,7 +54,7 @@ impl Model {
         let mut cache_src: Vec<KVCache<Const<1>, Dyn<'p'>>> = (0..NUM_LAYERS)
             .map(|_| (cx.named_tensor("Key Cache"), cx.named_tensor("Value Cache")))
             .collect();
-        cache_src.set_dyn(vec![], &[1, N_KV_HEADS, 0, HEAD_DIM]);
+        cache_src.set_dyn(Vec::<f32>::new(), &[1, N_KV_HEADS, 0, HEAD_DIM]);
         let model = MistralLM::initialize(&mut cx);
         let mut model_weights = params(&model);
         cx.keep_tensors(&model_weights);
@@ -83,7 +83,7 @@ impl Model {
                 #[cfg(feature = "cuda")]
                 luminal_cuda::CudaQuantizedCompiler::<f16>::new(q_weights),
                 #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
-                luminal_cpu::CPUCompiler::default(),
+                luminal_cpu::CPUCompiler::<f32>::default(),
             ),
             (
                 &mut input,
@@ -11,8 +11,8 @@ fn main() {
     // Feed tensor through model
     let b = model.forward(a).retrieve();
 
-    // Display the graph to see the ops
-    cx.display();
+    // Write out the graph to see the ops (render with `dot -Tsvg simple.dot -o simple.svg`)
+    cx.write_dot("simple.dot").unwrap();
     // Execute the graph
     cx.execute_debug();
     // Print the results
@@ -1,8 +1,10 @@
+#![allow(dead_code)]
+
 use luminal::prelude::*;
 
 mod audio;
 mod model;
 
 fn main() {
-    let mut graph = Graph::new();
+    let _graph = Graph::new();
 }
@@ -0,0 +1,268 @@
+use std::{borrow::Cow, fmt::Display, path::Path};
+
+use itertools::Itertools;
+use safetensors::{tensor::TensorInfo, Dtype, SafeTensorError, SafeTensors, View};
+
+use crate::{dispatch_dtype, op::Function, prelude::*};
+
+/// An error encountered while saving or loading model parameters
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(std::io::Error),
+    /// The file isn't a valid safetensors file
+    Format(SafeTensorError),
+    /// The file has no tensor with this name
+    MissingTensor {
+        name: String,
+    },
+    /// The graph has no CPU data for this parameter, for instance because it hasn't been ran yet
+    MissingData {
+        name: String,
+    },
+    /// The stored tensor's shape doesn't match the parameter's shape
+    ShapeMismatch {
+        name: String,
+        expected: Vec<BigExpression>,
+        found: Vec<usize>,
+    },
+    /// The stored tensor has an element type luminal doesn't support
+    UnsupportedDType {
+        name: String,
+        dtype: String,
+    },
+    /// The stored tensor's data can't be read from the file
+    InvalidData {
+        name: String,
+        error: MmapError,
+    },
+}
+
+impl Display for CheckpointError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheckpointError::Io(e) => write!(f, "{e}"),
+            CheckpointError::Format(e) => write!(f, "Invalid safetensors file: {e}"),
+            CheckpointError::MissingTensor { name } => write!(f, "No tensor named {name} in file"),
+            CheckpointError::MissingData { name } => write!(f, "No data for parameter {name}"),
+            CheckpointError::ShapeMismatch {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Tensor {name} has shape {found:?}, but the parameter has shape {expected:?}"
+            ),
+            CheckpointError::UnsupportedDType { name, dtype } => {
+                write!(f, "Tensor {name} has unsupported type {dtype}")
+            }
+            CheckpointError::InvalidData { name, error } => {
+                write!(f, "Can't read tensor {name}: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+impl From<std::io::Error> for CheckpointError {
+    fn from(e: std::io::Error) -> Self {
+        CheckpointError::Io(e)
+    }
+}
+
+impl From<SafeTensorError> for CheckpointError {
+    fn from(e: SafeTensorError) -> Self {
+        CheckpointError::Format(e)
+    }
+}
+
+/// Save the model's parameters to a safetensors file, named as in `param_dict`
+pub fn save_safetensors<P: AsRef<Path>>(
+    model: impl SerializeModule,
+    graph: &Graph,
+    path: P,
+) -> Result<(), CheckpointError> {
+    save_safetensors_with(model, graph, path, |name| Some(name.to_string()))
+}
+
+/// Save the model's parameters to a safetensors file, renaming each with `remap`. Parameters it returns None for are skipped
+pub fn save_safetensors_with<P: AsRef<Path>>(
+    model: impl SerializeModule,
+    graph: &Graph,
+    path: P,
+    remap: impl Fn(&str) -> Option<String>,
+) -> Result<(), CheckpointError> {
+    let s = serialize(model);
+    let mut tensors = vec![];
+    for (name, node) in s.state.iter().sorted_by_key(|(k, _)| *k) {
+        let Some(file_name) = remap(name) else {
+            continue;
+        };
+        let missing = || CheckpointError::MissingData { name: name.clone() };
+        let tensor = graph.get_tensor_ref(*node, 0).ok_or_else(missing)?;
+        let dtype = tensor.dtype().ok_or_else(missing)?;
+        let data = dispatch_dtype!(dtype, T => {
+            let data = tensor.as_slice::<T>().unwrap();
+            // Safety: all element types are plain data, so their memory is valid to read as bytes
+            unsafe {
+                std::slice::from_raw_parts(data.as_ptr().cast::<u8>(), std::mem::size_of_val(data))
+            }
+        });
+        let expected = s.shapes[name].shape();
+        let shape = expected
+            .iter()
+            .map(|e| e.exec(&graph.dyn_map))
+            .collect::<Option<Vec<_>>>()
+            .filter(|shape| shape.iter().product::<usize>() * dtype.size_bytes() == data.len())
+            .ok_or_else(|| CheckpointError::ShapeMismatch {
+                name: name.clone(),
+                expected,
+                found: vec![data.len() / dtype.size_bytes()],
+            })?;
+        tensors.push((
+            file_name,
+            TensorBytes {
+                dtype: to_safetensors_dtype(dtype),
+                shape,
+                data,
+            },
+        ));
+    }
+    safetensors::serialize_to_file(tensors, &None, path.as_ref())?;
+    Ok(())
+}
+
+/// Load the model's parameters from a safetensors file, named as in `param_dict`. The file is memory-mapped, so aligned tensors are never copied into memory, and it must not be modified while the graph uses them
+pub fn load_safetensors<P: AsRef<Path>>(
+    model: impl SerializeModule,
+    graph: &mut Graph,
+    path: P,
+) -> Result<(), CheckpointError> {
+    load_safetensors_with(model, graph, path, |name| Some(name.to_string()))
+}
+
+/// Load the model's parameters from a safetensors file, looking each up under the name `remap` gives it. Parameters it returns None for are left as they are
+pub fn load_safetensors_with<P: AsRef<Path>>(
+    model: impl SerializeModule,
+    graph: &mut Graph,
+    path: P,
+    remap: impl Fn(&str) -> Option<String>,
+) -> Result<(), CheckpointError> {
+    // Safety: loaded tensors are only read, and the caller keeps the file unchanged while they're in use
+    let mmap = unsafe { map_file(path)? };
+    let (header_len, metadata) = SafeTensors::read_metadata(&mmap)?;
+    let infos = metadata.tensors();
+    let s = serialize(model);
+    for (name, node) in s.state.iter().sorted_by_key(|(k, _)| *k) {
+        let Some(file_name) = remap(name) else {
+            continue;
+        };
+        let TensorInfo {
+            dtype,
+            shape,
+            data_offsets,
+        } = infos
+            .get(&file_name)
+            .ok_or_else(|| CheckpointError::MissingTensor {
+                name: file_name.clone(),
+            })?;
+        let dtype =
+            from_safetensors_dtype(*dtype).ok_or_else(|| CheckpointError::UnsupportedDType {
+                name: file_name.clone(),
+                dtype: format!("{dtype:?}"),
+            })?;
+        // Dims that depend on unset dyn dims match anything
+        let expected = s.shapes[name].shape();
+        if expected.len() != shape.len()
+            || expected
+                .iter()
+                .zip(shape)
+                .any(|(e, s)| e.exec(&graph.dyn_map).map(|e| e != *s).unwrap_or_default())
+        {
+            return Err(CheckpointError::ShapeMismatch {
+                name: file_name,
+                expected,
+                found: shape.clone(),
+            });
+        }
+
+        // The data starts after the 8 byte header length and the header
+        let offset = 8 + header_len + data_offsets.0;
+        let len = shape.iter().product::<usize>();
+        let tensor = match MmapData::try_new(mmap.clone(), offset, len, dtype) {
+            Ok(data) => Tensor::new(data),
+            // Misaligned tensors can't be viewed in place, so copy them out. Bounds are checked first
+            Err(MmapError::Misaligned { .. }) => {
+                let bytes = &mmap[offset..offset + len * dtype.size_bytes()];
+                dispatch_dtype!(dtype, T => Tensor::new(
+                    bytes
+                        .chunks_exact(dtype.size_bytes())
+                        // Safety: bools are a single byte, so they're never misaligned and never get here
+                        .map(|b| unsafe { std::ptr::read_unaligned(b.as_ptr() as *const T) })
+                        .collect::<Vec<_>>()
+                ))
+            }
+            Err(error) => {
+                return Err(CheckpointError::InvalidData {
+                    name: file_name,
+                    error,
+                })
+            }
+        };
+        graph.set_dtype(*node, dtype);
+        graph.drop_tensors(*node);
+        graph.get_op_mut::<Function>(*node).1 = Box::new(move |_| vec![tensor.clone()]);
+    }
+    Ok(())
+}
+
+fn serialize(model: impl SerializeModule) -> Serializer {
+    let mut s = Serializer::default();
+    model.serialize(&mut s);
+    s
+}
+
+fn to_safetensors_dtype(dtype: DType) -> Dtype {
+    match dtype {
+        DType::F32 => Dtype::F32,
+        DType::F16 => Dtype::F16,
+        DType::Bf16 => Dtype::BF16,
+        DType::I32 => Dtype::I32,
+        DType::U8 => Dtype::U8,
+        DType::Bool => Dtype::BOOL,
+    }
+}
+
+fn from_safetensors_dtype(dtype: Dtype) -> Option<DType> {
+    match dtype {
+        Dtype::F32 => Some(DType::F32),
+        Dtype::F16 => Some(DType::F16),
+        Dtype::BF16 => Some(DType::Bf16),
+        Dtype::I32 => Some(DType::I32),
+        Dtype::U8 => Some(DType::U8),
+        Dtype::BOOL => Some(DType::Bool),
+        _ => None,
+    }
+}
+
+/// Raw tensor bytes in the form safetensors serializes
+struct TensorBytes<'a> {
+    dtype: Dtype,
+    shape: Vec<usize>,
+    data: &'a [u8],
+}
+
+impl View for TensorBytes<'_> {
+    fn dtype(&self) -> Dtype {
+        self.dtype
+    }
+    fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+    fn data(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.data)
+    }
+    fn data_len(&self) -> usize {
+        self.data.len()
+    }
+}
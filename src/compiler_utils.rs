@@ -1,17 +1,24 @@
 // Lots of utilities used by compilers
 
-use std::{any::TypeId, borrow::Borrow, collections::HashSet, fmt::Debug, sync::Arc};
+use std::{
+    any::TypeId,
+    borrow::Borrow,
+    collections::{BTreeMap, HashSet},
+    fmt::{Debug, Write},
+    path::Path,
+    sync::Arc,
+};
 
 use colored::Colorize;
 use itertools::Itertools;
 use petgraph::{
     algo::toposort,
     stable_graph::{EdgeIndex, EdgeReference, StableGraph},
-    visit::EdgeRef,
+    visit::{EdgeRef, IntoEdgeReferences},
     Direction,
 };
 use regex::Regex;
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 use uuid::Uuid;
 
 use crate::prelude::*;
@@ -220,24 +227,28 @@ impl<C: Compiler + Debug> Compiler for Timed<C> {
         println!("Starting {compiler_name}");
         let start = std::time::Instant::now();
         self.0.compile(graph, remap);
-        let finished_millis = start.elapsed().as_millis();
-        let minutes = finished_millis / 60_000;
-        let seconds = (finished_millis % 60_000) / 1000;
-        let millis = finished_millis % 1000;
         println!(
             "Finished {compiler_name} in {}",
-            if minutes > 0 {
-                format!("{minutes}m {seconds}s {millis}ms")
-            } else if seconds > 0 {
-                format!("{seconds}s {millis}ms")
-            } else {
-                format!("{millis}ms")
-            }
-            .bold()
+            format_duration(start.elapsed()).bold()
         );
     }
 }
 
+/// Format a duration as minutes, seconds and milliseconds, leaving out leading zero units
+pub(crate) fn format_duration(duration: std::time::Duration) -> String {
+    let finished_millis = duration.as_millis();
+    let minutes = finished_millis / 60_000;
+    let seconds = (finished_millis % 60_000) / 1000;
+    let millis = finished_millis % 1000;
+    if minutes > 0 {
+        format!("{minutes}m {seconds}s {millis}ms")
+    } else if seconds > 0 {
+        format!("{seconds}s {millis}ms")
+    } else {
+        format!("{millis}ms")
+    }
+}
+
 impl<C: Default + Compiler + Debug> Default for Timed<C> {
     fn default() -> Self {
         Self(C::default())
@@ -330,7 +341,7 @@ impl Graph {
     ///     .finish();
     /// let b = GraphTensor::<R1<3>>::from_id(b_id, a.shape, a.graph());
     /// ```
-    pub fn add_op<O: Operator + 'static>(&mut self, op: O) -> NewOp {
+    pub fn add_op<O: Operator + 'static>(&mut self, op: O) -> NewOp<'_> {
         self.linearized_graph = None;
         NewOp {
             new_op_id: self.graph.add_node(Box::new(op)),
@@ -350,11 +361,8 @@ impl Graph {
         key: &str,
         input: I,
     ) -> Option<O> {
-        let Some(node_weight) = self.graph.node_weight_mut(node) else {
-            return None;
-        };
-
-        node_weight
+        self.graph
+            .node_weight_mut(node)?
             .custom(key, Box::new(input))
             .and_then(|o| o.downcast::<O>().ok().map(|o| *o))
     }
@@ -428,16 +436,19 @@ impl Graph {
             .is::<T>()
     }
 
+    /// View the graph in the browser. This sends the graph to an external site, use `write_dot` to render it locally
     pub fn display(&self) {
         let (g, e, _) = self.debug_graph(false);
         display_graph(&g, &e, &[]);
     }
 
+    /// View the graph with shapes in the browser. This sends the graph to an external site, use `write_dot` to render it locally
     pub fn display_shapes(&self) {
         let (g, e, _) = self.debug_graph(true);
         display_graph(&g, &e, &[]);
     }
 
+    /// View the graph with a set of nodes highlighted in the browser. This sends the graph to an external site, use `write_dot_with` to render it locally
     pub fn display_set<T: ToIds>(&self, set: T) {
         let (g, e, id_map) = self.debug_graph(false);
         display_graph(
@@ -447,6 +458,117 @@ impl Graph {
         );
     }
 
+    /// Render the graph as DOT text, with shapes on data edges
+    pub fn to_dot(&self) -> String {
+        self.to_dot_with(&DotOptions::default().shapes())
+    }
+
+    /// Render the graph as DOT text
+    pub fn to_dot_with(&self, options: &DotOptions) -> String {
+        // Work out which module cluster each node belongs in
+        let mut clusters = ClusterTree::default();
+        let mut clustered = FxHashSet::default();
+        let module_of = |path: &String| path.rsplit_once('/').map(|(m, _)| m.to_string());
+        for node in self.graph.node_indices() {
+            let module = options
+                .modules
+                .get(&node)
+                .map(module_of)
+                .unwrap_or_else(|| {
+                    // Nodes directly consuming weights from a single module join that module
+                    self.graph
+                        .edges_directed(node, Direction::Incoming)
+                        .filter_map(|e| options.modules.get(&e.source()))
+                        .map(module_of)
+                        .all_equal_value()
+                        .ok()
+                        .flatten()
+                });
+            if let Some(module) = module {
+                let mut cluster = &mut clusters;
+                for component in module.split('/') {
+                    cluster = cluster.children.entry(component.to_string()).or_default();
+                }
+                cluster.nodes.push(node);
+                clustered.insert(node);
+            }
+        }
+
+        let mut dot = String::from("digraph {\n");
+        let mut n_clusters = 0;
+        clusters.write(self, options, &mut dot, &mut n_clusters, 1);
+        for node in self.graph.node_indices() {
+            if !clustered.contains(&node) {
+                self.write_dot_node(node, options, &mut dot, 1);
+            }
+        }
+        for edge in (&self.graph).edge_references() {
+            let attrs = match edge.weight() {
+                Dependency::Schedule => " [ color=\"green\" style=\"dashed\" ]".to_string(),
+                Dependency::Data { shape, dtype, .. } if options.shapes => {
+                    let mut label = format!("{:?}", shape.shape());
+                    // Only call out types that aren't the default
+                    if *dtype != DType::F32 {
+                        write!(label, " {dtype:?}").unwrap();
+                    }
+                    format!(" [ label=\"{}\" ]", escape_dot(&label))
+                }
+                Dependency::Data { .. } => String::new(),
+            };
+            writeln!(
+                dot,
+                "    {} -> {}{attrs}",
+                edge.source().index(),
+                edge.target().index()
+            )
+            .unwrap();
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Write the graph as a DOT file, with shapes on data edges
+    pub fn write_dot<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        std::fs::write(path, self.to_dot())
+    }
+
+    /// Write the graph as a DOT file
+    pub fn write_dot_with<P: AsRef<Path>>(
+        &self,
+        path: P,
+        options: &DotOptions,
+    ) -> std::io::Result<()> {
+        std::fs::write(path, self.to_dot_with(options))
+    }
+
+    fn write_dot_node(
+        &self,
+        node: NodeIndex,
+        options: &DotOptions,
+        dot: &mut String,
+        depth: usize,
+    ) {
+        let label = format!(
+            "{:?} | {}",
+            self.graph.node_weight(node).unwrap(),
+            node.index()
+        );
+        let style = if options.highlighted.contains(&node) {
+            "style=\"filled\" fillcolor=\"yellow\" "
+        } else {
+            ""
+        };
+        writeln!(
+            dot,
+            "{:indent$}{} [ {style}label=\"{}\" ]",
+            "",
+            node.index(),
+            escape_dot(&label),
+            indent = depth * 4
+        )
+        .unwrap();
+    }
+
     /// Remove node if it only has n dests
     pub fn safe_remove_node(&mut self, node: NodeIndex, dests: usize) {
         if self
@@ -532,6 +654,88 @@ pub fn display_graph(
     }
 }
 
+/// Options for rendering a graph as DOT
+#[derive(Debug, Clone, Default)]
+pub struct DotOptions {
+    /// Label data edges with their shapes
+    pub shapes: bool,
+    /// Nodes to fill in
+    pub highlighted: FxHashSet<NodeIndex>,
+    /// Module path of each weight, used to cluster nodes by module
+    pub modules: FxHashMap<NodeIndex, String>,
+}
+
+impl DotOptions {
+    /// Label data edges with their shapes
+    pub fn shapes(mut self) -> Self {
+        self.shapes = true;
+        self
+    }
+
+    /// Fill in a set of nodes
+    pub fn highlight<T: ToIds>(mut self, nodes: T) -> Self {
+        self.highlighted.extend(nodes.to_ids());
+        self
+    }
+
+    /// Cluster the model's weights, and the ops directly consuming them, by module
+    pub fn cluster_modules(mut self, model: impl SerializeModule) -> Self {
+        self.modules.extend(
+            param_dict(model)
+                .into_iter()
+                .map(|(path, node)| (node, path)),
+        );
+        self
+    }
+}
+
+/// Nested module clusters and the nodes directly inside them
+#[derive(Debug, Default)]
+struct ClusterTree {
+    nodes: Vec<NodeIndex>,
+    children: BTreeMap<String, ClusterTree>,
+}
+
+impl ClusterTree {
+    fn write(
+        &self,
+        graph: &Graph,
+        options: &DotOptions,
+        dot: &mut String,
+        n_clusters: &mut usize,
+        depth: usize,
+    ) {
+        for (name, child) in &self.children {
+            writeln!(
+                dot,
+                "{:indent$}subgraph cluster_{} {{",
+                "",
+                n_clusters,
+                indent = depth * 4
+            )
+            .unwrap();
+            writeln!(
+                dot,
+                "{:indent$}label=\"{}\"",
+                "",
+                escape_dot(name),
+                indent = (depth + 1) * 4
+            )
+            .unwrap();
+            *n_clusters += 1;
+            child.write(graph, options, dot, n_clusters, depth + 1);
+            writeln!(dot, "{:indent$}}}", "", indent = depth * 4).unwrap();
+        }
+        for node in &self.nodes {
+            graph.write_dot_node(*node, options, dot, depth);
+        }
+    }
+}
+
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
 pub struct NewOp<'a> {
     new_op_id: NodeIndex,
     graph_ref: &'a mut Graph,
@@ -544,6 +748,7 @@ impl<'a> NewOp<'a> {
     }
 
     pub fn input(mut self, id: NodeIndex, from_output: u8, shape: ShapeTracker) -> Self {
+        let dtype = self.graph_ref.node_dtype(id);
         self.graph_ref.graph.add_edge(
             id,
             self.new_op_id,
@@ -551,6 +756,7 @@ impl<'a> NewOp<'a> {
                 input_order: self.num_srcs,
                 output_order: from_output,
                 shape,
+                dtype,
             },
         );
         self.num_srcs += 1;
@@ -714,7 +920,7 @@ fn backtrack_match(
             if let Some(new_mapping) =
                 backtrack_match(pattern_parent, pattern_graph, *parent, main_graph)
             {
-                mapping.extend(new_mapping.into_iter());
+                mapping.extend(new_mapping);
                 continue 'pattern_loop;
             }
         }
@@ -0,0 +1,261 @@
+use std::{any::Any, fmt::Debug};
+
+use crate::prelude::*;
+
+/// The element type of a tensor
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum DType {
+    #[default]
+    F32,
+    F16,
+    Bf16,
+    I32,
+    U8,
+    Bool,
+}
+
+impl DType {
+    /// Size of a single element in bytes
+    pub fn size_bytes(&self) -> usize {
+        match self {
+            DType::F32 | DType::I32 => 4,
+            DType::F16 | DType::Bf16 => 2,
+            DType::U8 | DType::Bool => 1,
+        }
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(self, DType::F32 | DType::F16 | DType::Bf16)
+    }
+
+    /// The type two tensors of these types get combined as in a binary op
+    pub fn promote(self, other: DType) -> DType {
+        fn int_rank(d: DType) -> u8 {
+            match d {
+                DType::Bool => 0,
+                DType::U8 => 1,
+                _ => 2,
+            }
+        }
+        match (self.is_float(), other.is_float()) {
+            _ if self == other => self,
+            (true, true) => DType::F32,
+            (true, false) => self,
+            (false, true) => other,
+            (false, false) => {
+                if int_rank(self) >= int_rank(other) {
+                    self
+                } else {
+                    other
+                }
+            }
+        }
+    }
+
+    /// The type a tensor of this type gets combined with a constant as. Constants take the tensor's type, except that bools combined with whole numbers become i32, and integers combined with fractions become f32
+    pub fn promote_weak(self, integral: bool) -> DType {
+        match (self, integral) {
+            (d, _) if d.is_float() => d,
+            (_, false) => DType::F32,
+            (DType::Bool, true) => DType::I32,
+            (d, true) => d,
+        }
+    }
+
+    /// The type adding, multiplying and summing tensors of this type is done in. Bools are counted as i32, so summing a mask gives how many elements are set
+    pub fn arithmetic(self) -> DType {
+        match self {
+            DType::Bool => DType::I32,
+            d => d,
+        }
+    }
+
+    /// The type of a CPU tensor's data, if it's a Vec of a supported element type or a memory-mapped view
+    pub fn of(data: &dyn Any) -> Option<DType> {
+        if let Some(mmap) = data.downcast_ref::<MmapData>() {
+            Some(mmap.dtype())
+        } else if data.is::<Vec<f32>>() {
+            Some(DType::F32)
+        } else if data.is::<Vec<f16>>() {
+            Some(DType::F16)
+        } else if data.is::<Vec<bf16>>() {
+            Some(DType::Bf16)
+        } else if data.is::<Vec<i32>>() {
+            Some(DType::I32)
+        } else if data.is::<Vec<u8>>() {
+            Some(DType::U8)
+        } else if data.is::<Vec<bool>>() {
+            Some(DType::Bool)
+        } else {
+            None
+        }
+    }
+}
+
+/// Run an expression once for the element type matching a `DType`, with `$t` bound to that type
+#[macro_export]
+macro_rules! dispatch_dtype {
+    ($dtype: expr, $t: ident => $body: expr) => {
+        match $dtype {
+            $crate::dtype::DType::F32 => {
+                type $t = f32;
+                $body
+            }
+            $crate::dtype::DType::F16 => {
+                type $t = $crate::prelude::f16;
+                $body
+            }
+            $crate::dtype::DType::Bf16 => {
+                type $t = $crate::prelude::bf16;
+                $body
+            }
+            $crate::dtype::DType::I32 => {
+                type $t = i32;
+                $body
+            }
+            $crate::dtype::DType::U8 => {
+                type $t = u8;
+                $body
+            }
+            $crate::dtype::DType::Bool => {
+                type $t = bool;
+                $body
+            }
+        }
+    };
+}
+
+/// A scalar type CPU tensors can be stored as
+pub trait Element: Copy + Default + PartialOrd + Debug + Send + Sync + 'static {
+    const DTYPE: DType;
+    fn to_f32(self) -> f32;
+    fn from_f32(f: f32) -> Self;
+    fn add(self, rhs: Self) -> Self {
+        Self::from_f32(self.to_f32() + rhs.to_f32())
+    }
+    fn mul(self, rhs: Self) -> Self {
+        Self::from_f32(self.to_f32() * rhs.to_f32())
+    }
+    fn rem(self, rhs: Self) -> Self {
+        Self::from_f32(self.to_f32() % rhs.to_f32())
+    }
+    /// 1 if `self < rhs`, otherwise 0
+    fn less_than(self, rhs: Self) -> Self {
+        Self::from_f32((self < rhs) as i32 as f32)
+    }
+    /// The larger of the two values
+    fn larger(self, rhs: Self) -> Self {
+        if rhs > self {
+            rhs
+        } else {
+            self
+        }
+    }
+    /// The starting value of a max reduction
+    fn lowest() -> Self;
+}
+
+impl Element for f32 {
+    const DTYPE: DType = DType::F32;
+    fn to_f32(self) -> f32 {
+        self
+    }
+    fn from_f32(f: f32) -> Self {
+        f
+    }
+    fn lowest() -> Self {
+        -f32::INFINITY
+    }
+}
+
+impl Element for f16 {
+    const DTYPE: DType = DType::F16;
+    fn to_f32(self) -> f32 {
+        f16::to_f32(self)
+    }
+    fn from_f32(f: f32) -> Self {
+        f16::from_f32(f)
+    }
+    fn lowest() -> Self {
+        f16::NEG_INFINITY
+    }
+}
+
+impl Element for bf16 {
+    const DTYPE: DType = DType::Bf16;
+    fn to_f32(self) -> f32 {
+        bf16::to_f32(self)
+    }
+    fn from_f32(f: f32) -> Self {
+        bf16::from_f32(f)
+    }
+    fn lowest() -> Self {
+        bf16::NEG_INFINITY
+    }
+}
+
+impl Element for i32 {
+    const DTYPE: DType = DType::I32;
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+    fn from_f32(f: f32) -> Self {
+        f as i32
+    }
+    fn add(self, rhs: Self) -> Self {
+        self.wrapping_add(rhs)
+    }
+    fn mul(self, rhs: Self) -> Self {
+        self.wrapping_mul(rhs)
+    }
+    fn rem(self, rhs: Self) -> Self {
+        self.checked_rem(rhs).unwrap_or_default()
+    }
+    fn lowest() -> Self {
+        i32::MIN
+    }
+}
+
+impl Element for u8 {
+    const DTYPE: DType = DType::U8;
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+    fn from_f32(f: f32) -> Self {
+        f as u8
+    }
+    fn add(self, rhs: Self) -> Self {
+        self.wrapping_add(rhs)
+    }
+    fn mul(self, rhs: Self) -> Self {
+        self.wrapping_mul(rhs)
+    }
+    fn rem(self, rhs: Self) -> Self {
+        self.checked_rem(rhs).unwrap_or_default()
+    }
+    fn lowest() -> Self {
+        u8::MIN
+    }
+}
+
+impl Element for bool {
+    const DTYPE: DType = DType::Bool;
+    fn to_f32(self) -> f32 {
+        self as i32 as f32
+    }
+    fn from_f32(f: f32) -> Self {
+        f != 0.0
+    }
+    fn add(self, rhs: Self) -> Self {
+        self | rhs
+    }
+    fn mul(self, rhs: Self) -> Self {
+        self & rhs
+    }
+    fn larger(self, rhs: Self) -> Self {
+        self | rhs
+    }
+    fn lowest() -> Self {
+        false
+    }
+}
@@ -0,0 +1,682 @@
+//! Algebraic simplification through [equality saturation](https://egraphs-good.github.io/).
+//!
+//! Regions of primitive ops are added to an [`EGraph`], rewrite [`Rule`]s add every equivalent form they find, and the cheapest form of each output is extracted back into the graph.
+
+use itertools::Itertools;
+use petgraph::{algo::toposort, visit::EdgeRef, Direction};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    op::{
+        Add, Constant, ConstantValue, Contiguous, Exp2, LessThan, Log2, MaxReduce, Mod, Mul,
+        Operator, Recip, Sin, Sqrt, SumReduce,
+    },
+    prelude::*,
+};
+
+pub mod rules;
+pub use rules::{default_rules, fast_math_rules, Rule};
+
+/// An e-class id
+pub type Id = usize;
+/// An interned view id
+pub type ViewId = usize;
+
+/// A primitive op in an [`EGraph`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum EOp {
+    /// An output of a node outside the rewritten region
+    Input(NodeIndex, u8),
+    /// A float constant, stored as its bits
+    Constant(u32),
+    /// A constant computed from dynamic dimensions
+    Expr(BigExpression),
+    Contiguous,
+    Log2,
+    Exp2,
+    Sin,
+    Recip,
+    Sqrt,
+    Add,
+    Mul,
+    Mod,
+    LessThan,
+    SumReduce(usize),
+    MaxReduce(usize),
+}
+
+impl EOp {
+    /// The cost of running this op once, used to pick the cheapest equivalent graph
+    pub fn cost(&self) -> u64 {
+        match self {
+            EOp::Input(..) => 0,
+            EOp::Constant(_) | EOp::Expr(_) => 1,
+            EOp::Contiguous => 2,
+            EOp::Add | EOp::Mul | EOp::Mod | EOp::LessThan => 3,
+            EOp::Recip | EOp::Sqrt => 4,
+            EOp::Log2 | EOp::Exp2 | EOp::Sin | EOp::SumReduce(_) | EOp::MaxReduce(_) => 5,
+        }
+    }
+
+    /// Convert a primitive op, along with the number of inputs it takes
+    fn from_op(op: &dyn Operator) -> Option<(Self, usize)> {
+        let op = op.as_any();
+        if let Some(Constant(value, ..)) = op.downcast_ref::<Constant>() {
+            return Some((
+                match value {
+                    ConstantValue::Float(f) => EOp::Constant(f.to_bits()),
+                    ConstantValue::Expression(e) => EOp::Expr(e.clone()),
+                },
+                0,
+            ));
+        }
+        if let Some(SumReduce(axis)) = op.downcast_ref::<SumReduce>() {
+            return Some((EOp::SumReduce(*axis), 1));
+        }
+        if let Some(MaxReduce(axis)) = op.downcast_ref::<MaxReduce>() {
+            return Some((EOp::MaxReduce(*axis), 1));
+        }
+        [
+            (op.is::<Contiguous>(), EOp::Contiguous, 1),
+            (op.is::<Log2>(), EOp::Log2, 1),
+            (op.is::<Exp2>(), EOp::Exp2, 1),
+            (op.is::<Sin>(), EOp::Sin, 1),
+            (op.is::<Recip>(), EOp::Recip, 1),
+            (op.is::<Sqrt>(), EOp::Sqrt, 1),
+            (op.is::<Add>(), EOp::Add, 2),
+            (op.is::<Mul>(), EOp::Mul, 2),
+            (op.is::<Mod>(), EOp::Mod, 2),
+            (op.is::<LessThan>(), EOp::LessThan, 2),
+        ]
+        .into_iter()
+        .find(|(is, _, _)| *is)
+        .map(|(_, op, n)| (op, n))
+    }
+
+    fn to_op(&self, dyn_map: &FxHashMap<char, usize>) -> Box<dyn Operator> {
+        match self {
+            EOp::Input(..) => unreachable!("Inputs are already in the graph"),
+            EOp::Constant(bits) => Box::new(Constant(
+                ConstantValue::Float(f32::from_bits(*bits)),
+                dyn_map,
+                DType::F32,
+            )),
+            EOp::Expr(e) => Box::new(Constant(
+                ConstantValue::Expression(e.clone()),
+                dyn_map,
+                DType::F32,
+            )),
+            EOp::Contiguous => Box::new(Contiguous),
+            EOp::Log2 => Box::new(Log2),
+            EOp::Exp2 => Box::new(Exp2),
+            EOp::Sin => Box::new(Sin),
+            EOp::Recip => Box::new(Recip),
+            EOp::Sqrt => Box::new(Sqrt),
+            EOp::Add => Box::new(Add),
+            EOp::Mul => Box::new(Mul),
+            EOp::Mod => Box::new(Mod),
+            EOp::LessThan => Box::new(LessThan),
+            EOp::SumReduce(axis) => Box::new(SumReduce(*axis)),
+            EOp::MaxReduce(axis) => Box::new(MaxReduce(*axis)),
+        }
+    }
+}
+
+/// An op applied to e-classes, each read through a view
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ENode {
+    pub op: EOp,
+    pub children: Vec<(Id, ViewId)>,
+}
+
+/// A set of equivalence classes of primitive ops
+#[derive(Debug, Default)]
+pub struct EGraph {
+    parents: Vec<Id>,
+    /// Nodes of each class. Empty for classes that were merged into another
+    nodes: Vec<Vec<ENode>>,
+    /// Output shape of each class, if known
+    shapes: Vec<Option<Vec<Expression>>>,
+    memo: FxHashMap<ENode, Id>,
+    views: Vec<ShapeTracker>,
+    view_ids: FxHashMap<ShapeTracker, ViewId>,
+    node_count: usize,
+}
+
+impl EGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The canonical id of a class
+    pub fn find(&self, mut id: Id) -> Id {
+        while self.parents[id] != id {
+            id = self.parents[id];
+        }
+        id
+    }
+
+    /// All canonical classes
+    pub fn classes(&self) -> Vec<Id> {
+        (0..self.parents.len())
+            .filter(|i| self.parents[*i] == *i)
+            .collect()
+    }
+
+    /// The nodes in a canonical class
+    pub fn nodes(&self, class: Id) -> &[ENode] {
+        &self.nodes[class]
+    }
+
+    /// The output shape of a class, if known
+    pub fn shape(&self, class: Id) -> Option<&[Expression]> {
+        self.shapes[self.find(class)].as_deref()
+    }
+
+    /// The number of nodes added so far
+    pub fn node_count(&self) -> usize {
+        self.node_count
+    }
+
+    pub fn view(&self, id: ViewId) -> &ShapeTracker {
+        &self.views[id]
+    }
+
+    /// Intern a view
+    pub fn add_view(&mut self, view: ShapeTracker) -> ViewId {
+        if let Some(id) = self.view_ids.get(&view) {
+            return *id;
+        }
+        self.views.push(view);
+        self.view_ids.insert(view, self.views.len() - 1);
+        self.views.len() - 1
+    }
+
+    fn canonicalize(&self, mut node: ENode) -> ENode {
+        for (class, _) in &mut node.children {
+            *class = self.find(*class);
+        }
+        node
+    }
+
+    /// Add a node, returning the class it's in
+    pub fn add(&mut self, node: ENode) -> Id {
+        let node = self.canonicalize(node);
+        if let Some(id) = self.memo.get(&node) {
+            return self.find(*id);
+        }
+        let id = self.parents.len();
+        let shape = self.node_shape(&node);
+        self.parents.push(id);
+        self.shapes.push(shape);
+        self.nodes.push(vec![node.clone()]);
+        self.memo.insert(node, id);
+        self.node_count += 1;
+        id
+    }
+
+    fn node_shape(&self, node: &ENode) -> Option<Vec<Expression>> {
+        let (_, view) = node.children.first()?;
+        let mut shape = view_shape(self.view(*view))?;
+        if let EOp::SumReduce(axis) | EOp::MaxReduce(axis) = node.op {
+            if axis >= shape.len() {
+                return None;
+            }
+            shape.remove(axis);
+        }
+        Some(shape)
+    }
+
+    /// Merge two classes. Returns false if they were already the same class
+    pub fn union(&mut self, a: Id, b: Id) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return false;
+        }
+        let (root, other) = (a.min(b), a.max(b));
+        self.parents[other] = root;
+        let nodes = std::mem::take(&mut self.nodes[other]);
+        self.nodes[root].extend(nodes);
+        if self.shapes[root].is_none() {
+            self.shapes[root] = self.shapes[other].take();
+        }
+        true
+    }
+
+    /// Restore congruence after unions: nodes that became identical are deduplicated and their classes merged
+    pub fn rebuild(&mut self) {
+        loop {
+            self.memo.clear();
+            let mut merges = vec![];
+            for class in self.classes() {
+                let mut nodes = std::mem::take(&mut self.nodes[class]);
+                nodes = nodes
+                    .into_iter()
+                    .map(|n| self.canonicalize(n))
+                    .unique()
+                    .collect();
+                for node in &nodes {
+                    let existing = *self.memo.entry(node.clone()).or_insert(class);
+                    if existing != class {
+                        merges.push((existing, class));
+                    }
+                }
+                self.nodes[class] = nodes;
+            }
+            let mut merged = false;
+            for (a, b) in merges {
+                merged |= self.union(a, b);
+            }
+            if !merged {
+                break;
+            }
+        }
+    }
+
+    /// Apply rules until nothing changes, `iterations` rounds have ran or the graph grows past `node_limit` nodes
+    pub fn saturate(&mut self, rules: &[Rule], iterations: usize, node_limit: usize) {
+        for _ in 0..iterations {
+            let matches = rules
+                .iter()
+                .flat_map(|r| r.search(self).into_iter().map(move |m| (r, m)))
+                .collect::<Vec<_>>();
+            let mut changed = false;
+            for (rule, (class, node, subst)) in matches {
+                if self.node_count > node_limit {
+                    break;
+                }
+                if let Some(new) = rule.apply(self, &node, &subst) {
+                    changed |= self.union(class, new);
+                }
+            }
+            self.rebuild();
+            if !changed || self.node_count > node_limit {
+                break;
+            }
+        }
+    }
+
+    /// The cheapest node of each class, along with the total cost of the tree under it
+    pub fn extract(&self) -> FxHashMap<Id, (u64, ENode)> {
+        let mut best: FxHashMap<Id, (u64, ENode)> = FxHashMap::default();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for class in self.classes() {
+                for node in self.nodes(class) {
+                    let cost = node.children.iter().fold(node.op.cost(), |acc, (c, _)| {
+                        acc.saturating_add(best.get(&self.find(*c)).map_or(u64::MAX, |b| b.0))
+                    });
+                    if cost < best.get(&class).map_or(u64::MAX, |b| b.0) {
+                        best.insert(class, (cost, node.clone()));
+                        changed = true;
+                    }
+                }
+            }
+        }
+        best
+    }
+}
+
+/// The shape a view produces, if every dimension fits in an [`Expression`]
+pub fn view_shape(view: &ShapeTracker) -> Option<Vec<Expression>> {
+    let capacity = Expression::default().terms.capacity();
+    view.shape()
+        .into_iter()
+        .map(|e| (e.terms.len() <= capacity).then(|| e.into()))
+        .collect()
+}
+
+/// Simplify regions of primitive ops by equality saturation over a set of rewrite [`Rule`]s.
+///
+/// A region is only replaced if the extracted graph is cheaper than the original
+#[derive(Debug)]
+pub struct EqualitySaturation {
+    pub rules: Vec<Rule>,
+    /// The maximum number of rounds of rule application
+    pub iterations: usize,
+    /// Stop applying rules once the e-graph has this many nodes. Raised to 3x the initial size for large graphs
+    pub node_limit: usize,
+}
+
+impl Default for EqualitySaturation {
+    fn default() -> Self {
+        Self {
+            rules: default_rules(),
+            iterations: 6,
+            node_limit: 10_000,
+        }
+    }
+}
+
+impl EqualitySaturation {
+    /// The default rules plus [`fast_math_rules`], which assume values are finite
+    pub fn fast_math() -> Self {
+        Self {
+            rules: default_rules()
+                .into_iter()
+                .chain(fast_math_rules())
+                .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Compiler for EqualitySaturation {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        // Find the region of float primitive ops not tied to a schedule
+        let mut region = FxHashMap::default();
+        for node in toposort(&graph.graph, None).unwrap() {
+            let Some((op, n_inputs)) = EOp::from_op(graph.graph[node].as_ref()) else {
+                continue;
+            };
+            let incoming = graph
+                .graph
+                .edges_directed(node, Direction::Incoming)
+                .map(|e| *e.weight())
+                .collect::<Vec<_>>();
+            if incoming.len() != n_inputs
+                || incoming.iter().any(|d| d.dtype() != Some(DType::F32))
+                || graph
+                    .graph
+                    .edges_directed(node, Direction::Outgoing)
+                    .any(|e| e.weight().is_schedule())
+            {
+                continue;
+            }
+            region.insert(node, op);
+        }
+        if region.is_empty() {
+            return;
+        }
+
+        // Outputs of the region are anything used outside of it or referenced elsewhere
+        let tracked = ids
+            .to_ids_mut()
+            .into_iter()
+            .map(|i| *i)
+            .collect::<FxHashSet<_>>();
+        let mut roots = region
+            .keys()
+            .copied()
+            .filter(|n| {
+                graph.no_delete.contains(n)
+                    || graph.to_retrieve.contains_key(n)
+                    || tracked.contains(n)
+                    || graph
+                        .graph
+                        .neighbors_directed(*n, Direction::Outgoing)
+                        .any(|d| !region.contains_key(&d))
+            })
+            .collect::<Vec<_>>();
+        roots.sort();
+
+        // Build the e-graph
+        let mut egraph = EGraph::new();
+        let mut classes = FxHashMap::default();
+        for node in toposort(&graph.graph, None).unwrap() {
+            let Some(op) = region.get(&node) else {
+                continue;
+            };
+            let mut children = vec![];
+            for (src, output, shape) in graph.get_sources(node) {
+                let class = classes.get(&src).copied().unwrap_or_else(|| {
+                    egraph.add(ENode {
+                        op: EOp::Input(src, output),
+                        children: vec![],
+                    })
+                });
+                children.push((class, egraph.add_view(shape)));
+            }
+            classes.insert(
+                node,
+                egraph.add(ENode {
+                    op: op.clone(),
+                    children,
+                }),
+            );
+        }
+        let original_cost = region.values().map(EOp::cost).sum::<u64>();
+
+        egraph.saturate(
+            &self.rules,
+            self.iterations,
+            self.node_limit.max(egraph.node_count() * 3),
+        );
+        let best = egraph.extract();
+
+        // Order the extracted classes so inputs come first
+        let mut order = vec![];
+        let mut visited = FxHashSet::default();
+        let mut stack = roots
+            .iter()
+            .map(|r| (egraph.find(classes[r]), false))
+            .collect::<Vec<_>>();
+        while let Some((class, expanded)) = stack.pop() {
+            if expanded {
+                order.push(class);
+                continue;
+            }
+            if !visited.insert(class) {
+                continue;
+            }
+            stack.push((class, true));
+            for (child, _) in &best[&class].1.children {
+                stack.push((egraph.find(*child), false));
+            }
+        }
+        let cost = order.iter().map(|c| best[c].1.op.cost()).sum::<u64>();
+        if cost >= original_cost {
+            return;
+        }
+
+        // Rebuild the region from the extracted nodes, reusing the first output in each class so its id stays the same
+        let mut owners = FxHashMap::default();
+        for root in &roots {
+            owners.entry(egraph.find(classes[root])).or_insert(*root);
+        }
+        let mut built: FxHashMap<Id, (NodeIndex, u8)> = FxHashMap::default();
+        for class in order {
+            let node = &best[&class].1;
+            if let EOp::Input(src, output) = node.op {
+                built.insert(class, (src, output));
+                continue;
+            }
+            let op = node.op.to_op(&graph.dyn_map);
+            let new = if let Some(owner) = owners.get(&class) {
+                graph.graph[*owner] = op;
+                for edge in graph
+                    .graph
+                    .edges_directed(*owner, Direction::Incoming)
+                    .map(|e| e.id())
+                    .collect::<Vec<_>>()
+                {
+                    graph.graph.remove_edge(edge);
+                }
+                *owner
+            } else {
+                graph.graph.add_node(op)
+            };
+            for (i, (child, view)) in node.children.iter().enumerate() {
+                let (src, output) = built[&egraph.find(*child)];
+                let dtype = graph.node_dtype(src);
+                graph.graph.add_edge(
+                    src,
+                    new,
+                    Dependency::Data {
+                        input_order: i as u8,
+                        output_order: output,
+                        shape: *egraph.view(*view),
+                        dtype,
+                    },
+                );
+            }
+            built.insert(class, (new, 0));
+        }
+
+        // Point everything outside the region at the new outputs
+        for root in roots {
+            let (new, output) = built[&egraph.find(classes[&root])];
+            if new == root {
+                continue;
+            }
+            for (target, weight) in graph
+                .graph
+                .edges_directed(root, Direction::Outgoing)
+                .filter(|e| !region.contains_key(&e.target()))
+                .map(|e| (e.target(), *e.weight()))
+                .collect::<Vec<_>>()
+            {
+                let weight = match weight {
+                    Dependency::Data {
+                        input_order,
+                        shape,
+                        dtype,
+                        ..
+                    } => Dependency::Data {
+                        input_order,
+                        output_order: output,
+                        shape,
+                        dtype,
+                    },
+                    Dependency::Schedule => Dependency::Schedule,
+                };
+                graph.graph.add_edge(new, target, weight);
+            }
+            remap(root, new, &mut ids, graph);
+            if let Some((o, _)) = graph.to_retrieve.get_mut(&new) {
+                *o = output;
+            }
+        }
+        let kept = built.values().map(|(n, _)| *n).collect::<FxHashSet<_>>();
+        for node in region.keys().filter(|n| !kept.contains(n)) {
+            graph.graph.remove_node(*node);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    crate::test_imports!();
+
+    fn op_count(cx: &Graph) -> usize {
+        cx.graph
+            .node_indices()
+            .filter(|n| EOp::from_op(cx.graph[*n].as_ref()).is_some())
+            .count()
+    }
+
+    #[test]
+    fn test_mul_one() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+        let mut b = (a * 1.).retrieve();
+
+        cx.compile(EqualitySaturation::default(), &mut b);
+        assert_eq!(op_count(&cx), 0);
+        cx.execute();
+        assert_exact(&b.data(), &[1., 2., 3.]);
+    }
+
+    #[test]
+    fn test_inverse_pairs() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+        let mut b = a.log2().exp2().retrieve();
+        let mut c = a.recip().recip().retrieve();
+
+        cx.compile(EqualitySaturation::fast_math(), (&mut b, &mut c));
+        assert_eq!(op_count(&cx), 0);
+        cx.execute();
+        assert_close(&b.data(), &[1., 2., 3.]);
+        assert_close(&c.data(), &[1., 2., 3.]);
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+        let mut b = (a * 2. * 3.).retrieve();
+        let before = op_count(&cx);
+
+        cx.compile(EqualitySaturation::default(), &mut b);
+        assert!(op_count(&cx) < before);
+        cx.execute();
+        assert_close(&b.data(), &[6., 12., 18.]);
+    }
+
+    #[test]
+    fn test_sub_self() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+        let mut b = (a - a).retrieve();
+
+        cx.compile(EqualitySaturation::fast_math(), &mut b);
+        assert_eq!(op_count(&cx), 2);
+        cx.execute();
+        assert_exact(&b.data(), &[0., 0., 0.]);
+    }
+
+    #[test]
+    fn test_non_finite_preserved() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R1<3>>()
+            .set(vec![f32::INFINITY, f32::NEG_INFINITY, 0.]);
+        let mut b = (a * 0.).retrieve();
+        let mut c = (a - a).retrieve();
+        let mut d = (a / a).retrieve();
+
+        cx.compile(GenericCompiler::default(), (&mut b, &mut c, &mut d));
+        cx.execute();
+        assert!(b.data()[..2].iter().all(|v| v.is_nan()));
+        assert!(c.data()[..2].iter().all(|v| v.is_nan()));
+        assert!(d.data()[2].is_nan());
+    }
+
+    #[test]
+    fn test_sum_of_expand() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+        let mut b = a
+            .expand::<R2<3, 4>, _>()
+            .sum_reduce::<_, LAxis<1>>()
+            .retrieve();
+        let mut c = a
+            .expand::<R2<4, 3>, _>()
+            .max_reduce::<_, LAxis<0>>()
+            .retrieve();
+
+        cx.compile(EqualitySaturation::default(), (&mut b, &mut c));
+        assert!(!cx
+            .graph
+            .node_indices()
+            .any(|n| cx.check_node_type::<SumReduce>(n) || cx.check_node_type::<MaxReduce>(n)));
+        cx.execute();
+        assert_close(&b.data(), &[4., 8., 12.]);
+        assert_close(&c.data(), &[1., 2., 3.]);
+    }
+
+    #[test]
+    fn test_saturate() {
+        let mut egraph = EGraph::new();
+        let view = egraph.add_view(ShapeTracker::new(&[3.into()]));
+        let x = egraph.add(ENode {
+            op: EOp::Input(NodeIndex::new(0), 0),
+            children: vec![],
+        });
+        let log = egraph.add(ENode {
+            op: EOp::Log2,
+            children: vec![(x, view)],
+        });
+        let exp = egraph.add(ENode {
+            op: EOp::Exp2,
+            children: vec![(log, view)],
+        });
+        egraph.saturate(&EqualitySaturation::fast_math().rules, 6, 1000);
+        assert_eq!(egraph.find(exp), egraph.find(x));
+        assert_eq!(egraph.extract()[&egraph.find(exp)].0, 0);
+    }
+}
@@ -0,0 +1,497 @@
+use crate::prelude::*;
+
+use super::{view_shape, EGraph, ENode, EOp, Id, ViewId};
+
+/// The kind of a primitive op, ignoring its parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpKind {
+    Contiguous,
+    Log2,
+    Exp2,
+    Sin,
+    Recip,
+    Sqrt,
+    Add,
+    Mul,
+    Mod,
+    LessThan,
+    /// Sum reduce along any axis. Only usable on the left hand side of pattern rules
+    SumReduce,
+    /// Max reduce along any axis. Only usable on the left hand side of pattern rules
+    MaxReduce,
+}
+
+impl OpKind {
+    fn matches(self, op: &EOp) -> bool {
+        matches!(
+            (self, op),
+            (OpKind::Contiguous, EOp::Contiguous)
+                | (OpKind::Log2, EOp::Log2)
+                | (OpKind::Exp2, EOp::Exp2)
+                | (OpKind::Sin, EOp::Sin)
+                | (OpKind::Recip, EOp::Recip)
+                | (OpKind::Sqrt, EOp::Sqrt)
+                | (OpKind::Add, EOp::Add)
+                | (OpKind::Mul, EOp::Mul)
+                | (OpKind::Mod, EOp::Mod)
+                | (OpKind::LessThan, EOp::LessThan)
+                | (OpKind::SumReduce, EOp::SumReduce(_))
+                | (OpKind::MaxReduce, EOp::MaxReduce(_))
+        )
+    }
+
+    fn op(self) -> Option<EOp> {
+        Some(match self {
+            OpKind::Contiguous => EOp::Contiguous,
+            OpKind::Log2 => EOp::Log2,
+            OpKind::Exp2 => EOp::Exp2,
+            OpKind::Sin => EOp::Sin,
+            OpKind::Recip => EOp::Recip,
+            OpKind::Sqrt => EOp::Sqrt,
+            OpKind::Add => EOp::Add,
+            OpKind::Mul => EOp::Mul,
+            OpKind::Mod => EOp::Mod,
+            OpKind::LessThan => EOp::LessThan,
+            OpKind::SumReduce | OpKind::MaxReduce => return None,
+        })
+    }
+}
+
+/// A tensor in a rewrite rule
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// Any tensor. Repeated names must be the same tensor
+    Var(&'static str),
+    /// A float constant, binding its value
+    Const(&'static str),
+    /// A float constant with this value
+    Lit(f32),
+    /// A new constant computed from two bound constants. Only usable on the right hand side
+    Fold(fn(f32, f32) -> f32, &'static str, &'static str),
+    /// An op with its inputs
+    Op(OpKind, Vec<Input>),
+}
+
+/// How an input is viewed by the op consuming it
+#[derive(Debug, Clone)]
+pub enum View {
+    /// Any view. Repeated names must be the same view
+    Bind(&'static str),
+    /// Reads the whole input in order, without permutes, broadcasts, slices or padding
+    Identity,
+    /// Broadcasts a constant to the shape of a bound view. Only usable on the right hand side
+    FakeLike(&'static str),
+}
+
+/// An op input: a tensor and the view it's read through
+pub type Input = (Pattern, View);
+
+impl Pattern {
+    /// Read through any view, bound to `name`
+    pub fn view(self, name: &'static str) -> Input {
+        (self, View::Bind(name))
+    }
+    /// Read through an identity view
+    pub fn id(self) -> Input {
+        (self, View::Identity)
+    }
+    /// Broadcast to the shape of the view bound to `name`
+    pub fn fake_like(self, name: &'static str) -> Input {
+        (self, View::FakeLike(name))
+    }
+}
+
+pub fn var(name: &'static str) -> Pattern {
+    Pattern::Var(name)
+}
+
+pub fn constant(name: &'static str) -> Pattern {
+    Pattern::Const(name)
+}
+
+pub fn lit(value: f32) -> Pattern {
+    Pattern::Lit(value)
+}
+
+pub fn unary(kind: OpKind, a: Input) -> Pattern {
+    Pattern::Op(kind, vec![a])
+}
+
+pub fn add(a: Input, b: Input) -> Pattern {
+    Pattern::Op(OpKind::Add, vec![a, b])
+}
+
+pub fn mul(a: Input, b: Input) -> Pattern {
+    Pattern::Op(OpKind::Mul, vec![a, b])
+}
+
+/// What a rule's matches are rewritten to
+#[derive(Debug, Clone)]
+enum Rewrite {
+    Pattern(Pattern),
+    /// Rewrites that need to transform views, given the matched node
+    Custom(fn(&mut EGraph, &ENode, &Subst) -> Option<Id>),
+}
+
+/// A rewrite rule: anything matching the left hand side is equal to the right hand side
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub name: &'static str,
+    lhs: Pattern,
+    rhs: Rewrite,
+}
+
+impl Rule {
+    pub fn new(name: &'static str, lhs: Pattern, rhs: Pattern) -> Self {
+        Self {
+            name,
+            lhs,
+            rhs: Rewrite::Pattern(rhs),
+        }
+    }
+
+    /// Find all matches, as the matched class, node and bindings
+    pub(crate) fn search(&self, egraph: &EGraph) -> Vec<(Id, ENode, Subst)> {
+        let mut matches = vec![];
+        for class in egraph.classes() {
+            for node in egraph.nodes(class) {
+                for subst in match_node(egraph, &self.lhs, node, Subst::default()) {
+                    matches.push((class, node.clone(), subst));
+                }
+            }
+        }
+        matches
+    }
+
+    /// Build the right hand side for a match, returning its class
+    pub(crate) fn apply(&self, egraph: &mut EGraph, node: &ENode, subst: &Subst) -> Option<Id> {
+        match &self.rhs {
+            Rewrite::Pattern(p) => instantiate(egraph, p, subst),
+            Rewrite::Custom(f) => f(egraph, node, subst),
+        }
+    }
+}
+
+/// Bindings made while matching a pattern
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Subst {
+    classes: Vec<(&'static str, Id)>,
+    views: Vec<(&'static str, ViewId)>,
+    consts: Vec<(&'static str, f32)>,
+}
+
+impl Subst {
+    fn class(&self, name: &str) -> Option<Id> {
+        self.classes
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, i)| *i)
+    }
+    fn view(&self, name: &str) -> Option<ViewId> {
+        self.views.iter().find(|(n, _)| *n == name).map(|(_, i)| *i)
+    }
+    fn constant(&self, name: &str) -> Option<f32> {
+        self.consts
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, i)| *i)
+    }
+}
+
+fn match_class(egraph: &EGraph, pattern: &Pattern, class: Id, subst: Subst) -> Vec<Subst> {
+    if let Pattern::Var(name) = pattern {
+        return match subst.class(name) {
+            Some(bound) if egraph.find(bound) != egraph.find(class) => vec![],
+            Some(_) => vec![subst],
+            None => {
+                let mut subst = subst;
+                subst.classes.push((name, egraph.find(class)));
+                vec![subst]
+            }
+        };
+    }
+    egraph
+        .nodes(egraph.find(class))
+        .iter()
+        .flat_map(|node| match_node(egraph, pattern, node, subst.clone()))
+        .collect()
+}
+
+fn match_node(egraph: &EGraph, pattern: &Pattern, node: &ENode, subst: Subst) -> Vec<Subst> {
+    match pattern {
+        // Variables match whole classes, so they can't be the root of a rule
+        Pattern::Var(_) => vec![],
+        Pattern::Const(name) => match (&node.op, subst.constant(name)) {
+            (EOp::Constant(bits), Some(bound)) if f32::from_bits(*bits) == bound => vec![subst],
+            (EOp::Constant(bits), None) => {
+                let mut subst = subst;
+                subst.consts.push((name, f32::from_bits(*bits)));
+                vec![subst]
+            }
+            _ => vec![],
+        },
+        Pattern::Lit(value) => match node.op {
+            EOp::Constant(bits) if f32::from_bits(bits) == *value => vec![subst],
+            _ => vec![],
+        },
+        Pattern::Fold(..) => vec![],
+        Pattern::Op(kind, inputs) => {
+            if !kind.matches(&node.op) || inputs.len() != node.children.len() {
+                return vec![];
+            }
+            let mut substs = vec![subst];
+            for ((pattern, view), (class, view_id)) in inputs.iter().zip(&node.children) {
+                let constant = matches!(pattern, Pattern::Const(_) | Pattern::Lit(_));
+                if constant && !uniform(egraph.view(*view_id)) {
+                    return vec![];
+                }
+                substs = substs
+                    .into_iter()
+                    .filter_map(|s| match_view(egraph, view, *view_id, s))
+                    .flat_map(|s| match_class(egraph, pattern, *class, s))
+                    .collect();
+            }
+            substs
+        }
+    }
+}
+
+fn match_view(egraph: &EGraph, view: &View, id: ViewId, mut subst: Subst) -> Option<Subst> {
+    match view {
+        View::Bind(name) => match subst.view(name) {
+            Some(bound) => (bound == id).then_some(subst),
+            None => {
+                subst.views.push((name, id));
+                Some(subst)
+            }
+        },
+        View::Identity => (!egraph.view(id).is_reshaped()).then_some(subst),
+        View::FakeLike(_) => None,
+    }
+}
+
+/// A view of a single element constant that reads the same value everywhere
+fn uniform(view: &ShapeTracker) -> bool {
+    !view.is_padded() && view.n_physical_elements().to_usize() == Some(1)
+}
+
+fn instantiate(egraph: &mut EGraph, pattern: &Pattern, subst: &Subst) -> Option<Id> {
+    let constant = |value: f32| ENode {
+        op: EOp::Constant(value.to_bits()),
+        children: vec![],
+    };
+    match pattern {
+        Pattern::Var(name) => subst.class(name),
+        Pattern::Const(name) => Some(egraph.add(constant(subst.constant(name)?))),
+        Pattern::Lit(value) => Some(egraph.add(constant(*value))),
+        Pattern::Fold(f, a, b) => {
+            let value = f(subst.constant(a)?, subst.constant(b)?);
+            Some(egraph.add(constant(value)))
+        }
+        Pattern::Op(kind, inputs) => {
+            let mut children = vec![];
+            for (pattern, view) in inputs {
+                let class = instantiate(egraph, pattern, subst)?;
+                let view = match view {
+                    View::Bind(name) => subst.view(name)?,
+                    View::Identity => {
+                        let shape = egraph.shape(class)?.to_vec();
+                        egraph.add_view(ShapeTracker::new(&shape))
+                    }
+                    View::FakeLike(name) => {
+                        let shape = view_shape(egraph.view(subst.view(name)?))?;
+                        egraph.add_view(ShapeTracker::fake(&shape))
+                    }
+                };
+                children.push((class, view));
+            }
+            Some(egraph.add(ENode {
+                op: kind.op()?,
+                children,
+            }))
+        }
+    }
+}
+
+/// Reducing along a broadcasted dim: a sum becomes a multiply by the dim's size, a max is just the input
+fn reduce_of_expand(egraph: &mut EGraph, node: &ENode, subst: &Subst) -> Option<Id> {
+    let mut view = *egraph.view(subst.view("v")?);
+    let (EOp::SumReduce(axis) | EOp::MaxReduce(axis)) = node.op else {
+        return None;
+    };
+    let index = *view.indexes.get(axis)?;
+    if !view.fake[index] || view.is_padded() || view.is_sliced() {
+        return None;
+    }
+    let size = view.remove_dim(axis);
+    let x = subst.class("x")?;
+    let reduced = egraph.add_view(view);
+    if let EOp::MaxReduce(_) = node.op {
+        return Some(egraph.add(ENode {
+            op: EOp::Contiguous,
+            children: vec![(x, reduced)],
+        }));
+    }
+    let size = egraph.add(ENode {
+        op: match size.to_usize() {
+            Some(n) => EOp::Constant((n as f32).to_bits()),
+            None => EOp::Expr(size.into()),
+        },
+        children: vec![],
+    });
+    let fake = egraph.add_view(ShapeTracker::fake(&view_shape(&view)?));
+    Some(egraph.add(ENode {
+        op: EOp::Mul,
+        children: vec![(x, reduced), (size, fake)],
+    }))
+}
+
+/// The rules the [`super::EqualitySaturation`] compiler uses by default. They keep infinities and
+/// NaNs as they are, so they're safe on attention masks and the like
+pub fn default_rules() -> Vec<Rule> {
+    use OpKind::*;
+    let x = || var("x");
+    vec![
+        Rule::new(
+            "add-commute",
+            add(var("a").view("va"), var("b").view("vb")),
+            add(var("b").view("vb"), var("a").view("va")),
+        ),
+        Rule::new(
+            "mul-commute",
+            mul(var("a").view("va"), var("b").view("vb")),
+            mul(var("b").view("vb"), var("a").view("va")),
+        ),
+        Rule::new(
+            "add-associate",
+            add(
+                add(var("a").view("va"), var("b").view("vb")).id(),
+                var("c").view("vc"),
+            ),
+            add(
+                var("a").view("va"),
+                add(var("b").view("vb"), var("c").view("vc")).id(),
+            ),
+        ),
+        Rule::new(
+            "mul-associate",
+            mul(
+                mul(var("a").view("va"), var("b").view("vb")).id(),
+                var("c").view("vc"),
+            ),
+            mul(
+                var("a").view("va"),
+                mul(var("b").view("vb"), var("c").view("vc")).id(),
+            ),
+        ),
+        Rule::new(
+            "add-zero",
+            add(x().view("v"), lit(0.).view("_c")),
+            unary(Contiguous, x().view("v")),
+        ),
+        Rule::new(
+            "mul-one",
+            mul(x().view("v"), lit(1.).view("_c")),
+            unary(Contiguous, x().view("v")),
+        ),
+        Rule::new(
+            "recip-recip",
+            unary(Recip, unary(Recip, x().view("v")).id()),
+            unary(Contiguous, x().view("v")),
+        ),
+        Rule::new("contiguous-identity", unary(Contiguous, x().id()), x()),
+        Rule::new(
+            "fold-add",
+            add(
+                add(x().view("v"), constant("a").view("va")).id(),
+                constant("b").view("_b"),
+            ),
+            add(
+                x().view("v"),
+                Pattern::Fold(|a, b| a + b, "a", "b").view("va"),
+            ),
+        ),
+        Rule::new(
+            "fold-mul",
+            mul(
+                mul(x().view("v"), constant("a").view("va")).id(),
+                constant("b").view("_b"),
+            ),
+            mul(
+                x().view("v"),
+                Pattern::Fold(|a, b| a * b, "a", "b").view("va"),
+            ),
+        ),
+        Rule::new(
+            "const-add",
+            add(constant("a").view("va"), constant("b").view("_b")),
+            unary(Contiguous, Pattern::Fold(|a, b| a + b, "a", "b").view("va")),
+        ),
+        Rule::new(
+            "const-mul",
+            mul(constant("a").view("va"), constant("b").view("_b")),
+            unary(Contiguous, Pattern::Fold(|a, b| a * b, "a", "b").view("va")),
+        ),
+        Rule::new(
+            "add-broadcast-constant",
+            add(
+                x().view("v"),
+                unary(Contiguous, constant("c").view("f")).id(),
+            ),
+            add(x().view("v"), constant("c").view("f")),
+        ),
+        Rule::new(
+            "mul-broadcast-constant",
+            mul(
+                x().view("v"),
+                unary(Contiguous, constant("c").view("f")).id(),
+            ),
+            mul(x().view("v"), constant("c").view("f")),
+        ),
+        Rule {
+            name: "sum-of-expand",
+            lhs: unary(SumReduce, x().view("v")),
+            rhs: Rewrite::Custom(reduce_of_expand),
+        },
+        Rule {
+            name: "max-of-expand",
+            lhs: unary(MaxReduce, x().view("v")),
+            rhs: Rewrite::Custom(reduce_of_expand),
+        },
+    ]
+}
+
+/// Rules that only hold for finite values, which [`super::EqualitySaturation::fast_math`] adds to
+/// the defaults. `x * 0`, `x - x` and `x / x` become constants even when `x` is infinite or NaN,
+/// and `exp2(log2(x))` and `log2(exp2(x))` become `x` even where the inner op gives NaN or overflows
+pub fn fast_math_rules() -> Vec<Rule> {
+    use OpKind::*;
+    let x = || var("x");
+    vec![
+        Rule::new(
+            "mul-zero",
+            mul(x().view("v"), lit(0.).view("_c")),
+            unary(Contiguous, lit(0.).fake_like("v")),
+        ),
+        Rule::new(
+            "sub-self",
+            add(x().view("v"), mul(x().view("v"), lit(-1.).view("_c")).id()),
+            unary(Contiguous, lit(0.).fake_like("v")),
+        ),
+        Rule::new(
+            "div-self",
+            mul(x().view("v"), unary(Recip, x().view("v")).id()),
+            unary(Contiguous, lit(1.).fake_like("v")),
+        ),
+        Rule::new(
+            "exp2-log2",
+            unary(Exp2, unary(Log2, x().view("v")).id()),
+            unary(Contiguous, x().view("v")),
+        ),
+        Rule::new(
+            "log2-exp2",
+            unary(Log2, unary(Exp2, x().view("v")).id()),
+            unary(Contiguous, x().view("v")),
+        ),
+    ]
+}
@@ -0,0 +1,79 @@
+use std::fmt::Display;
+
+use crate::prelude::*;
+
+/// An error encountered while compiling or executing a graph
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LuminalError {
+    /// An input tensor was never given a value
+    MissingInput { node: NodeIndex, name: String },
+    /// A dynamic dimension needed by a node was never set
+    UnresolvedDynDim {
+        node: NodeIndex,
+        name: String,
+        dim: char,
+    },
+    /// An input tensor's data doesn't match the shape it's used with
+    ShapeMismatch {
+        node: NodeIndex,
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    /// The graph contains a cycle going through this node
+    Cycle { node: NodeIndex, name: String },
+    /// An operator failed while running, either by panicking or by not producing an output a later node needs
+    OpFailure {
+        node: NodeIndex,
+        name: String,
+        message: String,
+    },
+}
+
+impl LuminalError {
+    /// The node this error originated from
+    pub fn node(&self) -> NodeIndex {
+        match self {
+            LuminalError::MissingInput { node, .. }
+            | LuminalError::UnresolvedDynDim { node, .. }
+            | LuminalError::ShapeMismatch { node, .. }
+            | LuminalError::Cycle { node, .. }
+            | LuminalError::OpFailure { node, .. } => *node,
+        }
+    }
+}
+
+impl Display for LuminalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LuminalError::MissingInput { node, name } => {
+                write!(f, "No value set for input {name} ({})", node.index())
+            }
+            LuminalError::UnresolvedDynDim { node, name, dim } => write!(
+                f,
+                "Dynamic dimension '{dim}' needed by {name} ({}) is not set",
+                node.index()
+            ),
+            LuminalError::ShapeMismatch {
+                node,
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{name} ({}) has {found} elements, but its shape needs {expected}",
+                node.index()
+            ),
+            LuminalError::Cycle { node, name } => {
+                write!(f, "Graph has a cycle through {name} ({})", node.index())
+            }
+            LuminalError::OpFailure {
+                node,
+                name,
+                message,
+            } => write!(f, "{name} ({}) failed: {message}", node.index()),
+        }
+    }
+}
+
+impl std::error::Error for LuminalError {}
@@ -1,4 +1,8 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+};
 
 use itertools::Itertools;
 use petgraph::{
@@ -8,8 +12,14 @@ use petgraph::{
     Direction,
 };
 
+use rustc_hash::{FxHashMap, FxHashSet};
+
 use crate::{
-    op::{Add, Constant, ConstantValue, Function, MaxReduce, Mul, Operator, Recip, SumReduce},
+    dispatch_dtype,
+    op::{
+        Add, Cast, Constant, ConstantValue, Contiguous, Exp2, Function, InputTensor, LessThan,
+        Log2, MaxReduce, Mod, Mul, Operator, Recip, Sin, Sqrt, SumReduce,
+    },
     prelude::*,
 };
 
@@ -17,7 +27,9 @@ use crate::{
 pub type GenericCompiler = (
     //RemoveSingleReductions,
     RemoveUnusedNodes,
+    ConstantFolding,
     ArithmeticElimination,
+    EqualitySaturation,
     CSE,
 );
 
@@ -35,13 +47,9 @@ impl Compiler for CSE {
             eliminated = false;
             let mut srcs_set: HashMap<Vec<NodeIndex>, Vec<NodeIndex>> = HashMap::new();
             for node in graph.graph.node_indices().collect_vec() {
-                if graph
-                    .graph
-                    .node_weight(node)
-                    .unwrap()
-                    .as_any()
-                    .is::<Function>()
-                {
+                let op = graph.graph.node_weight(node).unwrap().as_any();
+                // Loads and folded constants have no sources, so they'd all look the same
+                if op.is::<Function>() || op.is::<FoldedConstant>() {
                     continue;
                 }
                 let srcs = graph
@@ -176,6 +184,210 @@ impl Compiler for RemoveUnusedNodes {
     }
 }
 
+/// The outputs of a subgraph [`ConstantFolding`] evaluated at compile time
+#[derive(Clone)]
+pub struct FoldedConstant(pub Vec<Tensor>);
+
+impl Debug for FoldedConstant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FoldedConstant")
+    }
+}
+
+impl Operator for FoldedConstant {
+    fn process(&mut self, _: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        self.0.clone()
+    }
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "dtype" {
+            return Some(Box::new(self.0.first()?.dtype()?));
+        }
+        None
+    }
+}
+
+/// The outputs held by an op, if it's a [`FoldedConstant`]
+pub fn folded_tensors(op: &dyn Operator) -> Option<&[Tensor]> {
+    op.as_any()
+        .downcast_ref::<FoldedConstant>()
+        .map(|f| f.0.as_slice())
+}
+
+/// Whether an op is a [`FoldedConstant`] counting up from zero, which is what `arange` folds to
+pub fn is_folded_arange(op: &dyn Operator) -> bool {
+    folded_tensors(op).is_some_and(|t| match t {
+        [t] => t
+            .as_slice::<f32>()
+            .is_some_and(|d| d.iter().enumerate().all(|(i, v)| *v == i as f32)),
+        _ => false,
+    })
+}
+
+/// Whether an op is a primitive op, which can always be evaluated on CPU tensors
+fn is_primitive(op: &dyn Operator) -> bool {
+    let op = op.as_any();
+    op.is::<Contiguous>()
+        || op.is::<Cast>()
+        || op.is::<Log2>()
+        || op.is::<Exp2>()
+        || op.is::<Sin>()
+        || op.is::<Recip>()
+        || op.is::<Sqrt>()
+        || op.is::<Add>()
+        || op.is::<Mul>()
+        || op.is::<Mod>()
+        || op.is::<LessThan>()
+        || op.is::<SumReduce>()
+        || op.is::<MaxReduce>()
+}
+
+/// **Evaluates subgraphs that only depend on constants and weights at compile time**, replacing each of their outputs with a single loaded tensor.
+///
+/// Only subgraphs with static shapes are folded, unless their dynamic dimensions are given with [`ConstantFolding::with_dyn_map`]
+#[derive(Debug, Default)]
+pub struct ConstantFolding {
+    weights: FxHashSet<NodeIndex>,
+    dyn_map: FxHashMap<char, usize>,
+}
+
+impl ConstantFolding {
+    /// Also fold subgraphs depending on these weights. Their values must be set before compiling and can't change afterwards
+    pub fn new<T: ToIds>(weights: T) -> Self {
+        Self {
+            weights: weights.to_ids().into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Fold subgraphs depending on these dynamic dimensions, assuming they'll always have these values
+    pub fn with_dyn_map(mut self, dyn_map: FxHashMap<char, usize>) -> Self {
+        self.dyn_map = dyn_map;
+        self
+    }
+
+    /// Evaluate a node given its inputs' values, if it's foldable. Only primitive ops, constants and
+    /// loads of given weights are
+    fn evaluate(
+        &self,
+        graph: &mut Graph,
+        node: NodeIndex,
+        values: &FxHashMap<NodeIndex, Vec<Tensor>>,
+    ) -> Option<Vec<Tensor>> {
+        let sources = graph.get_sources(node);
+        let op = graph.graph.node_weight(node).unwrap();
+        if let Some(Constant(value, _, dtype)) = op.as_any().downcast_ref::<Constant>() {
+            // Evaluated here so expressions use the given dyn dims
+            let value = match value {
+                ConstantValue::Float(f) => *f,
+                ConstantValue::Expression(e) => e.exec(&self.dyn_map)? as f32,
+            };
+            return Some(vec![
+                dispatch_dtype!(*dtype, T => Tensor::new(vec![T::from_f32(value)])),
+            ]);
+        }
+        if let Some(tensors) = folded_tensors(op.as_ref()) {
+            return Some(tensors.to_vec());
+        }
+        if let Some(function) = op.as_any().downcast_ref::<Function>() {
+            // Functions with inputs may have side effects, and other inputs can change between runs
+            if !sources.is_empty() || !self.weights.contains(&node) {
+                return None;
+            }
+            if let Some(tensor) = graph.get_tensor_ref(node, 0) {
+                return Some(vec![tensor.clone()]);
+            }
+            // Inputs without a value don't produce anything
+            return Some((function.1)(vec![])).filter(|t| !t.is_empty());
+        }
+        if sources.is_empty() || !is_primitive(op.as_ref()) {
+            return None;
+        }
+        let mut inputs = vec![];
+        for (src, output, mut shape) in sources {
+            let tensor = values.get(&src)?.get(output as usize)?;
+            shape
+                .try_resolve_global_dyn_dims_stack(&self.dyn_map, &mut vec![])
+                .ok()?;
+            inputs.push((InputTensor::Borrowed(tensor), shape));
+        }
+        Some(graph.graph.node_weight_mut(node).unwrap().process(inputs))
+    }
+}
+
+impl Compiler for ConstantFolding {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        let order = toposort(&graph.graph, None).unwrap();
+        let mut values = FxHashMap::default();
+        for node in &order {
+            if graph
+                .graph
+                .edges_directed(*node, Direction::Incoming)
+                .chain(graph.graph.edges_directed(*node, Direction::Outgoing))
+                .any(|e| e.weight().is_schedule())
+            {
+                continue;
+            }
+            if let Some(tensors) = self.evaluate(graph, *node, &values) {
+                values.insert(*node, tensors);
+            }
+        }
+
+        // Replace outputs of folded subgraphs in place, so their ids stay the same
+        let tracked = ids
+            .to_ids_mut()
+            .into_iter()
+            .map(|i| *i)
+            .collect::<FxHashSet<_>>();
+        let folded = values
+            .keys()
+            .copied()
+            .filter(|n| {
+                graph
+                    .graph
+                    .edges_directed(*n, Direction::Incoming)
+                    .next()
+                    .is_some()
+                    && (graph.no_delete.contains(n)
+                        || graph.to_retrieve.contains_key(n)
+                        || tracked.contains(n)
+                        || graph
+                            .graph
+                            .neighbors_directed(*n, Direction::Outgoing)
+                            .any(|c| !values.contains_key(&c)))
+            })
+            .collect::<FxHashSet<_>>();
+        for node in &folded {
+            graph.graph[*node] = Box::new(FoldedConstant(values.remove(node).unwrap()));
+            for edge in graph
+                .graph
+                .edges_directed(*node, Direction::Incoming)
+                .map(|e| e.id())
+                .collect::<Vec<_>>()
+            {
+                graph.graph.remove_edge(edge);
+            }
+        }
+
+        // Remove whatever was only used by the folded subgraphs
+        for node in order.into_iter().rev() {
+            if values.contains_key(&node)
+                && !folded.contains(&node)
+                && !self.weights.contains(&node)
+                && !graph.no_delete.contains(&node)
+                && !tracked.contains(&node)
+                && graph
+                    .graph
+                    .edges_directed(node, Direction::Outgoing)
+                    .next()
+                    .is_none()
+            {
+                graph.graph.remove_node(node);
+            }
+        }
+    }
+}
+
 /// Enforce the graph gets ran in strictly depth-first order
 #[derive(Default, Debug)]
 pub struct DepthFirst;
@@ -284,6 +496,7 @@ impl Compiler for ArithmeticElimination {
                     .collect::<Vec<_>>()
                 {
                     if let Some(weight) = weight.as_data() {
+                        let dtype = graph.node_dtype(inp);
                         graph.graph.add_edge(
                             inp,
                             target,
@@ -291,6 +504,7 @@ impl Compiler for ArithmeticElimination {
                                 input_order: weight.0,
                                 output_order: weight.1,
                                 shape: input_shape,
+                                dtype,
                             },
                         );
                     }
@@ -349,6 +563,7 @@ impl Compiler for ArithmeticElimination {
                     .collect::<Vec<_>>()
                 {
                     if let Some(weight) = weight.as_data() {
+                        let dtype = graph.node_dtype(inp);
                         graph.graph.add_edge(
                             inp,
                             target,
@@ -356,6 +571,7 @@ impl Compiler for ArithmeticElimination {
                                 input_order: weight.0,
                                 output_order: weight.1,
                                 shape: input_shape,
+                                dtype,
                             },
                         );
                     }
@@ -406,6 +622,7 @@ impl Compiler for ArithmeticElimination {
                     .collect::<Vec<_>>()
                 {
                     if let Some(weight) = weight.as_data() {
+                        let dtype = graph.node_dtype(inp);
                         graph.graph.add_edge(
                             inp,
                             target,
@@ -413,6 +630,7 @@ impl Compiler for ArithmeticElimination {
                                 input_order: weight.0,
                                 output_order: weight.1,
                                 shape: input_shape,
+                                dtype,
                             },
                         );
                     }
@@ -431,7 +649,7 @@ impl Compiler for ArithmeticElimination {
 fn constant(num: f32) -> SelectGraph {
     let mut n = op::<Constant>();
     n.check(move |o, _| {
-        if let Some(Constant(ConstantValue::Float(f), _)) = o.as_any().downcast_ref::<Constant>() {
+        if let Some(Constant(ConstantValue::Float(f), ..)) = o.as_any().downcast_ref::<Constant>() {
             *f == num
         } else {
             false
@@ -2,8 +2,12 @@
 
 use crate::prelude::*;
 use std::{
+    any::Any,
+    collections::VecDeque,
     io::Write,
     ops::{Deref, DerefMut},
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Condvar, Mutex, MutexGuard},
     time::Duration,
 };
 
@@ -14,6 +18,25 @@ use petgraph::{stable_graph::StableGraph, visit::EdgeRef, Direction};
 use rustc_hash::{FxHashMap, FxHashSet};
 
 pub type MainGraph = StableGraph<Box<dyn Operator>, Dependency>;
+/// A node in the schedule, along with its sources in input order
+pub type ScheduledNode = (NodeIndex, Vec<(NodeIndex, u8, ShapeTracker)>);
+
+/// What executing panics with when an input tensor was never given a value
+pub(crate) const MISSING_INPUT_MESSAGE: &str = "You must set a value for this tensor!";
+
+/// A loader for an input that hasn't been given a value yet. It produces no outputs, so
+/// executing can tell the input is missing without running anything that reads it
+pub(crate) fn unset_input(name: String) -> Function {
+    Function(name, Box::new(|_| vec![]))
+}
+
+/// Panic because a node's output was never produced, which only happens when it's an unset input
+fn missing_source((node, output): (NodeIndex, u8)) -> ! {
+    panic!(
+        "{MISSING_INPUT_MESSAGE} (output {output} of node {})",
+        node.index()
+    )
+}
 
 /// A Luminal compute graph.
 ///
@@ -31,11 +54,22 @@ pub struct Graph {
     pub no_delete: FxHashSet<NodeIndex>,
     /// Tensors marked in this set need to be retrieved later (mostly for optimizers to insert copy back calls, the graph itself doesn't treat these differently)
     pub to_retrieve: FxHashMap<NodeIndex, (u8, ShapeTracker)>,
+    /// Element types declared for input tensors. Everything else is inferred from the ops
+    pub(crate) dtypes: FxHashMap<NodeIndex, DType>,
     /// A list of current node to run, source nodes, and view nodes to delete after execution.
     #[allow(clippy::type_complexity)]
-    pub(crate) linearized_graph: Option<Vec<(NodeIndex, Vec<(NodeIndex, u8, ShapeTracker)>)>>,
+    pub(crate) linearized_graph: Option<Vec<ScheduledNode>>,
     /// Cached consumers (for execution only)
     consumers_map: Option<FxHashMap<(NodeIndex, u8), usize>>,
+    /// Cached schedules and consumers for executing only part of the graph, keyed by the sorted target nodes
+    #[allow(clippy::type_complexity)]
+    pub(crate) partial_schedules: FxHashMap<
+        Vec<NodeIndex>,
+        (
+            Vec<(NodeIndex, Vec<(NodeIndex, u8, ShapeTracker)>)>,
+            FxHashMap<(NodeIndex, u8), usize>,
+        ),
+    >,
 }
 
 /// A dependency between two nodes
@@ -47,6 +81,8 @@ pub enum Dependency {
         input_order: u8,
         output_order: u8,
         shape: ShapeTracker,
+        /// The element type of the tensor being transferred
+        dtype: DType,
     },
     /// Explicit dependency for ordering. No tensors are transferred through this dependency
     Schedule,
@@ -59,6 +95,7 @@ impl Dependency {
             input_order,
             output_order,
             shape,
+            ..
         } = self
         {
             Some((input_order, output_order, shape))
@@ -67,6 +104,15 @@ impl Dependency {
         }
     }
 
+    /// The element type transferred through this dependency, if it's a data dependency
+    pub fn dtype(&self) -> Option<DType> {
+        if let Self::Data { dtype, .. } = self {
+            Some(*dtype)
+        } else {
+            None
+        }
+    }
+
     /// Is this a schedule dependency?
     pub fn is_schedule(&self) -> bool {
         matches!(self, Self::Schedule)
@@ -121,10 +167,9 @@ impl Graph {
     /// Create a new tensor with shape S and a name. This name will show up on the graph when displayed
     pub fn named_tensor<S: Shape>(&mut self, name: &str) -> GraphTensor<S> {
         GraphTensor {
-            id: self.graph.add_node(Box::new(Function(
-                format!("{name} Load"),
-                Box::new(|_| panic!("You must set a value for this tensor!")),
-            ))),
+            id: self
+                .graph
+                .add_node(Box::new(unset_input(format!("{name} Load")))),
             graph_ref: self,
             shape: S::to_tracker(),
             _phantom: Default::default(),
@@ -139,16 +184,82 @@ impl Graph {
         output
     }
 
+    /// Compile the graph using the given compiler, returning an error if the graph can't be scheduled
+    pub fn try_compile<T: ToIdsMut, C: Compiler>(
+        &mut self,
+        compiler: C,
+        remap: T,
+    ) -> Result<C::Output, LuminalError> {
+        self.try_toposort()?;
+        let output = compiler.compile(self, remap);
+        self.try_toposort()?;
+        self.reset();
+        Ok(output)
+    }
+
+    /// The nodes in the order they run
+    pub fn schedule(&mut self) -> &[ScheduledNode] {
+        if self.linearized_graph.is_none() {
+            self.toposort();
+        }
+        self.linearized_graph.as_deref().unwrap()
+    }
+
     /// Refresh the internally sorted graph
     pub(crate) fn toposort(&mut self) {
+        self.try_toposort().unwrap();
+    }
+
+    /// Refresh the internally sorted graph, returning an error if the graph has a cycle
+    pub(crate) fn try_toposort(&mut self) -> Result<(), LuminalError> {
         self.linearized_graph = Some(
             petgraph::algo::toposort(&self.graph, None)
-                .unwrap()
+                .map_err(|c| LuminalError::Cycle {
+                    node: c.node_id(),
+                    name: format!("{:?}", self.graph.node_weight(c.node_id()).unwrap()),
+                })?
                 .into_iter()
                 .map(|node| (node, self.get_sources(node)))
                 .collect(),
         );
 
+        // Refresh the element types flowing through each edge, now that inputs come before their consumers
+        let order = self
+            .linearized_graph
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|(n, _)| *n)
+            .collect::<Vec<_>>();
+        let constants = order
+            .iter()
+            .copied()
+            .filter(|n| self.check_node_type::<Constant>(*n))
+            .collect::<Vec<_>>();
+        for node in &constants {
+            self.set_constant_dtype(*node, DType::F32);
+        }
+        for node in order {
+            let dtype = self.node_dtype(node);
+            self.set_output_dtype(node, dtype);
+        }
+        // Constants are weakly typed, so they're produced as the type of what they're combined with
+        for node in constants {
+            let dtype = self
+                .graph
+                .neighbors_directed(node, Direction::Outgoing)
+                .collect::<Vec<_>>()
+                .into_iter()
+                .map(|c| self.node_dtype(c))
+                .reduce(DType::promote)
+                .unwrap_or_default();
+            self.set_constant_dtype(node, dtype);
+            self.set_output_dtype(node, dtype);
+        }
+
+        // Partial schedules were derived from the old order
+        self.partial_schedules.clear();
+
         // Refresh the internal remaining consumers map
         self.consumers_map = Some(
             self.graph
@@ -164,6 +275,111 @@ impl Graph {
                 })
                 .collect(),
         );
+        Ok(())
+    }
+
+    fn set_constant_dtype(&mut self, node: NodeIndex, dtype: DType) {
+        if let Some(constant) = self
+            .graph
+            .node_weight_mut(node)
+            .unwrap()
+            .as_any_mut()
+            .downcast_mut::<Constant>()
+        {
+            constant.2 = dtype;
+        }
+    }
+
+    /// Set the element type of every edge leaving a node
+    fn set_output_dtype(&mut self, node: NodeIndex, dtype: DType) {
+        let outgoing = self
+            .graph
+            .edges_directed(node, Direction::Outgoing)
+            .map(|e| e.id())
+            .collect::<Vec<_>>();
+        for edge in outgoing {
+            if let Some(Dependency::Data { dtype: d, .. }) = self.graph.edge_weight_mut(edge) {
+                *d = dtype;
+            }
+        }
+    }
+
+    /// The element type a node outputs. Inputs use their declared type, ops can report theirs through the "dtype" custom key given their input types, and everything else promotes the types of its inputs. Constants are weakly typed, and only change the type of what they're combined with when a fractional constant meets an integer tensor. Adding, multiplying and summing bools gives i32
+    pub fn node_dtype(&mut self, node: NodeIndex) -> DType {
+        if let (Some(dtype), true) = (
+            self.dtypes.get(&node),
+            self.graph
+                .node_weight(node)
+                .unwrap()
+                .as_any()
+                .is::<Function>(),
+        ) {
+            return *dtype;
+        }
+        let inputs = self
+            .graph
+            .edges_directed(node, Direction::Incoming)
+            .filter_map(|e| {
+                e.weight()
+                    .as_data()
+                    .map(|(i, _, _)| i)
+                    .zip(e.weight().dtype())
+            })
+            .sorted_by_key(|(i, _)| *i)
+            .map(|(_, d)| d)
+            .collect::<Vec<_>>();
+        if let Some(dtype) = self.node_custom::<DType, _>(node, "dtype", inputs.clone()) {
+            return dtype;
+        }
+        // Whether each input is a constant, and if so whether it's a whole number
+        let weak = self
+            .graph
+            .edges_directed(node, Direction::Incoming)
+            .filter_map(|e| {
+                let (i, _, _) = e.weight().as_data()?;
+                let constant = self
+                    .graph
+                    .node_weight(e.source())
+                    .unwrap()
+                    .as_any()
+                    .downcast_ref::<Constant>();
+                Some((i, constant.map(|c| c.is_integral())))
+            })
+            .sorted_by_key(|(i, _)| *i)
+            .map(|(_, w)| w)
+            .collect::<Vec<_>>();
+        let strong = inputs
+            .iter()
+            .zip(&weak)
+            .filter(|(_, w)| w.is_none())
+            .map(|(d, _)| *d)
+            .reduce(DType::promote);
+        let dtype = match strong {
+            Some(dtype) => weak
+                .into_iter()
+                .flatten()
+                .fold(dtype, |d, integral| d.promote_weak(integral)),
+            None => inputs
+                .into_iter()
+                .reduce(DType::promote)
+                .unwrap_or_default(),
+        };
+        if self.check_node_type::<Add>(node)
+            || self.check_node_type::<Mul>(node)
+            || self.check_node_type::<SumReduce>(node)
+        {
+            dtype.arithmetic()
+        } else {
+            dtype
+        }
+    }
+
+    /// Declare the element type of an input tensor
+    pub fn set_dtype(&mut self, node: NodeIndex, dtype: DType) {
+        if self.dtypes.insert(node, dtype).unwrap_or_default() != dtype {
+            // Edge types get refreshed on the next toposort
+            self.linearized_graph = None;
+        }
     }
 
     /// Swap the tensors with these ids
@@ -195,33 +411,226 @@ impl Graph {
         if self.linearized_graph.is_none() {
             self.toposort();
         }
-        let mut consumers = self.consumers_map.as_ref().unwrap().clone();
-        let mut dim_stack = Vec::new();
+        let (mut executor, schedule) = self.sequential(None);
+        for scheduled in schedule {
+            executor.step(scheduled, |op, srcs| op.process(srcs));
+        }
+        self.reset();
+    }
 
-        for (node, src_ids) in self.linearized_graph.as_ref().unwrap() {
-            if self.tensors.contains_key(&(*node, 0)) {
-                continue;
-            }
+    /// Execute only the nodes needed to compute the target nodes. The partial schedule is cached, so running the same targets again doesn't recompute it.
+    ///
+    /// Targets should be retrieved or kept to read their values afterwards.
+    pub fn execute_for<T: ToIds>(&mut self, targets: T) {
+        if self.linearized_graph.is_none() {
+            self.toposort();
+        }
+        let mut targets = targets.to_ids();
+        targets.sort();
+        targets.dedup();
+        if !self.partial_schedules.contains_key(&targets) {
+            let schedule = self.partial_schedule(&targets);
+            self.partial_schedules.insert(targets.clone(), schedule);
+        }
+        let (mut executor, schedule) = self.sequential(Some(&targets));
+        for scheduled in schedule {
+            executor.step(scheduled, |op, srcs| op.process(srcs));
+        }
+        self.reset();
+    }
 
-            let mut srcs =
-                get_source_tensors(&self.no_delete, &mut self.tensors, src_ids, &consumers);
+    /// Build the schedule of all nodes upstream of the targets, along with how many times each of their outputs gets consumed within it
+    #[allow(clippy::type_complexity)]
+    fn partial_schedule(
+        &self,
+        targets: &[NodeIndex],
+    ) -> (
+        Vec<(NodeIndex, Vec<(NodeIndex, u8, ShapeTracker)>)>,
+        FxHashMap<(NodeIndex, u8), usize>,
+    ) {
+        // Walk back from the targets through both data and schedule dependencies
+        let mut needed = FxHashSet::default();
+        let mut stack = targets.to_vec();
+        while let Some(node) = stack.pop() {
+            if needed.insert(node) {
+                stack.extend(self.graph.neighbors_directed(node, Direction::Incoming));
+            }
+        }
+        let schedule = self
+            .linearized_graph
+            .as_ref()
+            .unwrap()
+            .iter()
+            .filter(|(n, _)| needed.contains(n))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut consumers = FxHashMap::default();
+        for (id, ind, _) in schedule.iter().flat_map(|(_, srcs)| srcs) {
+            *consumers.entry((*id, *ind)).or_default() += 1;
+        }
+        (schedule, consumers)
+    }
 
-            // Substitute in the dyn dims
-            for (_, st) in srcs.iter_mut() {
-                st.resolve_global_dyn_dims_stack(&self.dyn_map, &mut dim_stack);
+    /// Split off an executor to run the partial schedule of the targets, or the whole schedule if there are none
+    fn sequential(&mut self, targets: Option<&[NodeIndex]>) -> (Executor<'_>, &[ScheduledNode]) {
+        let (schedule, consumers) = match targets {
+            Some(targets) => {
+                let (schedule, consumers) = &self.partial_schedules[targets];
+                (schedule.as_slice(), consumers.clone())
             }
+            None => (
+                self.linearized_graph.as_deref().unwrap(),
+                self.consumers_map.clone().unwrap(),
+            ),
+        };
+        let executor = Executor {
+            graph: &mut self.graph,
+            tensors: &mut self.tensors,
+            no_delete: &self.no_delete,
+            dyn_map: &self.dyn_map,
+            consumers,
+            dim_stack: Vec::new(),
+        };
+        (executor, schedule)
+    }
 
-            // Execute
-            let tensors = self.graph.node_weight_mut(*node).unwrap().process(srcs);
-            for (i, tensor) in tensors.into_iter().enumerate() {
-                self.tensors.insert((*node, i as u8), tensor);
+    /// Execute the graph, returning an error instead of panicking if something goes wrong.
+    ///
+    /// Ops that panic are reported as [`LuminalError::OpFailure`]. The panic is still printed by the panic hook, and the failed op may be left partway through updating any state it keeps.
+    ///
+    /// Intermediate tensors are cleared either way, so the graph can be ran again after fixing the error.
+    pub fn try_execute(&mut self) -> Result<(), LuminalError> {
+        if self.linearized_graph.is_none() {
+            self.try_toposort()?;
+        }
+        let result = self.try_execute_inner();
+        self.reset();
+        result
+    }
+
+    fn try_execute_inner(&mut self) -> Result<(), LuminalError> {
+        let (mut executor, schedule) = self.sequential(None);
+        for scheduled in schedule {
+            if !executor.is_done(scheduled.0) {
+                executor.check_inputs(scheduled)?;
+                let mut failure = None;
+                executor.step(scheduled, |op, srcs| {
+                    catch_unwind(AssertUnwindSafe(|| op.process(srcs))).unwrap_or_else(|panic| {
+                        failure = Some((format!("{op:?}"), panic_message(panic)));
+                        vec![]
+                    })
+                });
+                if let Some((name, message)) = failure {
+                    return Err(LuminalError::OpFailure {
+                        node: scheduled.0,
+                        name,
+                        message,
+                    });
+                }
             }
+        }
+        Ok(())
+    }
 
-            // Bookkeep remaining consumers
-            for (id, ind, _) in src_ids {
-                *consumers.get_mut(&(*id, *ind)).unwrap() -= 1;
+    /// Execute the graph, running independent nodes in parallel on all available cores.
+    ///
+    /// # Safety
+    /// Same as [`Graph::execute_parallel_threads`].
+    pub unsafe fn execute_parallel(&mut self) {
+        self.execute_parallel_threads(
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+        );
+    }
+
+    /// Execute the graph, running independent nodes in parallel on a pool of `threads` workers.
+    ///
+    /// # Safety
+    /// Operators run on worker threads at the same time as each other, and the tensors they produce
+    /// are moved between threads. Every operator in the graph and every tensor it outputs must be
+    /// safe to send to another thread and to use concurrently with the other operators, so they
+    /// can't hold unsynchronized shared state such as `Rc` or `RefCell`.
+    pub unsafe fn execute_parallel_threads(&mut self, threads: usize) {
+        if self.linearized_graph.is_none() {
+            self.toposort();
+        }
+        let linearized = self.linearized_graph.as_ref().unwrap();
+
+        // A node is ready once all nodes it depends on (through data or schedule edges) are done
+        let mut waiting_on = FxHashMap::default();
+        let mut ready = VecDeque::new();
+        for (node, _) in linearized {
+            let deps = self
+                .graph
+                .edges_directed(*node, Direction::Incoming)
+                .count();
+            if deps == 0 {
+                ready.push_back(*node);
+            } else {
+                waiting_on.insert(*node, deps);
             }
         }
+        let dependents = linearized
+            .iter()
+            .map(|(node, _)| {
+                (
+                    *node,
+                    self.graph
+                        .edges_directed(*node, Direction::Outgoing)
+                        .map(|e| e.target())
+                        .collect_vec(),
+                )
+            })
+            .collect();
+        let sources = linearized
+            .iter()
+            .map(|(node, srcs)| (*node, srcs.as_slice()))
+            .collect();
+        let ops = linearized
+            .iter()
+            .map(|(node, _)| {
+                (
+                    *node,
+                    self.graph.node_weight_mut(*node).unwrap() as *mut Box<dyn Operator>,
+                )
+            })
+            .collect();
+
+        let context = ParallelContext {
+            state: Mutex::new(ParallelState {
+                ready,
+                waiting_on,
+                remaining: linearized.len(),
+                aborted: false,
+                consumers: self.consumers_map.as_ref().unwrap().clone(),
+                // Boxed so borrowed inputs stay put while other workers insert tensors
+                tensors: std::mem::take(&mut self.tensors)
+                    .into_iter()
+                    .map(|(k, v)| (k, Box::new(v)))
+                    .collect(),
+            }),
+            wake: Condvar::new(),
+            ops,
+            sources,
+            dependents,
+            no_delete: &self.no_delete,
+            dyn_map: &self.dyn_map,
+        };
+        std::thread::scope(|s| {
+            for _ in 0..threads.max(1).min(linearized.len().max(1)) {
+                s.spawn(|| parallel_worker(&context));
+            }
+        });
+
+        self.tensors = context
+            .state
+            .into_inner()
+            .unwrap()
+            .tensors
+            .into_iter()
+            .map(|(k, v)| (k, *v))
+            .collect();
         self.reset();
     }
 
@@ -240,7 +649,11 @@ impl Graph {
                 .iter()
                 .map(|(id, ind, st)| {
                     (
-                        InputTensor::Borrowed(self.tensors.get(&(*id, *ind)).unwrap()),
+                        InputTensor::Borrowed(
+                            self.tensors
+                                .get(&(*id, *ind))
+                                .unwrap_or_else(|| missing_source((*id, *ind))),
+                        ),
                         *st,
                     )
                 })
@@ -259,6 +672,39 @@ impl Graph {
         }
     }
 
+    /// Execute the graph, recording timings, input shapes and output sizes of each op
+    pub fn execute_profiled(&mut self) -> ExecutionProfile {
+        if self.linearized_graph.is_none() {
+            self.toposort();
+        }
+        let (mut executor, schedule) = self.sequential(None);
+        let mut profile = ExecutionProfile::default();
+
+        let start = std::time::Instant::now();
+        for scheduled in schedule {
+            executor.step(scheduled, |op, srcs| {
+                let input_shapes = srcs.iter().map(|(_, st)| st.shape_usize()).collect();
+                let name = format!("{op:?}");
+                let op_start = start.elapsed();
+                let tensors = op.process(srcs);
+                let op_end = start.elapsed();
+                profile.nodes.push(NodeProfile {
+                    node: scheduled.0,
+                    name,
+                    op_type: as_any::AsAny::type_name(&**op),
+                    start: op_start,
+                    end: op_end,
+                    input_shapes,
+                    output_bytes: tensors.iter().filter_map(|t| t.size_bytes()).sum(),
+                });
+                tensors
+            });
+        }
+        profile.total = start.elapsed();
+        self.reset();
+        profile
+    }
+
     /// Execute the graph with debug prints
     pub fn execute_debug(&mut self) {
         fn format_duration(duration: &Duration) -> String {
@@ -274,10 +720,9 @@ impl Graph {
         if self.linearized_graph.is_none() {
             self.toposort();
         }
-        let mut dim_stack = Vec::new();
-        let mut consumers = self.consumers_map.as_ref().unwrap().clone();
-        let mut op_times = FxHashMap::default();
-        let width = term_size::dimensions().unwrap().0;
+        let (mut executor, schedule) = self.sequential(None);
+        let mut op_times = FxHashMap::<String, Duration>::default();
+        let width = term_size::dimensions().map(|(w, _)| w).unwrap_or(80);
 
         println!(
             "{:->2$} Executing {:->2$}",
@@ -286,63 +731,43 @@ impl Graph {
             (width.saturating_sub(" Executing ".len())) / 2
         );
         let start = std::time::Instant::now();
-        for (node, src_ids) in self.linearized_graph.as_ref().unwrap().iter() {
-            if self.tensors.contains_key(&(*node, 0)) {
-                continue;
-            }
-            let op_name = format!("{:?} | {}", self.node_weight(*node).unwrap(), node.index());
-            print!("{}", op_name.bold().bright_green());
-
-            let mut srcs =
-                get_source_tensors(&self.no_delete, &mut self.tensors, src_ids, &consumers);
-
-            // Substitute in the dyn dims
-            for (_, st) in srcs.iter_mut() {
-                st.resolve_global_dyn_dims_stack(&self.dyn_map, &mut dim_stack);
-            }
+        for scheduled in schedule {
+            executor.step(scheduled, |op, srcs| {
+                let op_name = format!("{op:?} | {}", scheduled.0.index());
+                print!("{}", op_name.bold().bright_green());
 
-            // All sources are ready
-            let mut shapes_string = srcs
-                .iter()
-                .map(|(_, s)| {
-                    format!(
-                        "{:?}",
-                        s.shape()
-                            .into_iter()
-                            .map(|i| i.to_usize().unwrap())
-                            .collect::<Vec<_>>()
-                    )
-                })
-                .join(", ");
-            if !shapes_string.is_empty() {
-                shapes_string = format!(" ({shapes_string})");
-            }
-            print!("{shapes_string}");
-            std::io::stdout().flush().unwrap();
-            // Execute
-            let now = std::time::Instant::now();
-            let tensors = self.graph.node_weight_mut(*node).unwrap().process(srcs);
-            let elapsed = now.elapsed();
-            println!(
-                "{:.>1$}",
-                format_duration(&elapsed).bold(),
-                width
-                    .saturating_sub(op_name.len())
-                    .saturating_sub(shapes_string.len()),
-            );
-            for (i, tensor) in tensors.into_iter().enumerate() {
-                self.tensors.insert((*node, i as u8), tensor);
-            }
-            if let Some(t) = op_times.get_mut(&op_name) {
-                *t += elapsed;
-            } else {
-                op_times.insert(op_name, elapsed);
-            }
-
-            // Check if we can delete the source tensors now
-            for (id, ind, _) in src_ids {
-                *consumers.get_mut(&(*id, *ind)).unwrap() -= 1;
-            }
+                // All sources are ready
+                let mut shapes_string = srcs
+                    .iter()
+                    .map(|(_, s)| {
+                        format!(
+                            "{:?}",
+                            s.shape()
+                                .into_iter()
+                                .map(|i| i.to_usize().unwrap())
+                                .collect::<Vec<_>>()
+                        )
+                    })
+                    .join(", ");
+                if !shapes_string.is_empty() {
+                    shapes_string = format!(" ({shapes_string})");
+                }
+                print!("{shapes_string}");
+                std::io::stdout().flush().unwrap();
+                // Execute
+                let now = std::time::Instant::now();
+                let tensors = op.process(srcs);
+                let elapsed = now.elapsed();
+                println!(
+                    "{:.>1$}",
+                    format_duration(&elapsed).bold(),
+                    width
+                        .saturating_sub(op_name.len())
+                        .saturating_sub(shapes_string.len()),
+                );
+                *op_times.entry(op_name).or_default() += elapsed;
+                tensors
+            });
         }
 
         // Print out total times
@@ -379,6 +804,125 @@ impl DerefMut for Graph {
     }
 }
 
+/// The message an op panicked with
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Op panicked".to_string())
+}
+
+/// Runs a schedule one node at a time, freeing intermediate tensors once nothing else consumes them
+struct Executor<'a> {
+    graph: &'a mut MainGraph,
+    tensors: &'a mut FxHashMap<(NodeIndex, u8), Tensor>,
+    no_delete: &'a FxHashSet<NodeIndex>,
+    dyn_map: &'a FxHashMap<char, usize>,
+    /// Remaining consumers of each output
+    consumers: FxHashMap<(NodeIndex, u8), usize>,
+    dim_stack: Vec<i64>,
+}
+
+impl Executor<'_> {
+    /// Whether a node's outputs are already there, because they were set or kept from a previous run
+    fn is_done(&self, node: NodeIndex) -> bool {
+        self.tensors.contains_key(&(node, 0))
+    }
+
+    /// Check a node's inputs were produced, have resolvable shapes and, for graph inputs, the right number of elements
+    fn check_inputs(&mut self, (node, src_ids): &ScheduledNode) -> Result<(), LuminalError> {
+        for (id, ind, st) in src_ids {
+            let Some(tensor) = self.tensors.get(&(*id, *ind)) else {
+                let name = format!("{:?}", self.graph.node_weight(*id).unwrap());
+                // Only loads without a value skip producing their outputs
+                let is_input = self
+                    .graph
+                    .node_weight(*id)
+                    .unwrap()
+                    .as_any()
+                    .is::<Function>()
+                    && self
+                        .graph
+                        .edges_directed(*id, Direction::Incoming)
+                        .next()
+                        .is_none();
+                return Err(if is_input {
+                    LuminalError::MissingInput { node: *id, name }
+                } else {
+                    LuminalError::OpFailure {
+                        node: *id,
+                        name,
+                        message: format!("Output {ind} was never produced"),
+                    }
+                });
+            };
+            let mut st = *st;
+            st.try_resolve_global_dyn_dims_stack(self.dyn_map, &mut self.dim_stack)
+                .map_err(|e| {
+                    let name = format!("{:?}", self.graph.node_weight(*node).unwrap());
+                    match e {
+                        ResolveError::Unset(dim) => LuminalError::UnresolvedDynDim {
+                            node: *node,
+                            name,
+                            dim,
+                        },
+                        ResolveError::Undefined => LuminalError::OpFailure {
+                            node: *node,
+                            name,
+                            message: "Shape overflows or divides by zero".to_string(),
+                        },
+                    }
+                })?;
+            // Graph inputs come from the user, so check they have the right number of elements
+            if let (None, Some(found), Some(expected)) = (
+                self.graph.edges_directed(*id, Direction::Incoming).next(),
+                tensor.num_elements(),
+                st.n_physical_elements().to_usize(),
+            ) {
+                if found != expected {
+                    return Err(LuminalError::ShapeMismatch {
+                        node: *id,
+                        name: format!("{:?}", self.graph.node_weight(*id).unwrap()),
+                        expected,
+                        found,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Run a node unless it's done, storing its outputs and bookkeeping its inputs. `run` processes
+    /// the op, so executors can time or print it
+    fn step(
+        &mut self,
+        (node, src_ids): &ScheduledNode,
+        run: impl FnOnce(&mut Box<dyn Operator>, Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor>,
+    ) {
+        if self.is_done(*node) {
+            return;
+        }
+        let mut srcs = get_source_tensors(self.no_delete, self.tensors, src_ids, &self.consumers);
+
+        // Substitute in the dyn dims
+        for (_, st) in srcs.iter_mut() {
+            st.resolve_global_dyn_dims_stack(self.dyn_map, &mut self.dim_stack);
+        }
+
+        // Execute
+        let tensors = run(self.graph.node_weight_mut(*node).unwrap(), srcs);
+        for (i, tensor) in tensors.into_iter().enumerate() {
+            self.tensors.insert((*node, i as u8), tensor);
+        }
+
+        // Bookkeep remaining consumers
+        for (id, ind, _) in src_ids {
+            *self.consumers.get_mut(&(*id, *ind)).unwrap() -= 1;
+        }
+    }
+}
+
 /// Get source tensor array for a node
 fn get_source_tensors<'a>(
     no_delete: &'a FxHashSet<NodeIndex>,
@@ -391,15 +935,154 @@ fn get_source_tensors<'a>(
         let id = &(*id, *ind);
         if consumers[id] == 1 && !no_delete.contains(&id.0) {
             srcs.push((
-                InputTensor::Owned(unsafe { tensors.as_mut().unwrap() }.remove(id).unwrap()),
+                InputTensor::Owned(
+                    unsafe { tensors.as_mut().unwrap() }
+                        .remove(id)
+                        .unwrap_or_else(|| missing_source(*id)),
+                ),
                 *sh,
             ));
         } else {
             srcs.push((
-                InputTensor::Borrowed(unsafe { tensors.as_ref().unwrap() }.get(id).unwrap()),
+                InputTensor::Borrowed(
+                    unsafe { tensors.as_ref().unwrap() }
+                        .get(id)
+                        .unwrap_or_else(|| missing_source(*id)),
+                ),
                 *sh,
             ));
         }
     }
     srcs
 }
+
+/// Mutable scheduling state shared between parallel workers
+struct ParallelState {
+    /// Nodes whose dependencies have all finished
+    ready: VecDeque<NodeIndex>,
+    /// Number of unfinished dependencies for each node not yet ready
+    waiting_on: FxHashMap<NodeIndex, usize>,
+    /// Number of nodes not yet finished
+    remaining: usize,
+    /// Set when a worker panics so the others stop waiting
+    aborted: bool,
+    consumers: FxHashMap<(NodeIndex, u8), usize>,
+    tensors: FxHashMap<(NodeIndex, u8), Box<Tensor>>,
+}
+
+/// Everything a parallel worker needs to execute nodes
+struct ParallelContext<'a> {
+    state: Mutex<ParallelState>,
+    wake: Condvar,
+    ops: FxHashMap<NodeIndex, *mut Box<dyn Operator>>,
+    sources: FxHashMap<NodeIndex, &'a [(NodeIndex, u8, ShapeTracker)]>,
+    dependents: FxHashMap<NodeIndex, Vec<NodeIndex>>,
+    no_delete: &'a FxHashSet<NodeIndex>,
+    dyn_map: &'a FxHashMap<char, usize>,
+}
+
+// SAFETY: Callers of `execute_parallel_threads` guarantee the ops and tensors can be used across
+// threads. Each node is only ever picked up by a single worker, so ops are never aliased.
+// Tensors are only touched while holding the state lock, except borrowed inputs, which can't be
+// removed until their consumers (including the borrowing node) have finished.
+unsafe impl Sync for ParallelContext<'_> {}
+
+impl ParallelContext<'_> {
+    fn lock(&self) -> MutexGuard<'_, ParallelState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Wakes up the other workers if this one panics, so they don't wait forever
+struct AbortOnPanic<'a, 'b>(&'a ParallelContext<'b>);
+
+impl Drop for AbortOnPanic<'_, '_> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.0.lock().aborted = true;
+            self.0.wake.notify_all();
+        }
+    }
+}
+
+/// Pull ready nodes off the shared queue and execute them until the whole graph is done
+fn parallel_worker(cx: &ParallelContext) {
+    let _guard = AbortOnPanic(cx);
+    let mut dim_stack = Vec::new();
+    loop {
+        let mut state = cx.lock();
+        let node = loop {
+            if state.aborted || state.remaining == 0 {
+                return;
+            }
+            if let Some(node) = state.ready.pop_front() {
+                break node;
+            }
+            state = cx.wake.wait(state).unwrap_or_else(|e| e.into_inner());
+        };
+        let src_ids = cx.sources[&node];
+        let skip = state.tensors.contains_key(&(node, 0));
+
+        // Grab the sources the same way get_source_tensors does
+        let mut srcs = vec![];
+        if !skip {
+            for (id, ind, sh) in src_ids {
+                let key = (*id, *ind);
+                if state.consumers[&key] == 1 && !cx.no_delete.contains(id) {
+                    srcs.push((
+                        InputTensor::Owned(
+                            *state
+                                .tensors
+                                .remove(&key)
+                                .unwrap_or_else(|| missing_source(key)),
+                        ),
+                        *sh,
+                    ));
+                } else {
+                    let tensor: *const Tensor = &**state
+                        .tensors
+                        .get(&key)
+                        .unwrap_or_else(|| missing_source(key));
+                    srcs.push((InputTensor::Borrowed(unsafe { &*tensor }), *sh));
+                }
+            }
+        }
+        drop(state);
+
+        // Execute without holding the lock
+        let tensors = if skip {
+            vec![]
+        } else {
+            for (_, st) in srcs.iter_mut() {
+                st.resolve_global_dyn_dims_stack(cx.dyn_map, &mut dim_stack);
+            }
+            unsafe { &mut *cx.ops[&node] }.process(srcs)
+        };
+
+        let mut state = cx.lock();
+        if !skip {
+            for (i, tensor) in tensors.into_iter().enumerate() {
+                state.tensors.insert((node, i as u8), Box::new(tensor));
+            }
+            // Bookkeep remaining consumers, freeing tensors nobody else needs
+            for (id, ind, _) in src_ids {
+                let remaining = state.consumers.get_mut(&(*id, *ind)).unwrap();
+                *remaining -= 1;
+                if *remaining == 0 && !cx.no_delete.contains(id) {
+                    state.tensors.remove(&(*id, *ind));
+                }
+            }
+        }
+        for dest in &cx.dependents[&node] {
+            let waiting = state.waiting_on.get_mut(dest).unwrap();
+            *waiting -= 1;
+            if *waiting == 0 {
+                state.waiting_on.remove(dest);
+                state.ready.push_back(*dest);
+            }
+        }
+        state.remaining -= 1;
+        drop(state);
+        cx.wake.notify_all();
+    }
+}
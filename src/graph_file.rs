@@ -0,0 +1,1050 @@
+// Saving and loading compiled graphs, so the compilers don't need to run on every start
+use std::{
+    any::{Any, TypeId},
+    fmt::Display,
+    path::Path,
+};
+
+use itertools::Itertools;
+use prost::Message;
+use rustc_hash::FxHashMap;
+use tinyvec::ArrayVec;
+
+use crate::{
+    graph::{unset_input, MainGraph},
+    op::{
+        Add, Cast, Constant, ConstantValue, Contiguous, Exp2, Function, LessThan, Log2, MaxReduce,
+        Mod, Mul, Recip, Sin, Sqrt, SumReduce,
+    },
+    prelude::*,
+};
+
+/// Bumped whenever the file layout changes
+const FORMAT_VERSION: u32 = 1;
+/// Input placeholders (`Function`s named "... Load") are stored under this op type, without their closure
+const LOAD_OP: &str = "Load";
+/// Folded constants are stored under this op type, along with their data
+const FOLDED_OP: &str = "FoldedConstant";
+/// The element types, in the order they're stored
+const DTYPES: [DType; 6] = [
+    DType::F32,
+    DType::F16,
+    DType::Bf16,
+    DType::I32,
+    DType::U8,
+    DType::Bool,
+];
+
+/// An error encountered while saving or loading a compiled graph
+#[derive(Debug)]
+pub enum GraphFileError {
+    Io(std::io::Error),
+    /// The file isn't a valid graph file
+    Decode(prost::DecodeError),
+    /// The file decodes, but doesn't describe a valid graph
+    Invalid(String),
+    /// Nodes whose ops can't be saved, in node order
+    Unsupported(Vec<UnsupportedOp>),
+    /// The file has an op type the registry doesn't know
+    UnknownOp {
+        node: NodeIndex,
+        op_type: String,
+    },
+    /// An op's stored parameters couldn't be read back
+    InvalidOp {
+        node: NodeIndex,
+        op_type: String,
+        message: String,
+    },
+    /// A different number of ids was passed when loading than when saving
+    IdCountMismatch {
+        expected: usize,
+        found: usize,
+    },
+}
+
+/// A node whose op isn't registered, or is an opaque `Function` closure
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsupportedOp {
+    pub node: NodeIndex,
+    /// The op's debug representation
+    pub op: String,
+}
+
+impl Display for GraphFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GraphFileError::Io(e) => write!(f, "{e}"),
+            GraphFileError::Decode(e) => write!(f, "Invalid graph file: {e}"),
+            GraphFileError::Invalid(message) => write!(f, "Invalid graph file: {message}"),
+            GraphFileError::Unsupported(ops) => {
+                write!(f, "Ops that can't be saved:")?;
+                for op in ops {
+                    write!(f, "\n  {} ({})", op.op, op.node.index())?;
+                }
+                Ok(())
+            }
+            GraphFileError::UnknownOp { node, op_type } => {
+                write!(f, "Unknown op type {op_type} ({})", node.index())
+            }
+            GraphFileError::InvalidOp {
+                node,
+                op_type,
+                message,
+            } => write!(f, "Can't load {op_type} ({}): {message}", node.index()),
+            GraphFileError::IdCountMismatch { expected, found } => write!(
+                f,
+                "Graph file was saved with {expected} ids, but {found} were given"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GraphFileError {}
+
+impl From<std::io::Error> for GraphFileError {
+    fn from(e: std::io::Error) -> Self {
+        GraphFileError::Io(e)
+    }
+}
+
+impl From<prost::DecodeError> for GraphFileError {
+    fn from(e: prost::DecodeError) -> Self {
+        GraphFileError::Decode(e)
+    }
+}
+
+/// The parameters of a saved op
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OpAttributes {
+    pub ints: Vec<i64>,
+    pub floats: Vec<f32>,
+    pub exprs: Vec<BigExpression>,
+    pub strings: Vec<String>,
+}
+
+impl OpAttributes {
+    pub fn int(&self, i: usize) -> Result<i64, String> {
+        self.ints.get(i).copied().ok_or_else(|| missing("int", i))
+    }
+    pub fn float(&self, i: usize) -> Result<f32, String> {
+        self.floats
+            .get(i)
+            .copied()
+            .ok_or_else(|| missing("float", i))
+    }
+    pub fn expr(&self, i: usize) -> Result<&BigExpression, String> {
+        self.exprs.get(i).ok_or_else(|| missing("expression", i))
+    }
+    pub fn string(&self, i: usize) -> Result<&str, String> {
+        self.strings
+            .get(i)
+            .map(|s| s.as_str())
+            .ok_or_else(|| missing("string", i))
+    }
+}
+
+fn missing(kind: &str, i: usize) -> String {
+    format!("Missing {kind} attribute {i}")
+}
+
+/// An op that can be saved to a graph file and loaded back
+pub trait SerializeOp: Operator + Sized + 'static {
+    /// The op type it's stored under. Should be unique within a registry
+    const NAME: &'static str;
+    fn serialize_op(&self) -> OpAttributes;
+    /// Rebuild the op. `graph` is the graph it's being loaded into, for ops that read its dynamic dimensions
+    fn deserialize_op(attrs: &OpAttributes, graph: &Graph) -> Result<Self, String>;
+}
+
+type SerializeFn = fn(&dyn Any) -> OpAttributes;
+type DeserializeFn = fn(&OpAttributes, &Graph) -> Result<Box<dyn Operator>, String>;
+
+/// The op types that can be saved and loaded. Starts out with the primitive ops, backends add their own with [`OpRegistry::register`]
+#[derive(Clone)]
+pub struct OpRegistry {
+    serializers: FxHashMap<TypeId, (&'static str, SerializeFn)>,
+    deserializers: FxHashMap<&'static str, DeserializeFn>,
+}
+
+impl Default for OpRegistry {
+    fn default() -> Self {
+        OpRegistry {
+            serializers: FxHashMap::default(),
+            deserializers: FxHashMap::default(),
+        }
+        .register::<Constant>()
+        .register::<Contiguous>()
+        .register::<Cast>()
+        .register::<Log2>()
+        .register::<Exp2>()
+        .register::<Sin>()
+        .register::<Recip>()
+        .register::<Sqrt>()
+        .register::<Add>()
+        .register::<Mul>()
+        .register::<Mod>()
+        .register::<LessThan>()
+        .register::<SumReduce>()
+        .register::<MaxReduce>()
+    }
+}
+
+impl OpRegistry {
+    /// Add an op type. An op registered under a name that's already taken replaces the earlier one when loading
+    pub fn register<T: SerializeOp>(mut self) -> Self {
+        self.serializers.insert(
+            TypeId::of::<T>(),
+            (T::NAME, |op| op.downcast_ref::<T>().unwrap().serialize_op()),
+        );
+        self.deserializers.insert(T::NAME, |attrs, graph| {
+            Ok(Box::new(T::deserialize_op(attrs, graph)?))
+        });
+        self
+    }
+}
+
+impl Graph {
+    /// Save the graph's nodes, edges and kept / retrieved tensors to a file.
+    ///
+    /// `ids` are tensors to remap when loading, usually the ones passed to `compile`. Tensor data, including values set on inputs, isn't saved, apart from constants folded by [`ConstantFolding`]
+    pub fn save_compiled<T: ToIds, P: AsRef<Path>>(
+        &self,
+        registry: &OpRegistry,
+        ids: T,
+        path: P,
+    ) -> Result<(), GraphFileError> {
+        std::fs::write(path, self.serialize_compiled(registry, ids)?)?;
+        Ok(())
+    }
+
+    /// Replace this graph with one saved by [`Graph::save_compiled`]. See [`Graph::deserialize_compiled`]
+    pub fn load_compiled<T: ToIdsMut, P: AsRef<Path>>(
+        &mut self,
+        registry: &OpRegistry,
+        ids: T,
+        path: P,
+    ) -> Result<(), GraphFileError> {
+        self.deserialize_compiled(registry, ids, &std::fs::read(path)?)
+    }
+
+    /// Encode the graph as in [`Graph::save_compiled`]
+    pub fn serialize_compiled<T: ToIds>(
+        &self,
+        registry: &OpRegistry,
+        ids: T,
+    ) -> Result<Vec<u8>, GraphFileError> {
+        let mut unsupported = vec![];
+        let mut nodes = vec![];
+        for node in self.graph.node_indices() {
+            let op = self.graph.node_weight(node).unwrap();
+            let (op_type, attrs) = if let Some(load) = op
+                .as_any()
+                .downcast_ref::<Function>()
+                .filter(|f| f.0.ends_with(" Load"))
+            {
+                let attrs = OpAttributes {
+                    strings: vec![load.0.clone()],
+                    ..Default::default()
+                };
+                (LOAD_OP, attrs)
+            } else if let Some(attrs) = folded_tensors(op.as_ref()).and_then(encode_tensors) {
+                (FOLDED_OP, attrs)
+            } else if let Some((name, serialize)) =
+                registry.serializers.get(&Any::type_id(op.as_any()))
+            {
+                (*name, serialize(op.as_any()))
+            } else {
+                unsupported.push(UnsupportedOp {
+                    node,
+                    op: format!("{op:?}"),
+                });
+                continue;
+            };
+            nodes.push(NodeProto {
+                index: node.index() as u32,
+                op_type: op_type.to_string(),
+                ints: attrs.ints,
+                floats: attrs.floats,
+                exprs: attrs.exprs.iter().map(expr_to_proto).collect(),
+                strings: attrs.strings,
+            });
+        }
+        if !unsupported.is_empty() {
+            return Err(GraphFileError::Unsupported(unsupported));
+        }
+
+        let edges = self
+            .graph
+            .edge_indices()
+            .map(|e| {
+                let (source, target) = self.graph.edge_endpoints(e).unwrap();
+                let mut edge = EdgeProto {
+                    source: source.index() as u32,
+                    target: target.index() as u32,
+                    schedule: true,
+                    ..Default::default()
+                };
+                if let Dependency::Data {
+                    input_order,
+                    output_order,
+                    shape,
+                    dtype,
+                } = self.graph.edge_weight(e).unwrap()
+                {
+                    edge.schedule = false;
+                    edge.input_order = *input_order as u32;
+                    edge.output_order = *output_order as u32;
+                    edge.shape = Some(shape_to_proto(shape));
+                    edge.dtype = dtype_code(*dtype);
+                }
+                edge
+            })
+            .collect();
+
+        Ok(GraphFileProto {
+            version: FORMAT_VERSION,
+            nodes,
+            edges,
+            no_delete: self
+                .no_delete
+                .iter()
+                .map(|n| n.index() as u32)
+                .sorted()
+                .collect(),
+            to_retrieve: self
+                .to_retrieve
+                .iter()
+                .sorted_by_key(|(n, _)| **n)
+                .map(|(node, (output, shape))| RetrieveProto {
+                    node: node.index() as u32,
+                    output: *output as u32,
+                    shape: Some(shape_to_proto(shape)),
+                })
+                .collect(),
+            dtypes: self
+                .dtypes
+                .iter()
+                // Removed inputs can leave their declared type behind
+                .filter(|(n, _)| self.graph.contains_node(**n))
+                .sorted_by_key(|(n, _)| **n)
+                .map(|(node, dtype)| NodeDTypeProto {
+                    node: node.index() as u32,
+                    dtype: dtype_code(*dtype),
+                })
+                .collect(),
+            ids: ids.to_ids().iter().map(|n| n.index() as u32).collect(),
+        }
+        .encode_to_vec())
+    }
+
+    /// Replace this graph with one encoded by [`Graph::serialize_compiled`], keeping node indexes and dynamic dimensions.
+    ///
+    /// `ids` must be the same tensors, in the same order, as the ones saved, and are remapped to the loaded graph like `compile` does. Inputs among them keep the values already set on them, any other tensor data is dropped
+    pub fn deserialize_compiled<T: ToIdsMut>(
+        &mut self,
+        registry: &OpRegistry,
+        mut ids: T,
+        bytes: &[u8],
+    ) -> Result<(), GraphFileError> {
+        let file = GraphFileProto::decode(bytes)?;
+        if file.version != FORMAT_VERSION {
+            return Err(GraphFileError::Invalid(format!(
+                "Unsupported version {}",
+                file.version
+            )));
+        }
+        let mut ids = ids.to_ids_mut();
+        if ids.len() != file.ids.len() {
+            return Err(GraphFileError::IdCountMismatch {
+                expected: file.ids.len(),
+                found: ids.len(),
+            });
+        }
+
+        // Load ops into their original slots
+        let mut slots: Vec<Option<Box<dyn Operator>>> = vec![];
+        for node in &file.nodes {
+            let index = NodeIndex::new(node.index as usize);
+            let invalid = |message| GraphFileError::InvalidOp {
+                node: index,
+                op_type: node.op_type.clone(),
+                message,
+            };
+            let attrs = OpAttributes {
+                ints: node.ints.clone(),
+                floats: node.floats.clone(),
+                exprs: node
+                    .exprs
+                    .iter()
+                    .map(expr_from_proto)
+                    .collect::<Result<_, _>>()
+                    .map_err(invalid)?,
+                strings: node.strings.clone(),
+            };
+            let op: Box<dyn Operator> = if node.op_type == LOAD_OP {
+                Box::new(unset_input(attrs.string(0).map_err(invalid)?.to_string()))
+            } else if node.op_type == FOLDED_OP {
+                Box::new(FoldedConstant(decode_tensors(&attrs).map_err(invalid)?))
+            } else {
+                let deserialize = registry
+                    .deserializers
+                    .get(node.op_type.as_str())
+                    .ok_or_else(|| GraphFileError::UnknownOp {
+                        node: index,
+                        op_type: node.op_type.clone(),
+                    })?;
+                deserialize(&attrs, self).map_err(invalid)?
+            };
+            if slots.len() <= index.index() {
+                slots.resize_with(index.index() + 1, || None);
+            }
+            if slots[index.index()].replace(op).is_some() {
+                return Err(GraphFileError::Invalid(format!(
+                    "Node {} is stored twice",
+                    index.index()
+                )));
+            }
+        }
+
+        // Fill gaps left by removed nodes with placeholders, so the stable graph hands out the same indexes
+        let mut graph = MainGraph::default();
+        let mut placeholders = vec![];
+        for op in slots {
+            let is_placeholder = op.is_none();
+            let node = graph.add_node(op.unwrap_or_else(|| Box::new(Contiguous)));
+            if is_placeholder {
+                placeholders.push(node);
+            }
+        }
+        for node in placeholders {
+            graph.remove_node(node);
+        }
+
+        let node = |n: u32| {
+            let node = NodeIndex::new(n as usize);
+            if graph.contains_node(node) {
+                Ok(node)
+            } else {
+                Err(GraphFileError::Invalid(format!("No node {n}")))
+            }
+        };
+        let mut edges = vec![];
+        for edge in &file.edges {
+            let dependency = if edge.schedule {
+                Dependency::Schedule
+            } else {
+                Dependency::Data {
+                    input_order: edge.input_order as u8,
+                    output_order: edge.output_order as u8,
+                    shape: edge_shape(edge.shape.as_ref())?,
+                    dtype: dtype_from_code(edge.dtype).map_err(GraphFileError::Invalid)?,
+                }
+            };
+            edges.push((node(edge.source)?, node(edge.target)?, dependency));
+        }
+        let no_delete = file
+            .no_delete
+            .iter()
+            .map(|n| node(*n))
+            .collect::<Result<_, _>>()?;
+        let to_retrieve = file
+            .to_retrieve
+            .iter()
+            .map(|r| {
+                Ok((
+                    node(r.node)?,
+                    (r.output as u8, edge_shape(r.shape.as_ref())?),
+                ))
+            })
+            .collect::<Result<_, GraphFileError>>()?;
+        let mut dtypes = file
+            .dtypes
+            .iter()
+            .map(|d| {
+                let dtype = dtype_from_code(d.dtype).map_err(GraphFileError::Invalid)?;
+                Ok((node(d.node)?, dtype))
+            })
+            .collect::<Result<FxHashMap<_, _>, GraphFileError>>()?;
+        let new_ids = file
+            .ids
+            .iter()
+            .map(|n| node(*n))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (source, target, dependency) in edges {
+            graph.add_edge(source, target, dependency);
+        }
+
+        // Move the values set on tracked inputs over to the loaded graph
+        let mut old_graph = std::mem::replace(&mut self.graph, graph);
+        for (id, new_id) in ids.iter_mut().zip(new_ids) {
+            if let (Some(mut old), Some(new)) = (
+                old_graph.remove_node(**id),
+                self.graph.node_weight_mut(new_id),
+            ) {
+                if let (Some(old), Some(new)) = (
+                    old.as_any_mut().downcast_mut::<Function>(),
+                    new.as_any_mut().downcast_mut::<Function>(),
+                ) {
+                    if old.0 == new.0 {
+                        std::mem::swap(&mut old.1, &mut new.1);
+                        if let Some(dtype) = self.dtypes.get(*id) {
+                            dtypes.insert(new_id, *dtype);
+                        }
+                    }
+                }
+            }
+            **id = new_id;
+        }
+        self.no_delete = no_delete;
+        self.to_retrieve = to_retrieve;
+        self.dtypes = dtypes;
+        self.tensors.clear();
+        self.partial_schedules.clear();
+        self.try_toposort()
+            .map_err(|e| GraphFileError::Invalid(e.to_string()))
+    }
+}
+
+fn edge_shape(shape: Option<&ShapeProto>) -> Result<ShapeTracker, GraphFileError> {
+    shape
+        .ok_or_else(|| "Missing shape".to_string())
+        .and_then(shape_from_proto)
+        .map_err(GraphFileError::Invalid)
+}
+
+/// Store the data of CPU tensors: their count, then the element type and length of each, then the elements. Integer elements go in `ints`, the rest in `floats`
+fn encode_tensors(tensors: &[Tensor]) -> Option<OpAttributes> {
+    let mut attrs = OpAttributes {
+        ints: vec![tensors.len() as i64],
+        ..Default::default()
+    };
+    for tensor in tensors {
+        attrs.ints.push(dtype_code(tensor.dtype()?) as i64);
+        attrs.ints.push(tensor.num_elements()? as i64);
+    }
+    for tensor in tensors {
+        match tensor.dtype()? {
+            DType::F32 | DType::F16 | DType::Bf16 => {
+                attrs.floats.extend(tensor.as_elements::<f32>().iter())
+            }
+            DType::I32 => attrs
+                .ints
+                .extend(tensor.as_slice::<i32>()?.iter().map(|i| *i as i64)),
+            DType::U8 => attrs
+                .ints
+                .extend(tensor.as_slice::<u8>()?.iter().map(|i| *i as i64)),
+            DType::Bool => attrs
+                .ints
+                .extend(tensor.as_slice::<bool>()?.iter().map(|i| *i as i64)),
+        }
+    }
+    Some(attrs)
+}
+
+fn decode_tensors(attrs: &OpAttributes) -> Result<Vec<Tensor>, String> {
+    let count = attrs.int(0)? as usize;
+    let (mut ints, mut floats) = (1 + 2 * count, 0);
+    let truncated = || "Folded constant data is truncated".to_string();
+    let mut tensors = vec![];
+    for i in 0..count {
+        let dtype = dtype_from_code(attrs.int(1 + 2 * i)? as i32)?;
+        let len = attrs.int(2 + 2 * i)? as usize;
+        tensors.push(if dtype.is_float() {
+            let data = attrs
+                .floats
+                .get(floats..floats + len)
+                .ok_or_else(truncated)?;
+            floats += len;
+            match dtype {
+                DType::F32 => Tensor::new(data.to_vec()),
+                DType::F16 => Tensor::new(data.iter().map(|f| f16::from_f32(*f)).collect_vec()),
+                _ => Tensor::new(data.iter().map(|f| bf16::from_f32(*f)).collect_vec()),
+            }
+        } else {
+            let data = attrs.ints.get(ints..ints + len).ok_or_else(truncated)?;
+            ints += len;
+            match dtype {
+                DType::I32 => Tensor::new(data.iter().map(|i| *i as i32).collect_vec()),
+                DType::U8 => Tensor::new(data.iter().map(|i| *i as u8).collect_vec()),
+                _ => Tensor::new(data.iter().map(|i| *i != 0).collect_vec()),
+            }
+        });
+    }
+    Ok(tensors)
+}
+
+fn dtype_code(dtype: DType) -> i32 {
+    DTYPES.iter().position(|d| *d == dtype).unwrap() as i32
+}
+
+fn dtype_from_code(code: i32) -> Result<DType, String> {
+    usize::try_from(code)
+        .ok()
+        .and_then(|c| DTYPES.get(c))
+        .copied()
+        .ok_or_else(|| format!("Unknown element type {code}"))
+}
+
+fn expr_to_proto<S: ExpressionStorage>(expr: &GenericExpression<S>) -> ExpressionProto {
+    ExpressionProto {
+        terms: expr
+            .terms
+            .clone()
+            .into_iter()
+            .map(|t| format!("{t:?}"))
+            .collect(),
+    }
+}
+
+/// Parse the terms written by the `Term` debug representation
+fn expr_from_proto(expr: &ExpressionProto) -> Result<BigExpression, String> {
+    let terms = expr
+        .terms
+        .iter()
+        .map(|t| {
+            Ok(match t.as_str() {
+                "+" => Term::Add,
+                "-" => Term::Sub,
+                "*" => Term::Mul,
+                "/" => Term::Div,
+                "%" => Term::Mod,
+                "min" => Term::Min,
+                "max" => Term::Max,
+                "&&" => Term::And,
+                "||" => Term::Or,
+                ">=" => Term::Gte,
+                "<" => Term::Lt,
+                _ => match (t.parse(), t.chars().exactly_one()) {
+                    (Ok(n), _) => Term::Num(n),
+                    (_, Ok(c)) => Term::Var(c),
+                    _ => return Err(format!("Invalid expression term {t}")),
+                },
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    if terms.is_empty() {
+        return Err("Empty expression".to_string());
+    }
+    Ok(BigExpression { terms })
+}
+
+fn small_expr_from_proto(expr: &ExpressionProto) -> Result<Expression, String> {
+    let mut terms = ArrayVec::new();
+    for term in expr_from_proto(expr)?.terms {
+        if terms.try_push(term).is_some() {
+            return Err("Expression has too many terms".to_string());
+        }
+    }
+    Ok(Expression { terms })
+}
+
+fn shape_to_proto(shape: &ShapeTracker) -> ShapeProto {
+    ShapeProto {
+        dims: shape.dims.iter().map(expr_to_proto).collect(),
+        indexes: shape.indexes.iter().map(|i| *i as u32).collect(),
+        fake: shape.fake.to_vec(),
+        mask: shape
+            .mask
+            .iter()
+            .flat_map(|(a, b)| [expr_to_proto(a), expr_to_proto(b)])
+            .collect(),
+        padding: shape
+            .padding
+            .iter()
+            .flat_map(|(a, b)| [expr_to_proto(a), expr_to_proto(b)])
+            .collect(),
+    }
+}
+
+fn shape_from_proto(shape: &ShapeProto) -> Result<ShapeTracker, String> {
+    let n = shape.dims.len();
+    if n > 6
+        || shape.fake.len() != n
+        || shape.mask.len() != n * 2
+        || shape.padding.len() != n * 2
+        || !shape.indexes.iter().sorted().copied().eq(0..n as u32)
+    {
+        return Err("Invalid shape".to_string());
+    }
+    let mut tracker = ShapeTracker::new(&[]);
+    for i in 0..n {
+        tracker.dims.push(small_expr_from_proto(&shape.dims[i])?);
+        tracker.indexes.push(shape.indexes[i] as usize);
+        tracker.fake.push(shape.fake[i]);
+        tracker.mask.push((
+            small_expr_from_proto(&shape.mask[i * 2])?,
+            small_expr_from_proto(&shape.mask[i * 2 + 1])?,
+        ));
+        tracker.padding.push((
+            small_expr_from_proto(&shape.padding[i * 2])?,
+            small_expr_from_proto(&shape.padding[i * 2 + 1])?,
+        ));
+    }
+    Ok(tracker)
+}
+
+// Primitive ops
+
+macro_rules! serialize_unit_ops {
+    ($($op:ident),*) => {
+        $(
+            impl SerializeOp for $op {
+                const NAME: &'static str = stringify!($op);
+                fn serialize_op(&self) -> OpAttributes {
+                    OpAttributes::default()
+                }
+                fn deserialize_op(_: &OpAttributes, _: &Graph) -> Result<Self, String> {
+                    Ok($op)
+                }
+            }
+        )*
+    };
+}
+
+serialize_unit_ops!(Contiguous, Log2, Exp2, Sin, Recip, Sqrt, Add, Mul, Mod, LessThan);
+
+impl SerializeOp for Constant {
+    const NAME: &'static str = "Constant";
+    fn serialize_op(&self) -> OpAttributes {
+        match &self.0 {
+            ConstantValue::Expression(e) => OpAttributes {
+                exprs: vec![e.clone()],
+                ..Default::default()
+            },
+            ConstantValue::Float(f) => OpAttributes {
+                floats: vec![*f],
+                ..Default::default()
+            },
+        }
+    }
+    fn deserialize_op(attrs: &OpAttributes, graph: &Graph) -> Result<Self, String> {
+        let value = if let Ok(e) = attrs.expr(0) {
+            ConstantValue::Expression(e.clone())
+        } else {
+            ConstantValue::Float(attrs.float(0)?)
+        };
+        // The type gets set when the graph is sorted
+        Ok(Constant(value, &graph.dyn_map, DType::F32))
+    }
+}
+
+impl SerializeOp for Cast {
+    const NAME: &'static str = "Cast";
+    fn serialize_op(&self) -> OpAttributes {
+        OpAttributes {
+            ints: vec![dtype_code(self.0) as i64],
+            ..Default::default()
+        }
+    }
+    fn deserialize_op(attrs: &OpAttributes, _: &Graph) -> Result<Self, String> {
+        let code = attrs.int(0)?;
+        Ok(Cast(dtype_from_code(
+            code.try_into()
+                .map_err(|_| format!("Unknown element type {code}"))?,
+        )?))
+    }
+}
+
+impl SerializeOp for SumReduce {
+    const NAME: &'static str = "SumReduce";
+    fn serialize_op(&self) -> OpAttributes {
+        OpAttributes {
+            ints: vec![self.0 as i64],
+            ..Default::default()
+        }
+    }
+    fn deserialize_op(attrs: &OpAttributes, _: &Graph) -> Result<Self, String> {
+        Ok(SumReduce(attrs.int(0)? as usize))
+    }
+}
+
+impl SerializeOp for MaxReduce {
+    const NAME: &'static str = "MaxReduce";
+    fn serialize_op(&self) -> OpAttributes {
+        OpAttributes {
+            ints: vec![self.0 as i64],
+            ..Default::default()
+        }
+    }
+    fn deserialize_op(attrs: &OpAttributes, _: &Graph) -> Result<Self, String> {
+        Ok(MaxReduce(attrs.int(0)? as usize))
+    }
+}
+
+// File layout
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct GraphFileProto {
+    #[prost(uint32, tag = "1")]
+    version: u32,
+    #[prost(message, repeated, tag = "2")]
+    nodes: Vec<NodeProto>,
+    /// In edge index order, so inputs are listed in the same order when loaded
+    #[prost(message, repeated, tag = "3")]
+    edges: Vec<EdgeProto>,
+    #[prost(uint32, repeated, tag = "4")]
+    no_delete: Vec<u32>,
+    #[prost(message, repeated, tag = "5")]
+    to_retrieve: Vec<RetrieveProto>,
+    /// Element types declared for input tensors
+    #[prost(message, repeated, tag = "6")]
+    dtypes: Vec<NodeDTypeProto>,
+    /// Tensors to remap when loading
+    #[prost(uint32, repeated, tag = "7")]
+    ids: Vec<u32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct NodeProto {
+    #[prost(uint32, tag = "1")]
+    index: u32,
+    #[prost(string, tag = "2")]
+    op_type: String,
+    #[prost(int64, repeated, tag = "3")]
+    ints: Vec<i64>,
+    #[prost(float, repeated, tag = "4")]
+    floats: Vec<f32>,
+    #[prost(message, repeated, tag = "5")]
+    exprs: Vec<ExpressionProto>,
+    #[prost(string, repeated, tag = "6")]
+    strings: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct EdgeProto {
+    #[prost(uint32, tag = "1")]
+    source: u32,
+    #[prost(uint32, tag = "2")]
+    target: u32,
+    /// Schedule dependencies have no other fields set
+    #[prost(bool, tag = "3")]
+    schedule: bool,
+    #[prost(uint32, tag = "4")]
+    input_order: u32,
+    #[prost(uint32, tag = "5")]
+    output_order: u32,
+    #[prost(message, optional, tag = "6")]
+    shape: Option<ShapeProto>,
+    #[prost(int32, tag = "7")]
+    dtype: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct ShapeProto {
+    #[prost(message, repeated, tag = "1")]
+    dims: Vec<ExpressionProto>,
+    #[prost(uint32, repeated, tag = "2")]
+    indexes: Vec<u32>,
+    #[prost(bool, repeated, tag = "3")]
+    fake: Vec<bool>,
+    /// Start and end of each dim, flattened
+    #[prost(message, repeated, tag = "4")]
+    mask: Vec<ExpressionProto>,
+    /// Start and end of each dim, flattened
+    #[prost(message, repeated, tag = "5")]
+    padding: Vec<ExpressionProto>,
+}
+
+/// An expression's terms in RPN, as written by their debug representation
+#[derive(Clone, PartialEq, prost::Message)]
+struct ExpressionProto {
+    #[prost(string, repeated, tag = "1")]
+    terms: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct RetrieveProto {
+    #[prost(uint32, tag = "1")]
+    node: u32,
+    #[prost(uint32, tag = "2")]
+    output: u32,
+    #[prost(message, optional, tag = "3")]
+    shape: Option<ShapeProto>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct NodeDTypeProto {
+    #[prost(uint32, tag = "1")]
+    node: u32,
+    #[prost(int32, tag = "2")]
+    dtype: i32,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::op::{Function, Tensor};
+
+    use super::*;
+    crate::test_imports!();
+
+    type Inputs = (
+        GraphTensor<(Dyn<'s'>, LConst<3>)>,
+        GraphTensor<R1<3>>,
+        GraphTensor<R1<3>>,
+    );
+
+    fn build(cx: &mut Graph) -> Inputs {
+        let a = cx.named_tensor::<(Dyn<'s'>, LConst<3>)>("A");
+        let b = cx.named_tensor::<R1<3>>("B");
+        let c = ((a + b.expand::<_, LAxis<0>>()).sin() * 2.).max(a.exp2());
+        let out = c
+            .permute::<(LConst<3>, Dyn<'s'>), _>()
+            .sum_reduce::<_, LAxis<1>>()
+            + cx.arange::<LConst<3>>();
+        (a, b, out.retrieve())
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (a_data, b_data) = (random_vec(6), random_vec(3));
+        let mut cx = Graph::new();
+        let (mut a, mut b, mut out) = build(&mut cx);
+        cx.compile(GenericCompiler::default(), (&mut a, &mut b, &mut out));
+        let bytes = cx
+            .serialize_compiled(&OpRegistry::default(), (a, b, out))
+            .unwrap();
+        a.set_dyn(a_data.clone(), &[2, 3]);
+        b.set(b_data.clone());
+        cx.execute();
+
+        // Load into a freshly built graph, with its inputs set before loading
+        let mut cx2 = Graph::new();
+        let (mut a2, mut b2, mut out2) = build(&mut cx2);
+        a2.set_dyn(a_data, &[2, 3]);
+        b2.set(b_data);
+        cx2.deserialize_compiled(
+            &OpRegistry::default(),
+            (&mut a2, &mut b2, &mut out2),
+            &bytes,
+        )
+        .unwrap();
+        assert_eq!(cx2.graph.node_count(), cx.graph.node_count());
+        assert_eq!(cx2.graph.edge_count(), cx.graph.edge_count());
+        assert_eq!((a2.id, b2.id, out2.id), (a.id, b.id, out.id));
+        cx2.execute();
+        assert_exact(&out2.data(), &out.data());
+    }
+
+    #[test]
+    fn test_save_load_file() {
+        let mut cx = Graph::new();
+        let (mut a, mut b, mut out) = build(&mut cx);
+        cx.compile(GenericCompiler::default(), (&mut a, &mut b, &mut out));
+        let path = std::env::temp_dir().join(format!("luminal_{}.graph", uuid::Uuid::new_v4()));
+        cx.save_compiled(&OpRegistry::default(), (a, b, out), &path)
+            .unwrap();
+
+        let mut cx2 = Graph::new();
+        let (mut a2, mut b2, mut out2) = build(&mut cx2);
+        cx2.load_compiled(&OpRegistry::default(), (&mut a2, &mut b2, &mut out2), &path)
+            .unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(cx2.no_delete, cx.no_delete);
+        assert_eq!(cx2.to_retrieve, cx.to_retrieve);
+
+        // Inputs set after loading need their values like any other input
+        a2.set_dyn(vec![1., 2., 3.], &[1, 3]);
+        assert!(matches!(
+            cx2.try_execute(),
+            Err(LuminalError::MissingInput { node, .. }) if node == b2.id
+        ));
+        let Err(GraphFileError::IdCountMismatch { expected, found }) =
+            cx2.deserialize_compiled(&OpRegistry::default(), &mut a2, &bytes)
+        else {
+            panic!("Expected an error");
+        };
+        assert_eq!((expected, found), (3, 1));
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let mut cx = Graph::new();
+        let a = cx.named_tensor::<R1<3>>("a");
+        let custom = cx
+            .add_op(Function(
+                "Custom".to_string(),
+                Box::new(|inp| {
+                    vec![Tensor::new(
+                        inp[0].0.borrowed().as_elements::<f32>().to_vec(),
+                    )]
+                }),
+            ))
+            .input(a.id, 0, a.shape)
+            .finish();
+        let out = GraphTensor::<R1<3>>::from_id(custom, a.shape, &mut cx)
+            .exp()
+            .retrieve();
+
+        let Err(GraphFileError::Unsupported(ops)) =
+            cx.serialize_compiled(&OpRegistry::default(), out)
+        else {
+            panic!("Expected unsupported ops");
+        };
+        assert_eq!(
+            ops,
+            vec![UnsupportedOp {
+                node: custom,
+                op: "Custom".to_string()
+            }]
+        );
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Scale(f32);
+
+    impl Operator for Scale {
+        fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+            vec![Tensor::new(
+                inp[0]
+                    .0
+                    .borrowed()
+                    .as_elements::<f32>()
+                    .iter()
+                    .map(|i| i * self.0)
+                    .collect::<Vec<_>>(),
+            )]
+        }
+    }
+
+    impl SerializeOp for Scale {
+        const NAME: &'static str = "Scale";
+        fn serialize_op(&self) -> OpAttributes {
+            OpAttributes {
+                floats: vec![self.0],
+                ..Default::default()
+            }
+        }
+        fn deserialize_op(attrs: &OpAttributes, _: &Graph) -> Result<Self, String> {
+            Ok(Scale(attrs.float(0)?))
+        }
+    }
+
+    #[test]
+    fn test_registered_op() {
+        let mut cx = Graph::new();
+        let a = cx.named_tensor::<R1<3>>("a");
+        let scale = cx.add_op(Scale(3.)).input(a.id, 0, a.shape).finish();
+        let out = GraphTensor::<R1<3>>::from_id(scale, a.shape, &mut cx).retrieve();
+        let registry = OpRegistry::default().register::<Scale>();
+        let bytes = cx.serialize_compiled(&registry, (a, out)).unwrap();
+
+        let mut cx2 = Graph::new();
+        let mut a2 = cx2.named_tensor::<R1<3>>("a");
+        let mut out2 = a2;
+        let Err(GraphFileError::UnknownOp { node, op_type }) =
+            cx2.deserialize_compiled(&OpRegistry::default(), (&mut a2, &mut out2), &bytes)
+        else {
+            panic!("Expected an unknown op");
+        };
+        assert_eq!((node, op_type.as_str()), (scale, "Scale"));
+
+        a2.set(vec![1., 2., 3.]);
+        cx2.deserialize_compiled(&registry, (&mut a2, &mut out2), &bytes)
+            .unwrap();
+        assert_eq!(cx2.get_op::<Scale>(out2.id), &Scale(3.));
+        cx2.execute();
+        assert_exact(&out2.data(), &[3., 6., 9.]);
+    }
+}
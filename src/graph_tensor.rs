@@ -1,4 +1,4 @@
-use crate::prelude::*;
+use crate::{kernels::ViewReader, prelude::*};
 use std::fmt::Debug;
 use std::marker::PhantomData;
 
@@ -78,6 +78,9 @@ impl<S: Shape> GraphTensor<S> {
                 self.graph().dyn_map.insert(c, *s);
             }
         }
+        if let Some(dtype) = DType::of(data.as_any()) {
+            self.graph().set_dtype(self.id, dtype);
+        }
         self.graph().get_op_mut::<Function>(self.id).1 =
             Box::new(move |_| vec![Tensor::new(data.to_owned())]);
         self
@@ -93,23 +96,33 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(self.id, self.shape, self.graph_ref)
     }
 
+    /// The element type of this tensor
+    pub fn dtype(&self) -> DType {
+        self.graph().node_dtype(self.id)
+    }
+
+    /// Declare the element type of this input tensor, for when it gets set after being used
+    pub fn set_dtype(self, dtype: DType) -> Self {
+        self.graph().set_dtype(self.id, dtype);
+        self
+    }
+
     /// Get the contiguous data of the tensor
     pub fn data(&self) -> Vec<f32> {
+        self.typed_data()
+    }
+
+    /// Get the contiguous data of the tensor as elements of type `T`, converting if it's stored as a different type
+    pub fn typed_data<T: Element>(&self) -> Vec<T> {
         let tensor = self.graph().get_tensor_ref(self.id, 0).unwrap();
-        let orig_data = tensor.downcast_ref::<Vec<f32>>().unwrap();
+        let orig_data = tensor.as_elements::<T>();
         let mut st = self.shape;
         if !st.is_reshaped() {
-            return orig_data.clone();
+            return orig_data.into_owned();
         }
         st.resolve_global_dyn_dims(&self.graph().dyn_map);
-        let mut data = vec![0.; st.n_elements().to_usize().unwrap()];
-        let (ind, val) = (st.index_expression(), st.valid_expression());
-        #[allow(unused_mut)]
-        for (i, mut r) in data.iter_mut().enumerate() {
-            if val.exec_single_var(i) != 0 {
-                *r = orig_data[ind.exec_single_var(i)];
-            }
-        }
+        let mut data = vec![T::default(); st.n_elements().to_usize().unwrap()];
+        ViewReader::new(&orig_data, &st).read_into(0, &mut data);
         data
     }
 }
@@ -118,6 +131,9 @@ impl<S: ConstShape> GraphTensor<S> {
     /// Set the value of the tensor matching the constant shape
     pub fn set<T: Data + Clone, D: ToData<S, T>>(self, data: D) -> Self {
         let data = data.to_data_vec();
+        if let Some(dtype) = DType::of(data.as_any()) {
+            self.graph().set_dtype(self.id, dtype);
+        }
         self.graph().get_op_mut::<Function>(self.id).1 =
             Box::new(move |_| vec![Tensor::new(data.to_owned())]);
         self
@@ -344,8 +360,13 @@ pub trait ToData<S: Shape, T> {
     fn to_data_vec(self) -> T;
 }
 
-impl<S: Shape> ToData<S, Vec<f32>> for Vec<f32> {
-    fn to_data_vec(self) -> Vec<f32> {
+impl<S: Shape, T: Element> ToData<S, Vec<T>> for Vec<T> {
+    fn to_data_vec(self) -> Vec<T> {
+        self
+    }
+}
+impl<S: Shape> ToData<S, MmapData> for MmapData {
+    fn to_data_vec(self) -> MmapData {
         self
     }
 }
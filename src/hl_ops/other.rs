@@ -73,7 +73,8 @@ impl Graph {
     /// A scalar constant
     pub fn constant(&mut self, i: impl Into<ConstantValue>) -> GraphTensor<R0> {
         GraphTensor::from_id(
-            self.add_op(Constant(i.into(), &self.dyn_map)).finish(),
+            self.add_op(Constant(i.into(), &self.dyn_map, DType::F32))
+                .finish(),
             ShapeTracker::new(&[]),
             self,
         )
@@ -85,6 +86,7 @@ impl Graph {
             self.add_op(Constant(
                 ConstantValue::Expression(expr.into().simplify()),
                 &self.dyn_map,
+                DType::F32,
             ))
             .finish(),
             ShapeTracker::new(&[]),
@@ -146,7 +148,7 @@ impl<S: Shape> GraphTensor<S> {
                 Box::new(move |inp| {
                     for (i, (tensor, tracker)) in inp.iter().enumerate() {
                         println!("{message}");
-                        let d = tensor.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+                        let d = tensor.borrowed().as_elements::<f32>();
                         println!("{} Data: {:?}", i + 1, &d[..d.len().min(10)]);
                         println!("{} Shape: {:?}", i + 1, tracker);
                     }
@@ -168,14 +170,15 @@ impl<S: Shape> GraphTensor<S> {
                 Box::new(move |mut inp| {
                     // Get tensor data and file data
                     let (tensor, shape) = inp.pop().unwrap();
-                    let d = tensor.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+                    let d = tensor.borrowed().as_elements::<f32>();
                     let mut data = vec![0.; d.len()];
-                    let (ind, val) = (shape.index_expression(), shape.valid_expression());
-                    let mut stack = vec![];
-                    #[allow(unused_mut)]
-                    for (i, mut r) in data.iter_mut().enumerate() {
-                        if val.exec_single_var_stack(i, &mut stack) != 0 {
-                            *r = d[ind.exec_single_var_stack(i, &mut stack)];
+                    let (ind, val) = (
+                        shape.index_expression().compile(),
+                        shape.valid_expression().compile(),
+                    );
+                    for (i, r) in data.iter_mut().enumerate() {
+                        if val.exec(i) != 0 {
+                            *r = d[ind.exec(i)];
                         }
                     }
                     let bin_data = std::fs::read(&path)
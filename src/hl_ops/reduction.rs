@@ -6,6 +6,7 @@ use crate::{
 };
 
 impl<S: Shape> GraphTensor<S> {
+    /// Sum along the axes. Integer sums wrap around when they overflow, so cast small integer types up first if they might
     pub fn sum_reduce<Dst: Shape, Ax: Axes>(self) -> GraphTensor<Dst>
     where
         S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
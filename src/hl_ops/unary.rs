@@ -30,6 +30,16 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
     }
 
+    /// Convert each element to a different element type
+    pub fn cast(self, dtype: DType) -> GraphTensor<S> {
+        let new_id = self
+            .graph()
+            .add_op(op::Cast(dtype))
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+    }
+
     /// Natural exp
     pub fn exp(self) -> GraphTensor<S> {
         (self * (1.0 / f32::ln(2.))).exp2()
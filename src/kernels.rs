@@ -0,0 +1,302 @@
+//! Strided, multithreaded loops over CPU tensors, used by the primitive ops and CPU backends
+//!
+//! There's no hand written SIMD here. Contiguous views are read as slices borrowed straight from the
+//! data, so the elementwise and reduction loops run over plain slices that the compiler
+//! auto-vectorizes. Strided and padded views are first gathered into a buffer a chunk at a time
+
+use rayon::prelude::*;
+
+use crate::prelude::*;
+
+/// Kernels doing less work than this (in elements touched) run on the calling thread
+pub const PARALLEL_THRESHOLD: usize = 1 << 16;
+
+/// Number of threads kernels split their work across
+pub fn num_threads() -> usize {
+    rayon::current_num_threads()
+}
+
+/// Split the output into chunks with lengths a multiple of `align` and fill them across threads.
+/// `cost` is the work per output element, and `f` gets the offset of its chunk into the output.
+/// Chunks run on rayon's global pool, so threads are reused between kernels rather than spawned
+/// for each one
+pub fn par_chunks<T: Send>(
+    out: &mut [T],
+    align: usize,
+    cost: usize,
+    f: impl Fn(usize, &mut [T]) + Sync,
+) {
+    let align = align.max(1);
+    let threads = num_threads().min(out.len() / align);
+    if threads <= 1 || out.len() * cost.max(1) < PARALLEL_THRESHOLD {
+        return f(0, out);
+    }
+    let chunk = out.len().div_ceil(threads).next_multiple_of(align);
+    out.par_chunks_mut(chunk)
+        .enumerate()
+        .for_each(|(i, c)| f(i * chunk, c));
+}
+
+/// How the logical elements of a view map to its data
+#[derive(Debug)]
+enum Layout {
+    /// In order, starting at an offset
+    Contiguous(usize),
+    /// An offset plus a (size, stride) per logical dim
+    Strided(usize, Vec<(usize, usize)>),
+    /// Compiled index and valid expressions, for padded views
+    Expression(CompiledExpression, CompiledExpression),
+}
+
+/// Reads the logical elements of a view over CPU data
+#[derive(Debug)]
+pub struct ViewReader<'a, T> {
+    data: &'a [T],
+    layout: Layout,
+}
+
+impl<'a, T: Element> ViewReader<'a, T> {
+    /// The shape's dims must already be resolved
+    pub fn new(data: &'a [T], shape: &ShapeTracker) -> Self {
+        let layout = match shape.strided_layout() {
+            Some((offset, dims)) => {
+                let mut expected = 1;
+                let contiguous = dims.iter().rev().all(|&(size, stride)| {
+                    let in_order = size == 1 || stride == expected;
+                    expected *= size;
+                    in_order
+                });
+                if contiguous {
+                    Layout::Contiguous(offset)
+                } else {
+                    Layout::Strided(offset, dims)
+                }
+            }
+            None => Layout::Expression(
+                shape.index_expression().compile(),
+                shape.valid_expression().compile(),
+            ),
+        };
+        Self { data, layout }
+    }
+
+    /// Write the logical elements `start..start + out.len()` into `out`
+    pub fn read_into(&self, start: usize, out: &mut [T]) {
+        match &self.layout {
+            Layout::Contiguous(offset) => {
+                out.copy_from_slice(&self.data[offset + start..][..out.len()])
+            }
+            Layout::Strided(offset, dims) => strided_read(&self.data[*offset..], dims, start, out),
+            Layout::Expression(ind, val) => {
+                for (i, o) in out.iter_mut().enumerate() {
+                    *o = if val.exec(start + i) != 0 {
+                        self.data[ind.exec(start + i)]
+                    } else {
+                        T::default()
+                    };
+                }
+            }
+        }
+    }
+
+    /// The logical elements `start..start + len`, borrowed straight from the data when they're
+    /// contiguous and otherwise copied into `buf`
+    pub fn read<'b>(&'b self, start: usize, len: usize, buf: &'b mut Vec<T>) -> &'b [T] {
+        if let Layout::Contiguous(offset) = self.layout {
+            return &self.data[offset + start..][..len];
+        }
+        buf.resize(len, T::default());
+        self.read_into(start, buf);
+        buf
+    }
+}
+
+/// Walk a strided view, copying a run of the innermost dim at a time
+fn strided_read<T: Copy>(data: &[T], dims: &[(usize, usize)], start: usize, out: &mut [T]) {
+    let last = dims.len() - 1;
+    // Position of the first element in each dim
+    let mut idx = vec![0; dims.len()];
+    let mut rem = start;
+    for (i, (size, _)) in dims.iter().enumerate().rev() {
+        idx[i] = rem % size;
+        rem /= size;
+    }
+    let mut pos = idx.iter().zip(dims).map(|(i, (_, s))| i * s).sum::<usize>();
+    let (size, stride) = dims[last];
+    let mut written = 0;
+    while written < out.len() {
+        let run = (size - idx[last]).min(out.len() - written);
+        let dst = &mut out[written..written + run];
+        match stride {
+            0 => dst.fill(data[pos]),
+            1 => dst.copy_from_slice(&data[pos..pos + run]),
+            _ => {
+                for (k, o) in dst.iter_mut().enumerate() {
+                    *o = data[pos + k * stride];
+                }
+            }
+        }
+        written += run;
+        idx[last] += run;
+        pos += run * stride;
+        // Carry into the outer dims
+        let mut d = last;
+        while d > 0 && idx[d] == dims[d].0 {
+            pos -= idx[d] * dims[d].1;
+            idx[d] = 0;
+            d -= 1;
+            idx[d] += 1;
+            pos += dims[d].1;
+        }
+    }
+}
+
+/// Write `f(a)` for every logical element of the input
+pub fn unary_into<T: Element, O: Send>(
+    a: &ViewReader<T>,
+    out: &mut [O],
+    f: impl Fn(T) -> O + Sync,
+) {
+    par_chunks(out, 1, 1, |start, out| {
+        let mut buf = vec![];
+        let a = a.read(start, out.len(), &mut buf);
+        for (o, a) in out.iter_mut().zip(a) {
+            *o = f(*a);
+        }
+    });
+}
+
+/// Write `f(a, b)` for every logical element of the inputs
+pub fn binary_into<T: Element, O: Send>(
+    a: &ViewReader<T>,
+    b: &ViewReader<T>,
+    out: &mut [O],
+    f: impl Fn(T, T) -> O + Sync,
+) {
+    par_chunks(out, 1, 2, |start, out| {
+        let (mut a_buf, mut b_buf) = (vec![], vec![]);
+        let a = a.read(start, out.len(), &mut a_buf);
+        let b = b.read(start, out.len(), &mut b_buf);
+        for ((o, a), b) in out.iter_mut().zip(a).zip(b) {
+            *o = f(*a, *b);
+        }
+    });
+}
+
+/// Fold the input along `axis` of its logical `shape`. `out` holds every dim but the reduced one, and
+/// can be a wider type to accumulate in
+pub fn reduce_into<T: Element, A: Copy + Send + Sync>(
+    a: &ViewReader<T>,
+    shape: &[usize],
+    axis: usize,
+    init: A,
+    f: impl Fn(A, T) -> A + Sync,
+    out: &mut [A],
+) {
+    let dim = shape[axis];
+    let back = shape[axis + 1..].iter().product::<usize>();
+    par_chunks(out, back, dim, |start, out| {
+        let mut buf = vec![];
+        for (row, out) in out.chunks_mut(back).enumerate() {
+            let block = a.read((start / back + row) * dim * back, dim * back, &mut buf);
+            out.fill(init);
+            for chunk in block.chunks_exact(back) {
+                for (o, a) in out.iter_mut().zip(chunk) {
+                    *o = f(*o, *a);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{par_chunks, ViewReader, PARALLEL_THRESHOLD};
+    crate::test_imports!();
+
+    /// Read every element through the index and valid expressions
+    fn expected(data: &[f32], shape: &ShapeTracker) -> Vec<f32> {
+        let (ind, val) = (shape.index_expression(), shape.valid_expression());
+        (0..shape.n_elements().to_usize().unwrap())
+            .map(|i| {
+                if val.exec_single_var(i) != 0 {
+                    data[ind.exec_single_var(i)]
+                } else {
+                    0.
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_view_reader() {
+        let data = (0..120).map(|i| i as f32).collect::<Vec<_>>();
+        let base = ShapeTracker::new(&[4.into(), 5.into(), 6.into()]);
+        let mut permuted = base;
+        permuted.permute(&[2, 0, 1]);
+        let mut expanded = ShapeTracker::new(&[4.into(), 6.into()]);
+        expanded.expand(1, 5);
+        let mut sliced = base;
+        sliced.slice(&[
+            (1.into(), 3.into()),
+            (0.into(), 4.into()),
+            (2.into(), 5.into()),
+        ]);
+        let mut padded = base;
+        padded.pad(&[
+            (0.into(), 0.into()),
+            (1.into(), 2.into()),
+            (0.into(), 1.into()),
+        ]);
+        for shape in [base, permuted, expanded, sliced, padded] {
+            let reader = ViewReader::new(&data, &shape);
+            let expected = expected(&data, &shape);
+            let mut out = vec![0.; expected.len()];
+            reader.read_into(0, &mut out);
+            assert_exact(&out, &expected);
+            // Start partway through a row
+            let mut out = vec![0.; expected.len() - 7];
+            reader.read_into(7, &mut out);
+            assert_exact(&out, &expected[7..]);
+        }
+    }
+
+    #[test]
+    fn test_contiguous_read() {
+        let data = (0..120).map(|i| i as f32).collect::<Vec<_>>();
+        let base = ShapeTracker::new(&[4.into(), 5.into(), 6.into()]);
+        // Slicing the outer dim keeps the view contiguous, just offset
+        let mut sliced = base;
+        sliced.slice(&[
+            (1.into(), 3.into()),
+            (0.into(), 5.into()),
+            (0.into(), 6.into()),
+        ]);
+        for (shape, offset) in [(base, 0), (sliced, 30)] {
+            let reader = ViewReader::new(&data, &shape);
+            let mut buf = vec![];
+            let read = reader.read(7, 20, &mut buf);
+            // Borrowed from the data without copying
+            assert_eq!(read.as_ptr(), data[offset + 7..].as_ptr());
+            assert!(buf.is_empty());
+        }
+        let mut permuted = base;
+        permuted.permute(&[2, 0, 1]);
+        let reader = ViewReader::new(&data, &permuted);
+        let mut buf = vec![];
+        let read = reader.read(7, 20, &mut buf).as_ptr();
+        assert_eq!(read, buf.as_ptr());
+    }
+
+    #[test]
+    fn test_par_chunks() {
+        let mut out = vec![0; PARALLEL_THRESHOLD * 3 + 5];
+        par_chunks(&mut out, 4, 1, |start, chunk| {
+            assert_eq!(start % 4, 0);
+            for (i, o) in chunk.iter_mut().enumerate() {
+                *o = start + i;
+            }
+        });
+        assert!(out.iter().enumerate().all(|(i, o)| i == *o));
+    }
+}
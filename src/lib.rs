@@ -1,23 +1,44 @@
+pub mod checkpoint;
 pub mod compiler_utils;
+pub mod dtype;
+pub mod egraph;
+pub mod error;
 pub mod generic_compiler;
 pub mod graph;
+pub mod graph_file;
 pub mod graph_tensor;
 pub mod hl_ops;
+pub mod kernels;
+pub mod mmap;
 pub mod module;
+pub mod onnx;
 pub mod op;
+pub mod pipeline;
+pub mod profile;
 pub mod shape;
+pub mod validation;
 
 pub mod tests;
 
 pub mod prelude {
+    pub use crate::checkpoint::*;
     pub use crate::compiler_utils::*;
+    pub use crate::dtype::*;
+    pub use crate::egraph::EqualitySaturation;
+    pub use crate::error::*;
     pub use crate::generic_compiler::*;
     pub use crate::graph::*;
+    pub use crate::graph_file::*;
     pub use crate::graph_tensor::*;
     pub use crate::hl_ops::*;
+    pub use crate::mmap::*;
     pub use crate::module::*;
+    pub use crate::onnx::*;
     pub use crate::op::*;
+    pub use crate::pipeline::*;
+    pub use crate::profile::*;
     pub use crate::shape::*;
+    pub use crate::validation::*;
     pub use half::{bf16, f16};
     pub use petgraph;
     pub use petgraph::stable_graph::NodeIndex;
@@ -0,0 +1,160 @@
+use std::{
+    any::Any,
+    fmt::{Debug, Display},
+    fs::File,
+    path::Path,
+    sync::Arc,
+};
+
+use memmap2::Mmap;
+
+use crate::prelude::*;
+
+/// Map a file into memory read-only, so tensors can be backed by it without copying
+///
+/// # Safety
+/// The file must not be modified or truncated while the map, or any tensor viewing it, is alive.
+/// Reading the map after that is undefined behavior
+pub unsafe fn map_file<P: AsRef<Path>>(path: P) -> std::io::Result<Arc<Mmap>> {
+    Ok(Arc::new(unsafe { Mmap::map(&File::open(path)?)? }))
+}
+
+/// Why a view into a memory-mapped file couldn't be made
+#[derive(Debug, Clone, PartialEq)]
+pub enum MmapError {
+    /// The data runs past the end of the map
+    OutOfBounds {
+        offset: usize,
+        n_bytes: usize,
+        map_len: usize,
+    },
+    /// The data doesn't start at a multiple of its element size
+    Misaligned { offset: usize, dtype: DType },
+    /// Bool data contains bytes other than 0 and 1
+    InvalidBool { offset: usize },
+}
+
+impl Display for MmapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MmapError::OutOfBounds {
+                offset,
+                n_bytes,
+                map_len,
+            } => write!(
+                f,
+                "Tensor data at {offset} of {n_bytes} bytes is out of bounds for a map of {map_len} bytes"
+            ),
+            MmapError::Misaligned { offset, dtype } => {
+                write!(f, "Tensor data at {offset} isn't aligned for {dtype:?}")
+            }
+            MmapError::InvalidBool { offset } => write!(
+                f,
+                "Bool tensor data at {offset} contains bytes other than 0 and 1"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MmapError {}
+
+/// Tensor data stored in a memory-mapped file. Cloning only clones the handle to the map, so the data is never copied into memory
+#[derive(Clone)]
+pub struct MmapData {
+    mmap: Arc<Mmap>,
+    offset: usize,
+    len: usize,
+    dtype: DType,
+}
+
+impl MmapData {
+    /// View `len` elements of type `dtype` starting `offset` bytes into the map
+    ///
+    /// # Panics
+    /// If the view is out of bounds, misaligned, or holds invalid bools. See [`MmapData::try_new`]
+    pub fn new(mmap: Arc<Mmap>, offset: usize, len: usize, dtype: DType) -> Self {
+        Self::try_new(mmap, offset, len, dtype).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// View `len` elements of type `dtype` starting `offset` bytes into the map, checking the view
+    /// is in bounds, aligned for the type, and, for bools, only holds 0s and 1s
+    pub fn try_new(
+        mmap: Arc<Mmap>,
+        offset: usize,
+        len: usize,
+        dtype: DType,
+    ) -> Result<Self, MmapError> {
+        let n_bytes = len.saturating_mul(dtype.size_bytes());
+        if offset.saturating_add(n_bytes) > mmap.len() {
+            return Err(MmapError::OutOfBounds {
+                offset,
+                n_bytes,
+                map_len: mmap.len(),
+            });
+        }
+        if !(mmap.as_ptr() as usize + offset).is_multiple_of(dtype.size_bytes()) {
+            return Err(MmapError::Misaligned { offset, dtype });
+        }
+        let data = Self {
+            mmap,
+            offset,
+            len,
+            dtype,
+        };
+        if dtype == DType::Bool && data.bytes().iter().any(|b| *b > 1) {
+            return Err(MmapError::InvalidBool { offset });
+        }
+        Ok(data)
+    }
+
+    pub fn dtype(&self) -> DType {
+        self.dtype
+    }
+
+    /// Number of elements in the view
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The raw bytes of the view
+    pub fn bytes(&self) -> &[u8] {
+        &self.mmap[self.offset..self.offset + self.len * self.dtype.size_bytes()]
+    }
+
+    /// Borrow the view as a slice of `T`, if it's stored as `T`
+    pub fn as_slice<T: Element>(&self) -> Option<&[T]> {
+        if T::DTYPE != self.dtype {
+            return None;
+        }
+        // Safety: the type, bounds and alignment were checked, and bool bytes were checked to be 0 or 1
+        let data =
+            unsafe { std::slice::from_raw_parts(self.bytes().as_ptr() as *const T, self.len) };
+        Some(data)
+    }
+}
+
+impl Debug for MmapData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "MmapData({:?}, {} elements at {})",
+            self.dtype, self.len, self.offset
+        )
+    }
+}
+
+impl Data for MmapData {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn size_bytes(&self) -> Option<usize> {
+        Some(self.len * self.dtype.size_bytes())
+    }
+}
@@ -39,7 +39,7 @@ pub fn transfer_data(
     dests: impl ToIds,
     dest_graph: &mut Graph,
 ) {
-    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids().into_iter()) {
+    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids()) {
         let mut output_num = 0;
         while let Some(tensor) = src_graph.tensors.remove(&(src, output_num)) {
             dest_graph.tensors.insert((dest, output_num), tensor);
@@ -53,7 +53,7 @@ pub fn transfer_data(
 
 /// Transfer data from one set of nodes to another set in the same graph
 pub fn transfer_data_same_graph(srcs: impl ToIds, dests: impl ToIds, graph: &mut Graph) {
-    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids().into_iter()) {
+    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids()) {
         let mut output_num = 0;
         while let Some(tensor) = graph.tensors.remove(&(src, output_num)) {
             graph.tensors.insert((dest, output_num), tensor);
@@ -210,6 +210,8 @@ impl<T: SerializeModule> SerializeModule for &T {
 pub struct Serializer {
     current_path: Vec<String>,
     pub state: FxHashMap<String, NodeIndex>,
+    /// Shapes of the tensors in `state`, under the same names
+    pub shapes: FxHashMap<String, ShapeTracker>,
 }
 
 impl Serializer {
@@ -220,6 +222,8 @@ impl Serializer {
         }
         // Insert tensor id
         self.state.insert(self.current_path.join("/"), tensor.id);
+        self.shapes
+            .insert(self.current_path.join("/"), tensor.shape);
         if !name.is_empty() {
             // Remove new path component
             self.current_path.pop();
@@ -0,0 +1,673 @@
+use std::path::Path;
+
+use itertools::Itertools;
+use petgraph::Direction;
+use prost::Message;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use super::{
+    proto::{
+        AttributeProto, AttributeType, DataType, Dimension, DimensionValue, GraphProto, ModelProto,
+        NodeProto, OperatorSetIdProto, TensorProto, TensorShapeProto, TypeProto, TypeProtoTensor,
+        ValueInfoProto,
+    },
+    OnnxError, OpResult, UnsupportedNode,
+};
+use crate::{
+    op::{self, Constant, ConstantValue, Function},
+    prelude::*,
+};
+
+/// The opset exported models use
+const OPSET: i64 = 17;
+
+impl Graph {
+    /// Export the graph to an ONNX file. See [`Graph::export_onnx_model`]
+    pub fn export_onnx<I: ToIds, O: ToIds, P: AsRef<Path>>(
+        &mut self,
+        inputs: I,
+        outputs: O,
+        path: P,
+    ) -> Result<(), OnnxError> {
+        let model = self.export_onnx_model(inputs, outputs)?;
+        std::fs::write(path, model.encode_to_vec())?;
+        Ok(())
+    }
+
+    /// Convert an uncompiled graph of primitive ops to an ONNX model. Inputs are named after their tensors, and outputs are named `output0`, `output1`, ... in order. Retrieved outputs keep the shape they're retrieved with, and other tensors loaded with data are stored as initializers
+    pub fn export_onnx_model<I: ToIds, O: ToIds>(
+        &mut self,
+        inputs: I,
+        outputs: O,
+    ) -> Result<ModelProto, OnnxError> {
+        if self.linearized_graph.is_none() {
+            self.toposort();
+        }
+        let order = self.linearized_graph.clone().unwrap();
+        let inputs = inputs.to_ids();
+        let dtypes = order
+            .iter()
+            .map(|(n, _)| (*n, self.node_dtype(*n)))
+            .collect::<FxHashMap<_, _>>();
+        // Loaded tensors that aren't inputs, and folded constants, are weights, so get their data. Unset ones load nothing
+        let mut weights = vec![];
+        for (node, _) in &order {
+            let op = self.graph.node_weight(*node).unwrap().as_any();
+            let is_load = (op.is::<Function>() || op.is::<FoldedConstant>())
+                && self
+                    .graph
+                    .edges_directed(*node, Direction::Incoming)
+                    .next()
+                    .is_none();
+            if is_load && !inputs.contains(node) {
+                let tensor = match self.get_tensor_ref(*node, 0) {
+                    Some(t) => Some(t.clone()),
+                    None => self
+                        .graph
+                        .node_weight_mut(*node)
+                        .unwrap()
+                        .process(vec![])
+                        .into_iter()
+                        .next(),
+                };
+                weights.push((*node, tensor));
+            }
+        }
+
+        let mut exporter = Exporter {
+            graph: self,
+            dtypes,
+            nodes: vec![],
+            initializers: vec![],
+            values: FxHashMap::default(),
+            views: FxHashMap::default(),
+            dyn_dims: FxHashMap::default(),
+            names: FxHashSet::default(),
+            count: 0,
+        };
+        let graph = exporter.export(&order, &inputs, weights, &outputs.to_ids())?;
+        Ok(ModelProto {
+            ir_version: 8,
+            opset_import: vec![OperatorSetIdProto {
+                domain: String::new(),
+                version: OPSET,
+            }],
+            producer_name: "luminal".to_string(),
+            graph: Some(graph),
+            ..Default::default()
+        })
+    }
+}
+
+struct Exporter<'a> {
+    graph: &'a Graph,
+    dtypes: FxHashMap<NodeIndex, DType>,
+    nodes: Vec<NodeProto>,
+    initializers: Vec<TensorProto>,
+    /// The ONNX value each node outputs, and its shape
+    values: FxHashMap<NodeIndex, (String, Vec<Expression>)>,
+    /// Values seen through an edge's shape tracker, so views shared by several consumers are only built once
+    views: FxHashMap<(NodeIndex, ShapeTracker), String>,
+    /// The input value and axis each dyn dim can be read from, and the value holding it once it's been read
+    dyn_dims: FxHashMap<char, (String, usize, Option<String>)>,
+    names: FxHashSet<String>,
+    count: usize,
+}
+
+impl Exporter<'_> {
+    #[allow(clippy::type_complexity)]
+    fn export(
+        &mut self,
+        order: &[(NodeIndex, Vec<(NodeIndex, u8, ShapeTracker)>)],
+        inputs: &[NodeIndex],
+        weights: Vec<(NodeIndex, Option<Tensor>)>,
+        outputs: &[NodeIndex],
+    ) -> Result<GraphProto, OnnxError> {
+        let mut unsupported = vec![];
+        // Nodes that weren't exported because of an unsupported node
+        let mut failed = FxHashSet::default();
+        let mut fail = |node: NodeIndex, op_type: &str, reason: String| {
+            unsupported.push(UnsupportedNode {
+                name: node.index().to_string(),
+                op_type: op_type.to_string(),
+                reason,
+            })
+        };
+
+        let mut graph_inputs = vec![];
+        for input in inputs {
+            match self.input(*input) {
+                Ok(info) => graph_inputs.push(info),
+                Err(reason) => {
+                    fail(*input, "Input", reason);
+                    failed.insert(*input);
+                }
+            }
+        }
+        for (node, tensor) in weights {
+            let Some(tensor) = tensor else {
+                fail(
+                    node,
+                    "Initializer",
+                    "weight has no value and isn't an input".to_string(),
+                );
+                failed.insert(node);
+                continue;
+            };
+            if let Err(reason) = self.weight(node, &tensor) {
+                fail(node, "Initializer", reason);
+                failed.insert(node);
+            }
+        }
+
+        for (node, sources) in order {
+            if self.values.contains_key(node) || failed.contains(node) {
+                continue;
+            }
+            if sources.iter().any(|(s, _, _)| failed.contains(s)) {
+                failed.insert(*node);
+                continue;
+            }
+            match self.node(*node, sources) {
+                Ok(value) => {
+                    self.values.insert(*node, value);
+                }
+                Err(reason) => {
+                    let op = self.graph.graph.node_weight(*node).unwrap();
+                    fail(*node, &format!("{op:?}"), reason);
+                    failed.insert(*node);
+                }
+            }
+        }
+
+        let mut graph_outputs = vec![];
+        for (i, output) in outputs.iter().enumerate() {
+            let Some((mut value, mut shape)) = self.values.get(output).cloned() else {
+                continue;
+            };
+            // Retrieved tensors are viewed through their own shape
+            if let Some((_, st)) = self.graph.to_retrieve.get(output) {
+                match self.view(*output, *st) {
+                    Ok(view) => value = view,
+                    Err(reason) => {
+                        fail(*output, "Output", reason);
+                        continue;
+                    }
+                }
+                shape = st.shape().into_iter().map(|d| d.small()).collect();
+            }
+            let name = format!("output{i}");
+            self.nodes.push(NodeProto {
+                input: vec![value],
+                output: vec![name.clone()],
+                op_type: "Identity".to_string(),
+                ..Default::default()
+            });
+            graph_outputs.push(value_info(name, &shape, self.dtypes[output]));
+        }
+        if !unsupported.is_empty() {
+            return Err(OnnxError::Unsupported(unsupported));
+        }
+        Ok(GraphProto {
+            node: std::mem::take(&mut self.nodes),
+            name: "luminal".to_string(),
+            initializer: std::mem::take(&mut self.initializers),
+            input: graph_inputs,
+            output: graph_outputs,
+            ..Default::default()
+        })
+    }
+
+    /// A fresh value name
+    fn name(&mut self, prefix: &str) -> String {
+        self.count += 1;
+        format!("{prefix}_{}", self.count)
+    }
+
+    /// The name of a loaded tensor, made unique if it's shared with another tensor
+    fn tensor_name(&mut self, node: NodeIndex) -> String {
+        let op = self.graph.graph.node_weight(node).unwrap();
+        let name = match op.as_any().downcast_ref::<Function>() {
+            Some(f) => f.0.trim_end_matches(" Load").to_string(),
+            None => format!("{op:?}"),
+        };
+        let name = if self.names.contains(&name) {
+            format!("{name}_{}", node.index())
+        } else {
+            name
+        };
+        self.names.insert(name.clone());
+        name
+    }
+
+    /// The shape a loaded tensor is stored in, taken from how it's used
+    fn loaded_shape(&self, node: NodeIndex) -> OpResult<Vec<Expression>> {
+        self.graph
+            .graph
+            .edges_directed(node, Direction::Outgoing)
+            .find_map(|e| e.weight().as_data())
+            .map(|(_, _, st)| physical_dims(&st))
+            .ok_or_else(|| "the tensor isn't used, so its shape is unknown".to_string())
+    }
+
+    fn input(&mut self, node: NodeIndex) -> OpResult<ValueInfoProto> {
+        let op = self.graph.graph.node_weight(node).unwrap();
+        let has_sources = self
+            .graph
+            .graph
+            .edges_directed(node, Direction::Incoming)
+            .next()
+            .is_some();
+        if !op.as_any().is::<Function>() || has_sources {
+            return Err("inputs have to be loaded tensors".to_string());
+        }
+        let shape = self.loaded_shape(node)?;
+        let name = self.tensor_name(node);
+        for (axis, d) in shape.iter().enumerate() {
+            if let [Term::Var(c)] = d.terms.as_slice() {
+                self.dyn_dims
+                    .entry(*c)
+                    .or_insert_with(|| (name.clone(), axis, None));
+            }
+        }
+        let info = value_info(name.clone(), &shape, self.dtypes[&node]);
+        self.values.insert(node, (name, shape));
+        Ok(info)
+    }
+
+    fn weight(&mut self, node: NodeIndex, tensor: &Tensor) -> OpResult<()> {
+        let shape = self.loaded_shape(node)?;
+        let dims = shape
+            .iter()
+            .map(|d| d.to_usize().map(|d| d as i64))
+            .collect::<Option<Vec<_>>>()
+            .ok_or("weights can't have dyn dims")?;
+        let dtype = self.dtypes[&node];
+        let name = self.tensor_name(node);
+        self.initializers.push(TensorProto {
+            dims,
+            data_type: data_type(dtype) as i32,
+            name: name.clone(),
+            raw_data: raw_data(tensor, dtype),
+            ..Default::default()
+        });
+        self.values.insert(node, (name, shape));
+        Ok(())
+    }
+
+    /// Add a node with a single output, returning the output's name
+    fn add(
+        &mut self,
+        op_type: &str,
+        inputs: Vec<String>,
+        attribute: Vec<AttributeProto>,
+    ) -> String {
+        let output = self.name(op_type);
+        self.nodes.push(NodeProto {
+            input: inputs,
+            output: vec![output.clone()],
+            name: output.clone(),
+            op_type: op_type.to_string(),
+            attribute,
+            ..Default::default()
+        });
+        output
+    }
+
+    fn cast(&mut self, x: String, to: DataType) -> String {
+        self.add("Cast", vec![x], vec![int_attr("to", to as i64)])
+    }
+
+    /// A 1D int64 initializer
+    fn ints(&mut self, data: &[i64]) -> String {
+        let name = self.name("const");
+        self.initializers.push(TensorProto {
+            dims: vec![data.len() as i64],
+            data_type: DataType::Int64 as i32,
+            name: name.clone(),
+            raw_data: data.iter().flat_map(|i| i.to_le_bytes()).collect(),
+            ..Default::default()
+        });
+        name
+    }
+
+    /// A scalar of the given type
+    fn scalar(&mut self, v: f32, dtype: DType) -> String {
+        let name = self.name("const");
+        self.initializers.push(TensorProto {
+            data_type: DataType::Float as i32,
+            name: name.clone(),
+            float_data: vec![v],
+            ..Default::default()
+        });
+        if dtype == DType::F32 {
+            name
+        } else {
+            self.cast(name, data_type(dtype))
+        }
+    }
+
+    /// A 1D int64 tensor holding a shape, computed from the inputs' shapes if it has dyn dims
+    fn shape(&mut self, dims: &[Expression]) -> OpResult<String> {
+        if let Some(dims) = dims
+            .iter()
+            .map(|d| d.to_usize().map(|d| d as i64))
+            .collect::<Option<Vec<_>>>()
+        {
+            return Ok(self.ints(&dims));
+        }
+        let elements = dims
+            .iter()
+            .map(|d| self.expr(&d.terms))
+            .collect::<OpResult<Vec<_>>>()?;
+        Ok(self.add("Concat", elements, vec![int_attr("axis", 0)]))
+    }
+
+    /// A single element int64 tensor computed from an expression
+    fn expr(&mut self, terms: &[Term]) -> OpResult<String> {
+        let mut stack = vec![];
+        for term in terms {
+            let value = match term {
+                Term::Num(n) => self.ints(&[*n as i64]),
+                Term::Var(c) => self.dyn_dim(*c)?,
+                _ => {
+                    let (a, b) = (stack.pop().unwrap(), stack.pop().unwrap());
+                    match term {
+                        Term::Gte | Term::Lt => {
+                            let op_type = if *term == Term::Gte {
+                                "GreaterOrEqual"
+                            } else {
+                                "Less"
+                            };
+                            let c = self.add(op_type, vec![a, b], vec![]);
+                            self.cast(c, DataType::Int64)
+                        }
+                        Term::And | Term::Or => {
+                            let a = self.cast(a, DataType::Bool);
+                            let b = self.cast(b, DataType::Bool);
+                            let op_type = if *term == Term::And { "And" } else { "Or" };
+                            let c = self.add(op_type, vec![a, b], vec![]);
+                            self.cast(c, DataType::Int64)
+                        }
+                        _ => {
+                            let op_type = match term {
+                                Term::Add => "Add",
+                                Term::Sub => "Sub",
+                                Term::Mul => "Mul",
+                                Term::Div => "Div",
+                                Term::Mod => "Mod",
+                                Term::Min => "Min",
+                                _ => "Max",
+                            };
+                            self.add(op_type, vec![a, b], vec![])
+                        }
+                    }
+                }
+            };
+            stack.push(value);
+        }
+        stack.pop().ok_or_else(|| "empty expression".to_string())
+    }
+
+    /// A single element tensor holding a dyn dim, read from the shape of an input
+    fn dyn_dim(&mut self, c: char) -> OpResult<String> {
+        let (input, axis, value) = self
+            .dyn_dims
+            .get(&c)
+            .cloned()
+            .ok_or_else(|| format!("dyn dim {c} isn't a dim of any input"))?;
+        if let Some(value) = value {
+            return Ok(value);
+        }
+        let shape = self.add("Shape", vec![input], vec![]);
+        let index = self.ints(&[axis as i64]);
+        let value = self.add("Gather", vec![shape, index], vec![]);
+        self.dyn_dims.get_mut(&c).unwrap().2 = Some(value.clone());
+        Ok(value)
+    }
+
+    /// A node's output as seen through a shape tracker: reshaped to the tracker's dims, padded, sliced, expanded along fake dims and permuted
+    fn view(&mut self, node: NodeIndex, st: ShapeTracker) -> OpResult<String> {
+        if let Some(view) = self.views.get(&(node, st)) {
+            return Ok(view.clone());
+        }
+        let (mut x, shape) = self.values[&node].clone();
+        let real = (0..st.len()).filter(|i| !st.fake[*i]).collect_vec();
+        let dims = physical_dims(&st);
+        if dims != shape {
+            let s = self.shape(&dims)?;
+            x = self.add("Reshape", vec![x, s], vec![]);
+        }
+        if real
+            .iter()
+            .any(|i| st.padding[*i].0 != 0 || st.padding[*i].1 != 0)
+        {
+            let pads = real
+                .iter()
+                .map(|i| st.padding[*i].0)
+                .chain(real.iter().map(|i| st.padding[*i].1))
+                .collect_vec();
+            let pads = self.shape(&pads)?;
+            x = self.add("Pad", vec![x, pads], vec![]);
+        }
+        let sliced = real
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| st.mask[**i].0 != 0 || st.mask[**i].1 != i32::MAX)
+            .collect_vec();
+        if !sliced.is_empty() {
+            let starts = sliced.iter().map(|(_, i)| st.mask[**i].0).collect_vec();
+            let ends = sliced
+                .iter()
+                .map(|(_, i)| slice_end(&st, **i))
+                .collect_vec();
+            let axes = sliced.iter().map(|(a, _)| *a as i64).collect_vec();
+            let (starts, ends, axes) = (self.shape(&starts)?, self.shape(&ends)?, self.ints(&axes));
+            x = self.add("Slice", vec![x, starts, ends, axes], vec![]);
+        }
+        if st.fake.contains(&true) {
+            let fake = (0..st.len())
+                .filter(|i| st.fake[*i])
+                .map(|i| i as i64)
+                .collect_vec();
+            let axes = self.ints(&fake);
+            x = self.add("Unsqueeze", vec![x, axes], vec![]);
+            let sizes = (0..st.len())
+                .map(|i| {
+                    if st.fake[i] {
+                        slice_end(&st, i) - st.mask[i].0
+                    } else {
+                        1.into()
+                    }
+                })
+                .collect_vec();
+            let sizes = self.shape(&sizes)?;
+            x = self.add("Expand", vec![x, sizes], vec![]);
+        }
+        if st.indexes.iter().enumerate().any(|(a, i)| a != *i) {
+            let perm = st.indexes.iter().map(|i| *i as i64).collect_vec();
+            x = self.add("Transpose", vec![x], vec![ints_attr("perm", &perm)]);
+        }
+        self.views.insert((node, st), x.clone());
+        Ok(x)
+    }
+
+    /// Export a primitive op, returning its output and output shape
+    fn node(
+        &mut self,
+        node: NodeIndex,
+        sources: &[(NodeIndex, u8, ShapeTracker)],
+    ) -> OpResult<(String, Vec<Expression>)> {
+        let op = self.graph.graph.node_weight(node).unwrap().as_any();
+        let dtype = self.dtypes[&node];
+        if let Some(Constant(value, ..)) = op.downcast_ref::<Constant>() {
+            let x = match value {
+                ConstantValue::Float(f) => self.scalar(*f, dtype),
+                ConstantValue::Expression(e) => {
+                    let x = self.expr(&e.terms)?;
+                    let x = self.cast(x, data_type(dtype));
+                    let scalar = self.ints(&[]);
+                    self.add("Reshape", vec![x, scalar], vec![])
+                }
+            };
+            return Ok((x, vec![]));
+        }
+        let mut inputs = vec![];
+        for (source, _, st) in sources {
+            let mut x = self.view(*source, *st)?;
+            // Binary ops promote their inputs to a common type
+            if self.dtypes[source] != dtype && !op.is::<op::Cast>() {
+                x = self.cast(x, data_type(dtype));
+            }
+            inputs.push(x);
+        }
+        let Some((_, _, st)) = sources.first() else {
+            return Err("op isn't a primitive".to_string());
+        };
+        let mut shape = st.shape().into_iter().map(|d| d.small()).collect_vec();
+        let x = if op.is::<op::Contiguous>() {
+            inputs.remove(0)
+        } else if let Some(op::Cast(to)) = op.downcast_ref::<op::Cast>() {
+            self.cast(inputs.remove(0), data_type(*to))
+        } else if op.is::<op::Log2>() {
+            let ln = self.add("Log", inputs, vec![]);
+            let scale = self.scalar(std::f32::consts::LOG2_E, dtype);
+            self.add("Mul", vec![ln, scale], vec![])
+        } else if op.is::<op::Exp2>() {
+            let scale = self.scalar(std::f32::consts::LN_2, dtype);
+            let x = self.add("Mul", vec![inputs.remove(0), scale], vec![]);
+            self.add("Exp", vec![x], vec![])
+        } else if op.is::<op::Sin>() {
+            self.add("Sin", inputs, vec![])
+        } else if op.is::<op::Sqrt>() {
+            self.add("Sqrt", inputs, vec![])
+        } else if op.is::<op::Recip>() {
+            self.add("Reciprocal", inputs, vec![])
+        } else if op.is::<op::Add>() {
+            self.add("Add", inputs, vec![])
+        } else if op.is::<op::Mul>() {
+            self.add("Mul", inputs, vec![])
+        } else if op.is::<op::Mod>() {
+            self.add("Mod", inputs, vec![int_attr("fmod", 1)])
+        } else if op.is::<op::LessThan>() {
+            let x = self.add("Less", inputs, vec![]);
+            self.cast(x, data_type(dtype))
+        } else if let Some(op::SumReduce(axis)) = op.downcast_ref::<op::SumReduce>() {
+            shape.remove(*axis);
+            let axes = self.ints(&[*axis as i64]);
+            inputs.push(axes);
+            self.add("ReduceSum", inputs, vec![int_attr("keepdims", 0)])
+        } else if let Some(op::MaxReduce(axis)) = op.downcast_ref::<op::MaxReduce>() {
+            shape.remove(*axis);
+            let attrs = vec![ints_attr("axes", &[*axis as i64]), int_attr("keepdims", 0)];
+            self.add("ReduceMax", inputs, attrs)
+        } else {
+            return Err("op isn't a primitive".to_string());
+        };
+        Ok((x, shape))
+    }
+}
+
+/// The dims of a tracker's underlying data, in storage order
+fn physical_dims(st: &ShapeTracker) -> Vec<Expression> {
+    (0..st.len())
+        .filter(|i| !st.fake[*i])
+        .map(|i| st.dims[i])
+        .collect()
+}
+
+/// Where the slice of a padded dim ends, indexed in storage order
+fn slice_end(st: &ShapeTracker, i: usize) -> Expression {
+    let padded = st.dims[i] + st.padding[i].0 + st.padding[i].1;
+    if st.mask[i].1 == i32::MAX {
+        padded
+    } else {
+        padded.min(st.mask[i].1)
+    }
+}
+
+fn value_info(name: String, shape: &[Expression], dtype: DType) -> ValueInfoProto {
+    let dim = shape
+        .iter()
+        .map(|d| Dimension {
+            value: match (d.to_usize(), d.terms.as_slice()) {
+                (Some(d), _) => Some(DimensionValue::DimValue(d as i64)),
+                (None, [Term::Var(c)]) => Some(DimensionValue::DimParam(c.to_string())),
+                _ => None,
+            },
+            ..Default::default()
+        })
+        .collect();
+    ValueInfoProto {
+        name,
+        r#type: Some(TypeProto {
+            tensor_type: Some(TypeProtoTensor {
+                elem_type: data_type(dtype) as i32,
+                shape: Some(TensorShapeProto { dim }),
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+fn data_type(dtype: DType) -> DataType {
+    match dtype {
+        DType::F32 => DataType::Float,
+        DType::F16 => DataType::Float16,
+        DType::Bf16 => DataType::Bfloat16,
+        DType::I32 => DataType::Int32,
+        DType::U8 => DataType::Uint8,
+        DType::Bool => DataType::Bool,
+    }
+}
+
+/// A tensor's data as little-endian bytes of the given type
+fn raw_data(tensor: &Tensor, dtype: DType) -> Vec<u8> {
+    match dtype {
+        DType::F32 => tensor
+            .as_elements::<f32>()
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect(),
+        DType::F16 => tensor
+            .as_elements::<f16>()
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect(),
+        DType::Bf16 => tensor
+            .as_elements::<bf16>()
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect(),
+        DType::I32 => tensor
+            .as_elements::<i32>()
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect(),
+        DType::U8 => tensor.as_elements::<u8>().into_owned(),
+        DType::Bool => tensor
+            .as_elements::<bool>()
+            .iter()
+            .map(|v| *v as u8)
+            .collect(),
+    }
+}
+
+fn int_attr(name: &str, i: i64) -> AttributeProto {
+    AttributeProto {
+        name: name.to_string(),
+        r#type: AttributeType::Int as i32,
+        i,
+        ..Default::default()
+    }
+}
+
+fn ints_attr(name: &str, ints: &[i64]) -> AttributeProto {
+    AttributeProto {
+        name: name.to_string(),
+        r#type: AttributeType::Ints as i32,
+        ints: ints.to_vec(),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,1721 @@
+use itertools::Itertools;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use super::{
+    proto::{
+        AttributeProto, DataType, DimensionValue, GraphProto, NodeProto, TensorProto,
+        ValueInfoProto,
+    },
+    OnnxError, OnnxModel, OpResult, UnsupportedNode,
+};
+use crate::{dispatch_dtype, op, prelude::*};
+
+/// A value passed between nodes
+#[derive(Clone)]
+#[allow(clippy::large_enum_variant)]
+enum Value {
+    Tensor(GraphTensor<()>),
+    /// An integer tensor known while importing, such as a shape, which ops like Reshape need to know. Elements can depend on dyn dims, and it becomes an i32 tensor when used as one
+    Const(Const),
+}
+
+impl Value {
+    fn dims(&self) -> Vec<Expression> {
+        match self {
+            Value::Tensor(t) => dims(t),
+            Value::Const(c) => c.dims.iter().map(|d| (*d).into()).collect(),
+        }
+    }
+}
+
+impl From<GraphTensor<()>> for Value {
+    fn from(t: GraphTensor<()>) -> Self {
+        Value::Tensor(t)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Const {
+    dims: Vec<usize>,
+    data: Vec<Expression>,
+}
+
+impl From<Const> for Value {
+    fn from(c: Const) -> Self {
+        Value::Const(c)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Reduce {
+    Sum,
+    Mean,
+    Max,
+}
+
+pub(super) fn import(
+    graph: &mut Graph,
+    onnx: &GraphProto,
+    opset: i64,
+) -> Result<OnnxModel, OnnxError> {
+    let mut importer = Importer {
+        graph: graph as *mut Graph,
+        opset,
+        values: FxHashMap::default(),
+        materialized: FxHashMap::default(),
+        scalars: FxHashMap::default(),
+    };
+    let mut model = OnnxModel::default();
+    let mut unsupported = vec![];
+    // Values that weren't built because of an unsupported node
+    let mut failed = FxHashSet::default();
+    let mut fail = |name: &str, op_type: &str, reason: String| {
+        unsupported.push(UnsupportedNode {
+            name: name.to_string(),
+            op_type: op_type.to_string(),
+            reason,
+        })
+    };
+
+    for init in &onnx.initializer {
+        match importer.constant(&init.name, init) {
+            Ok(value) => {
+                if let Value::Tensor(t) = value {
+                    model.weights.push((init.name.clone(), t));
+                }
+                importer.values.insert(init.name.clone(), value);
+            }
+            Err(reason) => {
+                fail(&init.name, "Initializer", reason);
+                failed.insert(init.name.clone());
+            }
+        }
+    }
+
+    for input in &onnx.input {
+        // Older models also list initializers as inputs
+        if importer.values.contains_key(&input.name) || failed.contains(&input.name) {
+            continue;
+        }
+        match importer.input(input, &mut model.dyn_dims) {
+            Ok(t) => {
+                model.inputs.push((input.name.clone(), t));
+                importer.values.insert(input.name.clone(), t.into());
+            }
+            Err(reason) => {
+                fail(&input.name, "Input", reason);
+                failed.insert(input.name.clone());
+            }
+        }
+    }
+
+    for node in &onnx.node {
+        let name = if node.name.is_empty() {
+            node.output.first().cloned().unwrap_or_default()
+        } else {
+            node.name.clone()
+        };
+        let result = match node
+            .input
+            .iter()
+            .find(|i| !i.is_empty() && !importer.values.contains_key(*i))
+        {
+            Some(input) if failed.contains(input) => Err(None),
+            Some(input) => Err(Some(format!("input {input} isn't defined"))),
+            None if !node.domain.is_empty() && node.domain != "ai.onnx" => {
+                Err(Some(format!("domain {} isn't supported", node.domain)))
+            }
+            None => importer.node(node).map_err(Some),
+        };
+        match result {
+            Ok(outputs) => {
+                for (output, value) in node.output.iter().zip(outputs) {
+                    if !output.is_empty() {
+                        importer.values.insert(output.clone(), value);
+                    }
+                }
+            }
+            Err(reason) => {
+                if let Some(reason) = reason {
+                    fail(&name, &node.op_type, reason);
+                }
+                failed.extend(node.output.iter().cloned());
+            }
+        }
+    }
+
+    for output in &onnx.output {
+        if failed.contains(&output.name) {
+            continue;
+        }
+        match importer.tensor(&output.name) {
+            Ok(t) => model.outputs.push((output.name.clone(), t)),
+            Err(reason) => fail(&output.name, "Output", reason),
+        }
+    }
+
+    if !unsupported.is_empty() {
+        return Err(OnnxError::Unsupported(unsupported));
+    }
+    Ok(model)
+}
+
+struct Importer {
+    graph: *mut Graph,
+    opset: i64,
+    values: FxHashMap<String, Value>,
+    /// Tensors made from consts that were used as tensors
+    materialized: FxHashMap<String, GraphTensor<()>>,
+    /// Floating point constants with a single element, for ops like Pow and Clip that need to know them
+    scalars: FxHashMap<String, f32>,
+}
+
+impl Importer {
+    #[allow(clippy::mut_from_ref)]
+    fn graph(&self) -> &mut Graph {
+        unsafe { self.graph.as_mut().unwrap() }
+    }
+
+    /// Create a graph input with the shape declared in the model, assigning a dyn dim to each symbolic dimension
+    fn input(
+        &self,
+        info: &ValueInfoProto,
+        dyn_dims: &mut Vec<(String, char)>,
+    ) -> OpResult<GraphTensor<()>> {
+        let tensor_type = info
+            .r#type
+            .as_ref()
+            .and_then(|t| t.tensor_type.as_ref())
+            .ok_or("only tensor inputs are supported")?;
+        let shape = tensor_type.shape.as_ref().ok_or("the input has no shape")?;
+        let mut dyn_dim = |name: String| -> OpResult<Expression> {
+            if let Some((_, c)) = dyn_dims.iter().find(|(n, _)| *n == name) {
+                return Ok((*c).into());
+            }
+            if dyn_dims.len() == 26 {
+                return Err("only 26 symbolic dimensions are supported".to_string());
+            }
+            let c = (b'a' + dyn_dims.len() as u8) as char;
+            dyn_dims.push((name, c));
+            Ok(c.into())
+        };
+        let dims = shape
+            .dim
+            .iter()
+            .enumerate()
+            .map(|(i, d)| match &d.value {
+                Some(DimensionValue::DimValue(v)) if *v >= 0 => Ok((*v as usize).into()),
+                Some(DimensionValue::DimParam(p)) if !p.is_empty() => dyn_dim(p.clone()),
+                _ => dyn_dim(format!("{}[{i}]", info.name)),
+            })
+            .collect::<OpResult<Vec<_>>>()?;
+        let t = self
+            .graph()
+            .named_tensor::<()>(&info.name)
+            .set_dtype(dtype(tensor_type.elem_type)?);
+        Ok(GraphTensor::from_id(t.id, tracker(&dims)?, self.graph))
+    }
+
+    /// Load a constant tensor: floating point ones become weights, integer ones consts
+    fn constant(&mut self, name: &str, tensor: &TensorProto) -> OpResult<Value> {
+        let dtype = dtype(tensor.data_type)?;
+        let (dims, data) = tensor_data(tensor)?;
+        Ok(match data {
+            TensorData::Float(data) => {
+                if data.len() == 1 {
+                    self.scalars.insert(name.to_string(), data[0]);
+                }
+                self.data_tensor(name, &dims, data, dtype)?.into()
+            }
+            TensorData::Int(data) => Const {
+                dims,
+                data: data.into_iter().map(expr).collect(),
+            }
+            .into(),
+        })
+    }
+
+    /// A tensor holding constant data, stored as the given type
+    fn data_tensor(
+        &self,
+        name: &str,
+        dims: &[usize],
+        data: Vec<f32>,
+        dtype: DType,
+    ) -> OpResult<GraphTensor<()>> {
+        let t = self.graph().named_tensor::<()>(name);
+        let dims = dims.iter().map(|d| (*d).into()).collect_vec();
+        let t = GraphTensor::<()>::from_id(t.id, tracker(&dims)?, self.graph);
+        Ok(
+            dispatch_dtype!(dtype, T => t.set(data.into_iter().map(T::from_f32).collect::<Vec<T>>())),
+        )
+    }
+
+    /// Get a value as a tensor, building consts into the graph the first time they're used as one
+    fn tensor(&mut self, name: &str) -> OpResult<GraphTensor<()>> {
+        let c = match self.values.get(name) {
+            Some(Value::Tensor(t)) => return Ok(*t),
+            Some(Value::Const(c)) => c.clone(),
+            None => return Err(format!("{name} isn't produced by any node")),
+        };
+        if let Some(t) = self.materialized.get(name) {
+            return Ok(*t);
+        }
+        let t = self.const_tensor(name, &c)?;
+        self.materialized.insert(name.to_string(), t);
+        Ok(t)
+    }
+
+    /// Build a const into the graph
+    fn const_tensor(&self, name: &str, c: &Const) -> OpResult<GraphTensor<()>> {
+        let dims = c.dims.iter().map(|d| (*d).into()).collect_vec();
+        if let Some(data) = c.data.iter().map(|e| known(*e)).collect::<Option<Vec<_>>>() {
+            return self.data_tensor(
+                name,
+                &c.dims,
+                data.into_iter().map(|i| i as f32).collect(),
+                DType::I32,
+            );
+        }
+        // Elements that depend on dyn dims are computed when the graph runs
+        let elements = c
+            .data
+            .iter()
+            .map(|e| {
+                let mut t = self.graph().constant_expr(*e).no_shape();
+                t.shape.add_dim(0, 1);
+                t
+            })
+            .collect_vec();
+        Ok(reshape(concat(elements, 0)?, &dims)?.cast(DType::I32))
+    }
+
+    fn tensor_input(&mut self, node: &NodeProto, i: usize) -> OpResult<GraphTensor<()>> {
+        match node.input.get(i).filter(|n| !n.is_empty()) {
+            Some(name) => self.tensor(name),
+            None => Err(format!("input {i} is missing")),
+        }
+    }
+
+    fn optional_tensor_input(
+        &mut self,
+        node: &NodeProto,
+        i: usize,
+    ) -> OpResult<Option<GraphTensor<()>>> {
+        match node.input.get(i).filter(|n| !n.is_empty()) {
+            Some(name) => self.tensor(name).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn value(&self, node: &NodeProto, i: usize) -> Option<&Value> {
+        node.input
+            .get(i)
+            .filter(|n| !n.is_empty())
+            .map(|n| &self.values[n])
+    }
+
+    /// Get an input that has to be given
+    fn required_value(&self, node: &NodeProto, i: usize) -> OpResult<&Value> {
+        self.value(node, i)
+            .ok_or_else(|| format!("input {i} is missing"))
+    }
+
+    /// Get an optional input that has to be known while importing
+    fn const_input(&self, node: &NodeProto, i: usize) -> OpResult<Option<Const>> {
+        match self.value(node, i) {
+            Some(Value::Const(c)) => Ok(Some(c.clone())),
+            Some(Value::Tensor(_)) => Err(format!(
+                "input {} has to be a constant integer tensor",
+                node.input[i]
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Get an optional input that has to be known while importing, and can't depend on dyn dims
+    fn ints_input(&self, node: &NodeProto, i: usize) -> OpResult<Option<Vec<i64>>> {
+        self.const_input(node, i)?
+            .map(|c| {
+                c.data
+                    .iter()
+                    .map(|e| known(*e))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| format!("input {} depends on dyn dims", node.input[i]))
+            })
+            .transpose()
+    }
+
+    /// Get an optional input that has to be a constant scalar
+    fn scalar_input(&self, node: &NodeProto, i: usize) -> OpResult<Option<f32>> {
+        let Some(name) = node.input.get(i).filter(|n| !n.is_empty()) else {
+            return Ok(None);
+        };
+        if let Some(s) = self.scalars.get(name) {
+            return Ok(Some(*s));
+        }
+        match self.ints_input(node, i) {
+            Ok(Some(v)) if v.len() == 1 => Ok(Some(v[0] as f32)),
+            _ => Err(format!("input {name} has to be a constant scalar")),
+        }
+    }
+
+    /// Axes given as an input in newer opsets, or an attribute in older ones
+    fn axes(&self, node: &NodeProto, input: usize) -> OpResult<Option<Vec<i64>>> {
+        match self.ints_input(node, input)? {
+            Some(axes) => Ok(Some(axes)),
+            None => Ok(attr_ints(node, "axes")),
+        }
+    }
+
+    fn arange(&self, n: Expression) -> OpResult<GraphTensor<()>> {
+        let t = tracker(&[n])?;
+        if known(n) == Some(1) {
+            return Ok(self.graph().constant(0.).expand_to(t));
+        }
+        Ok(self
+            .graph()
+            .constant(1.)
+            .expand_to::<()>(t)
+            .cumsum_last_dim()
+            - 1.)
+    }
+
+    /// Import a node, returning its outputs. Ops are built in separate functions since GraphTensors are large, and one big function overflows the stack in debug builds
+    fn node(&mut self, node: &NodeProto) -> OpResult<Vec<Value>> {
+        let op_type = node.op_type.as_str();
+        let out: Value = match op_type {
+            "Identity" | "Dropout" => self.required_value(node, 0)?.clone(),
+            "Cast" | "CastLike" => self.cast(node)?,
+            "Constant" => self.constant_node(node)?,
+            "Add" | "Sub" | "Mul" | "Div" | "Mod" | "Equal" | "Less" | "Greater"
+            | "LessOrEqual" | "GreaterOrEqual" | "Max" | "Min" | "Sum" | "Mean" | "Where"
+            | "Pow" => self.elementwise(node)?,
+            "Neg" | "Abs" | "Relu" | "Sigmoid" | "Tanh" | "Exp" | "Log" | "Sqrt" | "Reciprocal"
+            | "Sin" | "Cos" | "Not" | "Softplus" | "LeakyRelu" | "Gelu" | "Clip" => {
+                self.unary(node)?
+            }
+            "MatMul" => matmul(self.tensor_input(node, 0)?, self.tensor_input(node, 1)?)?.into(),
+            "Gemm" => self.gemm(node)?.into(),
+            "Softmax" | "LogSoftmax" | "LayerNormalization" | "BatchNormalization" => {
+                self.normalization(node)?
+            }
+            "ReduceSum" | "ReduceMean" | "ReduceMax" | "ReduceMin" | "GlobalAveragePool"
+            | "GlobalMaxPool" => self.reduction(node)?,
+            "Reshape" | "Flatten" | "Squeeze" | "Unsqueeze" | "Transpose" | "Expand" | "Pad" => {
+                self.movement(node)?
+            }
+            "Concat" => self.concat_node(node)?,
+            "Slice" => self.slice(node)?,
+            "Split" => return self.split(node),
+            "Gather" => self.gather(node)?,
+            "Shape" | "Size" => self.shape_of(node)?,
+            "ConstantOfShape" | "Range" => self.generate(node)?,
+            "Conv" => self.conv(node)?.into(),
+            "MaxPool" | "AveragePool" => self.pool(node)?.into(),
+            _ => return Err("op isn't supported".to_string()),
+        };
+        Ok(vec![out])
+    }
+
+    /// Convert a value to the type given by the `to` attribute, or of another value for CastLike. Consts stay consts if they're cast to an integer type
+    fn cast(&mut self, node: &NodeProto) -> OpResult<Value> {
+        let to = if node.op_type == "Cast" {
+            dtype(attr_int(node, "to", 0) as i32)?
+        } else {
+            match self.required_value(node, 1)? {
+                Value::Tensor(t) => t.dtype(),
+                Value::Const(_) => DType::I32,
+            }
+        };
+        if let (Value::Const(c), DType::I32) = (self.required_value(node, 0)?, to) {
+            return Ok(c.clone().into());
+        }
+        let x = self.tensor_input(node, 0)?;
+        Ok(if x.dtype() == to { x } else { x.cast(to) }.into())
+    }
+
+    /// Elementwise ops with broadcast inputs
+    fn elementwise(&mut self, node: &NodeProto) -> OpResult<Value> {
+        let op_type = node.op_type.as_str();
+        if let (2, Some(Value::Const(a)), Some(Value::Const(b))) =
+            (node.input.len(), self.value(node, 0), self.value(node, 1))
+        {
+            if matches!(
+                op_type,
+                "Add" | "Sub" | "Mul" | "Div" | "Mod" | "Max" | "Min"
+            ) {
+                return Ok(fold_binary(op_type, a, b)?.into());
+            }
+        }
+        Ok(match op_type {
+            "Add" | "Sub" | "Mul" | "Div" | "Mod" => {
+                if op_type == "Mod" && attr_int(node, "fmod", 0) == 0 {
+                    return Err("only fmod = 1 is supported for tensors".to_string());
+                }
+                let (mut a, mut b) =
+                    broadcast(self.tensor_input(node, 0)?, self.tensor_input(node, 1)?)?;
+                // Integer reciprocals round to 0, so integers are divided as floats
+                if op_type == "Div" && !a.dtype().is_float() && !b.dtype().is_float() {
+                    (a, b) = (a.cast(DType::F32), b.cast(DType::F32));
+                }
+                match op_type {
+                    "Add" => a + b,
+                    "Sub" => a - b,
+                    "Mul" => a * b,
+                    "Div" => a / b,
+                    _ => a % b,
+                }
+                .into()
+            }
+            "Equal" | "Less" | "Greater" | "LessOrEqual" | "GreaterOrEqual" => {
+                let (a, b) = broadcast(self.tensor_input(node, 0)?, self.tensor_input(node, 1)?)?;
+                match op_type {
+                    "Equal" => a.equals(b),
+                    "Less" => a.less_than(b),
+                    "Greater" => a.greater_than(b),
+                    "LessOrEqual" => a.less_than_equal(b),
+                    _ => a.greater_than_equal(b),
+                }
+                .into()
+            }
+            "Max" | "Min" | "Sum" | "Mean" => {
+                let mut out = self.tensor_input(node, 0)?;
+                for i in 1..node.input.len() {
+                    let (a, b) = broadcast(out, self.tensor_input(node, i)?)?;
+                    out = match op_type {
+                        "Max" => a.max(b),
+                        "Min" => a.min(b),
+                        _ => a + b,
+                    };
+                }
+                if op_type == "Mean" {
+                    out = out * (1. / node.input.len() as f32);
+                }
+                out.into()
+            }
+            "Where" => {
+                let (c, x, y) = (
+                    self.tensor_input(node, 0)?,
+                    self.tensor_input(node, 1)?,
+                    self.tensor_input(node, 2)?,
+                );
+                let shape = broadcast_shape(&dims(&c), &dims(&x))?;
+                let shape = broadcast_shape(&shape, &dims(&y))?;
+                let (c, x, y) = (
+                    broadcast_to(c, &shape)?,
+                    broadcast_to(x, &shape)?,
+                    broadcast_to(y, &shape)?,
+                );
+                (c * x + (1. - c) * y).into()
+            }
+            "Pow" => {
+                let x = self.tensor_input(node, 0)?;
+                match self.scalar_input(node, 1).ok().flatten() {
+                    Some(1.) => x,
+                    Some(2.) => x * x,
+                    Some(3.) => x * x * x,
+                    Some(0.5) => x.sqrt(),
+                    Some(e) => x.pow(e),
+                    None => {
+                        let (x, e) = broadcast(x, self.tensor_input(node, 1)?)?;
+                        x.pow(e)
+                    }
+                }
+                .into()
+            }
+            _ => unreachable!(),
+        })
+    }
+
+    /// Unary activations and math functions
+    fn unary(&mut self, node: &NodeProto) -> OpResult<Value> {
+        let op_type = node.op_type.as_str();
+        Ok(match op_type {
+            "Neg" | "Abs" | "Relu" | "Sigmoid" | "Tanh" | "Exp" | "Log" | "Sqrt" | "Reciprocal"
+            | "Sin" | "Cos" | "Not" | "Softplus" | "LeakyRelu" | "Gelu" => {
+                let x = self.tensor_input(node, 0)?;
+                match op_type {
+                    "Neg" => -x,
+                    "Abs" => x.abs(),
+                    "Relu" => x.relu(),
+                    "Sigmoid" => x.sigmoid(),
+                    "Tanh" => x.tanh(),
+                    "Exp" => x.exp(),
+                    "Log" => x.ln(),
+                    "Sqrt" => x.sqrt(),
+                    "Reciprocal" => x.recip(),
+                    "Sin" => x.sin(),
+                    "Cos" => x.cos(),
+                    "Not" => 1. - x,
+                    "Softplus" => (x.exp() + 1.).ln(),
+                    "LeakyRelu" => x.leaky_relu(attr_float(node, "alpha", 0.01)),
+                    _ => {
+                        if attr_str(node, "approximate").as_deref() != Some("tanh") {
+                            return Err(
+                                "exact Gelu needs Erf, only approximate = tanh is supported"
+                                    .to_string(),
+                            );
+                        }
+                        let inner = (x + x * x * x * 0.044715) * (2. / std::f32::consts::PI).sqrt();
+                        x * 0.5 * (inner.tanh() + 1.)
+                    }
+                }
+                .into()
+            }
+            "Clip" => {
+                let x = self.tensor_input(node, 0)?;
+                let (min, max) = if self.opset >= 11 {
+                    (self.scalar_input(node, 1)?, self.scalar_input(node, 2)?)
+                } else {
+                    (
+                        attr(node, "min").map(|a| a.f),
+                        attr(node, "max").map(|a| a.f),
+                    )
+                };
+                let x = min.map(|m| x.max_f32(m)).unwrap_or(x);
+                max.map(|m| x.min_f32(m)).unwrap_or(x).into()
+            }
+            _ => unreachable!(),
+        })
+    }
+
+    /// A matmul of optionally transposed inputs, scaled and with a bias added
+    fn gemm(&mut self, node: &NodeProto) -> OpResult<GraphTensor<()>> {
+        let (mut a, mut b) = (self.tensor_input(node, 0)?, self.tensor_input(node, 1)?);
+        if attr_int(node, "transA", 0) != 0 {
+            a.shape.permute(&[1, 0]);
+        }
+        if attr_int(node, "transB", 0) != 0 {
+            b.shape.permute(&[1, 0]);
+        }
+        let mut y = matmul(a, b)?;
+        let alpha = attr_float(node, "alpha", 1.);
+        if alpha != 1. {
+            y = y * alpha;
+        }
+        if let Some(c) = self.optional_tensor_input(node, 2)? {
+            let beta = attr_float(node, "beta", 1.);
+            y = y + broadcast_to(c, &dims(&y))? * beta;
+        }
+        Ok(y)
+    }
+
+    /// Softmax, layer norm and batch norm
+    fn normalization(&mut self, node: &NodeProto) -> OpResult<Value> {
+        let op_type = node.op_type.as_str();
+        Ok(match op_type {
+            "Softmax" | "LogSoftmax" => {
+                let x = self.tensor_input(node, 0)?;
+                let rank = x.shape.len();
+                // Before opset 13 the softmax is over all dims from the axis on
+                let axes = if self.opset >= 13 {
+                    vec![axis(attr_int(node, "axis", -1), rank)?]
+                } else {
+                    (axis(attr_int(node, "axis", 1), rank)?..rank).collect()
+                };
+                let m = x - unreduce(reduce(x, &axes, Reduce::Max), &axes, &dims(&x))?;
+                let exp = m.exp();
+                let sum = unreduce(reduce(exp, &axes, Reduce::Sum), &axes, &dims(&x))?;
+                if op_type == "Softmax" {
+                    exp / sum
+                } else {
+                    m - sum.ln()
+                }
+                .into()
+            }
+            "LayerNormalization" => {
+                let x = self.tensor_input(node, 0)?;
+                let rank = x.shape.len();
+                let axes = (axis(attr_int(node, "axis", -1), rank)?..rank).collect_vec();
+                let d = x - unreduce(reduce(x, &axes, Reduce::Mean), &axes, &dims(&x))?;
+                let var = reduce(d * d, &axes, Reduce::Mean) + attr_float(node, "epsilon", 1e-5);
+                let mut y = d * unreduce(var.sqrt().recip(), &axes, &dims(&x))?;
+                y = y * broadcast_to(self.tensor_input(node, 1)?, &dims(&y))?;
+                if let Some(b) = self.optional_tensor_input(node, 2)? {
+                    y = y + broadcast_to(b, &dims(&y))?;
+                }
+                y.into()
+            }
+            "BatchNormalization" => {
+                if attr_int(node, "training_mode", 0) != 0 {
+                    return Err("training mode isn't supported".to_string());
+                }
+                let x = self.tensor_input(node, 0)?;
+                let shape = dims(&x);
+                // Per channel parameters are broadcast along dim 1
+                let mut channel = |i| -> OpResult<GraphTensor<()>> {
+                    let mut p = self.tensor_input(node, i)?;
+                    for axis in 2..shape.len() {
+                        p = expand_dim(p, axis - 1, 1)?;
+                    }
+                    broadcast_to(p, &shape)
+                };
+                let (scale, bias, mean, var) = (channel(1)?, channel(2)?, channel(3)?, channel(4)?);
+                let inv_std = (var + attr_float(node, "epsilon", 1e-5)).sqrt().recip();
+                ((x - mean) * inv_std * scale + bias).into()
+            }
+            _ => unreachable!(),
+        })
+    }
+
+    /// Reductions over some axes, which are kept as size 1 dims if keepdims is set
+    fn reduction(&mut self, node: &NodeProto) -> OpResult<Value> {
+        let op_type = node.op_type.as_str();
+        Ok(match op_type {
+            "ReduceSum" | "ReduceMean" | "ReduceMax" | "ReduceMin" => {
+                let x = self.tensor_input(node, 0)?;
+                let rank = x.shape.len();
+                let axes = match self.axes(node, 1)? {
+                    Some(axes) if !axes.is_empty() => axes
+                        .into_iter()
+                        .map(|a| axis(a, rank))
+                        .collect::<OpResult<Vec<_>>>()?,
+                    _ if attr_int(node, "noop_with_empty_axes", 0) != 0 => {
+                        return Ok(x.into());
+                    }
+                    _ => (0..rank).collect(),
+                };
+                let axes = axes.into_iter().sorted().dedup().collect_vec();
+                let mut out = match op_type {
+                    "ReduceSum" => reduce(x, &axes, Reduce::Sum),
+                    "ReduceMean" => reduce(x, &axes, Reduce::Mean),
+                    "ReduceMax" => reduce(x, &axes, Reduce::Max),
+                    _ => -reduce(-x, &axes, Reduce::Max),
+                };
+                if attr_int(node, "keepdims", 1) != 0 {
+                    out = unreduce(out, &axes, &vec![Expression::from(1); rank])?;
+                }
+                out.into()
+            }
+            "GlobalAveragePool" | "GlobalMaxPool" => {
+                let x = self.tensor_input(node, 0)?;
+                let axes = (2..x.shape.len()).collect_vec();
+                let kind = if op_type == "GlobalMaxPool" {
+                    Reduce::Max
+                } else {
+                    Reduce::Mean
+                };
+                unreduce(
+                    reduce(x, &axes, kind),
+                    &axes,
+                    &vec![Expression::from(1); x.shape.len()],
+                )?
+                .into()
+            }
+            _ => unreachable!(),
+        })
+    }
+
+    /// Ops that change the shape without touching the data
+    fn movement(&mut self, node: &NodeProto) -> OpResult<Value> {
+        let op_type = node.op_type.as_str();
+        Ok(match op_type {
+            "Reshape" => {
+                let shape = self
+                    .const_input(node, 1)?
+                    .ok_or("the shape input is missing")?
+                    .data;
+                let input = self.required_value(node, 0)?.dims();
+                let allow_zero = attr_int(node, "allowzero", 0) != 0;
+                let mut shape = shape
+                    .iter()
+                    .enumerate()
+                    .map(|(i, d)| match known(*d) {
+                        Some(0) if !allow_zero => input
+                            .get(i)
+                            .copied()
+                            .ok_or_else(|| format!("dim {i} can't be copied from the input")),
+                        _ => Ok(*d),
+                    })
+                    .collect::<OpResult<Vec<_>>>()?;
+                if let Some(infer) = shape.iter().position(|d| known(*d) == Some(-1)) {
+                    // Cancel dims shared with the input so dyn dims don't end up divided by themselves
+                    let mut remaining = input.clone();
+                    let mut rest = vec![];
+                    for (i, d) in shape.iter().enumerate() {
+                        if i == infer {
+                            continue;
+                        }
+                        match remaining.iter().position(|r| r == d) {
+                            Some(p) => {
+                                remaining.remove(p);
+                            }
+                            None => rest.push(*d),
+                        }
+                    }
+                    shape[infer] = product(&remaining) / product(&rest);
+                }
+                match self.required_value(node, 0)? {
+                    Value::Const(c) => Const {
+                        dims: shape
+                            .iter()
+                            .map(|d| d.to_usize())
+                            .collect::<Option<_>>()
+                            .ok_or("a const can't be reshaped to a dynamic shape")?,
+                        data: c.data.clone(),
+                    }
+                    .into(),
+                    Value::Tensor(t) => reshape(*t, &shape)?.into(),
+                }
+            }
+            "Flatten" => {
+                let x = self.tensor_input(node, 0)?;
+                let d = dims(&x);
+                // The axis can be one past the last dim
+                let a = attr_int(node, "axis", 1);
+                let a = if a < 0 { a + d.len() as i64 } else { a };
+                if !(0..=d.len() as i64).contains(&a) {
+                    return Err(format!("axis {a} is out of range for {} dims", d.len()));
+                }
+                let a = a as usize;
+                reshape(x, &[product(&d[..a]), product(&d[a..])])?.into()
+            }
+            "Squeeze" | "Unsqueeze" => {
+                let axes = if self.opset >= 13 {
+                    self.ints_input(node, 1)?
+                } else {
+                    attr_ints(node, "axes")
+                };
+                let mut shape = self.required_value(node, 0)?.dims();
+                let mut x = self.required_value(node, 0)?.clone();
+                if op_type == "Squeeze" {
+                    let axes = match axes {
+                        Some(axes) => axes
+                            .into_iter()
+                            .map(|a| axis(a, shape.len()))
+                            .collect::<OpResult<Vec<_>>>()?,
+                        None => (0..shape.len())
+                            .filter(|i| known(shape[*i]) == Some(1))
+                            .collect(),
+                    };
+                    for a in axes.into_iter().sorted_by(|a, b| b.cmp(a)).dedup() {
+                        shape.remove(a);
+                        if let Value::Tensor(t) = &x {
+                            x = remove_dim(*t, a).into();
+                        }
+                    }
+                } else {
+                    let axes = axes.ok_or("the axes are missing")?;
+                    let rank = shape.len() + axes.len();
+                    let axes = axes
+                        .into_iter()
+                        .map(|a| axis(a, rank))
+                        .collect::<OpResult<Vec<_>>>()?;
+                    for a in axes.into_iter().sorted() {
+                        shape.insert(a, Expression::from(1));
+                        if let Value::Tensor(t) = &x {
+                            x = expand_dim(*t, a, 1)?.into();
+                        }
+                    }
+                }
+                if let Value::Const(c) = &mut x {
+                    c.dims = shape.iter().map(|d| d.to_usize().unwrap()).collect();
+                }
+                x
+            }
+            "Transpose" => {
+                let mut x = self.tensor_input(node, 0)?;
+                let perm = attr_ints(node, "perm")
+                    .unwrap_or_else(|| (0..x.shape.len() as i64).rev().collect())
+                    .into_iter()
+                    .map(|a| axis(a, x.shape.len()))
+                    .collect::<OpResult<Vec<_>>>()?;
+                x.shape.permute(&perm);
+                x.into()
+            }
+            "Expand" => {
+                let x = self.tensor_input(node, 0)?;
+                let shape = self
+                    .const_input(node, 1)?
+                    .ok_or("the shape input is missing")?;
+                let shape = broadcast_shape(&dims(&x), &shape.data)?;
+                broadcast_to(x, &shape)?.into()
+            }
+            "Pad" => {
+                let mut x = self.tensor_input(node, 0)?;
+                let rank = x.shape.len();
+                let (pads, value) = if self.opset >= 11 {
+                    (
+                        self.const_input(node, 1)?
+                            .ok_or("the pads input is missing")?
+                            .data,
+                        self.scalar_input(node, 2)?,
+                    )
+                } else {
+                    (
+                        attr_ints(node, "pads")
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(expr)
+                            .collect(),
+                        attr(node, "value").map(|a| a.f),
+                    )
+                };
+                if attr_str(node, "mode").is_some_and(|m| m != "constant") {
+                    return Err("only constant padding is supported".to_string());
+                }
+                if value.is_some_and(|v| v != 0.) {
+                    return Err("only padding with zeros is supported".to_string());
+                }
+                let axes = match self.ints_input(node, 3)? {
+                    Some(axes) => axes
+                        .into_iter()
+                        .map(|a| axis(a, rank))
+                        .collect::<OpResult<Vec<_>>>()?,
+                    None => (0..rank).collect(),
+                };
+                if pads.len() != 2 * axes.len() {
+                    return Err(format!("expected {} pads", 2 * axes.len()));
+                }
+                if pads.iter().any(|p| known(*p).is_some_and(|p| p < 0)) {
+                    return Err("negative pads aren't supported".to_string());
+                }
+                let mut padding = vec![(Expression::from(0), Expression::from(0)); rank];
+                for (i, a) in axes.iter().enumerate() {
+                    padding[*a] = (pads[i], pads[i + axes.len()]);
+                }
+                // Padding a sliced dim isn't supported by the shape tracker
+                if x.shape.is_sliced() {
+                    x = x.contiguous();
+                }
+                x.shape.pad(&padding);
+                x.into()
+            }
+            _ => unreachable!(),
+        })
+    }
+
+    /// Concatenate values along an axis, folding consts
+    fn concat_node(&mut self, node: &NodeProto) -> OpResult<Value> {
+        let inputs = (0..node.input.len())
+            .map(|i| self.required_value(node, i).cloned())
+            .collect::<OpResult<Vec<_>>>()?;
+        let rank = inputs.first().ok_or("there are no inputs")?.dims().len();
+        let a = axis(attr_int(node, "axis", 0), rank)?;
+        if let Some(consts) = inputs
+            .iter()
+            .map(|v| match v {
+                Value::Const(c) => Some(c),
+                Value::Tensor(_) => None,
+            })
+            .collect::<Option<Vec<_>>>()
+        {
+            Ok(concat_consts(&consts, a).into())
+        } else {
+            let tensors = (0..node.input.len())
+                .map(|i| self.tensor_input(node, i))
+                .collect::<OpResult<Vec<_>>>()?;
+            Ok(concat(tensors, a)?.into())
+        }
+    }
+
+    /// The shape or number of elements of a value
+    fn shape_of(&mut self, node: &NodeProto) -> OpResult<Value> {
+        let op_type = node.op_type.as_str();
+        Ok(match op_type {
+            "Shape" | "Size" => {
+                let shape = self.required_value(node, 0)?.dims();
+                if op_type == "Size" {
+                    Const {
+                        dims: vec![],
+                        data: vec![product(&shape)],
+                    }
+                } else {
+                    let rank = shape.len() as i64;
+                    let bound = |a: i64| (if a < 0 { a + rank } else { a }).clamp(0, rank) as usize;
+                    let start = bound(attr_int(node, "start", 0));
+                    let end = bound(attr_int(node, "end", rank)).max(start);
+                    Const {
+                        dims: vec![end - start],
+                        data: shape[start..end].to_vec(),
+                    }
+                }
+                .into()
+            }
+            _ => unreachable!(),
+        })
+    }
+
+    /// Tensors generated from a shape or range
+    fn generate(&mut self, node: &NodeProto) -> OpResult<Value> {
+        let op_type = node.op_type.as_str();
+        Ok(match op_type {
+            "ConstantOfShape" => {
+                let shape = self
+                    .const_input(node, 0)?
+                    .ok_or("the shape input is missing")?
+                    .data;
+                let value = attr(node, "value")
+                    .and_then(|a| a.t.as_ref())
+                    .map(tensor_data)
+                    .transpose()?
+                    .map(|(_, d)| d)
+                    .unwrap_or(TensorData::Float(vec![0.]));
+                match (
+                    value,
+                    shape
+                        .iter()
+                        .map(|d| d.to_usize())
+                        .collect::<Option<Vec<_>>>(),
+                ) {
+                    (TensorData::Int(v), Some(dims)) => Const {
+                        data: vec![expr(v[0]); dims.iter().product()],
+                        dims,
+                    }
+                    .into(),
+                    (value, _) => {
+                        let v = match value {
+                            TensorData::Float(v) => v[0],
+                            TensorData::Int(v) => v[0] as f32,
+                        };
+                        self.graph()
+                            .constant(v)
+                            .expand_to::<()>(tracker(&shape)?)
+                            .into()
+                    }
+                }
+            }
+            "Range" => {
+                let (start, limit, delta) = (
+                    self.const_input(node, 0)?.ok_or("start is missing")?,
+                    self.const_input(node, 1)?.ok_or("limit is missing")?,
+                    self.const_input(node, 2)?.ok_or("delta is missing")?,
+                );
+                match (
+                    known(start.data[0]),
+                    known(limit.data[0]),
+                    known(delta.data[0]),
+                ) {
+                    (Some(start), Some(limit), Some(delta)) if delta != 0 => {
+                        let data = std::iter::successors(Some(start), |i| Some(i + delta))
+                            .take_while(|i| if delta > 0 { *i < limit } else { *i > limit })
+                            .map(expr)
+                            .collect_vec();
+                        Const {
+                            dims: vec![data.len()],
+                            data,
+                        }
+                        .into()
+                    }
+                    // A range up to a dynamic limit has to be computed when the graph runs
+                    (Some(0), None, Some(1)) => self.arange(limit.data[0])?.into(),
+                    _ => {
+                        return Err(
+                            "only constant ranges, or ranges from 0 in steps of 1, are supported"
+                                .to_string(),
+                        )
+                    }
+                }
+            }
+            _ => unreachable!(),
+        })
+    }
+
+    fn constant_node(&mut self, node: &NodeProto) -> OpResult<Value> {
+        let name = node.output.first().ok_or("the node has no outputs")?;
+        if let Some(t) = attr(node, "value").and_then(|a| a.t.as_ref()) {
+            return self.constant(name, t);
+        }
+        if let Some(a) = attr(node, "value_float") {
+            self.scalars.insert(name.clone(), a.f);
+            return Ok(self.data_tensor(name, &[], vec![a.f], DType::F32)?.into());
+        }
+        if let Some(a) = attr(node, "value_floats") {
+            return Ok(self
+                .data_tensor(name, &[a.floats.len()], a.floats.clone(), DType::F32)?
+                .into());
+        }
+        if let Some(a) = attr(node, "value_int") {
+            return Ok(Const {
+                dims: vec![],
+                data: vec![expr(a.i)],
+            }
+            .into());
+        }
+        if let Some(a) = attr(node, "value_ints") {
+            return Ok(Const {
+                dims: vec![a.ints.len()],
+                data: a.ints.iter().map(|i| expr(*i)).collect(),
+            }
+            .into());
+        }
+        Err("only tensor, float and int constants are supported".to_string())
+    }
+
+    fn slice(&mut self, node: &NodeProto) -> OpResult<Value> {
+        let (starts, ends, axes, steps) = if self.opset >= 10 {
+            (
+                self.const_input(node, 1)?.ok_or("starts are missing")?.data,
+                self.const_input(node, 2)?.ok_or("ends are missing")?.data,
+                self.ints_input(node, 3)?,
+                self.ints_input(node, 4)?,
+            )
+        } else {
+            let ints = |name| attr_ints(node, name).unwrap_or_default();
+            (
+                ints("starts").into_iter().map(expr).collect(),
+                ints("ends").into_iter().map(expr).collect(),
+                attr_ints(node, "axes"),
+                None,
+            )
+        };
+        if steps.is_some_and(|s| s.iter().any(|s| *s != 1)) {
+            return Err("steps other than 1 aren't supported".to_string());
+        }
+        let shape = self.required_value(node, 0)?.dims();
+        let axes = axes.unwrap_or_else(|| (0..starts.len() as i64).collect());
+        let mut ranges = vec![(Expression::from(0), Expression::from(i32::MAX)); shape.len()];
+        for ((a, start), end) in axes.into_iter().zip(starts).zip(ends) {
+            let a = axis(a, shape.len())?;
+            let dim = shape[a];
+            // Negative bounds count from the end, and bounds past the end are clamped to it
+            let bound = |v: Expression| match (known(v), known(dim)) {
+                (Some(v), Some(d)) => expr((if v < 0 { v + d } else { v }).clamp(0, d)),
+                (Some(v), None) if v < 0 => dim + expr(v),
+                (Some(v), None) if v >= i32::MAX as i64 => dim,
+                _ => v,
+            };
+            ranges[a] = (bound(start), bound(end));
+        }
+        if let (Value::Const(c), [(start, end)]) = (self.required_value(node, 0)?, &ranges[..]) {
+            if let (Some(start), Some(end)) = (known(*start), known(*end)) {
+                let end = (end as usize).min(c.data.len());
+                let start = (start as usize).min(end);
+                return Ok(Const {
+                    dims: vec![end - start],
+                    data: c.data[start..end].to_vec(),
+                }
+                .into());
+            }
+        }
+        let mut x = self.tensor_input(node, 0)?;
+        // Slicing an already sliced or padded dim isn't supported by the shape tracker
+        if x.shape.is_sliced() || x.shape.is_padded() {
+            x = x.contiguous();
+        }
+        x.shape.slice(&ranges);
+        Ok(x.into())
+    }
+
+    fn split(&mut self, node: &NodeProto) -> OpResult<Vec<Value>> {
+        let mut x = self.tensor_input(node, 0)?;
+        let shape = dims(&x);
+        let a = axis(attr_int(node, "axis", 0), shape.len())?;
+        let splits = match self
+            .ints_input(node, 1)?
+            .or_else(|| attr_ints(node, "split"))
+        {
+            Some(splits) => splits.into_iter().map(expr).collect_vec(),
+            None => {
+                let n = node.output.len();
+                vec![shape[a] / n; n]
+            }
+        };
+        if x.shape.is_sliced() || x.shape.is_padded() {
+            x = x.contiguous();
+        }
+        let mut start = Expression::from(0);
+        Ok(splits
+            .into_iter()
+            .map(|size| {
+                let mut ranges =
+                    vec![(Expression::from(0), Expression::from(i32::MAX)); shape.len()];
+                ranges[a] = (start, start + size);
+                start += size;
+                let mut part = x;
+                part.shape.slice(&ranges);
+                part.into()
+            })
+            .collect())
+    }
+
+    fn gather(&mut self, node: &NodeProto) -> OpResult<Value> {
+        let (data, indices) = (
+            self.required_value(node, 0)?.clone(),
+            self.required_value(node, 1)?.clone(),
+        );
+        let shape = data.dims();
+        let a = axis(attr_int(node, "axis", 0), shape.len())?;
+        // Wrap negative constant indices
+        let indices = match indices {
+            Value::Const(mut c) => {
+                for i in &mut c.data {
+                    if known(*i).is_some_and(|v| v < 0) {
+                        *i = shape[a] + *i;
+                    }
+                }
+                Value::Const(c)
+            }
+            t => t,
+        };
+        match (data, indices) {
+            (Value::Const(d), Value::Const(i)) if d.dims.len() == 1 => {
+                let data = i
+                    .data
+                    .iter()
+                    .map(|i| known(*i).and_then(|i| d.data.get(i as usize).copied()))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or("const gather indices are out of range or depend on dyn dims")?;
+                Ok(Const { dims: i.dims, data }.into())
+            }
+            (data, Value::Const(i)) if i.dims.is_empty() => {
+                // A single index is a slice
+                let mut x = match data {
+                    Value::Tensor(t) => t,
+                    Value::Const(_) => self.tensor_input(node, 0)?,
+                };
+                if x.shape.is_sliced() || x.shape.is_padded() {
+                    x = x.contiguous();
+                }
+                let mut ranges =
+                    vec![(Expression::from(0), Expression::from(i32::MAX)); shape.len()];
+                ranges[a] = (i.data[0], i.data[0] + 1);
+                x.shape.slice(&ranges);
+                Ok(remove_dim(x, a).into())
+            }
+            (_, indices) => {
+                let data = self.tensor_input(node, 0)?;
+                let indices = match indices {
+                    Value::Tensor(t) => t,
+                    Value::Const(c) => self.const_tensor(&node.input[1], &c)?,
+                };
+                Ok(self.gather_tensor(data, indices, a)?.into())
+            }
+        }
+    }
+
+    /// Gather along an axis by summing over a one-hot encoding of the indices
+    fn gather_tensor(
+        &self,
+        mut data: GraphTensor<()>,
+        indices: GraphTensor<()>,
+        a: usize,
+    ) -> OpResult<GraphTensor<()>> {
+        // Move the gathered axis to the front
+        let rank = data.shape.len();
+        let mut perm = (0..rank).collect_vec();
+        perm.remove(a);
+        perm.insert(0, a);
+        data.shape.permute(&perm);
+        let data_dims = dims(&data);
+        let index_dims = dims(&indices);
+        let r = index_dims.len();
+
+        // [indices..., n]
+        let mut range = self.arange(data_dims[0])?;
+        // Compare integer indices as integers, so large ones aren't rounded
+        if !indices.dtype().is_float() {
+            range = range.cast(indices.dtype());
+        }
+        for (i, d) in index_dims.iter().enumerate() {
+            range = expand_dim(range, i, *d)?;
+        }
+        let mut one_hot = range.equals(expand_dim(indices, r, data_dims[0])?);
+        // [indices..., n, rest...]
+        for (i, d) in data_dims[1..].iter().enumerate() {
+            one_hot = expand_dim(one_hot, r + 1 + i, *d)?;
+        }
+        for (i, d) in index_dims.iter().enumerate() {
+            data = expand_dim(data, i, *d)?;
+        }
+        let mut out = reduce(one_hot * data, &[r], Reduce::Sum);
+        // Put the index dims where the axis was
+        if a > 0 {
+            let perm = (r..r + a)
+                .chain(0..r)
+                .chain(r + a..out.shape.len())
+                .collect_vec();
+            out.shape.permute(&perm);
+        }
+        Ok(out)
+    }
+
+    /// Convolve [N, C, H, W] or [N, C, L] inputs
+    fn conv(&mut self, node: &NodeProto) -> OpResult<GraphTensor<()>> {
+        let (x, w) = (self.tensor_input(node, 0)?, self.tensor_input(node, 1)?);
+        if attr_int(node, "group", 1) != 1 {
+            return Err("grouped convolutions aren't supported".to_string());
+        }
+        let kernel = dims(&w)[2..]
+            .iter()
+            .map(|d| d.to_usize())
+            .collect::<Option<Vec<_>>>()
+            .ok_or("the kernel size has to be known")?;
+        let (mut x, mut w, one_d) = (x, w, x.shape.len() == 3);
+        let window = self.window(node, &kernel, x.shape.len())?;
+        if one_d {
+            x = expand_dim(x, 2, 1)?;
+            w = expand_dim(w, 2, 1)?;
+        }
+        let mut p = windows(x, window)?;
+        let [n, c, oh, ow, kh, kw] = dims(&p)[..] else {
+            unreachable!()
+        };
+        // [N, out H, out W, C, kernel H, kernel W]
+        p.shape.permute(&[0, 2, 3, 1, 4, 5]);
+        let p = reshape(p, &[n, oh * ow, c * kh * kw])?;
+        let m = dims(&w)[0];
+        let mut w = reshape(w, &[m, c * kh * kw])?;
+        w.shape.permute(&[1, 0]);
+        let mut y = matmul(p, w)?;
+        y.shape.permute(&[0, 2, 1]);
+        let mut y = reshape(y, &[n, m, oh, ow])?;
+        if let Some(b) = self.optional_tensor_input(node, 2)? {
+            let b = expand_dim(expand_dim(b, 1, 1)?, 2, 1)?;
+            y = y + broadcast_to(b, &dims(&y))?;
+        }
+        Ok(if one_d { remove_dim(y, 2) } else { y })
+    }
+
+    /// Max or average pool [N, C, H, W] or [N, C, L] inputs
+    fn pool(&mut self, node: &NodeProto) -> OpResult<GraphTensor<()>> {
+        let mut x = self.tensor_input(node, 0)?;
+        if attr_int(node, "ceil_mode", 0) != 0 {
+            return Err("ceil_mode isn't supported".to_string());
+        }
+        let kernel = attr_ints(node, "kernel_shape")
+            .ok_or("kernel_shape is missing")?
+            .into_iter()
+            .map(|k| k as usize)
+            .collect_vec();
+        let one_d = x.shape.len() == 3;
+        let window = self.window(node, &kernel, x.shape.len())?;
+        let padded = window.2.iter().any(|p| *p != 0);
+        let kind = if node.op_type == "MaxPool" {
+            if padded {
+                return Err("padded max pooling isn't supported".to_string());
+            }
+            Reduce::Max
+        } else {
+            if padded && attr_int(node, "count_include_pad", 0) == 0 {
+                return Err("padded average pooling needs count_include_pad = 1".to_string());
+            }
+            Reduce::Mean
+        };
+        if one_d {
+            x = expand_dim(x, 2, 1)?;
+        }
+        let y = reduce(windows(x, window)?, &[4, 5], kind);
+        Ok(if one_d { remove_dim(y, 2) } else { y })
+    }
+
+    /// The kernel, strides and pads of a 1D or 2D window op, with 1D windows made 2D
+    fn window(&self, node: &NodeProto, kernel: &[usize], rank: usize) -> OpResult<Window> {
+        if !(3..=4).contains(&rank) || kernel.len() != rank - 2 {
+            return Err("only 1D and 2D windows are supported".to_string());
+        }
+        if attr_ints(node, "dilations").is_some_and(|d| d.iter().any(|d| *d != 1)) {
+            return Err("dilations other than 1 aren't supported".to_string());
+        }
+        if let Some(pad) = attr_str(node, "auto_pad").filter(|p| p != "NOTSET" && p != "VALID") {
+            return Err(format!("auto_pad {pad} isn't supported"));
+        }
+        let ints = |name, default| {
+            attr_ints(node, name)
+                .map(|v| v.into_iter().map(|i| i as usize).collect_vec())
+                .unwrap_or(vec![
+                    default;
+                    kernel.len() * if name == "pads" { 2 } else { 1 }
+                ])
+        };
+        let (strides, pads) = (ints("strides", 1), ints("pads", 0));
+        Ok(if kernel.len() == 1 {
+            ([1, kernel[0]], [1, strides[0]], [0, pads[0], 0, pads[1]])
+        } else {
+            (
+                [kernel[0], kernel[1]],
+                [strides[0], strides[1]],
+                [pads[0], pads[1], pads[2], pads[3]],
+            )
+        })
+    }
+}
+
+/// Kernel size, strides and begin and end pads of a 2D window op
+type Window = ([usize; 2], [usize; 2], [usize; 4]);
+
+/// Windows of a [N, C, H, W] tensor as [N, C, out H, out W, kernel H, kernel W]
+fn windows(mut x: GraphTensor<()>, (kernel, strides, pads): Window) -> OpResult<GraphTensor<()>> {
+    if pads.iter().any(|p| *p != 0) {
+        if x.shape.is_sliced() {
+            x = x.contiguous();
+        }
+        let zero = || Expression::from(0);
+        x.shape.pad(&[
+            (zero(), zero()),
+            (zero(), zero()),
+            (pads[0].into(), pads[2].into()),
+            (pads[1].into(), pads[3].into()),
+        ]);
+    }
+    let mut x = x
+        .contiguous()
+        .pool_last_dim::<()>(kernel[1].into(), strides[1].into(), 0);
+    x.shape.permute(&[0, 1, 3, 4, 2]);
+    let mut x = x.pool_last_dim::<()>(kernel[0].into(), strides[0].into(), 0);
+    x.shape.permute(&[0, 1, 4, 2, 5, 3]);
+    Ok(x)
+}
+
+/// The logical shape of a tensor
+fn dims(t: &GraphTensor<()>) -> Vec<Expression> {
+    t.shape.shape().iter().map(|d| d.small()).collect()
+}
+
+fn product(dims: &[Expression]) -> Expression {
+    dims.iter().fold(Expression::from(1), |a, d| a * *d)
+}
+
+/// The value of an expression that doesn't depend on dyn dims
+fn known(e: Expression) -> Option<i64> {
+    e.to_usize().map(|i| i as i64)
+}
+
+fn expr(i: i64) -> Expression {
+    (i.clamp(i32::MIN as i64, i32::MAX as i64) as i32).into()
+}
+
+fn tracker(dims: &[Expression]) -> OpResult<ShapeTracker> {
+    if dims.len() > 6 {
+        return Err("tensors with more than 6 dims aren't supported".to_string());
+    }
+    Ok(ShapeTracker::new(dims))
+}
+
+fn axis(a: i64, rank: usize) -> OpResult<usize> {
+    let i = if a < 0 { a + rank as i64 } else { a };
+    if !(0..rank as i64).contains(&i) {
+        return Err(format!("axis {a} is out of range for {rank} dims"));
+    }
+    Ok(i as usize)
+}
+
+/// Insert a broadcasted dim
+fn expand_dim(
+    mut t: GraphTensor<()>,
+    axis: usize,
+    size: impl Into<Expression>,
+) -> OpResult<GraphTensor<()>> {
+    if t.shape.len() == 6 {
+        return Err("tensors with more than 6 dims aren't supported".to_string());
+    }
+    t.shape.expand(axis, size);
+    Ok(t)
+}
+
+/// Remove a dim of size 1
+fn remove_dim(mut t: GraphTensor<()>, axis: usize) -> GraphTensor<()> {
+    // A sliced or padded dim of size 1 can be bigger in memory
+    if !t.shape.fake[t.shape.indexes[axis]] && (t.shape.is_sliced() || t.shape.is_padded()) {
+        t = t.contiguous();
+    }
+    t.shape.remove_dim(axis);
+    t
+}
+
+fn reshape(t: GraphTensor<()>, shape: &[Expression]) -> OpResult<GraphTensor<()>> {
+    let t = t.contiguous();
+    Ok(GraphTensor::from_id(t.id, tracker(shape)?, t.graph_ref))
+}
+
+/// The shape two shapes broadcast to, aligning their last dims
+fn broadcast_shape(a: &[Expression], b: &[Expression]) -> OpResult<Vec<Expression>> {
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let offset = long.len() - short.len();
+    long.iter()
+        .enumerate()
+        .map(|(i, l)| {
+            let Some(s) = i.checked_sub(offset).map(|i| short[i]) else {
+                return Ok(*l);
+            };
+            match (known(*l), known(s)) {
+                (Some(1), _) => Ok(s),
+                (_, Some(1)) => Ok(*l),
+                (Some(x), Some(y)) if x != y => Err(format!(
+                    "shapes {a:?} and {b:?} can't be broadcast together"
+                )),
+                // Dynamic dims are assumed to match
+                _ => Ok(*l),
+            }
+        })
+        .collect()
+}
+
+/// Broadcast a tensor to a shape its own shape broadcasts to
+fn broadcast_to(mut t: GraphTensor<()>, shape: &[Expression]) -> OpResult<GraphTensor<()>> {
+    let offset = shape
+        .len()
+        .checked_sub(t.shape.len())
+        .ok_or_else(|| format!("{:?} can't be broadcast to {shape:?}", dims(&t)))?;
+    for (i, d) in shape[..offset].iter().enumerate() {
+        t = expand_dim(t, i, *d)?;
+    }
+    for (i, d) in dims(&t).into_iter().enumerate() {
+        if known(d) == Some(1) && known(shape[i]) != Some(1) {
+            t = expand_dim(remove_dim(t, i), i, shape[i])?;
+        }
+    }
+    Ok(t)
+}
+
+fn broadcast(
+    a: GraphTensor<()>,
+    b: GraphTensor<()>,
+) -> OpResult<(GraphTensor<()>, GraphTensor<()>)> {
+    let shape = broadcast_shape(&dims(&a), &dims(&b))?;
+    Ok((broadcast_to(a, &shape)?, broadcast_to(b, &shape)?))
+}
+
+/// Reduce along sorted axes, removing them
+fn reduce(mut t: GraphTensor<()>, axes: &[usize], kind: Reduce) -> GraphTensor<()> {
+    let mut size = BigExpression::from(1);
+    for &a in axes.iter().rev() {
+        size *= dims(&t)[a];
+        let new_id = match kind {
+            Reduce::Max => t.graph().add_op(op::MaxReduce(a)),
+            _ => t.graph().add_op(op::SumReduce(a)),
+        }
+        .input(t.id, 0, t.shape)
+        .finish();
+        t.shape.remove_dim(a);
+        t = GraphTensor::from_id(new_id, t.shape.contiguous(), t.graph_ref);
+    }
+    if kind == Reduce::Mean {
+        t = t * t.graph().constant_expr(size).recip().expand_to(t.shape);
+    }
+    t
+}
+
+/// Broadcast reduced axes back to the sizes in `shape`
+fn unreduce(
+    mut t: GraphTensor<()>,
+    axes: &[usize],
+    shape: &[Expression],
+) -> OpResult<GraphTensor<()>> {
+    for &a in axes {
+        t = expand_dim(t, a, shape[a])?;
+    }
+    Ok(t)
+}
+
+/// Batched matrix multiply with numpy semantics
+fn matmul(mut a: GraphTensor<()>, mut b: GraphTensor<()>) -> OpResult<GraphTensor<()>> {
+    let (a_vec, b_vec) = (a.shape.len() == 1, b.shape.len() == 1);
+    if a_vec {
+        a = expand_dim(a, 0, 1)?;
+    }
+    if b_vec {
+        b = expand_dim(b, 1, 1)?;
+    }
+    let (a_dims, b_dims) = (dims(&a), dims(&b));
+    let (m, k) = (a_dims[a_dims.len() - 2], a_dims[a_dims.len() - 1]);
+    let n = b_dims[b_dims.len() - 1];
+    let batch = broadcast_shape(&a_dims[..a_dims.len() - 2], &b_dims[..b_dims.len() - 2])?;
+    let r = batch.len() + 2;
+    // [batch..., m, n, k]
+    let a = expand_dim(broadcast_to(a, &[&batch[..], &[m, k]].concat())?, r - 1, n)?;
+    let mut b = broadcast_to(b, &[&batch[..], &[k, n]].concat())?;
+    let mut perm = (0..r).collect_vec();
+    perm.swap(r - 2, r - 1);
+    b.shape.permute(&perm);
+    let b = expand_dim(b, r - 2, m)?;
+    let mut out = reduce(a * b, &[r], Reduce::Sum);
+    if b_vec {
+        out = remove_dim(out, r - 1);
+    }
+    if a_vec {
+        out = remove_dim(out, r - 2);
+    }
+    Ok(out)
+}
+
+/// Concatenate tensors by padding each out to the full size and adding them
+fn concat(tensors: Vec<GraphTensor<()>>, axis: usize) -> OpResult<GraphTensor<()>> {
+    let sizes = tensors.iter().map(|t| dims(t)[axis]).collect_vec();
+    let total = sizes.iter().fold(Expression::from(0), |a, s| a + *s);
+    let mut offset = Expression::from(0);
+    let mut out: Option<GraphTensor<()>> = None;
+    for (mut t, size) in tensors.into_iter().zip(sizes) {
+        if t.shape.is_sliced() || t.shape.fake[t.shape.indexes[axis]] {
+            t = t.contiguous();
+        }
+        let mut padding = vec![(Expression::from(0), Expression::from(0)); t.shape.len()];
+        padding[axis] = (offset, total - offset - size);
+        t.shape.pad(&padding);
+        offset += size;
+        out = Some(match out {
+            Some(o) => o + t,
+            None => t,
+        });
+    }
+    out.ok_or_else(|| "there are no inputs".to_string())
+}
+
+fn concat_consts(consts: &[&Const], axis: usize) -> Const {
+    let mut dims = consts[0].dims.clone();
+    dims[axis] = consts.iter().map(|c| c.dims[axis]).sum();
+    let outer = dims[..axis].iter().product::<usize>();
+    let mut data = vec![];
+    for o in 0..outer {
+        for c in consts {
+            let chunk = c.dims[axis..].iter().product::<usize>();
+            data.extend_from_slice(&c.data[o * chunk..(o + 1) * chunk]);
+        }
+    }
+    Const { dims, data }
+}
+
+/// Elementwise arithmetic on consts, where one of them can be a single element
+fn fold_binary(op_type: &str, a: &Const, b: &Const) -> OpResult<Const> {
+    let dims = match (a.data.len(), b.data.len()) {
+        (x, y) if x == y => a.dims.clone(),
+        (1, _) => b.dims.clone(),
+        (_, 1) => a.dims.clone(),
+        _ => return Err("const broadcasting beyond single elements isn't supported".to_string()),
+    };
+    let n = a.data.len().max(b.data.len());
+    let data = (0..n)
+        .map(|i| {
+            let (x, y) = (a.data[i % a.data.len()], b.data[i % b.data.len()]);
+            match op_type {
+                "Add" => x + y,
+                "Sub" => x - y,
+                "Mul" => x * y,
+                "Div" => x / y,
+                "Mod" => x % y,
+                "Max" => x.max(y),
+                _ => x.min(y),
+            }
+        })
+        .collect();
+    Ok(Const { dims, data })
+}
+
+/// The luminal type of an ONNX element type. Doubles are narrowed to f32 and integers wider or narrower than a byte to i32
+fn dtype(data_type: i32) -> OpResult<DType> {
+    Ok(match DataType::try_from(data_type) {
+        Ok(DataType::Float | DataType::Double) => DType::F32,
+        Ok(DataType::Float16) => DType::F16,
+        Ok(DataType::Bfloat16) => DType::Bf16,
+        Ok(
+            DataType::Int8
+            | DataType::Int16
+            | DataType::Uint16
+            | DataType::Int32
+            | DataType::Int64
+            | DataType::Uint32
+            | DataType::Uint64,
+        ) => DType::I32,
+        Ok(DataType::Uint8) => DType::U8,
+        Ok(DataType::Bool) => DType::Bool,
+        Ok(d) => return Err(format!("{d:?} tensors aren't supported")),
+        Err(_) => return Err(format!("unknown data type {data_type}")),
+    })
+}
+
+/// The elements of a constant tensor: f32s for floating point types, i64s for integer and bool types
+enum TensorData {
+    Float(Vec<f32>),
+    Int(Vec<i64>),
+}
+
+fn tensor_data(t: &TensorProto) -> OpResult<(Vec<usize>, TensorData)> {
+    if t.data_location == 1 {
+        return Err("external data isn't supported".to_string());
+    }
+    fn le<const N: usize, T>(raw: &[u8], f: fn([u8; N]) -> T) -> Vec<T> {
+        raw.chunks_exact(N)
+            .map(|c| f(c.try_into().unwrap()))
+            .collect()
+    }
+    let raw = &t.raw_data;
+    let small_ints = |f: fn(&[u8]) -> Vec<i64>| {
+        if raw.is_empty() {
+            t.int32_data.iter().map(|i| *i as i64).collect()
+        } else {
+            f(raw)
+        }
+    };
+    let data = match DataType::try_from(t.data_type) {
+        Ok(DataType::Float) if raw.is_empty() => TensorData::Float(t.float_data.clone()),
+        Ok(DataType::Float) => TensorData::Float(le(raw, f32::from_le_bytes)),
+        Ok(DataType::Double) if raw.is_empty() => {
+            TensorData::Float(t.double_data.iter().map(|d| *d as f32).collect())
+        }
+        Ok(DataType::Double) => TensorData::Float(le(raw, |b| f64::from_le_bytes(b) as f32)),
+        Ok(DataType::Float16) if raw.is_empty() => TensorData::Float(
+            t.int32_data
+                .iter()
+                .map(|b| f16::from_bits(*b as u16).to_f32())
+                .collect(),
+        ),
+        Ok(DataType::Float16) => TensorData::Float(le(raw, |b| f16::from_le_bytes(b).to_f32())),
+        Ok(DataType::Bfloat16) if raw.is_empty() => TensorData::Float(
+            t.int32_data
+                .iter()
+                .map(|b| bf16::from_bits(*b as u16).to_f32())
+                .collect(),
+        ),
+        Ok(DataType::Bfloat16) => TensorData::Float(le(raw, |b| bf16::from_le_bytes(b).to_f32())),
+        Ok(DataType::Int64) if raw.is_empty() => TensorData::Int(t.int64_data.clone()),
+        Ok(DataType::Int64) => TensorData::Int(le(raw, i64::from_le_bytes)),
+        Ok(DataType::Int32) => {
+            TensorData::Int(small_ints(|r| le(r, |b| i32::from_le_bytes(b) as i64)))
+        }
+        Ok(DataType::Int16) => {
+            TensorData::Int(small_ints(|r| le(r, |b| i16::from_le_bytes(b) as i64)))
+        }
+        Ok(DataType::Uint16) => {
+            TensorData::Int(small_ints(|r| le(r, |b| u16::from_le_bytes(b) as i64)))
+        }
+        Ok(DataType::Int8) => {
+            TensorData::Int(small_ints(|r| le(r, |b: [u8; 1]| b[0] as i8 as i64)))
+        }
+        Ok(DataType::Uint8 | DataType::Bool) => {
+            TensorData::Int(small_ints(|r| le(r, |b: [u8; 1]| b[0] as i64)))
+        }
+        Ok(DataType::Uint32) if raw.is_empty() => {
+            TensorData::Int(t.uint64_data.iter().map(|i| *i as i64).collect())
+        }
+        Ok(DataType::Uint32) => TensorData::Int(le(raw, |b| u32::from_le_bytes(b) as i64)),
+        Ok(DataType::Uint64) if raw.is_empty() => {
+            TensorData::Int(t.uint64_data.iter().map(|i| *i as i64).collect())
+        }
+        Ok(DataType::Uint64) => TensorData::Int(le(raw, |b| u64::from_le_bytes(b) as i64)),
+        Ok(d) => return Err(format!("{d:?} tensors aren't supported")),
+        Err(_) => return Err(format!("unknown data type {}", t.data_type)),
+    };
+    let dims = t.dims.iter().map(|d| *d as usize).collect_vec();
+    let len = match &data {
+        TensorData::Float(d) => d.len(),
+        TensorData::Int(d) => d.len(),
+    };
+    if len != dims.iter().product::<usize>() {
+        return Err(format!(
+            "the tensor has {len} elements but its shape {dims:?} needs {}",
+            dims.iter().product::<usize>()
+        ));
+    }
+    Ok((dims, data))
+}
+
+fn attr<'a>(node: &'a NodeProto, name: &str) -> Option<&'a AttributeProto> {
+    node.attribute.iter().find(|a| a.name == name)
+}
+
+fn attr_int(node: &NodeProto, name: &str, default: i64) -> i64 {
+    attr(node, name).map(|a| a.i).unwrap_or(default)
+}
+
+fn attr_float(node: &NodeProto, name: &str, default: f32) -> f32 {
+    attr(node, name).map(|a| a.f).unwrap_or(default)
+}
+
+fn attr_ints(node: &NodeProto, name: &str) -> Option<Vec<i64>> {
+    attr(node, name).map(|a| a.ints.clone())
+}
+
+fn attr_str(node: &NodeProto, name: &str) -> Option<String> {
+    attr(node, name).map(|a| String::from_utf8_lossy(&a.s).into_owned())
+}
@@ -0,0 +1,694 @@
+// Conversion between luminal graphs and ONNX models
+mod export;
+mod import;
+pub mod proto;
+
+use std::{fmt::Display, path::Path};
+
+use prost::Message;
+
+use crate::prelude::*;
+
+type OpResult<T> = Result<T, String>;
+
+/// An error encountered while importing or exporting an ONNX model
+#[derive(Debug)]
+pub enum OnnxError {
+    Io(std::io::Error),
+    /// The file isn't a valid ONNX protobuf
+    Decode(prost::DecodeError),
+    /// The model has no graph
+    MissingGraph,
+    /// Parts of the model that couldn't be imported or exported, in graph order. Nodes that only failed because an earlier node did aren't listed
+    Unsupported(Vec<UnsupportedNode>),
+}
+
+/// A node, initializer or graph input or output that couldn't be imported or exported
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsupportedNode {
+    /// The node's name, or the value's name for initializers, inputs and outputs. Exported nodes are named by their index in the luminal graph
+    pub name: String,
+    /// The node's op type, or `Initializer`, `Input` or `Output`
+    pub op_type: String,
+    pub reason: String,
+}
+
+impl Display for UnsupportedNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}): {}", self.op_type, self.name, self.reason)
+    }
+}
+
+impl Display for OnnxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OnnxError::Io(e) => write!(f, "{e}"),
+            OnnxError::Decode(e) => write!(f, "Invalid ONNX file: {e}"),
+            OnnxError::MissingGraph => write!(f, "ONNX model has no graph"),
+            OnnxError::Unsupported(nodes) => {
+                write!(f, "Unsupported ONNX nodes:")?;
+                for node in nodes {
+                    write!(f, "\n  {node}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for OnnxError {}
+
+impl From<std::io::Error> for OnnxError {
+    fn from(e: std::io::Error) -> Self {
+        OnnxError::Io(e)
+    }
+}
+
+impl From<prost::DecodeError> for OnnxError {
+    fn from(e: prost::DecodeError) -> Self {
+        OnnxError::Decode(e)
+    }
+}
+
+/// An ONNX model built into a graph. Tensors keep their element types, except that doubles become f32 and other integer types i32
+#[derive(Clone, Default)]
+pub struct OnnxModel {
+    /// The graph inputs in model order, not including initializers
+    pub inputs: Vec<(String, GraphTensor<()>)>,
+    /// The graph outputs in model order
+    pub outputs: Vec<(String, GraphTensor<()>)>,
+    /// The floating point initializers, loaded as weights
+    pub weights: Vec<(String, GraphTensor<()>)>,
+    /// The dyn dim assigned to each symbolic input dimension. Unnamed dimensions are named `input[axis]`
+    pub dyn_dims: Vec<(String, char)>,
+}
+
+impl OnnxModel {
+    pub fn input(&self, name: &str) -> Option<GraphTensor<()>> {
+        find(&self.inputs, name)
+    }
+
+    pub fn output(&self, name: &str) -> Option<GraphTensor<()>> {
+        find(&self.outputs, name)
+    }
+
+    /// The dyn dim a symbolic input dimension was assigned
+    pub fn dyn_dim(&self, name: &str) -> Option<char> {
+        self.dyn_dims
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, c)| *c)
+    }
+}
+
+fn find(tensors: &[(String, GraphTensor<()>)], name: &str) -> Option<GraphTensor<()>> {
+    tensors.iter().find(|(n, _)| n == name).map(|(_, t)| *t)
+}
+
+impl SerializeModule for OnnxModel {
+    fn serialize(&self, s: &mut Serializer) {
+        for (name, weight) in &self.weights {
+            s.tensor(name, *weight);
+        }
+    }
+}
+
+/// Import an ONNX model file into the graph
+pub fn import_onnx<P: AsRef<Path>>(graph: &mut Graph, path: P) -> Result<OnnxModel, OnnxError> {
+    import_onnx_model(graph, &proto::ModelProto::decode(&*std::fs::read(path)?)?)
+}
+
+/// Import a decoded ONNX model into the graph. If any node is unsupported, all of them are reported and the graph is left partially built
+pub fn import_onnx_model(
+    graph: &mut Graph,
+    model: &proto::ModelProto,
+) -> Result<OnnxModel, OnnxError> {
+    let opset = model
+        .opset_import
+        .iter()
+        .find(|o| o.domain.is_empty() || o.domain == "ai.onnx")
+        .map(|o| o.version)
+        .unwrap_or(1);
+    import::import(
+        graph,
+        model.graph.as_ref().ok_or(OnnxError::MissingGraph)?,
+        opset,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::proto::{
+        AttributeProto, AttributeType, DataType, Dimension, DimensionValue, GraphProto, ModelProto,
+        NodeProto, OperatorSetIdProto, TensorProto, TensorShapeProto, TypeProto, TypeProtoTensor,
+        ValueInfoProto,
+    };
+    crate::test_imports!();
+
+    fn node(
+        op_type: &str,
+        inputs: &[&str],
+        outputs: &[&str],
+        attrs: Vec<AttributeProto>,
+    ) -> NodeProto {
+        NodeProto {
+            op_type: op_type.to_string(),
+            input: inputs.iter().map(|s| s.to_string()).collect(),
+            output: outputs.iter().map(|s| s.to_string()).collect(),
+            attribute: attrs,
+            ..Default::default()
+        }
+    }
+
+    fn int_attr(name: &str, i: i64) -> AttributeProto {
+        AttributeProto {
+            name: name.to_string(),
+            r#type: AttributeType::Int as i32,
+            i,
+            ..Default::default()
+        }
+    }
+
+    fn ints_attr(name: &str, ints: &[i64]) -> AttributeProto {
+        AttributeProto {
+            name: name.to_string(),
+            r#type: AttributeType::Ints as i32,
+            ints: ints.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn floats(name: &str, dims: &[i64], data: Vec<f32>) -> TensorProto {
+        TensorProto {
+            name: name.to_string(),
+            dims: dims.to_vec(),
+            data_type: DataType::Float as i32,
+            float_data: data,
+            ..Default::default()
+        }
+    }
+
+    /// An int64 tensor stored as raw bytes
+    fn ints(name: &str, dims: &[i64], data: &[i64]) -> TensorProto {
+        TensorProto {
+            name: name.to_string(),
+            dims: dims.to_vec(),
+            data_type: DataType::Int64 as i32,
+            raw_data: data.iter().flat_map(|i| i.to_le_bytes()).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// A float tensor value, with numeric dims fixed and others symbolic
+    fn value(name: &str, dims: &[&str]) -> ValueInfoProto {
+        typed_value(name, dims, DataType::Float)
+    }
+
+    fn typed_value(name: &str, dims: &[&str], elem_type: DataType) -> ValueInfoProto {
+        let dim = dims
+            .iter()
+            .map(|d| Dimension {
+                value: Some(match d.parse() {
+                    Ok(v) => DimensionValue::DimValue(v),
+                    Err(_) => DimensionValue::DimParam(d.to_string()),
+                }),
+                ..Default::default()
+            })
+            .collect();
+        ValueInfoProto {
+            name: name.to_string(),
+            r#type: Some(TypeProto {
+                tensor_type: Some(TypeProtoTensor {
+                    elem_type: elem_type as i32,
+                    shape: Some(TensorShapeProto { dim }),
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn model(
+        node: Vec<NodeProto>,
+        initializer: Vec<TensorProto>,
+        input: Vec<ValueInfoProto>,
+        outputs: &[&str],
+    ) -> ModelProto {
+        ModelProto {
+            ir_version: 8,
+            opset_import: vec![OperatorSetIdProto {
+                domain: String::new(),
+                version: 17,
+            }],
+            graph: Some(GraphProto {
+                node,
+                initializer,
+                input,
+                output: outputs.iter().map(|o| value(o, &[])).collect(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_mlp() {
+        let (x_data, w1, b1, w2, b2) = (
+            random_vec(6),
+            random_vec(12),
+            random_vec(4),
+            random_vec(8),
+            random_vec(2),
+        );
+        let onnx = model(
+            vec![
+                node("MatMul", &["x", "w1"], &["h"], vec![]),
+                node("Add", &["h", "b1"], &["h1"], vec![]),
+                node("Relu", &["h1"], &["h2"], vec![]),
+                node(
+                    "Gemm",
+                    &["h2", "w2", "b2"],
+                    &["y"],
+                    vec![int_attr("transB", 1)],
+                ),
+                node("Softmax", &["y"], &["out"], vec![]),
+            ],
+            vec![
+                floats("w1", &[3, 4], w1.clone()),
+                floats("b1", &[4], b1.clone()),
+                floats("w2", &[2, 4], w2.clone()),
+                floats("b2", &[2], b2.clone()),
+            ],
+            vec![value("x", &["batch", "3"])],
+            &["out"],
+        );
+        let path = std::env::temp_dir().join(format!("luminal_{}.onnx", uuid::Uuid::new_v4()));
+        std::fs::write(&path, prost::Message::encode_to_vec(&onnx)).unwrap();
+
+        let mut cx = Graph::new();
+        let m = import_onnx(&mut cx, &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            m.weights
+                .iter()
+                .map(|(n, _)| n.as_str())
+                .collect::<Vec<_>>(),
+            ["w1", "b1", "w2", "b2"]
+        );
+        m.input("x").unwrap().set(x_data.clone());
+        cx.set_dyn_dim(m.dyn_dim("batch").unwrap(), 2);
+        let out = m.output("out").unwrap().retrieve();
+        cx.execute();
+
+        let mut cx2 = Graph::new();
+        let x = cx2.tensor::<R2<2, 3>>().set(x_data);
+        let w1 = cx2.tensor::<R2<3, 4>>().set(w1);
+        let b1 = cx2.tensor::<R1<4>>().set(b1);
+        let w2 = cx2.tensor::<R2<2, 4>>().set(w2);
+        let b2 = cx2.tensor::<R1<2>>().set(b2);
+        let expected = ((x.matmul(w1) + b1.expand()).relu().matmul(w2.permute())
+            + b2.expand::<_, LAxis<0>>())
+        .softmax::<LAxis<1>>()
+        .retrieve();
+        cx2.execute();
+
+        assert_close(&out.data(), &expected.data());
+    }
+
+    #[test]
+    fn test_shape_ops() {
+        let onnx = model(
+            vec![
+                // Reshape to [batch, -1] with the batch size taken from the input's shape
+                node("Shape", &["x"], &["s"], vec![]),
+                node("Gather", &["s", "zero"], &["n"], vec![]),
+                node("Unsqueeze", &["n", "axes"], &["n1"], vec![]),
+                node(
+                    "Concat",
+                    &["n1", "minus_one"],
+                    &["shape"],
+                    vec![int_attr("axis", 0)],
+                ),
+                node(
+                    "Transpose",
+                    &["x"],
+                    &["xt"],
+                    vec![ints_attr("perm", &[0, 2, 1])],
+                ),
+                node("Reshape", &["xt", "shape"], &["r"], vec![]),
+                node(
+                    "Slice",
+                    &["r", "one", "minus_one", "axes_1"],
+                    &["sl"],
+                    vec![],
+                ),
+                node("Concat", &["sl", "r"], &["c"], vec![int_attr("axis", 1)]),
+                node(
+                    "Gather",
+                    &["c", "indices"],
+                    &["g"],
+                    vec![int_attr("axis", 1)],
+                ),
+                node(
+                    "ReduceSum",
+                    &["c", "axes_1"],
+                    &["sum"],
+                    vec![int_attr("keepdims", 0)],
+                ),
+            ],
+            vec![
+                ints("zero", &[], &[0]),
+                ints("axes", &[1], &[0]),
+                ints("minus_one", &[1], &[-1]),
+                ints("one", &[1], &[1]),
+                ints("axes_1", &[1], &[1]),
+                ints("indices", &[2], &[3, -1]),
+            ],
+            vec![value("x", &["batch", "2", "3"])],
+            &["c", "g", "sum"],
+        );
+        let mut cx = Graph::new();
+        let m = import_onnx_model(&mut cx, &onnx).unwrap();
+        let x_data = (0..12).map(|i| i as f32).collect::<Vec<_>>();
+        m.input("x").unwrap().set(x_data.clone());
+        cx.set_dyn_dim(m.dyn_dim("batch").unwrap(), 2);
+        let (c, g, sum) = (
+            m.output("c").unwrap().retrieve(),
+            m.output("g").unwrap().retrieve(),
+            m.output("sum").unwrap().retrieve(),
+        );
+        cx.execute();
+
+        let mut expected_c = vec![];
+        for b in x_data.chunks(6) {
+            // Transposed and flattened
+            let r = (0..6).map(|i| b[(i % 2) * 3 + i / 2]).collect::<Vec<_>>();
+            expected_c.extend(r[1..5].iter().chain(&r).copied().collect::<Vec<_>>());
+        }
+        assert_exact(&c.data(), &expected_c);
+        let expected_g = expected_c
+            .chunks(10)
+            .flat_map(|r| [r[3], r[9]])
+            .collect::<Vec<_>>();
+        assert_exact(&g.data(), &expected_g);
+        let expected_sum = expected_c
+            .chunks(10)
+            .map(|r| r.iter().sum::<f32>())
+            .collect::<Vec<_>>();
+        assert_exact(&sum.data(), &expected_sum);
+    }
+
+    #[test]
+    fn test_embedding_layer_norm() {
+        let (emb, scale, bias) = (random_vec(15), random_vec(3), random_vec(3));
+        let onnx = model(
+            vec![
+                node("Gather", &["emb", "ids"], &["e"], vec![]),
+                node(
+                    "LayerNormalization",
+                    &["e", "scale", "bias"],
+                    &["out"],
+                    vec![],
+                ),
+            ],
+            vec![
+                floats("emb", &[5, 3], emb.clone()),
+                floats("scale", &[3], scale.clone()),
+                floats("bias", &[3], bias.clone()),
+            ],
+            vec![value("ids", &["4"])],
+            &["out"],
+        );
+        let mut cx = Graph::new();
+        let m = import_onnx_model(&mut cx, &onnx).unwrap();
+        m.input("ids").unwrap().set(vec![4., 0., 2., 4.]);
+        let out = m.output("out").unwrap().retrieve();
+        cx.execute();
+
+        let mut cx2 = Graph::new();
+        let emb = cx2.tensor::<R2<5, 3>>().set(emb);
+        let ids = cx2.tensor::<R1<4>>().set(vec![4., 0., 2., 4.]);
+        let scale = cx2.tensor::<R1<3>>().set(scale);
+        let bias = cx2.tensor::<R1<3>>().set(bias);
+        let expected = (emb.gather(ids).layer_norm::<LAxis<1>, _>(1e-5) * scale.expand()
+            + bias.expand())
+        .retrieve();
+        cx2.execute();
+
+        assert_close(&out.data(), &expected.data());
+    }
+
+    #[test]
+    fn test_conv() {
+        let (x, w, b) = (random_vec(50), random_vec(54), random_vec(3));
+        let onnx = model(
+            vec![
+                node(
+                    "Conv",
+                    &["x", "w", "b"],
+                    &["c"],
+                    vec![
+                        ints_attr("strides", &[2, 2]),
+                        ints_attr("pads", &[1, 1, 1, 1]),
+                    ],
+                ),
+                node(
+                    "MaxPool",
+                    &["c"],
+                    &["p"],
+                    vec![ints_attr("kernel_shape", &[2, 2])],
+                ),
+                node("GlobalAveragePool", &["c"], &["g"], vec![]),
+            ],
+            vec![
+                floats("w", &[3, 2, 3, 3], w.clone()),
+                floats("b", &[3], b.clone()),
+            ],
+            vec![value("x", &["1", "2", "5", "5"])],
+            &["c", "p", "g"],
+        );
+        let mut cx = Graph::new();
+        let m = import_onnx_model(&mut cx, &onnx).unwrap();
+        m.input("x").unwrap().set(x.clone());
+        let (c, p, g) = (
+            m.output("c").unwrap().retrieve(),
+            m.output("p").unwrap().retrieve(),
+            m.output("g").unwrap().retrieve(),
+        );
+        cx.execute();
+
+        // Pad by 1, then 3x3 windows with a stride of 2 give a 3x3 output
+        let mut expected_c = vec![0.; 27];
+        for (o, out) in expected_c.iter_mut().enumerate() {
+            let (m, oy, ox) = (o / 9, (o / 3) % 3, o % 3);
+            *out = b[m];
+            for (ci, ky, kx) in itertools::iproduct!(0..2, 0..3, 0..3) {
+                let (y, x_) = ((oy * 2 + ky) as i32 - 1, (ox * 2 + kx) as i32 - 1);
+                if (0..5).contains(&y) && (0..5).contains(&x_) {
+                    *out += x[ci * 25 + y as usize * 5 + x_ as usize]
+                        * w[m * 18 + ci * 9 + ky * 3 + kx];
+                }
+            }
+        }
+        assert_close(&c.data(), &expected_c);
+        let expected_p = itertools::iproduct!(0..3, 0..2, 0..2)
+            .map(|(m, y, x)| {
+                itertools::iproduct!(0..2, 0..2)
+                    .map(|(ky, kx)| expected_c[m * 9 + (y + ky) * 3 + x + kx])
+                    .fold(f32::MIN, f32::max)
+            })
+            .collect::<Vec<_>>();
+        assert_close(&p.data(), &expected_p);
+        let expected_g = expected_c
+            .chunks(9)
+            .map(|c| c.iter().sum::<f32>() / 9.)
+            .collect::<Vec<_>>();
+        assert_close(&g.data(), &expected_g);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let onnx = model(
+            vec![
+                node("Erf", &["x"], &["a"], vec![]),
+                node("Relu", &["a"], &["b"], vec![]),
+                node("Foo", &["x"], &["c"], vec![]),
+                node(
+                    "Slice",
+                    &["x", "zero", "three", "zero", "two"],
+                    &["d"],
+                    vec![],
+                ),
+                node("Relu", &["missing"], &["e"], vec![]),
+                node("Identity", &[], &["f"], vec![]),
+            ],
+            vec![
+                ints("zero", &[1], &[0]),
+                ints("two", &[1], &[2]),
+                ints("three", &[1], &[3]),
+            ],
+            vec![value("x", &["3"])],
+            &["b", "c", "d", "e", "f"],
+        );
+        let Err(OnnxError::Unsupported(nodes)) = import_onnx_model(&mut Graph::new(), &onnx) else {
+            panic!("Expected unsupported nodes");
+        };
+        assert_eq!(
+            nodes.iter().map(|n| n.op_type.as_str()).collect::<Vec<_>>(),
+            ["Erf", "Foo", "Slice", "Relu", "Identity"]
+        );
+        assert_eq!(nodes[1].reason, "op isn't supported");
+        assert_eq!(nodes[2].reason, "steps other than 1 aren't supported");
+        assert_eq!(nodes[3].reason, "input missing isn't defined");
+        assert_eq!(nodes[4].reason, "input 0 is missing");
+    }
+
+    #[test]
+    fn test_dtypes() {
+        let onnx = model(
+            vec![
+                node("Gather", &["table", "ids"], &["rows"], vec![]),
+                node(
+                    "Cast",
+                    &["x"],
+                    &["truncated"],
+                    vec![int_attr("to", DataType::Int64 as i64)],
+                ),
+            ],
+            vec![floats("table", &[3, 2], vec![0., 1., 2., 3., 4., 5.])],
+            vec![
+                typed_value("ids", &["2"], DataType::Int64),
+                value("x", &["2"]),
+            ],
+            &["rows", "truncated"],
+        );
+        let mut cx = Graph::new();
+        let m = import_onnx_model(&mut cx, &onnx).unwrap();
+        let ids = m.input("ids").unwrap();
+        assert_eq!(ids.dtype(), DType::I32);
+        ids.set(vec![2i32, 0]);
+        m.input("x").unwrap().set(vec![1.7, -2.5]);
+        let (rows, truncated) = (
+            m.output("rows").unwrap().retrieve(),
+            m.output("truncated").unwrap().retrieve(),
+        );
+        assert_eq!(truncated.dtype(), DType::I32);
+        cx.execute();
+        assert_exact(&rows.data(), &[4., 5., 0., 1.]);
+        assert_exact(&truncated.data(), &[1., -2.]);
+    }
+
+    #[test]
+    fn test_export_round_trip() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(6);
+        let a = cx.named_tensor::<R2<2, 3>>("a").set(a_data.clone());
+        let w = cx.named_tensor::<R2<3, 4>>("w").set(random_vec(12));
+        let b = cx.named_tensor::<R1<4>>("b").set(random_vec(4));
+        let h = (a.matmul(w) + b.expand()).relu();
+        let o1 = h.softmax::<LAxis<1>>().retrieve();
+        let o2 = h
+            .slice((.., 1..3))
+            .realize::<R2<2, 2>>()
+            .concat_along::<R2<2, 6>, LAxis<1>, _>(h)
+            .permute::<R2<6, 2>, _>()
+            .retrieve();
+        let o3 = ((h + 1.).log2().sqrt() + h.exp2().recip() * h.sin() + h % 0.3
+            - h.less_than(h.sin()))
+        .retrieve();
+        let o4 =
+            (h.max_reduce::<R1<2>, LAxis<1>>() + h.mean_reduce::<R1<2>, LAxis<1>>()).retrieve();
+        cx.execute();
+
+        let path = std::env::temp_dir().join(format!("luminal_{}.onnx", uuid::Uuid::new_v4()));
+        cx.export_onnx(a, (o1, o2, o3, o4), &path).unwrap();
+        let mut cx2 = Graph::new();
+        let m = import_onnx(&mut cx2, &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // Scalar constants are stored as initializers too
+        for weight in ["w", "b"] {
+            assert!(m.weights.iter().any(|(n, _)| n == weight));
+        }
+        m.input("a").unwrap().set(a_data);
+        let outputs =
+            ["output0", "output1", "output2", "output3"].map(|o| m.output(o).unwrap().retrieve());
+        cx2.execute();
+
+        let expected = [o1.data(), o2.data(), o3.data(), o4.data()];
+        for (expected, out) in expected.iter().zip(outputs) {
+            assert_close(&out.data(), expected);
+        }
+    }
+
+    #[test]
+    fn test_export_dyn() {
+        let mut cx = Graph::new();
+        let x_data = random_vec(12);
+        let x = cx.named_tensor::<(Dyn<'s'>, LConst<3>)>("x");
+        let w = cx.named_tensor::<R2<3, 2>>("w").set(random_vec(6));
+        let o1 = x.matmul(w).softmax::<LAxis<1>>().retrieve();
+        let o2 = x.mean_reduce::<(LConst<3>,), LAxis<0>>().retrieve();
+        let o3 = x.slice((1.., ..)).contiguous().retrieve();
+        cx.set_dyn_dim('s', 4);
+        x.set_dyn(x_data.clone(), &[4, 3]);
+        cx.execute();
+
+        let onnx = cx.export_onnx_model(x, (o1, o2, o3)).unwrap();
+        let input = &onnx.graph.as_ref().unwrap().input[0];
+        let dims = &input.r#type.as_ref().unwrap().tensor_type.as_ref().unwrap();
+        assert_eq!(
+            dims.shape.as_ref().unwrap().dim[0].value,
+            Some(DimensionValue::DimParam("s".to_string()))
+        );
+        let mut cx2 = Graph::new();
+        let m = import_onnx_model(&mut cx2, &onnx).unwrap();
+        m.input("x").unwrap().set(x_data);
+        cx2.set_dyn_dim(m.dyn_dim("s").unwrap(), 4);
+        let outputs = ["output0", "output1", "output2"].map(|o| m.output(o).unwrap().retrieve());
+        cx2.execute();
+
+        for (expected, out) in [o1.data(), o2.data(), o3.data()].iter().zip(outputs) {
+            assert_close(&out.data(), expected);
+        }
+    }
+
+    #[test]
+    fn test_export_unsupported() {
+        let mut cx = Graph::new();
+        let a = cx.named_tensor::<R1<3>>("a");
+        let custom = cx
+            .add_op(crate::op::Function(
+                "Custom".to_string(),
+                Box::new(|inp| {
+                    vec![crate::op::Tensor::new(
+                        inp[0].0.borrowed().as_elements::<f32>().to_vec(),
+                    )]
+                }),
+            ))
+            .input(a.id, 0, a.shape)
+            .finish();
+        let out = (GraphTensor::<R1<3>>::from_id(custom, a.shape, &mut cx).exp()).retrieve();
+
+        let Err(OnnxError::Unsupported(nodes)) = cx.export_onnx_model(a, out) else {
+            panic!("Expected unsupported nodes");
+        };
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].op_type, "Custom");
+        assert_eq!(nodes[0].reason, "op isn't a primitive");
+    }
+
+    #[test]
+    fn test_export_unset_weight() {
+        let mut cx = Graph::new();
+        let a = cx.named_tensor::<R1<3>>("a");
+        let w = cx.named_tensor::<R1<3>>("w");
+        let out = (a * w).retrieve();
+
+        let Err(OnnxError::Unsupported(nodes)) = cx.export_onnx_model(a, out) else {
+            panic!("Expected unsupported nodes");
+        };
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].op_type, "Initializer");
+        assert_eq!(nodes[0].name, w.id.index().to_string());
+    }
+}
@@ -1,6 +1,10 @@
-use std::{any::Any, fmt::Debug};
+use std::{any::Any, borrow::Cow, fmt::Debug};
 
-use crate::prelude::*;
+use crate::{
+    dispatch_dtype,
+    kernels::{binary_into, reduce_into, unary_into, ViewReader},
+    prelude::*,
+};
 
 use dyn_clone::{clone_trait_object, DynClone};
 use rustc_hash::FxHashMap;
@@ -26,23 +30,66 @@ impl Tensor {
     pub fn is<T: Data>(&self) -> bool {
         self.data.as_any().is::<T>()
     }
+    /// Size of the tensor's data in bytes, if known
+    pub fn size_bytes(&self) -> Option<usize> {
+        self.data.size_bytes()
+    }
+    /// The element type of this tensor, if it's stored as a CPU Vec or memory-mapped view
+    pub fn dtype(&self) -> Option<DType> {
+        DType::of(self.data.as_any())
+    }
+    /// Number of elements in this tensor, if it's stored on the CPU
+    pub fn num_elements(&self) -> Option<usize> {
+        self.dtype()
+            .map(|d| dispatch_dtype!(d, T => self.as_slice::<T>().unwrap().len()))
+    }
+    /// Borrow the CPU data of this tensor as a slice of `T`, if it's stored as `T`
+    pub fn as_slice<T: Element>(&self) -> Option<&[T]> {
+        if let Some(data) = self.downcast_ref::<Vec<T>>() {
+            return Some(data);
+        }
+        self.downcast_ref::<MmapData>()
+            .and_then(|mmap| mmap.as_slice())
+    }
+    /// View the CPU data of this tensor as elements of type `T`, converting if it's stored as a different type
+    pub fn as_elements<T: Element>(&self) -> Cow<'_, [T]> {
+        if let Some(data) = self.as_slice::<T>() {
+            return Cow::Borrowed(data);
+        }
+        let dtype = self
+            .dtype()
+            .unwrap_or_else(|| panic!("Tensor isn't a CPU tensor: {self:?}"));
+        Cow::Owned(dispatch_dtype!(dtype, S => self
+            .as_slice::<S>()
+            .unwrap()
+            .iter()
+            .map(|i| T::from_f32(i.to_f32()))
+            .collect()))
+    }
 }
 
 /// Some sort of data, for instance a Vec<f32> on CPU, CudaSlice<f32> on Nvidia GPUs, or metal::Buffer for Apple GPUs
 pub trait Data: Any + Debug + DynClone {
     fn as_any(&self) -> &dyn Any;
     fn as_any_mut(&mut self) -> &mut dyn Any;
+    /// Size of the data in bytes, if known
+    fn size_bytes(&self) -> Option<usize> {
+        None
+    }
 }
 
 clone_trait_object!(Data);
 
-impl Data for Vec<f32> {
+impl<T: Element> Data for Vec<T> {
     fn as_any(&self) -> &dyn Any {
         self
     }
     fn as_any_mut(&mut self) -> &mut dyn Any {
         self
     }
+    fn size_bytes(&self) -> Option<usize> {
+        Some(self.len() * std::mem::size_of::<T>())
+    }
 }
 
 /// Either an owned or borrowed tensor that gets consumed by ops
@@ -116,9 +163,23 @@ pub enum ConstantValue {
     Float(f32),
 }
 
-/// Produces a single number constant from an expression or a float
+/// Produces a single number constant from an expression or a float. Constants are weakly typed: they're produced as the type of what they're combined with, which the graph sets when it sorts itself
 #[derive(Clone, PartialEq)]
-pub struct Constant(pub ConstantValue, pub *const FxHashMap<char, usize>);
+pub struct Constant(
+    pub ConstantValue,
+    pub *const FxHashMap<char, usize>,
+    pub DType,
+);
+
+impl Constant {
+    /// Whether the constant is a whole number, so it can be combined with integer tensors without making them floats
+    pub fn is_integral(&self) -> bool {
+        match &self.0 {
+            ConstantValue::Expression(_) => true,
+            ConstantValue::Float(f) => f.fract() == 0.,
+        }
+    }
+}
 impl Debug for Constant {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Constant(",)?;
@@ -132,12 +193,19 @@ impl Debug for Constant {
 
 impl Operator for Constant {
     fn process(&mut self, _: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        vec![Tensor::new(vec![match &self.0 {
+        let value = match &self.0 {
             ConstantValue::Expression(e) => {
                 e.exec(unsafe { self.1.as_ref().unwrap() }).unwrap() as f32
             }
             ConstantValue::Float(f) => *f,
-        }])]
+        };
+        dispatch_dtype!(self.2, T => vec![Tensor::new(vec![T::from_f32(value)])])
+    }
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "dtype" {
+            return Some(Box::new(self.2));
+        }
+        None
     }
 }
 
@@ -149,14 +217,29 @@ pub struct Contiguous;
 impl Operator for Contiguous {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         // Copy data over to new tensor
-        let inp_data = get_vec(&inp[0].0);
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let mut stack = vec![];
-        for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(inp_data, &expr, &mut stack, i);
+        let tensor = inp[0].0.borrowed();
+        dispatch_dtype!(dtype_of(tensor), T => vec![Tensor::new(contiguous(
+            tensor.as_slice::<T>().unwrap(),
+            &inp[0].1,
+        ))])
+    }
+}
+
+/// Convert a tensor to a different element type, laying it out contiguously
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cast(pub DType);
+impl Operator for Cast {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        dispatch_dtype!(self.0, T => vec![Tensor::new(contiguous(
+            &inp[0].0.borrowed().as_elements::<T>(),
+            &inp[0].1,
+        ))])
+    }
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "dtype" {
+            return Some(Box::new(self.0));
         }
-        vec![Tensor::new(out_data)]
+        None
     }
 }
 
@@ -164,14 +247,7 @@ impl Operator for Contiguous {
 pub struct Log2;
 impl Operator for Log2 {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let inp_data = get_vec(&inp[0].0);
-        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let mut stack = vec![];
-        for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(inp_data, &expr, &mut stack, i).log2();
-        }
-        vec![Tensor::new(out_data)]
+        unary(&inp, |a| a.log2())
     }
 }
 
@@ -179,14 +255,7 @@ impl Operator for Log2 {
 pub struct Exp2;
 impl Operator for Exp2 {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let inp_data = get_vec(&inp[0].0);
-        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let mut stack = vec![];
-        for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(inp_data, &expr, &mut stack, i).exp2();
-        }
-        vec![Tensor::new(out_data)]
+        unary(&inp, |a| a.exp2())
     }
 }
 
@@ -194,14 +263,7 @@ impl Operator for Exp2 {
 pub struct Sin;
 impl Operator for Sin {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let inp_data = get_vec(&inp[0].0);
-        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let mut stack = vec![];
-        for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(inp_data, &expr, &mut stack, i).sin();
-        }
-        vec![Tensor::new(out_data)]
+        unary(&inp, |a| a.sin())
     }
 }
 
@@ -209,14 +271,7 @@ impl Operator for Sin {
 pub struct Recip;
 impl Operator for Recip {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let inp_data = get_vec(&inp[0].0);
-        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let mut stack = vec![];
-        for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(inp_data, &expr, &mut stack, i).recip();
-        }
-        vec![Tensor::new(out_data)]
+        unary(&inp, |a| a.recip())
     }
 }
 
@@ -224,32 +279,42 @@ impl Operator for Recip {
 pub struct Sqrt;
 impl Operator for Sqrt {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let inp_data = get_vec(&inp[0].0);
-        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let mut stack = vec![];
-        for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(inp_data, &expr, &mut stack, i).sqrt();
-        }
-        vec![Tensor::new(out_data)]
+        unary(&inp, |a| a.sqrt())
     }
 }
 
 // Binary Ops (A x A -> A)
 
+/// Run a binary op elementwise, promoting both inputs to a common element type. Arithmetic ops
+/// promote bools to i32
+macro_rules! binary_op {
+    ($inp: expr, |$a: ident, $b: ident| $body: expr) => {
+        binary_op!($inp, |d: DType| d, |$a, $b| $body)
+    };
+    (arithmetic $inp: expr, |$a: ident, $b: ident| $body: expr) => {
+        binary_op!($inp, DType::arithmetic, |$a, $b| $body)
+    };
+    ($inp: expr, $promote: expr, |$a: ident, $b: ident| $body: expr) => {{
+        let (lhs, rhs) = ($inp[0].0.borrowed(), $inp[1].0.borrowed());
+        dispatch_dtype!($promote(dtype_of(lhs).promote(dtype_of(rhs))), T => {
+            let (lhs, rhs) = (lhs.as_elements::<T>(), rhs.as_elements::<T>());
+            let mut out_data = vec![T::default(); $inp[0].1.n_elements().to_usize().unwrap()];
+            binary_into(
+                &ViewReader::new(&lhs, &$inp[0].1),
+                &ViewReader::new(&rhs, &$inp[1].1),
+                &mut out_data,
+                |$a, $b| $body,
+            );
+            vec![Tensor::new(out_data)]
+        })
+    }};
+}
+
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct Add;
 impl Operator for Add {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let (lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
-        let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let rexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
-        let mut stack = vec![];
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(lhs, &lexpr, &mut stack, i) + get_index(rhs, &rexpr, &mut stack, i);
-        }
-        vec![Tensor::new(out_data)]
+        binary_op!(arithmetic inp, |a, b| a.add(b))
     }
 }
 
@@ -257,15 +322,7 @@ impl Operator for Add {
 pub struct Mul;
 impl Operator for Mul {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let (lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let rexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
-        let mut stack = vec![];
-        for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(lhs, &lexpr, &mut stack, i) * get_index(rhs, &rexpr, &mut stack, i);
-        }
-        vec![Tensor::new(out_data)]
+        binary_op!(arithmetic inp, |a, b| a.mul(b))
     }
 }
 
@@ -273,15 +330,7 @@ impl Operator for Mul {
 pub struct Mod;
 impl Operator for Mod {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let (lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let rexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
-        let mut stack = vec![];
-        for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(lhs, &lexpr, &mut stack, i) % get_index(rhs, &rexpr, &mut stack, i);
-        }
-        vec![Tensor::new(out_data)]
+        binary_op!(inp, |a, b| a.rem(b))
     }
 }
 
@@ -289,42 +338,28 @@ impl Operator for Mod {
 pub struct LessThan;
 impl Operator for LessThan {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let (lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
-        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let rexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
-        let mut stack = vec![];
-        for (i, out) in out_data.iter_mut().enumerate() {
-            *out = (get_index(lhs, &lexpr, &mut stack, i) < get_index(rhs, &rexpr, &mut stack, i))
-                as i32 as f32;
-        }
-        vec![Tensor::new(out_data)]
+        binary_op!(inp, |a, b| a.less_than(b))
     }
 }
 
 // Reduce Ops (A -> B (different shape))
 
+/// Sum along an axis. Half precision sums accumulate in f32, bools are counted as i32, and integer sums wrap around when they overflow the element type
 #[derive(Debug, Clone, PartialEq)]
 pub struct SumReduce(pub usize);
 impl Operator for SumReduce {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let sh = inp[0].1.shape_usize();
-        let front_size = sh.iter().take(self.0).product::<usize>().max(1);
-        let back_size = sh.iter().skip(self.0 + 1).product::<usize>().max(1);
-        let dim_size = sh[self.0];
-        let mut result = vec![0.0; front_size * back_size];
-        let input = get_vec(&inp[0].0);
-        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let mut stack = vec![];
-        for i in 0..front_size {
-            for j in 0..back_size {
-                for k in 0..dim_size {
-                    let orig_index = i * dim_size * back_size + k * back_size + j;
-                    result[i * back_size + j] += get_index(input, &expr, &mut stack, orig_index);
-                }
+        let tensor = inp[0].0.borrowed();
+        dispatch_dtype!(dtype_of(tensor).arithmetic(), T => {
+            let data = &*tensor.as_elements::<T>();
+            if matches!(T::DTYPE, DType::F16 | DType::Bf16) {
+                // Half precision sums accumulate in f32
+                let sums = reduce(data, &inp[0].1, self.0, 0., |a, b| a + b.to_f32());
+                vec![Tensor::new(sums.into_iter().map(T::from_f32).collect::<Vec<_>>())]
+            } else {
+                vec![Tensor::new(reduce(data, &inp[0].1, self.0, T::default(), |a, b| a.add(b)))]
             }
-        }
-        vec![Tensor::new(result)]
+        })
     }
 }
 
@@ -332,42 +367,64 @@ impl Operator for SumReduce {
 pub struct MaxReduce(pub usize);
 impl Operator for MaxReduce {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let sh = inp[0].1.shape_usize();
-        let front_size = sh.iter().take(self.0).product::<usize>().max(1);
-        let back_size = sh.iter().skip(self.0 + 1).product::<usize>().max(1);
-        let dim_size = sh[self.0];
-        let mut result = vec![-f32::INFINITY; front_size * back_size];
-        let input = get_vec(&inp[0].0);
-        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
-        let mut stack = vec![];
-
-        for i in 0..front_size {
-            for j in 0..back_size {
-                for k in 0..dim_size {
-                    let orig_index = i * dim_size * back_size + k * back_size + j;
-                    let new_index = i * back_size + j;
-                    result[new_index] =
-                        result[new_index].max(get_index(input, &expr, &mut stack, orig_index));
-                }
-            }
-        }
-        vec![Tensor::new(result)]
+        let tensor = inp[0].0.borrowed();
+        dispatch_dtype!(dtype_of(tensor), T => vec![Tensor::new(reduce(
+            tensor.as_slice::<T>().unwrap(),
+            &inp[0].1,
+            self.0,
+            T::lowest(),
+            |a, b| a.larger(b),
+        ))])
     }
 }
 
-fn get_vec<'a>(tensor: &'a InputTensor<'a>) -> &'a Vec<f32> {
-    tensor.borrowed().downcast_ref::<Vec<f32>>().unwrap()
+/// The element type of a CPU tensor, panicking if it isn't stored as a supported Vec
+fn dtype_of(tensor: &Tensor) -> DType {
+    tensor
+        .dtype()
+        .unwrap_or_else(|| panic!("Expected a CPU tensor, found {tensor:?}"))
 }
 
-fn get_index(
-    data: &[f32],
-    (ind, val): &(BigExpression, BigExpression),
-    stack: &mut Vec<i64>,
-    index: usize,
-) -> f32 {
-    if val.exec_single_var_stack(index, stack) != 0 {
-        data[ind.exec_single_var_stack(index, stack)]
-    } else {
-        0.0
-    }
+/// Run a unary op elementwise, keeping the input's element type
+fn unary(inp: &[(InputTensor, ShapeTracker)], f: fn(f32) -> f32) -> Vec<Tensor> {
+    let tensor = inp[0].0.borrowed();
+    dispatch_dtype!(dtype_of(tensor), T => {
+        let mut out_data = vec![T::default(); inp[0].1.n_elements().to_usize().unwrap()];
+        unary_into(
+            &ViewReader::new(tensor.as_slice::<T>().unwrap(), &inp[0].1),
+            &mut out_data,
+            |a| T::from_f32(f(a.to_f32())),
+        );
+        vec![Tensor::new(out_data)]
+    })
+}
+
+/// Copy the logical elements of a view out contiguously
+fn contiguous<T: Element>(data: &[T], shape: &ShapeTracker) -> Vec<T> {
+    let mut out_data = vec![T::default(); shape.n_elements().to_usize().unwrap()];
+    unary_into(&ViewReader::new(data, shape), &mut out_data, |a| a);
+    out_data
+}
+
+/// Reduce along an axis, folding each element into the running value with `f`
+fn reduce<T: Element, A: Copy + Send + Sync>(
+    input: &[T],
+    shape: &ShapeTracker,
+    axis: usize,
+    init: A,
+    f: impl Fn(A, T) -> A + Sync,
+) -> Vec<A> {
+    let sh = shape.shape_usize();
+    let front_size = sh.iter().take(axis).product::<usize>().max(1);
+    let back_size = sh.iter().skip(axis + 1).product::<usize>().max(1);
+    let mut result = vec![init; front_size * back_size];
+    reduce_into(
+        &ViewReader::new(input, shape),
+        &sh,
+        axis,
+        init,
+        f,
+        &mut result,
+    );
+    result
 }
@@ -0,0 +1,444 @@
+use std::{
+    fmt::{Debug, Display},
+    time::{Duration, Instant},
+};
+
+use colored::Colorize;
+
+use crate::{compiler_utils::format_duration, prelude::*};
+
+type Pass = Box<dyn Fn(&mut Graph, &mut Vec<&mut NodeIndex>)>;
+
+/// Runs compilers as named passes, recording how each one changed the graph and optionally checking the graph after each one.
+///
+/// Compiling with a pipeline returns a [`PipelineReport`]. Passes after the first one that fails a check aren't run
+#[derive(Default)]
+pub struct Pipeline {
+    passes: Vec<(String, Pass)>,
+    validate: bool,
+    tolerance: Option<f32>,
+    verbose: bool,
+}
+
+impl Debug for Pipeline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.passes.iter().map(|(name, _)| name))
+            .finish()
+    }
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a pass, named by the compiler's debug representation
+    pub fn pass<C: Compiler + Debug + 'static>(self, compiler: C) -> Self {
+        let name = format!("{compiler:?}");
+        self.named_pass(name, compiler)
+    }
+
+    /// Add a pass with a name
+    pub fn named_pass<C: Compiler + 'static>(mut self, name: impl ToString, compiler: C) -> Self {
+        self.passes.push((
+            name.to_string(),
+            Box::new(move |graph, ids| {
+                compiler.compile(graph, ids);
+            }),
+        ));
+        self
+    }
+
+    /// Run [`Graph::validate`] after each pass. Any diagnostic fails the pass
+    pub fn validate(mut self) -> Self {
+        self.validate = true;
+        self
+    }
+
+    /// Run the graph before the first pass and after each one, failing the pass if any tracked tensor that's kept on the CPU differs by more than `tolerance` from its value before compiling.
+    ///
+    /// The graph's inputs and dynamic dimensions need to be set before compiling
+    pub fn check_outputs(mut self, tolerance: f32) -> Self {
+        self.tolerance = Some(tolerance);
+        self
+    }
+
+    /// Print each pass's report as it finishes
+    pub fn verbose(mut self) -> Self {
+        self.verbose = true;
+        self
+    }
+}
+
+/// The result of running the graph after a pass, compared to before compiling
+#[derive(Debug, Clone)]
+pub enum OutputCheck {
+    /// All outputs matched, differing by at most this much
+    Matched { max_error: f32 },
+    /// An output differed by more than the tolerance
+    Mismatch { node: NodeIndex, max_error: f32 },
+    /// An output has a different number of elements than before compiling
+    SizeMismatch {
+        node: NodeIndex,
+        expected: usize,
+        found: usize,
+    },
+    /// The graph failed to run
+    Failed(LuminalError),
+}
+
+/// How a pass changed the graph
+#[derive(Debug, Clone)]
+pub struct PassReport {
+    pub name: String,
+    pub nodes_before: usize,
+    pub nodes_after: usize,
+    pub edges_before: usize,
+    pub edges_after: usize,
+    /// Time spent in the pass, not counting checks
+    pub duration: Duration,
+    /// Problems found after the pass, if validation is on
+    pub diagnostics: Vec<Diagnostic>,
+    /// The output check after the pass, if it's on
+    pub check: Option<OutputCheck>,
+}
+
+impl PassReport {
+    /// Did the pass leave the graph invalid, or change its outputs?
+    pub fn failed(&self) -> bool {
+        !self.diagnostics.is_empty()
+            || matches!(
+                self.check,
+                Some(
+                    OutputCheck::Mismatch { .. }
+                        | OutputCheck::SizeMismatch { .. }
+                        | OutputCheck::Failed(_)
+                )
+            )
+    }
+}
+
+impl Display for PassReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {} nodes, {} -> {} edges in {}",
+            self.name.bold(),
+            self.nodes_before,
+            self.nodes_after,
+            self.edges_before,
+            self.edges_after,
+            format_duration(self.duration)
+        )?;
+        for diagnostic in &self.diagnostics {
+            write!(f, "\n  {diagnostic}")?;
+        }
+        match &self.check {
+            Some(OutputCheck::Matched { max_error }) => {
+                write!(f, "\n  outputs match (max error {max_error})")
+            }
+            Some(OutputCheck::Mismatch { node, max_error }) => {
+                write!(f, "\n  output {} differs by {max_error}", node.index())
+            }
+            Some(OutputCheck::SizeMismatch {
+                node,
+                expected,
+                found,
+            }) => write!(
+                f,
+                "\n  output {} has {found} elements, expected {expected}",
+                node.index()
+            ),
+            Some(OutputCheck::Failed(e)) => write!(f, "\n  graph failed to run: {e}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What each pass of a [`Pipeline`] did, in order
+#[derive(Debug, Clone, Default)]
+pub struct PipelineReport {
+    pub passes: Vec<PassReport>,
+    /// Set if the graph couldn't run before compiling, in which case outputs aren't checked
+    pub baseline_error: Option<LuminalError>,
+}
+
+impl PipelineReport {
+    /// The first pass that left the graph invalid or changed its outputs, which is the last pass run
+    pub fn first_failure(&self) -> Option<&PassReport> {
+        self.passes.iter().find(|p| p.failed())
+    }
+
+    /// Total time spent in passes
+    pub fn duration(&self) -> Duration {
+        self.passes.iter().map(|p| p.duration).sum()
+    }
+}
+
+impl Display for PipelineReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(e) = &self.baseline_error {
+            writeln!(f, "Graph failed to run before compiling: {e}")?;
+        }
+        for pass in &self.passes {
+            writeln!(f, "{pass}")?;
+        }
+        write!(f, "Total: {}", format_duration(self.duration()))
+    }
+}
+
+impl Compiler for Pipeline {
+    type Output = PipelineReport;
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) -> PipelineReport {
+        let mut ids = ids.to_ids_mut();
+        let mut report = PipelineReport::default();
+        let mut expected = None;
+        if self.tolerance.is_some() {
+            let tracked = ids.iter().map(|i| **i).collect::<Vec<_>>();
+            match run_outputs(graph, &tracked) {
+                Ok(outputs) => expected = Some(outputs),
+                Err(e) => report.baseline_error = Some(e),
+            }
+        }
+
+        for (name, pass) in &self.passes {
+            let (nodes_before, edges_before) = (graph.graph.node_count(), graph.graph.edge_count());
+            let start = Instant::now();
+            pass(graph, &mut ids);
+            let duration = start.elapsed();
+            let mut pass_report = PassReport {
+                name: name.clone(),
+                nodes_before,
+                nodes_after: graph.graph.node_count(),
+                edges_before,
+                edges_after: graph.graph.edge_count(),
+                duration,
+                diagnostics: vec![],
+                check: None,
+            };
+            if self.validate {
+                pass_report.diagnostics = graph.validate();
+            }
+            if let (Some(expected), Some(tolerance)) = (&expected, self.tolerance) {
+                let tracked = ids.iter().map(|i| **i).collect::<Vec<_>>();
+                pass_report.check = Some(match run_outputs(graph, &tracked) {
+                    Ok(found) => compare_outputs(&tracked, expected, &found, tolerance),
+                    Err(e) => OutputCheck::Failed(e),
+                });
+            }
+            if self.verbose {
+                println!("{pass_report}");
+            }
+            let failed = pass_report.failed();
+            report.passes.push(pass_report);
+            if failed {
+                break;
+            }
+        }
+        report
+    }
+}
+
+/// Run the graph and read back the tracked tensors it keeps on the CPU, leaving the graph's stored tensors as they were
+fn run_outputs(
+    graph: &mut Graph,
+    ids: &[NodeIndex],
+) -> Result<Vec<Option<Vec<f32>>>, LuminalError> {
+    graph.try_toposort()?;
+    let tensors = graph.tensors.clone();
+    let result = graph.try_execute().map(|_| {
+        ids.iter()
+            .map(|id| {
+                graph
+                    .get_tensor_ref(*id, 0)
+                    .filter(|t| t.dtype().is_some())
+                    .map(|t| t.as_elements::<f32>().into_owned())
+            })
+            .collect()
+    });
+    graph.tensors = tensors;
+    result
+}
+
+fn compare_outputs(
+    ids: &[NodeIndex],
+    expected: &[Option<Vec<f32>>],
+    found: &[Option<Vec<f32>>],
+    tolerance: f32,
+) -> OutputCheck {
+    let mut max_error = 0f32;
+    for (node, (expected, found)) in ids.iter().zip(expected.iter().zip(found)) {
+        let (Some(expected), Some(found)) = (expected, found) else {
+            continue;
+        };
+        if expected.len() != found.len() {
+            return OutputCheck::SizeMismatch {
+                node: *node,
+                expected: expected.len(),
+                found: found.len(),
+            };
+        }
+        let error = expected
+            .iter()
+            .zip(found)
+            .map(|(a, b)| match (a.is_nan(), b.is_nan()) {
+                (true, true) => 0.,
+                (false, false) if a == b => 0.,
+                (false, false) => (a - b).abs(),
+                _ => f32::INFINITY,
+            })
+            .fold(0f32, f32::max);
+        if error > tolerance {
+            return OutputCheck::Mismatch {
+                node: *node,
+                max_error: error,
+            };
+        }
+        max_error = max_error.max(error);
+    }
+    OutputCheck::Matched { max_error }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::op::{Exp2, Sin};
+
+    use super::*;
+    crate::test_imports!();
+
+    /// Replaces every exp2 with a sin
+    #[derive(Debug)]
+    struct SwapExp;
+
+    impl Compiler for SwapExp {
+        type Output = ();
+        fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+            for node in graph.graph.node_indices().collect::<Vec<_>>() {
+                if graph.try_get_op::<Exp2>(node).is_some() {
+                    *graph.graph.node_weight_mut(node).unwrap() = Box::new(Sin);
+                }
+            }
+        }
+    }
+
+    fn build(cx: &mut Graph) -> (GraphTensor<R1<3>>, GraphTensor<R1<3>>) {
+        let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+        let b = ((a * 1.).exp2() + (a + 0.)).retrieve();
+        (a, b)
+    }
+
+    #[test]
+    fn test_pipeline_report() {
+        let mut cx = Graph::new();
+        let (mut a, mut b) = build(&mut cx);
+        let report = cx.compile(
+            Pipeline::new()
+                .pass(RemoveUnusedNodes)
+                .pass(ArithmeticElimination)
+                .named_pass("cse", CSE)
+                .validate()
+                .check_outputs(1e-6),
+            (&mut a, &mut b),
+        );
+        assert!(report.baseline_error.is_none());
+        assert!(report.first_failure().is_none());
+        let names = report
+            .passes
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["RemoveUnusedNodes", "ArithmeticElimination", "cse"]);
+        for (prev, next) in report.passes.iter().zip(&report.passes[1..]) {
+            assert_eq!(prev.nodes_after, next.nodes_before);
+            assert_eq!(prev.edges_after, next.edges_before);
+        }
+        // Multiplying by 1 and adding 0 are removed
+        assert!(report.passes[1].nodes_after < report.passes[1].nodes_before);
+        assert!(matches!(
+            report.passes[2].check,
+            Some(OutputCheck::Matched { max_error }) if max_error == 0.
+        ));
+
+        // Checking doesn't leave outputs behind
+        assert!(cx.get_tensor_ref(b.id, 0).is_none());
+        cx.execute();
+        assert_close(&b.data(), &[1f32, 2., 3.].map(|i| i.exp2() + i));
+    }
+
+    #[test]
+    fn test_pipeline_failure() {
+        let mut cx = Graph::new();
+        let (mut a, mut b) = build(&mut cx);
+        let report = cx.compile(
+            Pipeline::new()
+                .pass(ArithmeticElimination)
+                .named_pass("broken", SwapExp)
+                .pass(CSE)
+                .check_outputs(1e-6),
+            (&mut a, &mut b),
+        );
+        assert_eq!(report.passes.len(), 2);
+        let failure = report.first_failure().unwrap();
+        assert_eq!(failure.name, "broken");
+        assert!(matches!(
+            failure.check,
+            Some(OutputCheck::Mismatch { node, .. }) if node == b.id
+        ));
+        assert!(report.to_string().contains("differs by"));
+
+        // Unset inputs can't be checked
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>();
+        let mut b = a.exp2().retrieve();
+        let report = cx.compile(Pipeline::new().pass(SwapExp).check_outputs(1e-6), &mut b);
+        assert!(matches!(
+            report.baseline_error,
+            Some(LuminalError::MissingInput { node, .. }) if node == a.id
+        ));
+        assert!(report.passes[0].check.is_none());
+    }
+
+    #[test]
+    fn test_pipeline_validate() {
+        /// Gives every exp2 a second input
+        #[derive(Debug)]
+        struct ExtraInput;
+
+        impl Compiler for ExtraInput {
+            type Output = ();
+            fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+                for node in graph.graph.node_indices().collect::<Vec<_>>() {
+                    if graph.try_get_op::<Exp2>(node).is_some() {
+                        let (source, _, shape) = graph.get_sources(node)[0];
+                        graph.add_edge(
+                            source,
+                            node,
+                            Dependency::Data {
+                                input_order: 1,
+                                output_order: 0,
+                                shape,
+                                dtype: DType::F32,
+                            },
+                        );
+                    }
+                }
+            }
+        }
+
+        let mut cx = Graph::new();
+        let (mut a, mut b) = build(&mut cx);
+        let report = cx.compile(
+            Pipeline::new().pass(ExtraInput).pass(CSE).validate(),
+            (&mut a, &mut b),
+        );
+        assert_eq!(report.passes.len(), 1);
+        assert!(matches!(
+            report.passes[0].diagnostics[0].kind,
+            DiagnosticKind::InputCount {
+                expected: 1,
+                found: 2
+            }
+        ));
+    }
+}
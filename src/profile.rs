@@ -0,0 +1,115 @@
+use std::{fmt::Write, path::Path, time::Duration};
+
+use itertools::Itertools;
+use rustc_hash::FxHashMap;
+
+use crate::prelude::*;
+
+/// Timing and size information recorded for a single node during a profiled execution
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeProfile {
+    pub node: NodeIndex,
+    /// The op's debug name, for instance `SumReduce(2)`
+    pub name: String,
+    /// The op's concrete type, used to group nodes together
+    pub op_type: &'static str,
+    /// When the op started, relative to the start of execution
+    pub start: Duration,
+    /// When the op finished, relative to the start of execution
+    pub end: Duration,
+    pub input_shapes: Vec<Vec<usize>>,
+    /// Total size of the op's outputs, for outputs whose size is known
+    pub output_bytes: usize,
+}
+
+impl NodeProfile {
+    /// How long the op took to run
+    pub fn duration(&self) -> Duration {
+        self.end - self.start
+    }
+}
+
+/// Aggregated timings for all nodes of one op type
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpSummary {
+    pub op_type: &'static str,
+    pub count: usize,
+    pub total: Duration,
+    pub output_bytes: usize,
+}
+
+/// The recorded profile of a graph execution
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExecutionProfile {
+    /// Executed nodes, in the order they ran
+    pub nodes: Vec<NodeProfile>,
+    /// Wall time of the whole execution
+    pub total: Duration,
+}
+
+impl ExecutionProfile {
+    /// Aggregate node timings by op type, slowest first
+    pub fn summary(&self) -> Vec<OpSummary> {
+        let mut summaries = FxHashMap::<&'static str, OpSummary>::default();
+        for node in &self.nodes {
+            let summary = summaries.entry(node.op_type).or_insert_with(|| OpSummary {
+                op_type: node.op_type,
+                count: 0,
+                total: Duration::ZERO,
+                output_bytes: 0,
+            });
+            summary.count += 1;
+            summary.total += node.duration();
+            summary.output_bytes += node.output_bytes;
+        }
+        summaries
+            .into_values()
+            .sorted_by(|a, b| b.total.cmp(&a.total).then(a.op_type.cmp(b.op_type)))
+            .collect()
+    }
+
+    /// Render the profile as Chrome trace event JSON, viewable in chrome://tracing or Perfetto
+    pub fn to_chrome_trace(&self) -> String {
+        let mut json = String::from("{\"traceEvents\":[");
+        for (i, node) in self.nodes.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write!(
+                json,
+                "{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":0,\"tid\":0,\"args\":{{\"node\":{},\"input_shapes\":\"{:?}\",\"output_bytes\":{}}}}}",
+                escape_json(&node.name),
+                escape_json(node.op_type),
+                node.start.as_secs_f64() * 1e6,
+                node.duration().as_secs_f64() * 1e6,
+                node.node.index(),
+                node.input_shapes,
+                node.output_bytes,
+            )
+            .unwrap();
+        }
+        json.push_str("],\"displayTimeUnit\":\"ms\"}");
+        json
+    }
+
+    /// Write the profile as a Chrome trace event JSON file
+    pub fn write_chrome_trace<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        std::fs::write(path, self.to_chrome_trace())
+    }
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -299,6 +299,29 @@ where
         }
         stack.pop().unwrap() as usize
     }
+    /// Compile the expression into an evaluator with one value for all variables, for expressions
+    /// that get evaluated many times
+    pub fn compile(&self) -> CompiledExpression {
+        let mut stack = vec![];
+        for term in &self.terms {
+            let node = match *term {
+                Term::Num(n) => Node::Num(n as i64),
+                Term::Var(_) => Node::Var,
+                _ => {
+                    let a = stack.pop().unwrap();
+                    let b = stack.pop().unwrap();
+                    match (&a, &b) {
+                        (Node::Num(a), Node::Num(b)) => {
+                            Node::Num(term.as_op().unwrap()(*a, *b).unwrap())
+                        }
+                        _ => Node::Op(*term, Box::new(a), Box::new(b)),
+                    }
+                }
+            };
+            stack.push(node);
+        }
+        CompiledExpression(build(stack.pop().unwrap()))
+    }
     /// Evaluate the expression given variables.
     pub fn exec(&self, variables: &FxHashMap<char, usize>) -> Option<usize> {
         self.exec_stack(variables, &mut Vec::new())
@@ -324,7 +347,7 @@ where
                 _ => {
                     let a = stack.pop().unwrap();
                     let b = stack.pop().unwrap();
-                    stack.push(term.as_op().unwrap()(a, b).unwrap());
+                    stack.push(term.as_op().unwrap()(a, b)?);
                 }
             }
         }
@@ -661,7 +684,6 @@ pub fn reduce_triples<S: ExpressionStorage>(
         }
     }
 
-    #[macro_export]
     macro_rules! unwrap_cont {
         ($i: expr) => {
             if let Some(s) = $i {
@@ -717,6 +739,73 @@ pub fn reduce_triples<S: ExpressionStorage>(
     expr
 }
 
+/// An expression of a single variable compiled into a tree of closures, so evaluating it doesn't
+/// re-interpret its terms
+pub struct CompiledExpression(Box<dyn Fn(i64) -> i64 + Send + Sync>);
+
+impl CompiledExpression {
+    pub fn exec(&self, value: usize) -> usize {
+        (self.0)(value as i64) as usize
+    }
+}
+
+impl Debug for CompiledExpression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CompiledExpression")
+    }
+}
+
+/// An expression tree, with constant subtrees already folded
+enum Node {
+    Num(i64),
+    Var,
+    Op(Term, Box<Node>, Box<Node>),
+}
+
+type Eval = Box<dyn Fn(i64) -> i64 + Send + Sync>;
+
+fn build(node: Node) -> Eval {
+    match node {
+        Node::Num(n) => Box::new(move |_| n),
+        Node::Var => Box::new(|x| x),
+        Node::Op(term, a, b) => match term {
+            Term::Add => binary(|a, b| a + b, *a, *b),
+            Term::Sub => binary(|a, b| a - b, *a, *b),
+            Term::Mul => binary(|a, b| a * b, *a, *b),
+            Term::Div => binary(|a, b| a / b, *a, *b),
+            Term::Mod => binary(|a, b| a % b, *a, *b),
+            Term::Max => binary(|a, b| a.max(b), *a, *b),
+            Term::Min => binary(|a, b| a.min(b), *a, *b),
+            Term::And => binary(|a, b| (a != 0 && b != 0) as i64, *a, *b),
+            Term::Or => binary(|a, b| (a != 0 || b != 0) as i64, *a, *b),
+            Term::Gte => binary(|a, b| (a >= b) as i64, *a, *b),
+            Term::Lt => binary(|a, b| (a < b) as i64, *a, *b),
+            Term::Num(_) | Term::Var(_) => unreachable!(),
+        },
+    }
+}
+
+/// Specialize the common shapes of index math, like `x / 4` and `(x / 4) % 3`, so they don't call
+/// through a closure for each side
+fn binary(f: impl Fn(i64, i64) -> i64 + Copy + Send + Sync + 'static, a: Node, b: Node) -> Eval {
+    match (a, b) {
+        (Node::Var, Node::Num(c)) => Box::new(move |x| f(x, c)),
+        (Node::Num(c), Node::Var) => Box::new(move |x| f(c, x)),
+        (a, Node::Num(c)) => {
+            let a = build(a);
+            Box::new(move |x| f(a(x), c))
+        }
+        (Node::Num(c), b) => {
+            let b = build(b);
+            Box::new(move |x| f(c, b(x)))
+        }
+        (a, b) => {
+            let (a, b) = (build(a), build(b));
+            Box::new(move |x| f(a(x), b(x)))
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::prelude::*;
@@ -743,4 +832,27 @@ mod tests {
         let new = main.substitute('x', sub);
         assert_eq!(new, (Expression::from('x') / 2) - 255);
     }
+
+    #[test]
+    fn test_compile() {
+        let mut shape = ShapeTracker::new(&[4.into(), 5.into(), 6.into()]);
+        shape.permute(&[2, 0, 1]);
+        shape.pad(&[
+            (1.into(), 0.into()),
+            (0.into(), 2.into()),
+            (0.into(), 1.into()),
+        ]);
+        let exprs = [
+            shape.index_expression(),
+            shape.valid_expression(),
+            (BigExpression::from('x') - 3).max(0).min(7) * 2,
+            BigExpression::from('x').gte(4) & BigExpression::from('x').lt(9) | 0,
+        ];
+        for expr in exprs {
+            let compiled = expr.compile();
+            for i in 0..200 {
+                assert_eq!(compiled.exec(i), expr.exec_single_var(i), "{expr:?} at {i}");
+            }
+        }
+    }
 }
@@ -3,6 +3,15 @@ use tinyvec::ArrayVec;
 
 use crate::prelude::*;
 
+/// Why a shape's dynamic dimensions couldn't be resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResolveError {
+    /// A dynamic dimension isn't set
+    Unset(char),
+    /// An expression overflows or divides by zero with the dimensions that are set
+    Undefined,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct ShapeTracker {
     pub dims: ArrayVec<[Expression; 6]>,
@@ -98,6 +107,31 @@ impl ShapeTracker {
             .collect()
     }
 
+    /// The physical offset and (size, stride) of each logical dim, if the view can be walked with
+    /// strides alone. Padded views need the valid expression, and all dims must already be known
+    pub fn strided_layout(&self) -> Option<(usize, Vec<(usize, usize)>)> {
+        if self.is_padded() {
+            return None;
+        }
+        let strides = self.unordered_strides();
+        let mut offset = 0;
+        let dims = self
+            .indexes
+            .into_iter()
+            .map(|i| {
+                let size = pad_mask_dim(self.dims[i], self.padding[i], self.mask[i]).to_usize()?;
+                let stride = if self.fake[i] {
+                    0
+                } else {
+                    strides[i].to_usize()?
+                };
+                offset += self.mask[i].0.to_usize()? * stride;
+                Some((size, stride))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some((offset, dims))
+    }
+
     /// Create an expression to translate logical indexes into physical indexes
     pub fn index_expression(&self) -> BigExpression {
         if !self.is_reshaped() {
@@ -276,17 +310,40 @@ impl ShapeTracker {
         dyn_dim_map: &FxHashMap<char, usize>,
         stack: &mut Vec<i64>,
     ) {
-        for d in self.dims.iter_mut() {
-            *d = d.exec_stack(dyn_dim_map, stack).unwrap().into();
+        match self.try_resolve_global_dyn_dims_stack(dyn_dim_map, stack) {
+            Ok(()) => {}
+            Err(ResolveError::Unset(dim)) => panic!("Dynamic dimension '{dim}' is not set"),
+            Err(ResolveError::Undefined) => panic!("Shape can't be evaluated"),
         }
-        for (a, b) in self.padding.iter_mut() {
-            *a = a.exec_stack(dyn_dim_map, stack).unwrap().into();
-            *b = b.exec_stack(dyn_dim_map, stack).unwrap().into();
+    }
+
+    /// Given a dyn dim map, resolve global dyn dims into known dims, returning why a dimension couldn't be resolved
+    pub fn try_resolve_global_dyn_dims_stack(
+        &mut self,
+        dyn_dim_map: &FxHashMap<char, usize>,
+        stack: &mut Vec<i64>,
+    ) -> Result<(), ResolveError> {
+        let mut resolve = |e: &mut Expression| -> Result<(), ResolveError> {
+            *e = e
+                .exec_stack(dyn_dim_map, stack)
+                .ok_or_else(|| {
+                    stack.clear();
+                    e.to_symbols()
+                        .into_iter()
+                        .find(|c| !dyn_dim_map.contains_key(c))
+                        .map_or(ResolveError::Undefined, ResolveError::Unset)
+                })?
+                .into();
+            Ok(())
+        };
+        for d in self.dims.iter_mut() {
+            resolve(d)?;
         }
-        for (a, b) in self.mask.iter_mut() {
-            *a = a.exec_stack(dyn_dim_map, stack).unwrap().into();
-            *b = b.exec_stack(dyn_dim_map, stack).unwrap().into();
+        for (a, b) in self.padding.iter_mut().chain(self.mask.iter_mut()) {
+            resolve(a)?;
+            resolve(b)?;
         }
+        Ok(())
     }
 
     pub fn is_sliced(&self) -> bool {
@@ -364,6 +421,8 @@ pub fn resolve_local_dyn_dims(a: &mut ShapeTracker, b: &mut ShapeTracker, defaul
 
 #[cfg(test)]
 mod tests {
+    use rustc_hash::FxHashMap;
+
     use crate::prelude::*;
     #[test]
     fn test_idx_expr() {
@@ -394,4 +453,20 @@ mod tests {
 
         println!("x0: {:?}", x0.shape.index_expression());
     }
+
+    #[test]
+    fn test_resolve_errors() {
+        let mut tracker = ShapeTracker::new(&[Expression::from('a'), Expression::from(6) / 'b']);
+        let mut dyn_map = FxHashMap::default();
+        assert_eq!(
+            tracker.try_resolve_global_dyn_dims_stack(&dyn_map, &mut vec![]),
+            Err(ResolveError::Unset('a'))
+        );
+        dyn_map.insert('a', 2);
+        dyn_map.insert('b', 0);
+        assert_eq!(
+            tracker.try_resolve_global_dyn_dims_stack(&dyn_map, &mut vec![]),
+            Err(ResolveError::Undefined)
+        );
+    }
 }
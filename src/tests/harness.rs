@@ -1,56 +1,287 @@
-use petgraph::stable_graph::NodeIndex;
-
-use crate::prelude::*;
-
-use super::{assert_close, assert_exact};
-
-// #[allow(clippy::type_complexity)]
-// pub fn test_compilers_exact(
-//     graphs: &[fn() -> (Graph, Vec<GraphTensor<()>>)],
-//     compilers: &[Box<dyn Compiler>],
-// ) {
-//     test_compilers(graphs, compilers, assert_exact)
-// }
-
-// #[allow(clippy::type_complexity)]
-// pub fn test_compilers_close(
-//     graphs: &[fn() -> (Graph, Vec<GraphTensor<()>>)],
-//     compilers: &[Box<dyn Compiler>],
-// ) {
-//     test_compilers(graphs, compilers, assert_close)
-// }
-
-// #[allow(clippy::type_complexity)]
-// fn test_compilers<F: Fn(&[f32], &[f32])>(
-//     graphs: &[fn() -> (Graph, Vec<GraphTensor<()>>)],
-//     compilers: &[Box<dyn Compiler>],
-//     condition: F,
-// ) {
-//     for create_graph in graphs {
-//         let (mut cx, result_tensors) = create_graph();
-//         cx.execute();
-//         let unopt_results = result_tensors
-//             .into_iter()
-//             .map(|mut gt| {
-//                 gt.graph_ref = &mut cx;
-//                 gt.data()
-//             })
-//             .collect::<Vec<_>>();
-
-//         for compiler in compilers {
-//             let (mut cx, result_tensors) = create_graph();
-//             compiler.compile(&mut cx);
-//             cx.execute();
-//             let opt_results = result_tensors
-//                 .into_iter()
-//                 .map(|mut gt| {
-//                     gt.graph_ref = &mut cx;
-//                     gt.data()
-//                 })
-//                 .collect::<Vec<_>>();
-//             for (a, b) in unopt_results.iter().zip(opt_results.iter()) {
-//                 condition(a, b);
-//             }
-//         }
-//     }
-// }
+use std::fmt::Display;
+
+use itertools::Itertools;
+use petgraph::{stable_graph::NodeIndex, Direction};
+
+use crate::{kernels::ViewReader, prelude::*};
+
+/// How [`compare_compiler`] checks a compiled graph against the unoptimized one
+#[derive(Debug, Clone, Copy)]
+pub struct HarnessConfig {
+    /// The largest absolute difference allowed between two elements. 0 requires exact equality
+    pub tolerance: f32,
+    /// Also retrieve every intermediate tensor, so a mismatch is reported at the first node that
+    /// diverges instead of at the output. This stops compilers from fusing away those nodes
+    pub intermediates: bool,
+}
+
+impl Default for HarnessConfig {
+    fn default() -> Self {
+        Self {
+            tolerance: 1e-3,
+            intermediates: false,
+        }
+    }
+}
+
+/// A node whose compiled result doesn't match the unoptimized graph
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    pub node: NodeIndex,
+    /// The debug name of the op in the unoptimized graph
+    pub name: String,
+    pub kind: DivergenceKind,
+}
+
+/// How a compiled result differs from the unoptimized one
+#[derive(Debug, Clone)]
+pub enum DivergenceKind {
+    /// The compiled graph has no value for a retrieved output
+    Missing,
+    /// The results have different numbers of elements
+    Length { expected: usize, found: usize },
+    /// An element differs by more than the tolerance
+    Value {
+        index: usize,
+        expected: f32,
+        found: f32,
+    },
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}): ", self.name, self.node.index())?;
+        match &self.kind {
+            DivergenceKind::Missing => write!(f, "no value after compiling"),
+            DivergenceKind::Length { expected, found } => {
+                write!(f, "expected {expected} elements, found {found}")
+            }
+            DivergenceKind::Value {
+                index,
+                expected,
+                found,
+            } => write!(f, "expected {expected} at index {index}, found {found}"),
+        }
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+/// Build a graph with `build` and check that compiling it with `compiler` doesn't change any of its
+/// retrieved outputs, panicking with the first divergence
+pub fn test_compiler<F: Fn(&mut Graph), C: Compiler>(build: F, compiler: C) {
+    test_compiler_with(build, compiler, HarnessConfig::default())
+}
+
+/// Like [`test_compiler`], with a custom tolerance or intermediate retrieval
+pub fn test_compiler_with<F: Fn(&mut Graph), C: Compiler>(
+    build: F,
+    compiler: C,
+    config: HarnessConfig,
+) {
+    if let Err(d) = compare_compiler(build, compiler, config) {
+        panic!("Compiled graph diverged at {d}");
+    }
+}
+
+/// Run the graph `build` creates unoptimized and again after compiling it with `compiler`, comparing
+/// every retrieved output. `build` must set all inputs and create the same graph each time it's called
+pub fn compare_compiler<F: Fn(&mut Graph), C: Compiler>(
+    build: F,
+    compiler: C,
+    config: HarnessConfig,
+) -> Result<(), Divergence> {
+    let mut reference = Graph::new();
+    build(&mut reference);
+    let outputs = reference
+        .to_retrieve
+        .iter()
+        .map(|(n, (_, sh))| (*n, *sh))
+        .sorted_by_key(|(n, _)| *n)
+        .collect_vec();
+    // Computed nodes in the order they run, skipping loads since they're the same in both graphs
+    let intermediates = if config.intermediates {
+        let order = reference.schedule().iter().map(|(n, _)| *n).collect_vec();
+        order
+            .into_iter()
+            .filter(|n| {
+                !outputs.iter().any(|(o, _)| o == n)
+                    && reference
+                        .graph
+                        .edges_directed(*n, Direction::Incoming)
+                        .next()
+                        .is_some()
+            })
+            .collect_vec()
+    } else {
+        vec![]
+    };
+    reference.keep_tensors(&intermediates);
+    reference.execute();
+    let expected = outputs
+        .iter()
+        .map(|(n, sh)| read(&reference, *n, sh).unwrap())
+        .chain(
+            intermediates
+                .iter()
+                .map(|n| read(&reference, *n, &ShapeTracker::new(&[])).unwrap()),
+        )
+        .collect_vec();
+
+    let mut graph = Graph::new();
+    build(&mut graph);
+    for (node, data) in intermediates.iter().zip(&expected[outputs.len()..]) {
+        graph.keep_tensors(*node);
+        graph
+            .to_retrieve
+            .insert(*node, (0, ShapeTracker::new(&[data.len().into()])));
+    }
+    let mut ids = outputs
+        .iter()
+        .map(|(n, _)| *n)
+        .chain(intermediates.iter().copied())
+        .collect_vec();
+    graph.compile(compiler, &mut ids);
+    graph.execute();
+
+    let divergence = |node: NodeIndex, kind| Divergence {
+        node,
+        name: format!("{:?}", reference.graph.node_weight(node).unwrap()),
+        kind,
+    };
+    let mut divergences = vec![];
+    for (i, (orig, new)) in outputs
+        .iter()
+        .map(|(n, _)| *n)
+        .chain(intermediates.iter().copied())
+        .zip(ids)
+        .enumerate()
+    {
+        let is_output = i < outputs.len();
+        let shape = outputs.get(i).map_or(ShapeTracker::new(&[]), |(_, sh)| *sh);
+        let Some(found) = read(&graph, new, &shape) else {
+            if is_output {
+                divergences.push(divergence(orig, DivergenceKind::Missing));
+            }
+            continue;
+        };
+        if found.len() != expected[i].len() {
+            // An intermediate a compiler replaced with a different tensor isn't comparable
+            if is_output || new == orig {
+                divergences.push(divergence(
+                    orig,
+                    DivergenceKind::Length {
+                        expected: expected[i].len(),
+                        found: found.len(),
+                    },
+                ));
+            }
+            continue;
+        }
+        if let Some((index, (e, f))) = expected[i]
+            .iter()
+            .zip(&found)
+            .enumerate()
+            .find(|(_, (e, f))| !close(**e, **f, config.tolerance))
+        {
+            divergences.push(divergence(
+                orig,
+                DivergenceKind::Value {
+                    index,
+                    expected: *e,
+                    found: *f,
+                },
+            ));
+        }
+    }
+    // Report the divergence that happens first in the unoptimized graph
+    let order = reference.schedule();
+    match divergences
+        .into_iter()
+        .min_by_key(|d| order.iter().position(|(n, _)| *n == d.node))
+    {
+        Some(d) => Err(d),
+        None => Ok(()),
+    }
+}
+
+/// Read output 0 of a node through a shape, or as stored if the shape has no dimensions
+fn read(graph: &Graph, node: NodeIndex, shape: &ShapeTracker) -> Option<Vec<f32>> {
+    let data = graph.get_tensor_ref(node, 0)?.as_elements::<f32>();
+    if shape.is_empty() || !shape.is_reshaped() {
+        return Some(data.into_owned());
+    }
+    let mut st = *shape;
+    st.resolve_global_dyn_dims(&graph.dyn_map);
+    let mut out = vec![0.; st.n_elements().to_usize().unwrap()];
+    ViewReader::new(&data, &st).read_into(0, &mut out);
+    Some(out)
+}
+
+fn close(a: f32, b: f32, tolerance: f32) -> bool {
+    a == b || (a.is_nan() && b.is_nan()) || (a - b).abs() <= tolerance
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::{op::Operator, tests::random_vec_rng};
+
+    fn mlp(cx: &mut Graph) {
+        let mut rng = StdRng::seed_from_u64(0);
+        let x = cx
+            .tensor::<(Dyn<'s'>, Const<4>)>()
+            .set_dyn(random_vec_rng(3 * 4, &mut rng), &[3, 4]);
+        let w = cx.tensor::<R2<4, 5>>().set(random_vec_rng(4 * 5, &mut rng));
+        x.matmul(w).relu().softmax::<Axis<1>>().retrieve();
+        (x * 2.0).sum_reduce::<_, Axis<0>>().retrieve();
+    }
+
+    #[test]
+    fn test_generic_compiler() {
+        test_compiler(mlp, GenericCompiler::default());
+        test_compiler_with(
+            mlp,
+            GenericCompiler::default(),
+            HarnessConfig {
+                tolerance: 1e-5,
+                intermediates: true,
+            },
+        );
+    }
+
+    /// Breaks every Exp2 by turning it into a Sin
+    #[derive(Debug, Default)]
+    struct SinForExp;
+
+    impl Compiler for SinForExp {
+        type Output = ();
+        fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+            for node in graph.graph.node_indices().collect_vec() {
+                if graph.check_node_type::<crate::op::Exp2>(node) {
+                    *graph.graph.node_weight_mut(node).unwrap() =
+                        Box::new(crate::op::Sin) as Box<dyn Operator>;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_reports_first_divergence() {
+        let err = compare_compiler(mlp, SinForExp, HarnessConfig::default()).unwrap_err();
+        assert!(matches!(err.kind, DivergenceKind::Value { .. }));
+
+        let err = compare_compiler(
+            mlp,
+            SinForExp,
+            HarnessConfig {
+                intermediates: true,
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err.name, "Exp2");
+    }
+}
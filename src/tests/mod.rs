@@ -2,13 +2,11 @@
 
 #[cfg(test)]
 mod dynamic;
-#[cfg(test)]
 pub mod harness;
 pub mod test_graphs;
-#[cfg(test)]
 mod test_prim;
 
-use std::fmt::Debug;
+use std::{collections::HashSet, fmt::Debug};
 
 use rand::{distributions::uniform::SampleRange, thread_rng, Rng};
 
@@ -70,6 +68,234 @@ fn test_shapes() {
     assert_exact(&b.data(), &[1., 3., 2., 4.]);
 }
 
+#[test]
+fn test_parallel_execution() {
+    let mut cx = Graph::new();
+    let x = cx.tensor::<R2<4, 8>>().set(random_vec(4 * 8));
+    let w_q = cx.tensor::<R2<8, 8>>().set(random_vec(8 * 8));
+    let w_k = cx.tensor::<R2<8, 8>>().set(random_vec(8 * 8));
+    let w_v = cx.tensor::<R2<8, 8>>().set(random_vec(8 * 8));
+    cx.keep_tensors((x, w_q, w_k, w_v));
+
+    // Q, K and V projections don't depend on each other
+    let q = x.matmul(w_q);
+    let k = x.matmul(w_k);
+    let v = x.matmul(w_v);
+    let out = q
+        .matmul(k.permute::<_, Axes2<1, 0>>())
+        .softmax::<Axis<1>>()
+        .matmul(v)
+        .retrieve();
+    let other = (k * v).sum_reduce::<_, Axis<0>>().retrieve();
+
+    cx.execute();
+    let (out_seq, other_seq) = (out.data(), other.data());
+    cx.drop_tensors((out, other));
+
+    // Safety: the primitive CPU ops and their tensors are plain data with no shared state
+    unsafe { cx.execute_parallel_threads(4) };
+    assert_exact(&out.data(), &out_seq);
+    assert_exact(&other.data(), &other_seq);
+
+    // Only kept and retrieved tensors should be left around
+    assert_eq!(
+        cx.tensors.keys().map(|(n, _)| *n).collect::<HashSet<_>>(),
+        HashSet::from([x.id, w_q.id, w_k.id, w_v.id, out.id, other.id])
+    );
+}
+
+#[test]
+fn test_try_execute_errors() {
+    let mut cx = Graph::new();
+    let a = cx.named_tensor::<R1<3>>("A");
+    let b = cx.named_tensor::<(Dyn<'s'>,)>("B");
+    let c = (a * 2.0).retrieve();
+    let d = (b + 1.0).retrieve();
+
+    let err = cx.try_execute().unwrap_err();
+    assert!(matches!(err, LuminalError::MissingInput { node, .. } if node == a.id || node == b.id));
+
+    a.set(vec![1.0, 2.0, 3.0]);
+    b.set_dyn(vec![1.0, 2.0], &[2]);
+    cx.dyn_map.clear();
+    assert!(matches!(
+        cx.try_execute().unwrap_err(),
+        LuminalError::UnresolvedDynDim { dim: 's', .. }
+    ));
+
+    cx.set_dyn_dim('s', 3);
+    assert_eq!(
+        cx.try_execute().unwrap_err(),
+        LuminalError::ShapeMismatch {
+            node: b.id,
+            name: "B Load".to_string(),
+            expected: 3,
+            found: 2
+        }
+    );
+
+    cx.set_dyn_dim('s', 2);
+    cx.try_execute().unwrap();
+    assert_exact(&c.data(), &[2.0, 4.0, 6.0]);
+    assert_exact(&d.data(), &[2.0, 3.0]);
+}
+
+#[test]
+fn test_try_execute_op_failure() {
+    #[derive(Debug)]
+    struct Failing;
+    impl Operator for Failing {
+        fn process(&mut self, _: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+            panic!("Out of memory")
+        }
+    }
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set(vec![1.0, 2.0, 3.0]);
+    let failing = cx.add_op(Failing).input(a.id, 0, a.shape).finish();
+    cx.keep_tensors(failing);
+
+    assert_eq!(
+        cx.try_execute().unwrap_err(),
+        LuminalError::OpFailure {
+            node: failing,
+            name: "Failing".to_string(),
+            message: "Out of memory".to_string()
+        }
+    );
+    // Nothing is left behind, so the graph can be ran again
+    assert!(cx.tensors.is_empty());
+}
+
+#[test]
+fn test_try_compile_cycle() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set(vec![1.0, 2.0, 3.0]);
+    let mut b = a.exp2().retrieve();
+    cx.add_edge(b.id, a.id, Dependency::Schedule);
+
+    assert!(matches!(
+        cx.try_compile(GenericCompiler::default(), &mut b),
+        Err(LuminalError::Cycle { .. })
+    ));
+    assert!(matches!(cx.try_execute(), Err(LuminalError::Cycle { .. })));
+}
+
+#[test]
+fn test_profiled_execution() {
+    let mut cx = Graph::new();
+    let a = cx
+        .named_tensor::<R2<2, 3>>("Input \"A\"")
+        .set(vec![1., 2., 3., 4., 5., 6.]);
+    let c = cx
+        .named_tensor::<R2<2, 3>>("Input C")
+        .set(vec![6., 5., 4., 3., 2., 1.]);
+    let b = (a.exp2() * c.exp2()).sum_reduce::<_, Axis<1>>().retrieve();
+
+    let profile = cx.execute_profiled();
+    let unprofiled = b.data();
+    assert_eq!(profile.nodes.len(), 6);
+    assert!(profile.nodes.iter().all(|n| n.end >= n.start));
+    assert!(profile.nodes.windows(2).all(|n| n[1].start >= n[0].end));
+
+    let reduce = profile.nodes.last().unwrap();
+    assert_eq!(reduce.node, b.id);
+    assert_eq!(reduce.name, "SumReduce(1)");
+    assert_eq!(
+        reduce.op_type,
+        std::any::type_name::<crate::op::SumReduce>()
+    );
+    assert_eq!(reduce.input_shapes, vec![vec![2, 3]]);
+    assert_eq!(reduce.output_bytes, 2 * std::mem::size_of::<f32>());
+
+    let summary = profile.summary();
+    let exp = summary
+        .iter()
+        .find(|s| s.op_type == std::any::type_name::<crate::op::Exp2>())
+        .unwrap();
+    assert_eq!(exp.count, 2);
+    assert_eq!(exp.output_bytes, 2 * 6 * std::mem::size_of::<f32>());
+    // Loads are grouped together even though their names differ
+    let loads = summary
+        .iter()
+        .find(|s| s.op_type == std::any::type_name::<Function>())
+        .unwrap();
+    assert_eq!(loads.count, 2);
+    assert_eq!(summary.iter().map(|s| s.count).sum::<usize>(), 6);
+
+    let trace = profile.to_chrome_trace();
+    assert!(trace.starts_with("{\"traceEvents\":[{"));
+    assert!(trace.contains("\"name\":\"Input \\\"A\\\" Load\""));
+    assert_eq!(trace.matches("\"ph\":\"X\"").count(), 6);
+
+    // Debug execution shouldn't need a terminal
+    b.drop();
+    cx.execute_debug();
+    assert_exact(&b.data(), &unprofiled);
+}
+
+#[test]
+fn test_dot_export() {
+    struct Layer {
+        weight: GraphTensor<R2<3, 3>>,
+    }
+    impl SerializeModule for Layer {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("weight", self.weight);
+        }
+    }
+    struct Model {
+        layers: (Layer, Layer),
+    }
+    impl SerializeModule for Model {
+        fn serialize(&self, s: &mut Serializer) {
+            s.module("layers", &self.layers);
+        }
+    }
+
+    let mut cx = Graph::new();
+    let model = Model {
+        layers: (
+            Layer {
+                weight: cx.named_tensor("Weight 0"),
+            },
+            Layer {
+                weight: cx.named_tensor("Weight 1"),
+            },
+        ),
+    };
+    let a = cx.named_tensor::<R2<1, 3>>("Input \"A\"");
+    let b = a.matmul(model.layers.0.weight).exp2();
+    let c = b.matmul(model.layers.1.weight);
+    cx.add_schedule_dependency(b.id, c.id);
+
+    let dot = cx.to_dot();
+    assert!(dot.starts_with("digraph {"));
+    assert!(dot.contains("label=\"Input \\\"A\\\" Load | "));
+    assert!(dot.contains(&format!(
+        "{} -> {} [ color=\"green\" style=\"dashed\" ]",
+        b.id.index(),
+        c.id.index()
+    )));
+    assert!(dot.contains("[ label=\"[1, 3]\" ]"));
+    assert!(!dot.contains("subgraph"));
+
+    let dot = cx.to_dot_with(&DotOptions::default().highlight(b).cluster_modules(&model));
+    assert!(dot.contains(&format!(
+        "{} [ style=\"filled\" fillcolor=\"yellow\" label=",
+        b.id.index()
+    )));
+    assert_eq!(dot.matches("subgraph cluster_").count(), 3);
+    assert!(dot.contains("label=\"layers\""));
+    assert!(dot.contains("label=\"layer0\""));
+    assert!(dot.contains("label=\"layer1\""));
+    assert!(!dot.contains("label=\"[1, 3]\""));
+
+    let path = std::env::temp_dir().join(format!("luminal_dot_{}.dot", std::process::id()));
+    cx.write_dot(&path).unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), cx.to_dot());
+    std::fs::remove_file(path).unwrap();
+}
+
 /// Ensure two arrays are nearly equal
 pub fn assert_close(a_vec: &[f32], b_vec: &[f32]) {
     assert_close_precision(a_vec, b_vec, 1e-3);
@@ -146,3 +372,376 @@ macro_rules! test_imports {
         };
     };
 }
+
+#[test]
+fn test_validate() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<2, 3>>().set(vec![1., 2., 3., 4., 5., 6.]);
+    let b = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+    let c = (a + b.expand::<_, Axis<0>>())
+        .sum_reduce::<_, Axis<1>>()
+        .retrieve();
+    assert!(cx.validate().is_empty());
+
+    // Binary op with mismatched shapes and a reduce along a missing axis
+    let add = cx
+        .add_op(crate::op::Add)
+        .input(a.id, 0, a.shape)
+        .input(b.id, 0, b.shape)
+        .finish();
+    let reduce = cx
+        .add_op(crate::op::MaxReduce(1))
+        .input(b.id, 0, b.shape)
+        .finish();
+    // Unary op with too many inputs and non-contiguous input orders
+    let exp = cx
+        .add_op(crate::op::Exp2)
+        .input(a.id, 0, a.shape)
+        .input(a.id, 0, a.shape)
+        .finish();
+    let sin = cx.add_op(crate::op::Sin).input(a.id, 0, a.shape).finish();
+    let edge = cx.graph.find_edge(a.id, sin).unwrap();
+    cx.graph.remove_edge(edge);
+    cx.add_edge(
+        a.id,
+        sin,
+        Dependency::Data {
+            input_order: 1,
+            output_order: 0,
+            shape: a.shape,
+            dtype: DType::F32,
+        },
+    );
+    cx.add_edge(c.id, a.id, Dependency::Schedule);
+    let d = cx.tensor::<R1<3>>().keep();
+    cx.graph.remove_node(d.id);
+
+    let diagnostics = cx.validate();
+    let find = |node: NodeIndex| diagnostics.iter().find(|d| d.node == node).unwrap();
+    assert!(matches!(
+        find(exp).kind,
+        DiagnosticKind::InputCount {
+            expected: 1,
+            found: 2
+        }
+    ));
+    assert_eq!(find(exp).name, "Exp2");
+    assert!(matches!(
+        &find(sin).kind,
+        DiagnosticKind::InputOrder { orders } if orders == &[1]
+    ));
+    assert!(matches!(find(d.id).kind, DiagnosticKind::MissingNode));
+    assert!(diagnostics.iter().any(|d| matches!(
+        &d.kind,
+        DiagnosticKind::Cycle { nodes } if nodes.contains(&a.id) && nodes.contains(&c.id)
+    )));
+    assert!(matches!(
+        find(add).kind,
+        DiagnosticKind::ShapeMismatch { .. }
+    ));
+    assert!(matches!(
+        find(reduce).kind,
+        DiagnosticKind::ReduceAxis { axis: 1, rank: 1 }
+    ));
+}
+
+#[test]
+fn test_dtypes() {
+    let mut cx = Graph::new();
+    // Integers above 2^24 can't be represented exactly as f32
+    let ids = cx.tensor::<R1<2>>().set(vec![16_777_217i32, 3]);
+    let doubled = (ids + ids).retrieve();
+    // Constants take the type of the tensor they're combined with
+    let incremented = (ids + 1.).retrieve();
+    let halved = (ids * 0.5).retrieve();
+    let half = cx.tensor::<R1<3>>().set(vec![
+        f16::from_f32(1.),
+        f16::from_f32(2.),
+        f16::from_f32(3.),
+    ]);
+    let half_exp = half.exp2().retrieve();
+    let half_plus = (half + 0.5).retrieve();
+    let full = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+    let mixed = (half_exp * full).retrieve();
+    let cast = half_exp.cast(DType::F32).retrieve();
+    let mask = cx.tensor::<R1<3>>().set(vec![true, false, true]);
+    let masked = (mask.cast(DType::F32) * full).retrieve();
+    let late = cx.named_tensor::<R1<2>>("Late").set_dtype(DType::U8);
+    let late_sum = late.sum_reduce::<_, Axis<0>>().retrieve();
+    late.set(vec![200u8, 50]);
+
+    assert_eq!(doubled.dtype(), DType::I32);
+    assert_eq!(incremented.dtype(), DType::I32);
+    assert_eq!(halved.dtype(), DType::F32);
+    assert_eq!(half_plus.dtype(), DType::F16);
+    assert_eq!(half_exp.dtype(), DType::F16);
+    assert_eq!(mixed.dtype(), DType::F32);
+    assert_eq!(cast.dtype(), DType::F32);
+    assert_eq!(late_sum.dtype(), DType::U8);
+    cx.execute();
+
+    assert_eq!(doubled.typed_data::<i32>(), vec![33_554_434, 6]);
+    assert_eq!(incremented.typed_data::<i32>(), vec![16_777_218, 4]);
+    assert!(cx
+        .get_tensor_ref(incremented.id, 0)
+        .unwrap()
+        .is::<Vec<i32>>());
+    assert_exact(&halved.data(), &[8_388_608., 1.5]);
+    assert!(cx.get_tensor_ref(half_plus.id, 0).unwrap().is::<Vec<f16>>());
+    assert!(cx.get_tensor_ref(half_exp.id, 0).unwrap().is::<Vec<f16>>());
+    assert_exact(&half_exp.data(), &[2., 4., 8.]);
+    assert_exact(&mixed.data(), &[2., 8., 24.]);
+    assert!(cx.get_tensor_ref(cast.id, 0).unwrap().is::<Vec<f32>>());
+    assert_exact(&masked.data(), &[1., 0., 3.]);
+    assert_eq!(late_sum.typed_data::<u8>(), vec![250]);
+    assert!(cx
+        .graph
+        .edges_directed(half_exp.id, petgraph::Direction::Incoming)
+        .all(|e| e.weight().dtype() == Some(DType::F16)));
+}
+
+#[test]
+fn test_bool_arithmetic() {
+    let mut cx = Graph::new();
+    let mask = cx.tensor::<R1<4>>().set(vec![true, true, false, true]);
+    // Summing a mask counts it rather than or-ing it
+    let count = mask.sum_reduce::<_, Axis<0>>().retrieve();
+    let doubled = (mask + mask).retrieve();
+    let both = (mask * mask).retrieve();
+    let max = mask.max_reduce::<_, Axis<0>>().retrieve();
+
+    assert_eq!(count.dtype(), DType::I32);
+    assert_eq!(doubled.dtype(), DType::I32);
+    assert_eq!(both.dtype(), DType::I32);
+    assert_eq!(max.dtype(), DType::Bool);
+    cx.execute();
+
+    assert_eq!(count.typed_data::<i32>(), vec![3]);
+    assert_eq!(doubled.typed_data::<i32>(), vec![2, 2, 0, 2]);
+    assert_eq!(both.typed_data::<i32>(), vec![1, 1, 0, 1]);
+    assert_eq!(max.typed_data::<bool>(), vec![true]);
+}
+
+#[test]
+fn test_mmap_data() {
+    let path = std::env::temp_dir().join(format!("luminal_mmap_{}.bin", uuid::Uuid::new_v4()));
+    let mut bytes = [1f32, 2., 3., 4.]
+        .iter()
+        .flat_map(|f| f.to_le_bytes())
+        .collect::<Vec<_>>();
+    bytes.extend(
+        [0.5f32, 1.5]
+            .iter()
+            .flat_map(|f| f16::from_f32(*f).to_le_bytes()),
+    );
+    std::fs::write(&path, bytes).unwrap();
+    // Safety: nothing writes to the file until it's removed
+    let map = unsafe { map_file(&path) }.unwrap();
+
+    let mut cx = Graph::new();
+    let a = cx
+        .tensor::<R1<4>>()
+        .set(MmapData::new(map.clone(), 0, 4, DType::F32))
+        .retrieve();
+    let b = (a * 2.).retrieve();
+    let half = cx
+        .tensor::<R1<2>>()
+        .set(MmapData::new(map.clone(), 16, 2, DType::F16));
+    let half_sum = half.sum_reduce::<_, Axis<0>>().retrieve();
+    assert_eq!(half.dtype(), DType::F16);
+    cx.execute();
+
+    // Inputs are handed to ops as views into the map rather than copies
+    let a_data = cx.get_tensor_ref(a.id, 0).unwrap();
+    assert!(a_data.is::<MmapData>());
+    assert_eq!(
+        a_data.as_slice::<f32>().unwrap().as_ptr() as *const u8,
+        map.as_ptr()
+    );
+    assert_exact(&a.data(), &[1., 2., 3., 4.]);
+    assert_exact(&b.data(), &[2., 4., 6., 8.]);
+    assert_eq!(half_sum.typed_data::<f16>(), vec![f16::from_f32(2.)]);
+
+    // Views that can't be read in place are errors
+    assert_eq!(
+        MmapData::try_new(map.clone(), 16, 4, DType::F32).unwrap_err(),
+        MmapError::OutOfBounds {
+            offset: 16,
+            n_bytes: 16,
+            map_len: 20
+        }
+    );
+    assert_eq!(
+        MmapData::try_new(map.clone(), 2, 1, DType::F32).unwrap_err(),
+        MmapError::Misaligned {
+            offset: 2,
+            dtype: DType::F32
+        }
+    );
+    assert_eq!(
+        MmapData::try_new(map.clone(), 0, 4, DType::Bool).unwrap_err(),
+        MmapError::InvalidBool { offset: 0 }
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_execute_for() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+    let b = cx.tensor::<R1<3>>().set(vec![4., 5., 6.]);
+    let loss = (a * b).sum_reduce::<_, Axis<0>>().retrieve();
+    let update = (a + b).exp2().retrieve();
+
+    cx.execute_for(loss);
+    assert_exact(&loss.data(), &[32.]);
+    assert!(cx.get_tensor_ref(update.id, 0).is_none());
+
+    // The partial schedule gets reused for the same targets
+    cx.drop_tensors(loss);
+    cx.execute_for(loss);
+    assert_exact(&loss.data(), &[32.]);
+    assert_eq!(cx.partial_schedules.len(), 1);
+
+    cx.drop_tensors(loss);
+    cx.execute_for((loss, update));
+    assert_exact(&loss.data(), &[32.]);
+    assert_exact(&update.data(), &[32., 128., 512.]);
+    assert_eq!(cx.partial_schedules.len(), 2);
+}
+
+#[test]
+fn test_safetensors() {
+    struct Model {
+        weight: GraphTensor<R2<2, 3>>,
+        bias: GraphTensor<R1<3>>,
+    }
+    impl SerializeModule for Model {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("layer/weight", self.weight);
+            s.tensor("bias", self.bias);
+        }
+    }
+    struct Transposed(GraphTensor<R2<3, 2>>);
+    impl SerializeModule for Transposed {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("layer/weight", self.0);
+        }
+    }
+    fn model(cx: &mut Graph) -> Model {
+        Model {
+            weight: cx.named_tensor("Weight"),
+            bias: cx.named_tensor("Bias"),
+        }
+    }
+    let path = std::env::temp_dir().join(format!("luminal_{}.safetensors", uuid::Uuid::new_v4()));
+
+    let mut cx = Graph::new();
+    let src = model(&mut cx);
+    src.weight.set(vec![1., 2., 3., 4., 5., 6.]).keep();
+    src.bias
+        .set(vec![f16::from_f32(0.5), f16::ZERO, f16::ONE])
+        .keep();
+    // Parameters without data can't be saved
+    assert!(matches!(
+        save_safetensors(&src, &cx, &path),
+        Err(CheckpointError::MissingData { .. })
+    ));
+    cx.execute();
+    save_safetensors(&src, &cx, &path).unwrap();
+
+    let mut cx = Graph::new();
+    let dest = model(&mut cx);
+    let out = (dest.weight.sum_reduce::<_, Axis<0>>() + dest.bias).retrieve();
+    load_safetensors(&dest, &mut cx, &path).unwrap();
+    assert_eq!(dest.bias.dtype(), DType::F16);
+    cx.execute();
+    assert_exact(&out.data(), &[5.5, 7., 10.]);
+
+    // Names can be remapped, and skipped parameters are left alone
+    let mut cx = Graph::new();
+    let dest = model(&mut cx);
+    let weight = dest.weight.retrieve();
+    load_safetensors_with(&dest, &mut cx, &path, |name| {
+        (name != "bias").then(|| name.to_string())
+    })
+    .unwrap();
+    assert_eq!(dest.bias.dtype(), DType::F32);
+    cx.execute_for(weight);
+    assert_exact(&weight.data(), &[1., 2., 3., 4., 5., 6.]);
+    assert!(matches!(
+        load_safetensors_with(&dest, &mut cx, &path, |name| Some(name.replace('/', "."))),
+        Err(CheckpointError::MissingTensor { name }) if name == "layer.weight"
+    ));
+
+    // Shapes are checked against the parameters
+    let mut cx = Graph::new();
+    let err = load_safetensors(Transposed(cx.named_tensor("Weight")), &mut cx, &path);
+    assert!(matches!(
+        err,
+        Err(CheckpointError::ShapeMismatch { found, .. }) if found == vec![2, 3]
+    ));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_constant_folding() {
+    let folded = |cx: &Graph| {
+        cx.graph
+            .node_weights()
+            .filter(|o| folded_tensors(o.as_ref()).is_some())
+            .count()
+    };
+
+    // Masks only depend on constants
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<3, 3>>().set(vec![1.; 9]);
+    let mut b = (a * cx.triu::<Const<3>>(1)).retrieve();
+    cx.compile(ConstantFolding::default(), &mut b);
+    assert_eq!(folded(&cx), 1);
+    assert!(!cx.graph.node_weights().any(|o| o.as_any().is::<LessThan>()));
+    cx.execute();
+    assert_exact(&b.data(), &[0., 1., 1., 0., 0., 1., 0., 0., 0.]);
+
+    // Weights are folded when they're given
+    let mut cx = Graph::new();
+    let w = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+    let x = cx.tensor::<R1<3>>().set(vec![1., 1., 2.]);
+    let mut out = (w.exp2().sqrt() * x).retrieve();
+    cx.compile(ConstantFolding::default(), &mut out);
+    assert_eq!(folded(&cx), 0);
+    cx.compile(ConstantFolding::new(w), &mut out);
+    assert_eq!(folded(&cx), 1);
+    assert!(!cx.graph.node_weights().any(|o| o.as_any().is::<Exp2>()));
+    cx.execute();
+    assert_close(&out.data(), &[2f32.sqrt(), 2., 8f32.sqrt() * 2.]);
+
+    // Dynamic dimensions need values to fold
+    let mut cx = Graph::new();
+    let mut r = cx.arange::<Dyn<'s'>>().retrieve();
+    cx.compile(ConstantFolding::default(), &mut r);
+    assert_eq!(folded(&cx), 0);
+    cx.compile(
+        ConstantFolding::default().with_dyn_map([('s', 4)].into_iter().collect()),
+        &mut r,
+    );
+    assert_eq!(folded(&cx), 1);
+    cx.execute();
+    assert_exact(&r.data(), &[0., 1., 2., 3.]);
+
+    // Only primitive ops are evaluated
+    #[derive(Debug)]
+    struct Unfoldable;
+    impl Operator for Unfoldable {
+        fn process(&mut self, _: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+            panic!("Custom ops shouldn't be folded")
+        }
+    }
+    let mut cx = Graph::new();
+    let c = cx.constant(2.);
+    let mut custom = cx.add_op(Unfoldable).input(c.id, 0, c.shape).finish();
+    cx.keep_tensors(custom);
+    cx.compile(ConstantFolding::default(), &mut custom);
+    assert!(cx.check_node_type::<Unfoldable>(custom));
+    assert_eq!(folded(&cx), 0);
+}
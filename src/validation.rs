@@ -0,0 +1,206 @@
+use std::fmt::{Debug, Display};
+
+use itertools::Itertools;
+use petgraph::{algo::tarjan_scc, visit::EdgeRef, Direction};
+
+use crate::{op, prelude::*};
+
+/// A problem found in a graph by [`Graph::validate`]
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub node: NodeIndex,
+    /// The op's debug name, or `<removed>` if the node no longer exists
+    pub name: String,
+    pub kind: DiagnosticKind,
+}
+
+/// The kind of problem a [`Diagnostic`] describes
+#[derive(Debug, Clone)]
+pub enum DiagnosticKind {
+    /// The op has a different number of inputs than it expects
+    InputCount { expected: usize, found: usize },
+    /// The op's input orders don't count up from 0 without gaps or duplicates
+    InputOrder { orders: Vec<u8> },
+    /// The inputs to a binary op don't have the same shape
+    ShapeMismatch {
+        lhs: Vec<BigExpression>,
+        rhs: Vec<BigExpression>,
+    },
+    /// A reduce op reduces along an axis its input doesn't have
+    ReduceAxis { axis: usize, rank: usize },
+    /// A node marked to be retrieved or kept has been removed from the graph
+    MissingNode,
+    /// The node is part of a cycle going through these nodes
+    Cycle { nodes: Vec<NodeIndex> },
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}): ", self.name, self.node.index())?;
+        match &self.kind {
+            DiagnosticKind::InputCount { expected, found } => {
+                write!(f, "expected {expected} inputs, found {found}")
+            }
+            DiagnosticKind::InputOrder { orders } => {
+                write!(f, "input orders {orders:?} are not contiguous")
+            }
+            DiagnosticKind::ShapeMismatch { lhs, rhs } => {
+                write!(f, "input shapes {lhs:?} and {rhs:?} don't match")
+            }
+            DiagnosticKind::ReduceAxis { axis, rank } => {
+                write!(f, "reduce axis {axis} is out of range for rank {rank}")
+            }
+            DiagnosticKind::MissingNode => write!(f, "node is retrieved or kept but was removed"),
+            DiagnosticKind::Cycle { nodes } => write!(
+                f,
+                "cycle through nodes {:?}",
+                nodes.iter().map(|n| n.index()).collect::<Vec<_>>()
+            ),
+        }
+    }
+}
+
+impl Graph {
+    /// Check the graph for structural problems, such as wrong input counts, mismatched shapes or cycles. Returns an empty list if the graph is valid.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = vec![];
+        let diagnostic = |node: NodeIndex, kind| Diagnostic {
+            node,
+            name: format!("{:?}", self.graph.node_weight(node).unwrap()),
+            kind,
+        };
+
+        for node in self.graph.node_indices() {
+            let op = self.graph.node_weight(node).unwrap().as_any();
+            let mut orders = self
+                .graph
+                .edges_directed(node, Direction::Incoming)
+                .filter_map(|e| e.weight().as_data().map(|(i, _, _)| i))
+                .collect::<Vec<_>>();
+            orders.sort_unstable();
+            if orders.iter().enumerate().any(|(i, o)| *o as usize != i) {
+                diagnostics.push(diagnostic(node, DiagnosticKind::InputOrder { orders }));
+                continue;
+            }
+
+            let expected = if op.is::<op::Constant>() {
+                Some(0)
+            } else if op.is::<op::Contiguous>()
+                || op.is::<op::Log2>()
+                || op.is::<op::Exp2>()
+                || op.is::<op::Sin>()
+                || op.is::<op::Recip>()
+                || op.is::<op::Sqrt>()
+                || op.is::<op::SumReduce>()
+                || op.is::<op::MaxReduce>()
+            {
+                Some(1)
+            } else if op.is::<op::Add>()
+                || op.is::<op::Mul>()
+                || op.is::<op::Mod>()
+                || op.is::<op::LessThan>()
+            {
+                Some(2)
+            } else {
+                None
+            };
+            if let Some(expected) = expected {
+                if expected != orders.len() {
+                    diagnostics.push(diagnostic(
+                        node,
+                        DiagnosticKind::InputCount {
+                            expected,
+                            found: orders.len(),
+                        },
+                    ));
+                    continue;
+                }
+            }
+
+            let sources = self.get_sources(node);
+            if expected == Some(2) {
+                let (lhs, rhs) = (sources[0].2.shape(), sources[1].2.shape());
+                if !shapes_agree(&lhs, &rhs) {
+                    diagnostics.push(diagnostic(node, DiagnosticKind::ShapeMismatch { lhs, rhs }));
+                }
+            }
+            let axis = if let Some(op::SumReduce(axis)) = op.downcast_ref() {
+                Some(*axis)
+            } else if let Some(op::MaxReduce(axis)) = op.downcast_ref() {
+                Some(*axis)
+            } else {
+                None
+            };
+            if let Some(axis) = axis {
+                let rank = sources[0].2.len();
+                if axis >= rank {
+                    diagnostics.push(diagnostic(node, DiagnosticKind::ReduceAxis { axis, rank }));
+                }
+            }
+        }
+
+        for node in self
+            .to_retrieve
+            .keys()
+            .chain(self.no_delete.iter())
+            .unique()
+        {
+            if !self.graph.contains_node(*node) {
+                diagnostics.push(Diagnostic {
+                    node: *node,
+                    name: "<removed>".to_string(),
+                    kind: DiagnosticKind::MissingNode,
+                });
+            }
+        }
+
+        for mut nodes in tarjan_scc(&self.graph) {
+            if nodes.len() > 1
+                || self
+                    .graph
+                    .edges_directed(nodes[0], Direction::Outgoing)
+                    .any(|e| e.target() == nodes[0])
+            {
+                nodes.sort();
+                diagnostics.push(diagnostic(nodes[0], DiagnosticKind::Cycle { nodes }));
+            }
+        }
+        diagnostics
+    }
+}
+
+/// Check if two shapes agree, treating dimensions that can't be resolved without dyn dims as matching
+fn shapes_agree(lhs: &[BigExpression], rhs: &[BigExpression]) -> bool {
+    lhs.len() == rhs.len()
+        && lhs
+            .iter()
+            .zip(rhs)
+            .all(|(a, b)| match (a.to_usize(), b.to_usize()) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            })
+}
+
+/// Wrap this around a compiler to validate the graph after it runs, panicking if the compiler left the graph broken
+#[derive(Debug, Default)]
+pub struct Validated<C: Compiler + Debug>(pub C);
+
+impl<C: Compiler + Debug> Compiler for Validated<C> {
+    type Output = C::Output;
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, remap: T) -> C::Output {
+        let output = self.0.compile(graph, remap);
+        let diagnostics = graph.validate();
+        if !diagnostics.is_empty() {
+            panic!(
+                "{:?} left the graph invalid:\n{}",
+                self.0,
+                diagnostics
+                    .iter()
+                    .map(|d| d.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
+        }
+        output
+    }
+}